@@ -0,0 +1,159 @@
+//! Content checks for Relay artifacts.
+//!
+//! A safety net that works without a relay config: files that weren't
+//! generated by relay-compiler are refused, and artifacts still carrying
+//! Relay's own deduplication (`var v0 = ...` hoisting inside an IIFE) are
+//! inlined first, or refused when their shape isn't recognized. Artifacts
+//! still using the legacy `_xxxxxxxx` references are refused until migrated.
+
+use crate::inline::inline_relay_dedup;
+use crate::legacy::first_legacy_ref;
+use crate::scope::header_contains;
+use std::fmt;
+
+/// Marker relay-compiler puts in every artifact's header comment
+pub const GENERATED_MARKER: &str = "@generated";
+
+/// Why an artifact's contents can't be processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ArtifactProblem {
+	/// `var v0 = ...` hoisting or an IIFE-wrapped `node` that can't be
+	/// inlined: Relay's built-in deduplication is still enabled
+	RelayDedup,
+	/// No `@generated` header: not written by relay-compiler
+	NotGenerated,
+	/// Refers to legacy `_xxxxxxxx` structures (see [`crate::legacy`])
+	LegacyRefs,
+}
+
+impl fmt::Display for ArtifactProblem {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ArtifactProblem::RelayDedup => {
+				write!(
+					f,
+					"already deduplicated by Relay (v0 variables) in a shape that can't be inlined"
+				)
+			}
+			ArtifactProblem::NotGenerated => write!(f, "no {} header", GENERATED_MARKER),
+			ArtifactProblem::LegacyRefs => write!(f, "uses legacy _xxxxxxxx references"),
+		}
+	}
+}
+
+/// Check an artifact's contents
+pub fn check_artifact(content: &str) -> Option<ArtifactProblem> {
+	if !header_contains(content, GENERATED_MARKER) {
+		return Some(ArtifactProblem::NotGenerated);
+	}
+	if has_relay_dedup(content) {
+		return Some(ArtifactProblem::RelayDedup);
+	}
+	if first_legacy_ref(content).is_some() {
+		return Some(ArtifactProblem::LegacyRefs);
+	}
+	None
+}
+
+/// Check an artifact and inline Relay's own deduplication, if it has any
+pub fn prepare_artifact(content: String) -> Result<String, ArtifactProblem> {
+	match check_artifact(&content) {
+		None => Ok(content),
+		Some(ArtifactProblem::RelayDedup) => {
+			inline_relay_dedup(&content).ok_or(ArtifactProblem::RelayDedup)
+		}
+		Some(problem) => Err(problem),
+	}
+}
+
+/// Whether the `node` literal was deduplicated by relay-compiler itself
+pub fn has_relay_dedup(content: &str) -> bool {
+	relay_dedup_offset(content).is_some()
+}
+
+/// Byte offset of the first line showing Relay's own deduplication
+pub fn relay_dedup_offset(content: &str) -> Option<usize> {
+	let mut offset = 0;
+	for line in content.split_inclusive('\n') {
+		let trimmed = line.trim_start();
+		let is_node = trimmed.starts_with("var node") || trimmed.starts_with("const node");
+		if (is_node && trimmed.contains("(function(){")) || trimmed.starts_with("var v0 =") {
+			return Some(offset + line.len() - trimmed.len());
+		}
+		offset += line.len();
+	}
+	None
+}
+
+/// Byte offset a problem is reported at: the dedup output, or the header
+pub fn problem_offset(content: &str, problem: ArtifactProblem) -> usize {
+	match problem {
+		ArtifactProblem::RelayDedup => relay_dedup_offset(content).unwrap_or(0),
+		ArtifactProblem::NotGenerated => 0,
+		ArtifactProblem::LegacyRefs => first_legacy_ref(content).unwrap_or(0),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_check_artifact() {
+		let header = "/**\n * @generated SignedSource<<abc>>\n */\n";
+
+		assert_eq!(
+			check_artifact(&format!(
+				"{}const node = {{\"kind\": \"Fragment\"}};",
+				header
+			)),
+			None
+		);
+		assert_eq!(
+			check_artifact(&format!(
+				"{}var node/*: ConcreteRequest*/ = (function(){{\nvar v0 = [];\nreturn {{\"a\": v0}};\n}})();",
+				header
+			)),
+			Some(ArtifactProblem::RelayDedup)
+		);
+		assert_eq!(
+			check_artifact("export const handwritten = { \"kind\": \"Fragment\" };"),
+			Some(ArtifactProblem::NotGenerated)
+		);
+		assert_eq!(
+			check_artifact(&format!(
+				"{}import {{ _1a2b3c4d }} from \"./__shared\";\nconst node = {{\"a\": _1a2b3c4d}};",
+				header
+			)),
+			Some(ArtifactProblem::LegacyRefs)
+		);
+		// The marker only counts in the header
+		assert_eq!(
+			check_artifact("const node = {\"text\": \"@generated\"};"),
+			Some(ArtifactProblem::NotGenerated)
+		);
+	}
+
+	#[test]
+	fn test_prepare_artifact() {
+		let header = "/**\n * @generated SignedSource<<abc>>\n */\n";
+
+		let prepared = prepare_artifact(format!(
+			"{}var node = (function(){{\nvar v0 = [\"a\"];\nreturn {{\"b\": (v0/*: any*/), \"c\": (v0/*: any*/)}};\n}})();",
+			header
+		));
+		assert_eq!(
+			prepared,
+			Ok(format!(
+				"{}var node = {{\"b\": [\"a\"], \"c\": [\"a\"]}};",
+				header
+			))
+		);
+
+		let unrecognized = format!("{}var node = (function(){{\nvar v0 = [];\n", header);
+		assert_eq!(
+			prepare_artifact(unrecognized),
+			Err(ArtifactProblem::RelayDedup)
+		);
+	}
+}
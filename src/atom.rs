@@ -0,0 +1,460 @@
+//! Module references inside node literals.
+//!
+//! Relay artifacts point at other modules from inside their literals:
+//! `require('./FooRefetchQuery.graphql')` for `@refetchable` and `@match`,
+//! resolver modules for `@relay_resolver` and providers for provided
+//! variables, or the equivalent imported bindings with `eagerEsModules`.
+//! Each reference becomes an atom keyed by the module it resolves to, named
+//! `m_XXXXXXXX` in normalized forms, so literals containing them can still be
+//! matched across artifacts in different directories. The shared module
+//! declares every atom its structures use, except atoms pointing back at the
+//! artifacts or modules importing it, which would be require cycles
+//! ([`Atom::is_cyclic`]).
+
+use crate::hash_string;
+use crate::language::ModuleFormat;
+use crate::lexer::{tokenize, unquote, Token, TokenKind};
+use crate::naming::is_ref_name;
+use crate::statement::statements;
+use crate::writer::relative_specifier;
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+
+/// Prefix of atom names in normalized forms
+const ATOM_PREFIX: &str = "m_";
+
+/// Extensions tried for specifiers that leave them out (`./Foo.graphql`)
+const MODULE_EXTENSIONS: &[&str] = &["ts", "tsx", "js", "jsx", "mjs", "cjs"];
+
+/// Where a referenced module lives
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ModulePath {
+	/// A relative specifier, resolved to an absolute path (extension as written)
+	File(PathBuf),
+	/// A bare package specifier (`relay-runtime`)
+	Package(String),
+}
+
+/// What is taken from the module
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Binding {
+	/// `require('m')`
+	Require,
+	/// `require('m').name`
+	RequireMember(String),
+	/// `import x from 'm'`
+	Default,
+	/// `import { name as x } from 'm'`
+	Named(String),
+	/// `import * as x from 'm'`
+	Namespace,
+}
+
+/// A reference to another module
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Atom {
+	pub module: ModulePath,
+	pub binding: Binding,
+	/// Machine-independent identity: the module relative to the artifact root
+	key: String,
+}
+
+impl Atom {
+	/// Resolve `specifier` as written in the artifact at `artifact_dir`
+	pub fn new(specifier: &str, binding: Binding, artifact_dir: &Path, root: &Path) -> Self {
+		let (module, module_key) = if specifier.starts_with("./") || specifier.starts_with("../") {
+			let path = normalize_path(&absolute(artifact_dir).join(specifier));
+			let key = relative_specifier(&absolute(root), &path);
+			(ModulePath::File(path), key)
+		} else {
+			(
+				ModulePath::Package(specifier.to_string()),
+				specifier.to_string(),
+			)
+		};
+		let key = match &binding {
+			Binding::Require => format!("require:{}", module_key),
+			Binding::RequireMember(name) => format!("require:{}.{}", module_key, name),
+			Binding::Default => format!("default:{}", module_key),
+			Binding::Named(name) => format!("named:{}.{}", module_key, name),
+			Binding::Namespace => format!("namespace:{}", module_key),
+		};
+		Self {
+			module,
+			binding,
+			key,
+		}
+	}
+
+	/// Name used for this atom in normalized forms and the shared module
+	pub fn name(&self) -> String {
+		format!("{}{}", ATOM_PREFIX, &hash_string(&self.key)[..8])
+	}
+
+	/// Declaration of the atom in a shared module at `shared_dir`
+	pub fn declaration(&self, shared_dir: &Path, format: ModuleFormat) -> String {
+		let name = self.name();
+		let specifier = match &self.module {
+			ModulePath::File(path) => relative_specifier(&absolute(shared_dir), path),
+			ModulePath::Package(specifier) => specifier.clone(),
+		};
+		let specifier = serde_json::to_string(&specifier).unwrap_or_default();
+		match (&self.binding, format) {
+			(Binding::Require | Binding::Namespace, ModuleFormat::CommonJs)
+			| (Binding::Require, ModuleFormat::Esm) => {
+				format!("const {} = require({});", name, specifier)
+			}
+			(Binding::RequireMember(member) | Binding::Named(member), ModuleFormat::CommonJs)
+			| (Binding::RequireMember(member), ModuleFormat::Esm) => {
+				format!("const {} = require({}).{};", name, specifier, member)
+			}
+			(Binding::Default, ModuleFormat::CommonJs) => {
+				format!("const {} = require({}).default;", name, specifier)
+			}
+			(Binding::Default, ModuleFormat::Esm) => {
+				format!("import {} from {};", name, specifier)
+			}
+			(Binding::Named(member), ModuleFormat::Esm) => {
+				format!("import {{ {} as {} }} from {};", member, name, specifier)
+			}
+			(Binding::Namespace, ModuleFormat::Esm) => {
+				format!("import * as {} from {};", name, specifier)
+			}
+		}
+	}
+
+	/// Whether declaring the atom in the shared module at `shared_module`
+	/// would create a require cycle: its module is one of `artifacts` (which
+	/// import the shared module once rewritten), the shared module itself, or
+	/// a file that imports it. Paths are as returned by [`module_path`].
+	pub fn is_cyclic(&self, artifacts: &HashSet<PathBuf>, shared_module: &Path) -> bool {
+		let ModulePath::File(path) = &self.module else {
+			return false;
+		};
+		let candidates: Vec<PathBuf> = std::iter::once(path.clone())
+			.chain(MODULE_EXTENSIONS.iter().map(|ext| {
+				let mut with_ext = path.clone().into_os_string();
+				with_ext.push(".");
+				with_ext.push(ext);
+				PathBuf::from(with_ext)
+			}))
+			.collect();
+		if candidates
+			.iter()
+			.any(|c| artifacts.contains(c) || c == shared_module)
+		{
+			return true;
+		}
+		let Some((file, src)) = candidates
+			.iter()
+			.find_map(|c| Some((c, std::fs::read_to_string(c).ok()?)))
+		else {
+			return false;
+		};
+		let dir = file.parent().unwrap_or(Path::new(""));
+		let shared_stem = shared_module.with_extension("");
+		tokenize(&src)
+			.iter()
+			.filter(|t| t.kind == TokenKind::String)
+			.map(|t| unquote(t.text(&src)))
+			.filter(|s| s.starts_with("./") || s.starts_with("../"))
+			.map(|s| normalize_path(&dir.join(s)))
+			.any(|p| p == shared_stem || p == shared_module)
+	}
+}
+
+/// `path` made absolute, the way atoms resolve their modules
+pub fn module_path(path: &Path) -> PathBuf {
+	normalize_path(&absolute(path))
+}
+
+/// Whether an identifier is an atom name
+pub fn is_atom_name(ident: &str) -> bool {
+	ident.len() == ATOM_PREFIX.len() + 8
+		&& ident.starts_with(ATOM_PREFIX)
+		&& ident[ATOM_PREFIX.len()..]
+			.chars()
+			.all(|c| c.is_ascii_hexdigit())
+}
+
+/// Resolves the module references of one artifact
+#[derive(Debug, Clone, Default)]
+pub struct ModuleScope {
+	artifact_dir: PathBuf,
+	root: PathBuf,
+	/// Imported bindings by local name
+	imports: HashMap<String, Atom>,
+}
+
+impl ModuleScope {
+	/// Scope of the artifact at `artifact_path`, reading its `import`
+	/// statements from comment-free `tokens`
+	pub fn new(src: &str, tokens: &[Token], artifact_path: &Path, root: &Path) -> Self {
+		let artifact_dir = artifact_path
+			.parent()
+			.unwrap_or(Path::new(""))
+			.to_path_buf();
+		let mut scope = Self {
+			artifact_dir,
+			root: root.to_path_buf(),
+			imports: HashMap::new(),
+		};
+		for statement in statements(src, tokens) {
+			let statement = &tokens[statement.tokens];
+			let [import, clause @ ..] = statement else {
+				continue;
+			};
+			if !is_word(src, import, "import") {
+				continue;
+			}
+			let clause = match clause {
+				[clause @ .., semicolon] if semicolon.is_punct(src, ";") => clause,
+				_ => clause,
+			};
+			scope.add_import(src, clause);
+		}
+		scope
+	}
+
+	/// Record the bindings of `import <clause> from '<specifier>'`
+	fn add_import(&mut self, src: &str, clause: &[Token]) {
+		let [clause @ .., from, specifier] = clause else {
+			return;
+		};
+		if !is_word(src, from, "from") || specifier.kind != TokenKind::String {
+			return;
+		}
+		// `import type ...` only brings in types
+		if clause.first().is_some_and(|t| is_word(src, t, "type")) {
+			return;
+		}
+		let specifier = unquote(specifier.text(src));
+
+		let mut bindings: Vec<(String, Binding)> = Vec::new();
+		let mut i = 0;
+		while i < clause.len() {
+			let token = &clause[i];
+			if token.is_punct(src, "*") {
+				// `* as ns`
+				if let Some(local) = clause.get(i + 2) {
+					bindings.push((local.text(src).to_string(), Binding::Namespace));
+				}
+				i += 3;
+			} else if token.is_punct(src, "{") {
+				let close = clause[i..]
+					.iter()
+					.position(|t| t.is_punct(src, "}"))
+					.map_or(clause.len(), |j| i + j);
+				for specifier in clause[i + 1..close].split(|t| t.is_punct(src, ",")) {
+					match specifier {
+						[first, ..] if is_word(src, first, "type") => {}
+						[name] => bindings.push((
+							name.text(src).to_string(),
+							Binding::Named(name.text(src).to_string()),
+						)),
+						[name, kw, local] if is_word(src, kw, "as") => bindings.push((
+							local.text(src).to_string(),
+							Binding::Named(name.text(src).to_string()),
+						)),
+						_ => {}
+					}
+				}
+				i = close + 1;
+			} else if token.kind == TokenKind::Ident {
+				bindings.push((token.text(src).to_string(), Binding::Default));
+				i += 1;
+			} else {
+				i += 1;
+			}
+		}
+
+		for (local, binding) in bindings {
+			// Imports of our own refs from the shared module aren't atoms
+			if is_ref_name(&local) {
+				continue;
+			}
+			let atom = self.atom(&specifier, binding);
+			self.imports.insert(local, atom);
+		}
+	}
+
+	fn atom(&self, specifier: &str, binding: Binding) -> Atom {
+		Atom::new(specifier, binding, &self.artifact_dir, &self.root)
+	}
+
+	/// Replace every module reference in `content` (`require('m')`,
+	/// `require('m').name`, imported bindings) with its atom name.
+	///
+	/// Returns the rewritten content and the atoms it refers to.
+	pub fn atomize(&self, src: &str, tokens: &[Token]) -> (String, Vec<(String, Atom)>) {
+		let (Some(first), Some(last)) = (tokens.first(), tokens.last()) else {
+			return (String::new(), Vec::new());
+		};
+		let mut result = String::new();
+		let mut atoms = Vec::new();
+		let mut last_end = first.start;
+		let mut i = 0;
+		while i < tokens.len() {
+			let (atom, len) = match self.reference_at(src, &tokens[i..]) {
+				Some(found) => found,
+				None => {
+					i += 1;
+					continue;
+				}
+			};
+			let name = atom.name();
+			result.push_str(&src[last_end..tokens[i].start]);
+			result.push_str(&name);
+			last_end = tokens[i + len - 1].end;
+			atoms.push((name, atom));
+			i += len;
+		}
+		result.push_str(&src[last_end..last.end]);
+		(result, atoms)
+	}
+
+	/// The module reference starting at `tokens[0]` and its length in tokens
+	fn reference_at(&self, src: &str, tokens: &[Token]) -> Option<(Atom, usize)> {
+		let first = tokens.first()?;
+		if first.kind != TokenKind::Ident {
+			return None;
+		}
+		match tokens {
+			[require, open, specifier, close, rest @ ..]
+				if require.text(src) == "require"
+					&& open.is_punct(src, "(")
+					&& specifier.kind == TokenKind::String
+					&& close.is_punct(src, ")") =>
+			{
+				let specifier = unquote(specifier.text(src));
+				match rest {
+					[dot, member, ..]
+						if dot.is_punct(src, ".") && member.kind == TokenKind::Ident =>
+					{
+						let binding = Binding::RequireMember(member.text(src).to_string());
+						Some((self.atom(&specifier, binding), 6))
+					}
+					_ => Some((self.atom(&specifier, Binding::Require), 4)),
+				}
+			}
+			_ => self
+				.imports
+				.get(first.text(src))
+				.map(|atom| (atom.clone(), 1)),
+		}
+	}
+}
+
+fn is_word(src: &str, token: &Token, word: &str) -> bool {
+	token.kind == TokenKind::Ident && token.text(src) == word
+}
+
+fn absolute(path: &Path) -> PathBuf {
+	// The parent of a bare file name is empty, which `absolute` rejects
+	let path = if path.as_os_str().is_empty() {
+		Path::new(".")
+	} else {
+		path
+	};
+	std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Resolve `.` and `..` components without touching the file system
+fn normalize_path(path: &Path) -> PathBuf {
+	let mut result = PathBuf::new();
+	for component in path.components() {
+		match component {
+			Component::CurDir => {}
+			Component::ParentDir => {
+				result.pop();
+			}
+			other => result.push(other),
+		}
+	}
+	result
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::lexer::tokenize;
+
+	#[test]
+	fn test_atoms_resolve_to_the_same_module() {
+		let root = Path::new("/repo/src");
+		let one = r#"const node = {"operation": require('./../queries/FooQuery.graphql')};"#;
+		let two = r#"import FooQuery from "../../queries/FooQuery.graphql";
+const node = {"operation": require("../../queries/FooQuery.graphql"), "query": FooQuery};"#;
+
+		let atomize = |src: &str, path: &str| {
+			let tokens = tokenize(src);
+			let scope = ModuleScope::new(src, &tokens, Path::new(path), root);
+			let start = tokens.iter().position(|t| t.is_punct(src, "{")).unwrap();
+			scope.atomize(src, &tokens[start..tokens.len() - 1])
+		};
+		let (one, one_atoms) = atomize(one, "/repo/src/a/Foo.graphql.js");
+		let (two, two_atoms) = atomize(two, "/repo/src/a/b/Bar.graphql.js");
+
+		let require_name = one_atoms[0].0.clone();
+		assert!(is_atom_name(&require_name));
+		assert_eq!(one, format!(r#"{{"operation": {}}}"#, require_name));
+		assert_eq!(two_atoms[0].0, require_name);
+		// The default import is a different binding of the same module
+		assert_ne!(two_atoms[1].0, require_name);
+		assert!(two.ends_with(&format!(r#""query": {}}}"#, two_atoms[1].0)));
+
+		let atom = &one_atoms[0].1;
+		assert_eq!(
+			atom.module,
+			ModulePath::File(PathBuf::from("/repo/src/queries/FooQuery.graphql"))
+		);
+		assert_eq!(
+			atom.declaration(Path::new("/repo/src/__generated__"), ModuleFormat::Esm),
+			format!(
+				"const {} = require(\"../queries/FooQuery.graphql\");",
+				require_name
+			)
+		);
+		assert_eq!(
+			two_atoms[1]
+				.1
+				.declaration(Path::new("/repo/src"), ModuleFormat::Esm),
+			format!(
+				"import {} from \"./queries/FooQuery.graphql\";",
+				two_atoms[1].0
+			)
+		);
+	}
+
+	#[test]
+	fn test_cyclic_atoms() {
+		let root = Path::new("/repo");
+		let artifacts: HashSet<PathBuf> = [PathBuf::from("/repo/a/FooQuery.graphql.js")].into();
+		let shared = Path::new("/repo/__shared.js");
+		let atom =
+			|specifier: &str| Atom::new(specifier, Binding::Require, Path::new("/repo/a"), root);
+
+		// Artifacts are required without their extension
+		assert!(atom("./FooQuery.graphql").is_cyclic(&artifacts, shared));
+		assert!(atom("../__shared").is_cyclic(&artifacts, shared));
+		assert!(!atom("./BarQuery.graphql").is_cyclic(&artifacts, shared));
+		assert!(!atom("relay-runtime").is_cyclic(&artifacts, shared));
+	}
+
+	#[test]
+	fn test_import_clauses() {
+		let src = r#"import type { Foo } from 'relay-runtime';
+import Default, { a, b as c, type D } from './mod';
+import * as ns from 'pkg';"#;
+		let tokens = tokenize(src);
+		let scope = ModuleScope::new(src, &tokens, Path::new("/x/A.js"), Path::new("/x"));
+		let mut locals: Vec<&str> = scope.imports.keys().map(String::as_str).collect();
+		locals.sort();
+		assert_eq!(locals, vec!["Default", "a", "c", "ns"]);
+		assert_eq!(scope.imports["c"].binding, Binding::Named("b".to_string()));
+		assert_eq!(
+			scope.imports["ns"].module,
+			ModulePath::Package("pkg".to_string())
+		);
+	}
+}
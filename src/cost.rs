@@ -0,0 +1,225 @@
+//! Extraction cost model.
+//!
+//! Extracting a structure replaces each occurrence by a name, but adds a
+//! definition to the shared module and the name to the import list of every
+//! file using it. [`CostModel`] weighs those against each other so only
+//! structures that make the output smaller are extracted, however short they
+//! are. A structure found in a single file is hoisted into a `const` of
+//! that file instead, which costs no import. The fixed thresholds of earlier
+//! versions (15 bytes of content and `min_occurrences`) remain available as
+//! [`ExtractionModel::Threshold`].
+
+use crate::{Language, ModuleFormat};
+use std::fmt;
+use std::str::FromStr;
+
+/// Content shorter than this is never extracted by [`ExtractionModel::Threshold`]
+pub const THRESHOLD_MIN_CONTENT_LEN: usize = 15;
+
+/// Length of the shortest generated name (`x_abc`)
+pub(crate) const MIN_NAME_LEN: usize = 5;
+
+/// Rough gzip compression ratio of artifact JSON
+const GZIP_RATIO: f64 = 4.0;
+
+/// Approximate size of a gzip back-reference to content seen earlier in the file
+const GZIP_BACKREF: f64 = 3.0;
+
+/// Typical import specifier of the shared module (`"./__shared"`)
+const IMPORT_SOURCE_LEN: usize = "\"./__shared\"".len();
+
+/// How extraction candidates are chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExtractionModel {
+	/// Extract when the net size benefit is positive (see [`CostModel`])
+	#[default]
+	Cost,
+	/// Extract content of at least 15 bytes seen `min_occurrences` times
+	Threshold,
+}
+
+impl FromStr for ExtractionModel {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"cost" => Ok(ExtractionModel::Cost),
+			"threshold" => Ok(ExtractionModel::Threshold),
+			other => Err(format!(
+				"unknown extraction model '{}' (expected cost or threshold)",
+				other
+			)),
+		}
+	}
+}
+
+impl fmt::Display for ExtractionModel {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			ExtractionModel::Cost => "cost",
+			ExtractionModel::Threshold => "threshold",
+		})
+	}
+}
+
+/// Everything the model needs to know about one candidate structure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Candidate {
+	/// Bytes of the shared module definition's value (the normalized content)
+	pub definition_len: usize,
+	/// Bytes the occurrences take up in the artifacts now, in total
+	pub replaced_len: usize,
+	pub occurrences: usize,
+	/// Number of files with at least one occurrence
+	pub files: usize,
+	/// How many of those don't import the shared module yet
+	pub new_importers: usize,
+	/// Bytes the rewritten occurrences keep besides the name, in total (the
+	/// spread syntax and overriding members of a near-duplicate, see
+	/// [`crate::factor`]; 0 for plain references)
+	pub overrides_len: usize,
+}
+
+/// Net size benefit of extracting a structure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostModel {
+	/// Bytes a definition adds to the shared module besides its name and value
+	pub definition_overhead: usize,
+	/// Bytes each importing file pays besides the name (the `, ` separator)
+	pub import_overhead: usize,
+	/// Bytes of the import statement a file without shared imports gets
+	pub import_statement: usize,
+	/// Bytes a file-local declaration adds besides its name and value
+	pub local_overhead: usize,
+	/// Weight of the estimated gzip benefit, from 0 (raw bytes only) to 1
+	pub gzip_weight: f64,
+}
+
+impl CostModel {
+	/// The model for shared modules written in `language` and `format`.
+	/// `gzip_weight` must be a finite number from 0 to 1.
+	pub fn new(language: Language, format: ModuleFormat, gzip_weight: f64) -> Self {
+		// `export const ` / `const ` ... ` = ` ... `;\n`
+		let declaration = match format {
+			ModuleFormat::Esm => "export const  = ;\n".len(),
+			// Listed again in `module.exports = {\n\tx_abc,\n}`
+			ModuleFormat::CommonJs => "const  = ;\n".len() + "\t,\n".len() + MIN_NAME_LEN,
+		};
+		let annotation = match language {
+			Language::TypeScript => ": RelayNode".len(),
+			Language::Flow => "/*: RelayNode*/".len(),
+			Language::JavaScript => 0,
+		};
+		let import_statement = match format {
+			ModuleFormat::Esm => "import {  } from ;\n".len(),
+			ModuleFormat::CommonJs => "const {  } = require();\n".len(),
+		};
+		debug_assert!(gzip_weight.is_finite() && (0.0..=1.0).contains(&gzip_weight));
+		Self {
+			definition_overhead: declaration + annotation,
+			import_overhead: ", ".len(),
+			import_statement: import_statement + IMPORT_SOURCE_LEN,
+			local_overhead: "const  = ;\n".len(),
+			gzip_weight,
+		}
+	}
+
+	/// Bytes saved in the uncompressed output (negative when extraction grows it)
+	pub fn raw_benefit(&self, c: &Candidate) -> f64 {
+		let references = c.occurrences * MIN_NAME_LEN + c.overrides_len;
+		let definition = self.definition_overhead + MIN_NAME_LEN + c.definition_len;
+		let imports = c.files * (MIN_NAME_LEN + self.import_overhead)
+			+ c.new_importers * self.import_statement;
+		c.replaced_len as f64 - (references + definition + imports) as f64
+	}
+
+	/// Estimated bytes saved after gzip. Repeats inside one file already
+	/// compress to back-references, so only the copies in other files count.
+	pub fn gzip_benefit(&self, c: &Candidate) -> f64 {
+		let compressed = c.definition_len as f64 / GZIP_RATIO + GZIP_BACKREF;
+		let copies = c.files.saturating_sub(1) as f64 * compressed;
+		// Hex names barely compress
+		let names = (c.files * (MIN_NAME_LEN + self.import_overhead)) as f64
+			+ (c.new_importers * self.import_statement) as f64 / GZIP_RATIO
+			+ c.occurrences as f64 * GZIP_BACKREF
+			+ (self.definition_overhead / 4 + MIN_NAME_LEN) as f64;
+		copies - names
+	}
+
+	/// Raw benefit blended with the gzip estimate by `gzip_weight`
+	pub fn net_benefit(&self, c: &Candidate) -> f64 {
+		let w = self.gzip_weight;
+		if w == 0.0 {
+			return self.raw_benefit(c);
+		}
+		(1.0 - w) * self.raw_benefit(c) + w * self.gzip_benefit(c)
+	}
+
+	/// Whether extracting `c` pays off
+	pub fn worth_extracting(&self, c: &Candidate) -> bool {
+		c.occurrences >= 2 && self.net_benefit(c) > 0.0
+	}
+
+	/// Bytes saved by hoisting the occurrences of `c` (all in one file) into
+	/// a local `const`, blended like [`CostModel::net_benefit`]. After gzip
+	/// the repeats were back-references already, so only the cost remains.
+	pub fn local_benefit(&self, c: &Candidate) -> f64 {
+		let definition = self.local_overhead + MIN_NAME_LEN + c.definition_len;
+		let raw = c.replaced_len as f64 - (c.occurrences * MIN_NAME_LEN + definition) as f64;
+		let gzip = -((self.local_overhead + MIN_NAME_LEN) as f64);
+		let w = self.gzip_weight;
+		(1.0 - w) * raw + w * gzip
+	}
+
+	/// Whether hoisting `c` within its file pays off
+	pub fn worth_hoisting(&self, c: &Candidate) -> bool {
+		c.files == 1 && c.occurrences >= 2 && self.local_benefit(c) > 0.0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_worth_extracting() {
+		let model = CostModel::new(Language::TypeScript, ModuleFormat::Esm, 0.0);
+		let candidate = |len: usize, occurrences: usize, files: usize| Candidate {
+			definition_len: len,
+			replaced_len: len * occurrences,
+			occurrences,
+			files,
+			new_importers: 0,
+			overrides_len: 0,
+		};
+
+		// A short array repeated everywhere pays for itself...
+		let path = r#"["viewer","id"]"#.len();
+		assert!(model.worth_extracting(&candidate(path, 1000, 500)));
+		// ...but not when it only shows up twice
+		assert!(!model.worth_extracting(&candidate(path, 2, 2)));
+		// Long content is worth it twice; never once
+		assert!(model.worth_extracting(&candidate(200, 2, 2)));
+		assert!(!model.worth_extracting(&candidate(200, 1, 1)));
+		// Overhead that exceeds the savings
+		assert!(model.raw_benefit(&candidate(30, 2, 2)) < 0.0);
+		// The first shared import of a file costs a whole statement
+		let first_imports = Candidate {
+			new_importers: 2,
+			..candidate(60, 2, 2)
+		};
+		assert!(model.worth_extracting(&candidate(60, 2, 2)));
+		assert!(!model.worth_extracting(&first_imports));
+
+		// Copies inside one file are nearly free after gzip
+		let gzip = CostModel::new(Language::TypeScript, ModuleFormat::Esm, 1.0);
+		assert!(model.worth_extracting(&candidate(200, 10, 1)));
+		assert!(!gzip.worth_extracting(&candidate(200, 10, 1)));
+		assert!(gzip.worth_extracting(&candidate(200, 10, 10)));
+
+		// Within one file there is no import to pay for
+		assert!(model.worth_hoisting(&candidate(30, 2, 1)));
+		assert!(!model.worth_hoisting(&candidate(30, 2, 2)));
+		assert!(!model.worth_hoisting(&candidate(10, 2, 1)));
+	}
+}
@@ -0,0 +1,305 @@
+//! Problems found while processing artifacts.
+//!
+//! Every problem is recorded with its severity, file and 1-based line and
+//! column instead of being skipped silently. Diagnostics print one per line
+//! (`path:line:column: severity: message`) and can be exported as SARIF 2.1.0
+//! for CI code scanning.
+
+use serde_json::{json, Value};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// How bad a problem is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+	/// The run went on; the affected part of the file was left as is
+	Warning,
+	/// The file (or the run) couldn't be processed
+	Error,
+}
+
+impl Severity {
+	/// SARIF `level` of the severity
+	fn sarif_level(self) -> &'static str {
+		match self {
+			Severity::Warning => "warning",
+			Severity::Error => "error",
+		}
+	}
+}
+
+impl fmt::Display for Severity {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.sarif_level())
+	}
+}
+
+/// What kind of problem a diagnostic reports (the SARIF rule)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Rule {
+	/// The artifact couldn't be read
+	ReadError,
+	/// A file couldn't be written
+	WriteError,
+	/// Relay's own deduplication output that can't be inlined
+	RelayDedup,
+	/// No `@generated` header
+	NotGenerated,
+	/// A `{` or `[` that is never closed; the literal is left inline
+	UnbalancedLiteral,
+	/// Legacy `_xxxxxxxx` references outside of `migrate`
+	LegacyRefs,
+	/// A legacy reference the legacy shared module doesn't define
+	UnresolvedLegacyRef,
+}
+
+impl Rule {
+	pub const ALL: &'static [Rule] = &[
+		Rule::ReadError,
+		Rule::WriteError,
+		Rule::RelayDedup,
+		Rule::NotGenerated,
+		Rule::UnbalancedLiteral,
+		Rule::LegacyRefs,
+		Rule::UnresolvedLegacyRef,
+	];
+
+	/// Stable identifier used as the SARIF `ruleId`
+	pub fn id(self) -> &'static str {
+		match self {
+			Rule::ReadError => "read-error",
+			Rule::WriteError => "write-error",
+			Rule::RelayDedup => "relay-dedup-output",
+			Rule::NotGenerated => "not-generated",
+			Rule::UnbalancedLiteral => "unbalanced-literal",
+			Rule::LegacyRefs => "legacy-reference",
+			Rule::UnresolvedLegacyRef => "unresolved-legacy-reference",
+		}
+	}
+
+	fn description(self) -> &'static str {
+		match self {
+			Rule::ReadError => "The artifact couldn't be read",
+			Rule::WriteError => "The file couldn't be written",
+			Rule::RelayDedup => {
+				"Relay's built-in deduplication output has a shape that can't be inlined"
+			}
+			Rule::NotGenerated => "The file has no @generated header from relay-compiler",
+			Rule::UnbalancedLiteral => "An object or array literal is never closed",
+			Rule::LegacyRefs => "The artifact uses legacy _xxxxxxxx references",
+			Rule::UnresolvedLegacyRef => {
+				"A legacy reference isn't defined by the legacy shared module"
+			}
+		}
+	}
+}
+
+/// One recorded problem
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Diagnostic {
+	pub path: PathBuf,
+	/// 1-based line
+	pub line: usize,
+	/// 1-based column, in characters
+	pub column: usize,
+	pub severity: Severity,
+	pub rule: Rule,
+	pub message: String,
+}
+
+impl Diagnostic {
+	/// A diagnostic at byte `offset` of `content` (the contents of `path`)
+	pub fn at(
+		severity: Severity,
+		rule: Rule,
+		path: &Path,
+		content: &str,
+		offset: usize,
+		message: impl Into<String>,
+	) -> Self {
+		let (line, column) = line_column(content, offset);
+		Self {
+			path: path.to_path_buf(),
+			line,
+			column,
+			severity,
+			rule,
+			message: message.into(),
+		}
+	}
+
+	/// A diagnostic about a whole file
+	pub fn file(severity: Severity, rule: Rule, path: &Path, message: impl Into<String>) -> Self {
+		Self::at(severity, rule, path, "", 0, message)
+	}
+
+	/// `path:line:column: severity: message`, the path relative to `base`
+	pub fn render(&self, base: &Path) -> String {
+		format!(
+			"{}:{}:{}: {}: {}",
+			relative(&self.path, base),
+			self.line,
+			self.column,
+			self.severity,
+			self.message
+		)
+	}
+}
+
+/// Diagnostics collected during a run
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics {
+	items: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+	pub fn push(&mut self, diagnostic: Diagnostic) {
+		self.items.push(diagnostic);
+	}
+
+	pub fn extend(&mut self, other: Diagnostics) {
+		self.items.extend(other.items);
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.items.is_empty()
+	}
+
+	pub fn count(&self, severity: Severity) -> usize {
+		self.items.iter().filter(|d| d.severity == severity).count()
+	}
+
+	/// Diagnostics sorted by file and position
+	pub fn sorted(&self) -> Vec<&Diagnostic> {
+		let mut items: Vec<&Diagnostic> = self.items.iter().collect();
+		items.sort();
+		items
+	}
+
+	/// Human-readable form, one diagnostic per line (paths relative to `base`)
+	pub fn render(&self, base: &Path) -> String {
+		self.sorted()
+			.iter()
+			.map(|d| d.render(base))
+			.collect::<Vec<_>>()
+			.join("\n")
+	}
+
+	/// SARIF 2.1.0 log of the diagnostics. Artifact URIs are relative to
+	/// `base` (normally the repository root) when they lie below it.
+	pub fn to_sarif(&self, base: &Path) -> Value {
+		let rules: Vec<Value> = Rule::ALL
+			.iter()
+			.map(|rule| {
+				json!({
+					"id": rule.id(),
+					"shortDescription": { "text": rule.description() },
+				})
+			})
+			.collect();
+		let results: Vec<Value> = self
+			.sorted()
+			.iter()
+			.map(|d| {
+				json!({
+					"ruleId": d.rule.id(),
+					"level": d.severity.sarif_level(),
+					"message": { "text": d.message },
+					"locations": [{
+						"physicalLocation": {
+							"artifactLocation": { "uri": relative(&d.path, base) },
+							"region": { "startLine": d.line, "startColumn": d.column },
+						},
+					}],
+				})
+			})
+			.collect();
+		json!({
+			"$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+			"version": "2.1.0",
+			"runs": [{
+				"tool": {
+					"driver": {
+						"name": env!("CARGO_PKG_NAME"),
+						"version": env!("CARGO_PKG_VERSION"),
+						"informationUri": env!("CARGO_PKG_REPOSITORY"),
+						"rules": rules,
+					},
+				},
+				"results": results,
+			}],
+		})
+	}
+}
+
+/// 1-based line and column of byte `offset` in `content`
+pub fn line_column(content: &str, offset: usize) -> (usize, usize) {
+	let offset = offset.min(content.len());
+	let before = content.get(..offset).unwrap_or(content);
+	let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+	let line = before.matches('\n').count() + 1;
+	let column = before[line_start..].chars().count() + 1;
+	(line, column)
+}
+
+/// `path` relative to `base` with forward slashes
+fn relative(path: &Path, base: &Path) -> String {
+	path.strip_prefix(base)
+		.unwrap_or(path)
+		.to_string_lossy()
+		.replace('\\', "/")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_line_column() {
+		let content = "const a = 1;\nconst node = {\n  \"é\": [\n";
+		assert_eq!(line_column(content, 0), (1, 1));
+		assert_eq!(line_column(content, 13), (2, 1));
+		assert_eq!(line_column(content, content.find('[').unwrap()), (3, 8));
+	}
+
+	#[test]
+	fn test_render_and_sarif() {
+		let base = Path::new("/repo");
+		let mut diagnostics = Diagnostics::default();
+		let content = "const node = {\n  \"a\": [1, 2\n};";
+		diagnostics.push(Diagnostic::at(
+			Severity::Warning,
+			Rule::UnbalancedLiteral,
+			Path::new("/repo/src/__generated__/B.graphql.ts"),
+			content,
+			content.find('[').unwrap(),
+			"`[` is never closed; left inline",
+		));
+		diagnostics.push(Diagnostic::file(
+			Severity::Error,
+			Rule::ReadError,
+			Path::new("/repo/src/__generated__/A.graphql.ts"),
+			"can't read file: permission denied",
+		));
+
+		assert_eq!(
+			diagnostics.render(base),
+			"src/__generated__/A.graphql.ts:1:1: error: can't read file: permission denied\n\
+			 src/__generated__/B.graphql.ts:2:8: warning: `[` is never closed; left inline"
+		);
+
+		let sarif = diagnostics.to_sarif(base);
+		assert_eq!(sarif["version"], "2.1.0");
+		let results = sarif["runs"][0]["results"].as_array().unwrap();
+		assert_eq!(results.len(), 2);
+		assert_eq!(results[1]["ruleId"], "unbalanced-literal");
+		assert_eq!(results[1]["level"], "warning");
+		let location = &results[1]["locations"][0]["physicalLocation"];
+		assert_eq!(
+			location["artifactLocation"]["uri"],
+			"src/__generated__/B.graphql.ts"
+		);
+		assert_eq!(location["region"]["startLine"], 2);
+		assert_eq!(location["region"]["startColumn"], 8);
+	}
+}
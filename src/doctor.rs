@@ -0,0 +1,472 @@
+//! `relay-dedup doctor`: environment and compatibility checks.
+//!
+//! Reads the installed relay-compiler / relay-runtime versions from
+//! `node_modules`, checks the relay config's feature flags against a built-in
+//! compatibility table and scans the artifacts for shapes the tool can't
+//! safely rewrite.
+
+use crate::artifact::{has_relay_dedup, prepare_artifact};
+use crate::fix_config::{ALIAS_FLAG, DEDUP_FLAG};
+use crate::order::OrderRules;
+use crate::relay_config::{dedup_disabled, RelayConfig, RelayProject};
+use crate::tree::FileTree;
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Outcome of a single check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+	Ok,
+	Warning,
+	Error,
+}
+
+impl fmt::Display for Status {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Status::Ok => "ok",
+			Status::Warning => "warn",
+			Status::Error => "error",
+		})
+	}
+}
+
+/// One line of the doctor report
+#[derive(Debug, Clone)]
+pub struct Finding {
+	pub status: Status,
+	pub message: String,
+}
+
+/// Collected findings
+#[derive(Debug, Default)]
+pub struct Report {
+	pub findings: Vec<Finding>,
+	/// `--order-insensitive` value recommended for the installed relay-compiler
+	pub suggested_order_insensitive: Option<Vec<String>>,
+}
+
+impl Report {
+	pub fn push(&mut self, status: Status, message: impl Into<String>) {
+		self.findings.push(Finding {
+			status,
+			message: message.into(),
+		});
+	}
+
+	pub fn count(&self, status: Status) -> usize {
+		self.findings.iter().filter(|f| f.status == status).count()
+	}
+}
+
+/// A `major.minor.patch` package version (pre-release suffixes are ignored)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version {
+	pub major: u64,
+	pub minor: u64,
+	pub patch: u64,
+}
+
+impl Version {
+	pub fn parse(s: &str) -> Option<Self> {
+		let core = s.trim().trim_start_matches('v');
+		let core = core.split(['-', '+']).next()?;
+		let mut parts = core.split('.').map(|p| p.parse::<u64>());
+		Some(Self {
+			major: parts.next()?.ok()?,
+			minor: parts.next().unwrap_or(Ok(0)).ok()?,
+			patch: parts.next().unwrap_or(Ok(0)).ok()?,
+		})
+	}
+}
+
+impl Version {
+	/// Relay publishes nightly builds as `0.0.0-main-<sha>`
+	pub fn is_nightly(&self) -> bool {
+		self.major == 0 && self.minor == 0 && self.patch == 0
+	}
+}
+
+impl fmt::Display for Version {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+	}
+}
+
+/// What a range of relay-compiler major versions needs
+#[derive(Debug)]
+pub struct Compatibility {
+	pub majors: RangeInclusive<u64>,
+	/// Whether `enforce_fragment_alias_where_ambiguous` must be disabled
+	/// alongside the dedup flag (any feature flag turns on strict alias checks)
+	pub needs_alias_flag: bool,
+	/// Default order-insensitive arrays (`Kind.key` rules, see [`crate::order`])
+	pub order_insensitive: &'static [&'static str],
+}
+
+/// Oldest relay-compiler supported (the Rust compiler)
+pub const MIN_SUPPORTED_MAJOR: u64 = 13;
+
+/// Arrays whose order carries no meaning in the artifacts of the Rust
+/// compiler: selection sets of fields, fragments and operations, arguments
+/// and argument definitions
+const ORDER_INSENSITIVE: &[&str] = &[
+	"Operation.selections",
+	"SplitOperation.selections",
+	"Fragment.selections",
+	"LinkedField.selections",
+	"InlineFragment.selections",
+	"args",
+	"argumentDefinitions",
+];
+
+/// Relay-compiler versions the artifact format has been checked against
+pub const COMPATIBILITY: &[Compatibility] = &[
+	Compatibility {
+		majors: 13..=16,
+		needs_alias_flag: false,
+		order_insensitive: ORDER_INSENSITIVE,
+	},
+	Compatibility {
+		majors: 17..=20,
+		needs_alias_flag: true,
+		order_insensitive: ORDER_INSENSITIVE,
+	},
+];
+
+/// Order-insensitive rules for relay-compiler versions newer than the table:
+/// only fields whose order never carries meaning
+pub const CONSERVATIVE_ORDER_INSENSITIVE: &[&str] = &["args", "argumentDefinitions"];
+
+/// Look up the compatibility entry for a relay-compiler version
+pub fn compatibility(version: Version) -> Option<&'static Compatibility> {
+	COMPATIBILITY
+		.iter()
+		.find(|c| c.majors.contains(&version.major))
+}
+
+/// Read the version of a package installed in the nearest `node_modules`
+pub fn installed_version(start_dir: &Path, package: &str) -> Option<(PathBuf, Version)> {
+	start_dir.ancestors().find_map(|dir| {
+		let path = dir.join("node_modules").join(package).join("package.json");
+		let content = fs::read_to_string(&path).ok()?;
+		let json: Value = serde_json::from_str(&content).ok()?;
+		let version = Version::parse(json.get("version")?.as_str()?)?;
+		Some((path, version))
+	})
+}
+
+/// Check the installed relay-compiler and relay-runtime.
+///
+/// Returns the relay-compiler version, if found.
+pub fn check_versions(start_dir: &Path, report: &mut Report) -> Option<Version> {
+	let compiler = installed_version(start_dir, "relay-compiler").map(|(_, v)| v);
+	let runtime = installed_version(start_dir, "relay-runtime").map(|(_, v)| v);
+
+	match compiler {
+		None => report.push(
+			Status::Warning,
+			"relay-compiler not found in node_modules; skipping version checks",
+		),
+		// Nightlies are built from main, so they're newer than any release
+		Some(v) if v.is_nightly() => report.push(
+			Status::Warning,
+			"relay-compiler is a nightly build (0.0.0-*), newer than the versions relay-dedup has \
+			 been checked against; conservative order rules apply, review the output with --dry-run",
+		),
+		Some(v) if v.major < MIN_SUPPORTED_MAJOR => report.push(
+			Status::Error,
+			format!(
+				"relay-compiler {} is not supported (the Rust compiler, {}.0.0 or later, is required)",
+				v, MIN_SUPPORTED_MAJOR
+			),
+		),
+		Some(v) if compatibility(v).is_none() => report.push(
+			Status::Warning,
+			format!(
+				"relay-compiler {} is newer than the versions relay-dedup has been checked against; \
+				 artifact shapes may have changed, review the output with --dry-run",
+				v
+			),
+		),
+		Some(v) => report.push(Status::Ok, format!("relay-compiler {}", v)),
+	}
+
+	match (compiler, runtime) {
+		(_, None) => report.push(Status::Warning, "relay-runtime not found in node_modules"),
+		(Some(c), Some(r)) if c.major != r.major => report.push(
+			Status::Warning,
+			format!(
+				"relay-runtime {} does not match relay-compiler {}; artifacts may use features the runtime lacks",
+				r, c
+			),
+		),
+		(_, Some(r)) => report.push(Status::Ok, format!("relay-runtime {}", r)),
+	}
+
+	if let Some(v) = compiler.filter(|v| v.is_nightly() || v.major >= MIN_SUPPORTED_MAJOR) {
+		let fields = compatibility(v)
+			.map(|c| c.order_insensitive)
+			.unwrap_or(CONSERVATIVE_ORDER_INSENSITIVE);
+		report.suggested_order_insensitive = Some(fields.iter().map(|s| s.to_string()).collect());
+	}
+	compiler
+}
+
+/// Check a project's feature flags against the compatibility table
+pub fn check_project_flags(
+	config: &RelayConfig,
+	project: &RelayProject,
+	compiler: Option<Version>,
+	report: &mut Report,
+) {
+	let label = if config.is_multi_project() {
+		format!("project '{}'", project.name)
+	} else {
+		"relay config".to_string()
+	};
+
+	if dedup_disabled(project) {
+		report.push(Status::Ok, format!("{}: {} is enabled", label, DEDUP_FLAG));
+	} else {
+		report.push(
+			Status::Warning,
+			format!(
+				"{}: {} should be {{ \"kind\": \"enabled\" }}; Relay's own dedup output is inlined \
+				 before processing, which is slower (run `relay-dedup init`)",
+				label, DEDUP_FLAG
+			),
+		);
+	}
+
+	let alias_flag = project
+		.feature_flags
+		.as_ref()
+		.and_then(|flags| flags.get(ALIAS_FLAG))
+		.and_then(|flag| flag.get("kind"))
+		.and_then(|kind| kind.as_str());
+	let needs_alias_flag = compiler.and_then(compatibility).map(|c| c.needs_alias_flag);
+	match (needs_alias_flag, alias_flag) {
+		(Some(true), Some("disabled")) | (None, Some("disabled")) => {
+			report.push(Status::Ok, format!("{}: {} is disabled", label, ALIAS_FLAG))
+		}
+		(Some(true), _) => report.push(
+			Status::Warning,
+			format!(
+				"{}: relay-compiler {} enables strict alias checks when any feature flag is set; \
+				 set {} to {{ \"kind\": \"disabled\" }}",
+				label,
+				compiler.map(|v| v.to_string()).unwrap_or_default(),
+				ALIAS_FLAG
+			),
+		),
+		(Some(false), Some(_)) => report.push(
+			Status::Ok,
+			format!(
+				"{}: {} is not needed with relay-compiler {} and can be removed",
+				label,
+				ALIAS_FLAG,
+				compiler.map(|v| v.to_string()).unwrap_or_default()
+			),
+		),
+		_ => {}
+	}
+}
+
+/// Why an artifact can't be safely processed, if it can't
+pub fn artifact_hazard(content: &str) -> Option<String> {
+	let content = match prepare_artifact(content.to_string()) {
+		Ok(content) => content,
+		Err(problem) => return Some(problem.to_string()),
+	};
+	let tree = FileTree::new(content, &OrderRules::default());
+	if tree.root_nodes.is_empty() {
+		return Some("no node literal found".to_string());
+	}
+	if tree.nodes.iter().any(|n| n.end == 0) {
+		return Some("unbalanced braces or brackets".to_string());
+	}
+	None
+}
+
+/// Scan artifacts and report the ones the tool can't safely process
+pub fn check_artifacts(label: &str, paths: &[PathBuf], report: &mut Report) {
+	let mut hazards: Vec<(PathBuf, String)> = Vec::new();
+	let mut relay_deduped = 0;
+	for path in paths {
+		match fs::read_to_string(path) {
+			Ok(content) => {
+				if let Some(reason) = artifact_hazard(&content) {
+					hazards.push((path.clone(), reason));
+				} else if has_relay_dedup(&content) {
+					relay_deduped += 1;
+				}
+			}
+			Err(_) => hazards.push((path.clone(), "unreadable".to_string())),
+		}
+	}
+
+	if relay_deduped > 0 {
+		report.push(
+			Status::Warning,
+			format!(
+				"{}: {} artifacts were deduplicated by Relay and will be inlined before processing",
+				label, relay_deduped
+			),
+		);
+	}
+	if paths.is_empty() {
+		report.push(Status::Warning, format!("{}: no artifacts found", label));
+	} else if hazards.is_empty() {
+		report.push(
+			Status::Ok,
+			format!("{}: {} artifacts can be processed", label, paths.len()),
+		);
+	} else {
+		for (path, reason) in hazards {
+			report.push(
+				Status::Error,
+				format!("{}: {} ({})", label, path.display(), reason),
+			);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_version_parse() {
+		assert_eq!(
+			Version::parse("18.2.0"),
+			Some(Version {
+				major: 18,
+				minor: 2,
+				patch: 0
+			})
+		);
+		assert_eq!(Version::parse("v20.0.0-rc.1").map(|v| v.major), Some(20));
+		assert_eq!(
+			Version::parse("0.0.0-main-ab12cd").map(|v| v.major),
+			Some(0)
+		);
+		assert_eq!(Version::parse("latest"), None);
+	}
+
+	/// Run `check_versions` below a `node_modules` with the given compiler and
+	/// runtime versions
+	fn versions_report(compiler: &str, runtime: &str) -> (Option<Version>, Report) {
+		let temp = tempdir().unwrap();
+		for (package, version) in [("relay-compiler", compiler), ("relay-runtime", runtime)] {
+			let dir = temp.path().join("node_modules").join(package);
+			fs::create_dir_all(&dir).unwrap();
+			fs::write(
+				dir.join("package.json"),
+				format!(r#"{{ "name": "{}", "version": "{}" }}"#, package, version),
+			)
+			.unwrap();
+		}
+		let nested = temp.path().join("packages/app");
+		fs::create_dir_all(&nested).unwrap();
+
+		let mut report = Report::default();
+		let version = check_versions(&nested, &mut report);
+		(version, report)
+	}
+
+	fn statuses(report: &Report) -> Vec<(Status, &str)> {
+		report
+			.findings
+			.iter()
+			.map(|f| (f.status, f.message.as_str()))
+			.collect()
+	}
+
+	#[test]
+	fn test_check_versions() {
+		let (compiler, report) = versions_report("18.1.0", "17.0.0");
+		assert_eq!(compiler.map(|v| v.major), Some(18));
+		assert_eq!(
+			statuses(&report),
+			vec![
+				(Status::Ok, "relay-compiler 18.1.0"),
+				(
+					Status::Warning,
+					"relay-runtime 17.0.0 does not match relay-compiler 18.1.0; \
+					 artifacts may use features the runtime lacks"
+				),
+			]
+		);
+		// The full rules of the 17-20 entry
+		assert_eq!(
+			report.suggested_order_insensitive.as_deref(),
+			Some(
+				&ORDER_INSENSITIVE
+					.iter()
+					.map(|s| s.to_string())
+					.collect::<Vec<_>>()[..]
+			)
+		);
+
+		let (_, report) = versions_report("12.0.0", "12.0.0");
+		assert_eq!(report.findings[0].status, Status::Error);
+		assert!(report.findings[0].message.contains("not supported"));
+		assert_eq!(report.suggested_order_insensitive, None);
+	}
+
+	#[test]
+	fn test_nightly_compiler_is_newer_than_the_table() {
+		let (compiler, report) = versions_report("0.0.0-main-ab12cd", "0.0.0-main-ab12cd");
+		assert!(compiler.unwrap().is_nightly());
+		assert_eq!(report.count(Status::Error), 0);
+		assert_eq!(report.findings[0].status, Status::Warning);
+		assert!(
+			report.findings[0].message.contains("nightly build"),
+			"{}",
+			report.findings[0].message
+		);
+		assert_eq!(report.findings[1].status, Status::Ok);
+		assert_eq!(
+			report.suggested_order_insensitive.as_deref(),
+			Some(&["args".to_string(), "argumentDefinitions".to_string()][..])
+		);
+	}
+
+	#[test]
+	fn test_artifact_hazard() {
+		let header = "/** @generated */\n";
+		let hazard = |body: &str| artifact_hazard(&format!("{}{}", header, body));
+
+		assert_eq!(
+			hazard(r#"const node = { "kind": "Fragment" }; export default node;"#),
+			None
+		);
+		// Relay's own dedup output is fine as long as it can be inlined
+		assert_eq!(
+			hazard("const node = (function(){\nvar v0 = {\"a\": 1};\nreturn {\"b\": v0};\n})();"),
+			None
+		);
+		assert_eq!(
+			hazard("const node = (function(){\nvar v0 = {\"a\": 1};\nv0.b = 2;\nreturn {\"b\": v0};\n})();")
+				.as_deref(),
+			Some("already deduplicated by Relay (v0 variables) in a shape that can't be inlined")
+		);
+		assert_eq!(
+			hazard(r#"const node = { "kind": ["Fragment" };"#).as_deref(),
+			Some("unbalanced braces or brackets")
+		);
+		assert_eq!(
+			hazard("export type Foo = string;").as_deref(),
+			Some("no node literal found")
+		);
+		assert_eq!(
+			artifact_hazard("const node = {};").as_deref(),
+			Some("no @generated header")
+		);
+	}
+}
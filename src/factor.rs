@@ -0,0 +1,480 @@
+//! Near-duplicate factoring.
+//!
+//! Exact extraction only shares structures with the same normalized form,
+//! but many differ in a key or two: `LinkedField`s that only differ in
+//! `name` and `alias`, or arrays that start with the same elements. The
+//! common part can move to the shared module with each occurrence rewritten
+//! as a spread of it:
+//!
+//! ```js
+//! {...x_ab1,"alias":"author","name":"author"}
+//! [...x_ab2,x_f00]
+//! ```
+//!
+//! The base and the overriding members keep their order and formatting from
+//! the source (or the compact form with [`crate::Config::compact_shared`]).
+//! A spread copies the base, so the rewritten structures never share
+//! identity. [`select`] only factors clusters that [`CostModel`] finds worth
+//! it, whichever [`crate::cost::ExtractionModel`] chose the exact extractions.
+
+use crate::cost::{Candidate, CostModel, MIN_NAME_LEN};
+use crate::identifiers;
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::literal::canonical_key;
+use crate::naming::is_local_name;
+use crate::value::{self, Value};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::ops::Range;
+
+/// Bytes of a spread besides the name, the separator and the rest of the
+/// structure (`...`)
+const SPREAD_SYNTAX_LEN: usize = "...".len();
+
+/// A structure exact extraction left in place
+#[derive(Debug, Clone, Copy)]
+pub struct Form<'a> {
+	pub normalized: &'a str,
+	/// The structure as it would be emitted (see [`crate::Config::compact_shared`])
+	pub text: &'a str,
+	pub candidate: Candidate,
+	/// Files (indices) it occurs in
+	pub files: &'a [usize],
+}
+
+/// One form of a cluster and what it adds to the base
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+	/// Index into the forms given to [`select`]
+	pub form: usize,
+	/// The form without the base's parts, as written (`{"name": "id"}`)
+	rest: String,
+	/// What separates two parts in the form (`,` or `,\n  `)
+	separator: String,
+	/// Estimated bytes of the spread at each occurrence besides the name
+	len: usize,
+}
+
+/// Near-duplicates written as spreads of a shared base
+#[derive(Debug, Clone)]
+pub struct Cluster {
+	/// Normalized form of the common part
+	pub base: String,
+	/// The common part as written by the first member, in its order
+	pub base_text: String,
+	pub members: Vec<Member>,
+	/// The cluster as one extraction candidate
+	pub candidate: Candidate,
+}
+
+impl Cluster {
+	/// The text replacing `member` when the base is named `name`: the spread
+	/// goes first, the member's own parts follow as written
+	pub fn spread(&self, member: &Member, name: &str) -> String {
+		let rest = &member.rest;
+		let inner = &rest[1..rest.len() - 1];
+		let body = inner.trim_start();
+		let leading = &inner[..inner.len() - body.len()];
+		format!(
+			"{}{}...{}{}{}{}",
+			&rest[..1],
+			leading,
+			name,
+			member.separator,
+			body,
+			&rest[rest.len() - 1..]
+		)
+	}
+}
+
+/// One object member or array element: canonical (for matching) and where
+/// it is written
+struct Part {
+	key: String,
+	/// Canonical property name of an object member
+	property: String,
+	span: Range<usize>,
+}
+
+/// Top-level parts of an object or array literal, in source order
+struct Parts<'a> {
+	text: &'a str,
+	is_array: bool,
+	parts: Vec<Part>,
+}
+
+impl<'a> Parts<'a> {
+	fn parse(text: &'a str) -> Option<Self> {
+		let is_array = text.starts_with('[');
+		if !is_array && !text.starts_with('{') {
+			return None;
+		}
+		let mut parts = Vec::new();
+		for span in top_level_spans(text)? {
+			let part = &text[span.clone()];
+			// Spreads already factored can't be taken apart again
+			let (key, property) = if is_array {
+				match Value::parse(part)? {
+					Value::Spread(_) => return None,
+					element => (element.canonical(), String::new()),
+				}
+			} else {
+				let wrapped = format!("{{{}}}", part);
+				match Value::parse(&wrapped)? {
+					Value::Object(members) => match members.as_slice() {
+						[value::Member::Pair(key, value)] => {
+							let property = canonical_key(key).into_owned();
+							(format!("{}:{}", property, value.canonical()), property)
+						}
+						_ => return None,
+					},
+					_ => return None,
+				}
+			};
+			parts.push(Part {
+				key,
+				property,
+				span,
+			});
+		}
+
+		if !is_array {
+			// A repeated key overrides the earlier one
+			let mut keys: Vec<&str> = parts.iter().map(|part| part.property.as_str()).collect();
+			keys.sort_unstable();
+			if keys.windows(2).any(|w| w[0] == w[1]) {
+				return None;
+			}
+		}
+		Some(Self {
+			text,
+			is_array,
+			parts,
+		})
+	}
+
+	/// What separates two parts as written (the first such separator)
+	fn separator(&self) -> &'a str {
+		match self.parts.as_slice() {
+			[first, second, ..] => &self.text[first.span.end..second.span.start],
+			_ => ",",
+		}
+	}
+
+	/// The literal with only the parts at the `kept` indices, in their
+	/// order, keeping the whitespace around them
+	fn write(&self, kept: &[usize]) -> String {
+		let (first, last) = (&self.parts[0], &self.parts[self.parts.len() - 1]);
+		let open = &self.text[..first.span.start];
+		let close = &self.text[last.span.end..];
+		let body: Vec<&str> = kept
+			.iter()
+			.map(|&i| &self.text[self.parts[i].span.clone()])
+			.collect();
+		if body.is_empty() {
+			return format!("{}{}", &open[..1], &close[close.len() - 1..]);
+		}
+		format!("{}{}{}", open, body.join(self.separator()), close)
+	}
+
+	/// Canonical form of the parts at `indices`: object members in key order
+	fn canonical(&self, indices: &[usize]) -> String {
+		let mut keys: Vec<&str> = indices
+			.iter()
+			.map(|&i| self.parts[i].key.as_str())
+			.collect();
+		if self.is_array {
+			format!("[{}]", keys.join(","))
+		} else {
+			keys.sort_unstable();
+			format!("{{{}}}", keys.join(","))
+		}
+	}
+
+	/// Every way of splitting the parts into a base and the rest: one or two
+	/// object members left out, or array elements after a prefix of two or
+	/// more. Each split is the base's canonical form, the base as written and
+	/// the rest as written.
+	fn splits(&self) -> Vec<(String, String, String)> {
+		let n = self.parts.len();
+		let split = |left_out: &[usize]| {
+			let base: Vec<usize> = (0..n).filter(|i| !left_out.contains(i)).collect();
+			(
+				self.canonical(&base),
+				self.write(&base),
+				self.write(left_out),
+			)
+		};
+		let mut splits = Vec::new();
+		if self.is_array {
+			for prefix in 2..n {
+				let rest: Vec<usize> = (prefix..n).collect();
+				splits.push(split(&rest));
+			}
+		} else {
+			for i in 0..n {
+				if n > 1 {
+					splits.push(split(&[i]));
+				}
+				for j in i + 1..n {
+					if n > 2 {
+						splits.push(split(&[i, j]));
+					}
+				}
+			}
+		}
+		splits
+	}
+}
+
+/// Spans of the top-level parts of an object or array literal, trimmed.
+/// `None` when the brackets don't balance.
+fn top_level_spans(text: &str) -> Option<Vec<Range<usize>>> {
+	let tokens: Vec<Token> = tokenize(text)
+		.into_iter()
+		.filter(|t| t.kind != TokenKind::Comment)
+		.collect();
+	let mut spans = Vec::new();
+	let mut depth = 0usize;
+	let mut part: Option<Range<usize>> = None;
+	for token in &tokens {
+		let punct = if token.kind == TokenKind::Punct {
+			token.text(text)
+		} else {
+			""
+		};
+		match punct {
+			"[" | "{" if depth == 0 => {
+				depth = 1;
+				continue;
+			}
+			"]" | "}" if depth == 1 => {
+				depth = 0;
+				spans.extend(part.take());
+				continue;
+			}
+			"," if depth == 1 => {
+				spans.extend(part.take());
+				continue;
+			}
+			"[" | "{" => depth += 1,
+			"]" | "}" => depth = depth.checked_sub(1)?,
+			_ => {}
+		}
+		if depth == 0 {
+			return None;
+		}
+		part = Some(part.map_or(token.start..token.end, |p| p.start..token.end));
+	}
+	(depth == 0).then_some(spans)
+}
+
+/// A cluster's benefit when it was last evaluated
+struct Scored {
+	benefit: f64,
+	group: usize,
+}
+
+impl PartialEq for Scored {
+	fn eq(&self, other: &Self) -> bool {
+		self.cmp(other) == Ordering::Equal
+	}
+}
+
+impl Eq for Scored {}
+
+impl PartialOrd for Scored {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Scored {
+	// Highest benefit first, then the group with the smallest base
+	fn cmp(&self, other: &Self) -> Ordering {
+		self.benefit
+			.total_cmp(&other.benefit)
+			.then_with(|| other.group.cmp(&self.group))
+	}
+}
+
+/// Choose the clusters of `forms` to factor, most beneficial first. Each
+/// form joins at most one cluster, and a cluster needs two forms or more.
+///
+/// `imports_shared` tells which files (indices) import the shared module
+/// already; `importing` holds the ones this pass makes import it, and
+/// grows with every cluster chosen.
+pub fn select(
+	forms: &[Form<'_>],
+	model: &CostModel,
+	imports_shared: &[bool],
+	importing: &mut HashSet<usize>,
+) -> Vec<Cluster> {
+	// Keyed by canonical base; the text is the first member's
+	let mut groups: HashMap<String, (String, Vec<Member>)> = HashMap::new();
+	for (index, form) in forms.iter().enumerate() {
+		let Some(parts) = Parts::parse(form.text) else {
+			continue;
+		};
+		// Occurrences are indented deeper than the text: each line of the
+		// spread gets that indentation back
+		let lines = form.text.matches('\n').count();
+		let written = form.candidate.replaced_len / form.candidate.occurrences.max(1);
+		let indent = written.saturating_sub(form.text.len()) / lines.max(1);
+		let separator = parts.separator();
+		for (base, base_text, rest) in parts.splits() {
+			let lines = rest.matches('\n').count() + separator.matches('\n').count();
+			let len = SPREAD_SYNTAX_LEN + separator.len() + rest.len() + lines * indent;
+			// Rewriting must pay off for the form itself
+			if form.candidate.replaced_len <= form.candidate.occurrences * (MIN_NAME_LEN + len) {
+				continue;
+			}
+			let (_, members) = groups
+				.entry(base)
+				.or_insert_with(|| (base_text, Vec::new()));
+			members.push(Member {
+				form: index,
+				rest,
+				separator: separator.to_string(),
+				len,
+			});
+		}
+	}
+	// Local names can't be exported
+	let mut groups: Vec<(String, (String, Vec<Member>))> = groups
+		.into_iter()
+		.filter(|(base, (_, members))| members.len() >= 2 && !identifiers(base).any(is_local_name))
+		.collect();
+	groups.sort_by(|a, b| a.0.cmp(&b.0));
+
+	let mut taken = vec![false; forms.len()];
+	let evaluate = |group: usize, taken: &[bool], importing: &HashSet<usize>| {
+		let (_, (base_text, members)) = &groups[group];
+		let members: Vec<&Member> = members.iter().filter(|m| !taken[m.form]).collect();
+		if members.len() < 2 {
+			return None;
+		}
+		let mut files: HashSet<usize> = HashSet::new();
+		let mut candidate = Candidate {
+			definition_len: base_text.len(),
+			replaced_len: 0,
+			occurrences: 0,
+			files: 0,
+			new_importers: 0,
+			overrides_len: 0,
+		};
+		for member in &members {
+			let form = &forms[member.form];
+			candidate.replaced_len += form.candidate.replaced_len;
+			candidate.occurrences += form.candidate.occurrences;
+			candidate.overrides_len += form.candidate.occurrences * member.len;
+			files.extend(form.files);
+		}
+		candidate.files = files.len();
+		candidate.new_importers = files
+			.iter()
+			.filter(|&&f| !imports_shared[f] && !importing.contains(&f))
+			.count();
+		Some((model.net_benefit(&candidate), candidate, files))
+	};
+
+	let mut heap: BinaryHeap<Scored> = (0..groups.len())
+		.filter_map(|group| {
+			let (benefit, _, _) = evaluate(group, &taken, importing)?;
+			(benefit > 0.0).then_some(Scored { benefit, group })
+		})
+		.collect();
+
+	let mut clusters = Vec::new();
+	while let Some(scored) = heap.pop() {
+		let Some((benefit, candidate, files)) = evaluate(scored.group, &taken, importing) else {
+			continue;
+		};
+		if benefit <= 0.0 {
+			continue;
+		}
+		// Members taken by earlier clusters made it worse: try again later
+		if benefit < scored.benefit {
+			heap.push(Scored {
+				benefit,
+				group: scored.group,
+			});
+			continue;
+		}
+		let (base, (base_text, members)) = &groups[scored.group];
+		let members: Vec<Member> = members.iter().filter(|m| !taken[m.form]).cloned().collect();
+		for member in &members {
+			taken[member.form] = true;
+		}
+		importing.extend(files.into_iter().filter(|&f| !imports_shared[f]));
+		clusters.push(Cluster {
+			base: base.clone(),
+			base_text: base_text.clone(),
+			members,
+			candidate,
+		});
+	}
+	clusters
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{Language, ModuleFormat};
+
+	#[test]
+	fn test_select_clusters() {
+		let field = |name: &str| {
+			format!(
+				r#"{{"alias":null,"args":null,"concreteType":"User","kind":"LinkedField","name":"{}","plural":false,"selections":x_abc,"storageKey":null}}"#,
+				name
+			)
+		};
+		let array = |last: &str| format!("[x_a01,x_a02,x_a03,x_a04,x_a05,x_a06,{}]", last);
+		let normalized = [
+			field("author"),
+			field("editor"),
+			field("owner"),
+			array("x_b01"),
+			array("x_b02"),
+			// Too short to gain anything
+			r#"{"a":1,"b":2}"#.to_string(),
+			r#"{"a":1,"b":3}"#.to_string(),
+		];
+		let files = [0, 1];
+		let forms: Vec<Form> = normalized
+			.iter()
+			.map(|n| Form {
+				normalized: n,
+				text: n,
+				candidate: Candidate {
+					definition_len: n.len(),
+					replaced_len: n.len() * 2,
+					occurrences: 2,
+					files: 2,
+					new_importers: 0,
+					overrides_len: 0,
+				},
+				files: &files,
+			})
+			.collect();
+		let model = CostModel::new(Language::TypeScript, ModuleFormat::Esm, 0.0);
+		let clusters = select(&forms, &model, &[true, true], &mut HashSet::new());
+
+		assert_eq!(clusters.len(), 2);
+		let fields = &clusters[0];
+		assert_eq!(fields.members.len(), 3);
+		assert!(!fields.base.contains("\"name\""), "{}", fields.base);
+		assert_eq!(
+			fields.spread(&fields.members[0], "x_f00"),
+			r#"{...x_f00,"name":"author"}"#
+		);
+		let arrays = &clusters[1];
+		assert_eq!(arrays.base, "[x_a01,x_a02,x_a03,x_a04,x_a05,x_a06]");
+		assert_eq!(
+			arrays.spread(&arrays.members[1], "x_f01"),
+			"[...x_f01,x_b02]"
+		);
+		assert!(model.net_benefit(&arrays.candidate) > 0.0);
+	}
+}
@@ -0,0 +1,597 @@
+//! Automatic relay config setup (`relay-dedup init` / `--fix-config`).
+//!
+//! Adds the feature flags relay-dedup needs to `relay.config.json` (or
+//! `package.json`'s `relay` key) and a `relay:dedup` script to `package.json`.
+//! Files are edited as text so key order, indentation and formatting of the
+//! untouched parts are preserved.
+
+use crate::relay_config::{dedup_disabled, is_javascript_config, is_package_json, RelayConfig};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Flag that turns off Relay's built-in deduplication
+pub const DEDUP_FLAG: &str = "disable_deduping_common_structures_in_artifacts";
+
+/// Flag that works around Relay's strict alias checking when any flag is set
+pub const ALIAS_FLAG: &str = "enforce_fragment_alias_where_ambiguous";
+
+/// Name of the package.json script added by `init`
+pub const SCRIPT_NAME: &str = "relay:dedup";
+
+/// A pending change to one file
+#[derive(Debug, Clone)]
+pub struct FilePatch {
+	pub path: PathBuf,
+	pub original: String,
+	pub updated: String,
+}
+
+impl FilePatch {
+	/// Render the change as a unified diff
+	pub fn diff(&self) -> String {
+		unified_diff(
+			&self.original,
+			&self.updated,
+			&self.path.display().to_string(),
+		)
+	}
+
+	pub fn apply(&self) -> Result<()> {
+		fs::write(&self.path, &self.updated)
+			.with_context(|| format!("Failed to write {}", self.path.display()))
+	}
+}
+
+/// Compute the edits that make `config` pass validation and add the
+/// `relay:dedup` script to the nearest package.json.
+///
+/// Returns no patches when everything is already in place.
+pub fn plan_config_fixes(config: &RelayConfig) -> Result<Vec<FilePatch>> {
+	let config_path = &config.config_path;
+	let needs_flags = config.projects.iter().any(|p| !dedup_disabled(p));
+	if needs_flags && is_javascript_config(config_path) {
+		bail!(
+			"{} is a JavaScript config and can't be patched automatically.\n\
+			 Add \"{}\": {{ \"kind\": \"enabled\" }} to its featureFlags by hand.",
+			config_path.display(),
+			DEDUP_FLAG
+		);
+	}
+
+	let mut patches: Vec<FilePatch> = Vec::new();
+
+	if needs_flags {
+		let original = read(config_path)?;
+		let base: Vec<&str> = if is_package_json(config_path) {
+			vec!["relay"]
+		} else {
+			Vec::new()
+		};
+
+		let mut updated = original.clone();
+		for project in config.projects.iter().filter(|p| !dedup_disabled(p)) {
+			let mut flags_path = base.clone();
+			if config.is_multi_project() {
+				flags_path.extend(["projects", project.name.as_str()]);
+			}
+			flags_path.push("featureFlags");
+
+			let with_key = |key| {
+				let mut path = flags_path.clone();
+				path.push(key);
+				path
+			};
+			updated = upsert(
+				&updated,
+				&with_key(DEDUP_FLAG),
+				&json!({ "kind": "enabled" }),
+			)?;
+
+			let has_alias_flag = project
+				.feature_flags
+				.as_ref()
+				.and_then(|flags| flags.get(ALIAS_FLAG))
+				.is_some();
+			if !has_alias_flag {
+				updated = upsert(
+					&updated,
+					&with_key(ALIAS_FLAG),
+					&json!({ "kind": "disabled" }),
+				)?;
+			}
+		}
+		patches.push(FilePatch {
+			path: config_path.clone(),
+			original,
+			updated,
+		});
+	}
+
+	// Add the script to the nearest package.json (possibly the config itself)
+	let config_dir = config_path.parent().unwrap_or(Path::new(""));
+	let package_json = config_dir
+		.ancestors()
+		.map(|dir| dir.join("package.json"))
+		.find(|path| path.exists());
+	if let Some(package_json) = package_json {
+		let index = patches.iter().position(|p| p.path == package_json);
+		let current = match index {
+			Some(i) => patches[i].updated.clone(),
+			None => read(&package_json)?,
+		};
+		let parsed: Value = serde_json::from_str(&current)
+			.with_context(|| format!("Failed to parse {}", package_json.display()))?;
+		let has_script = parsed
+			.get("scripts")
+			.and_then(|s| s.get(SCRIPT_NAME))
+			.is_some();
+		if !has_script {
+			let updated = upsert(&current, &["scripts", SCRIPT_NAME], &json!("relay-dedup"))?;
+			match index {
+				Some(i) => patches[i].updated = updated,
+				None => patches.push(FilePatch {
+					path: package_json,
+					original: current,
+					updated,
+				}),
+			}
+		}
+	}
+
+	patches.retain(|p| p.original != p.updated);
+	Ok(patches)
+}
+
+fn read(path: &Path) -> Result<String> {
+	fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+/// Byte spans of one member of a JSON object
+struct Member {
+	key: String,
+	start: usize,
+	value_start: usize,
+	value_end: usize,
+}
+
+/// Byte spans of a JSON object and its members
+struct ObjectSpan {
+	open: usize,
+	close: usize,
+	members: Vec<Member>,
+}
+
+/// Set the value at `path` (a chain of object keys) in JSON text.
+///
+/// An existing value is replaced in place; missing keys are appended to the
+/// innermost existing object, using the indentation of its members.
+pub fn upsert(text: &str, path: &[&str], value: &Value) -> Result<String> {
+	let bytes = text.as_bytes();
+	let root = skip_ws(bytes, 0);
+	if bytes.get(root) != Some(&b'{') {
+		bail!("expected a JSON object");
+	}
+	let unit = indent_unit(text, root)?;
+
+	let mut object = parse_object(text, root)?;
+	for (depth, key) in path.iter().enumerate() {
+		let Some(member) = object.members.iter().find(|m| m.key == *key) else {
+			let rest = &path[depth + 1..];
+			return Ok(insert_member(text, &object, key, rest, value, &unit));
+		};
+
+		if depth == path.len() - 1 {
+			let indent = line_indent(text, member.start);
+			let rendered = render(value, indent, &unit);
+			return Ok(format!(
+				"{}{}{}",
+				&text[..member.value_start],
+				rendered,
+				&text[member.value_end..]
+			));
+		}
+		if bytes[member.value_start] != b'{' {
+			bail!("\"{}\" is not an object", key);
+		}
+		object = parse_object(text, member.value_start)?;
+	}
+	bail!("empty key path")
+}
+
+/// Append `key` to `object`, wrapping `value` in one new object per key in `rest`
+fn insert_member(
+	text: &str,
+	object: &ObjectSpan,
+	key: &str,
+	rest: &[&str],
+	value: &Value,
+	unit: &str,
+) -> String {
+	let key = serde_json::to_string(key).unwrap_or_default();
+	let Some(last) = object.members.last() else {
+		// Empty object: expand it onto its own lines
+		let outer = line_indent(text, object.open);
+		let inner = format!("{}{}", outer, unit);
+		return format!(
+			"{}{{\n{}{}: {}\n{}}}{}",
+			&text[..object.open],
+			inner,
+			key,
+			render_nested(rest, value, &inner, unit),
+			outer,
+			&text[object.close + 1..]
+		);
+	};
+
+	// Objects written on one line stay on one line
+	let single_line = !text[object.open..object.close].contains('\n');
+	let indent = line_indent(text, last.start);
+	let (separator, rendered) = if single_line {
+		let nested = rest
+			.iter()
+			.rev()
+			.fold(value.clone(), |inner, key| json!({ *key: inner }));
+		(", ".to_string(), render_inline(&nested))
+	} else {
+		(
+			format!(",\n{}", indent),
+			render_nested(rest, value, indent, unit),
+		)
+	};
+	format!(
+		"{}{}{}: {}{}",
+		&text[..last.value_end],
+		separator,
+		key,
+		rendered,
+		&text[last.value_end..]
+	)
+}
+
+/// Render `value` wrapped in one multi-line object per key in `rest`
+fn render_nested(rest: &[&str], value: &Value, indent: &str, unit: &str) -> String {
+	let Some((key, rest)) = rest.split_first() else {
+		return render(value, indent, unit);
+	};
+	let inner = format!("{}{}", indent, unit);
+	format!(
+		"{{\n{}{}: {}\n{}}}",
+		inner,
+		serde_json::to_string(key).unwrap_or_default(),
+		render_nested(rest, value, &inner, unit),
+		indent
+	)
+}
+
+/// Render a value; objects of scalars stay on one line like `{ "kind": "enabled" }`
+fn render(value: &Value, indent: &str, unit: &str) -> String {
+	let Value::Object(map) = value else {
+		return value.to_string();
+	};
+	if map.is_empty() {
+		return "{}".to_string();
+	}
+	let key = |k: &String| serde_json::to_string(k).unwrap_or_default();
+	if map.values().all(|v| !v.is_object() && !v.is_array()) {
+		let members: Vec<String> = map
+			.iter()
+			.map(|(k, v)| format!("{}: {}", key(k), v))
+			.collect();
+		return format!("{{ {} }}", members.join(", "));
+	}
+	let inner = format!("{}{}", indent, unit);
+	let members: Vec<String> = map
+		.iter()
+		.map(|(k, v)| format!("{}{}: {}", inner, key(k), render(v, &inner, unit)))
+		.collect();
+	format!("{{\n{}\n{}}}", members.join(",\n"), indent)
+}
+
+fn render_inline(value: &Value) -> String {
+	match value {
+		Value::Object(map) if !map.is_empty() => {
+			let members: Vec<String> = map
+				.iter()
+				.map(|(k, v)| {
+					let key = serde_json::to_string(k).unwrap_or_default();
+					format!("{}: {}", key, render_inline(v))
+				})
+				.collect();
+			format!("{{ {} }}", members.join(", "))
+		}
+		_ => value.to_string(),
+	}
+}
+
+/// Leading whitespace of the line containing `pos`
+fn line_indent(text: &str, pos: usize) -> &str {
+	let line_start = text[..pos].rfind('\n').map(|i| i + 1).unwrap_or(0);
+	let line = &text[line_start..];
+	&line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// The indentation step used by the file (two spaces if it can't be told)
+fn indent_unit(text: &str, root: usize) -> Result<String> {
+	let object = parse_object(text, root)?;
+	let unit = object
+		.members
+		.first()
+		.map(|m| line_indent(text, m.start))
+		.filter(|indent| !indent.is_empty() && text[root..object.close].contains('\n'));
+	Ok(unit.unwrap_or("  ").to_string())
+}
+
+fn skip_ws(bytes: &[u8], mut i: usize) -> usize {
+	while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+		i += 1;
+	}
+	i
+}
+
+/// End (exclusive) of the string literal starting at `i`
+fn string_end(bytes: &[u8], mut i: usize) -> Result<usize> {
+	i += 1;
+	while i < bytes.len() {
+		match bytes[i] {
+			b'\\' => i += 2,
+			b'"' => return Ok(i + 1),
+			_ => i += 1,
+		}
+	}
+	bail!("unterminated string")
+}
+
+/// End (exclusive) of the value starting at `i`
+fn value_end(bytes: &[u8], i: usize) -> Result<usize> {
+	match bytes.get(i) {
+		Some(b'"') => string_end(bytes, i),
+		Some(b'{' | b'[') => {
+			let mut depth = 0;
+			let mut j = i;
+			while j < bytes.len() {
+				match bytes[j] {
+					b'"' => {
+						j = string_end(bytes, j)?;
+						continue;
+					}
+					b'{' | b'[' => depth += 1,
+					b'}' | b']' => {
+						depth -= 1;
+						if depth == 0 {
+							return Ok(j + 1);
+						}
+					}
+					_ => {}
+				}
+				j += 1;
+			}
+			bail!("unterminated object or array")
+		}
+		Some(_) => {
+			let mut j = i;
+			while j < bytes.len() && !matches!(bytes[j], b',' | b'}' | b']') {
+				j += 1;
+			}
+			// Trailing whitespace isn't part of the value
+			while j > i && bytes[j - 1].is_ascii_whitespace() {
+				j -= 1;
+			}
+			Ok(j)
+		}
+		None => bail!("unexpected end of input"),
+	}
+}
+
+fn parse_object(text: &str, open: usize) -> Result<ObjectSpan> {
+	let bytes = text.as_bytes();
+	let mut members = Vec::new();
+	let mut i = open + 1;
+	loop {
+		i = skip_ws(bytes, i);
+		match bytes.get(i) {
+			Some(b'}') => {
+				return Ok(ObjectSpan {
+					open,
+					close: i,
+					members,
+				})
+			}
+			Some(b'"') => {}
+			_ => bail!("expected a key at byte {}", i),
+		}
+
+		let key_end = string_end(bytes, i)?;
+		let key: String = serde_json::from_str(&text[i..key_end])
+			.map_err(|e| anyhow!("invalid key at byte {}: {}", i, e))?;
+		let colon = skip_ws(bytes, key_end);
+		if bytes.get(colon) != Some(&b':') {
+			bail!("expected ':' at byte {}", colon);
+		}
+		let value_start = skip_ws(bytes, colon + 1);
+		let end = value_end(bytes, value_start)?;
+		members.push(Member {
+			key,
+			start: i,
+			value_start,
+			value_end: end,
+		});
+
+		i = skip_ws(bytes, end);
+		match bytes.get(i) {
+			Some(b',') => i += 1,
+			Some(b'}') => {}
+			_ => bail!("expected ',' or '}}' at byte {}", i),
+		}
+	}
+}
+
+/// A minimal unified diff (LCS over lines, three lines of context)
+fn unified_diff(old: &str, new: &str, label: &str) -> String {
+	const CONTEXT: usize = 3;
+	let a: Vec<&str> = old.lines().collect();
+	let b: Vec<&str> = new.lines().collect();
+
+	// lcs[i][j] = length of the LCS of a[i..] and b[j..]
+	let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+	for i in (0..a.len()).rev() {
+		for j in (0..b.len()).rev() {
+			lcs[i][j] = if a[i] == b[j] {
+				lcs[i + 1][j + 1] + 1
+			} else {
+				lcs[i + 1][j].max(lcs[i][j + 1])
+			};
+		}
+	}
+
+	// (tag, old line index, new line index, text)
+	let mut ops: Vec<(char, usize, usize, &str)> = Vec::new();
+	let (mut i, mut j) = (0, 0);
+	while i < a.len() || j < b.len() {
+		if i < a.len() && j < b.len() && a[i] == b[j] {
+			ops.push((' ', i, j, a[i]));
+			i += 1;
+			j += 1;
+		} else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+			ops.push(('-', i, j, a[i]));
+			i += 1;
+		} else {
+			ops.push(('+', i, j, b[j]));
+			j += 1;
+		}
+	}
+
+	let mut out = format!("--- {}\n+++ {}\n", label, label);
+	let changes: Vec<usize> = (0..ops.len()).filter(|&k| ops[k].0 != ' ').collect();
+	let mut k = 0;
+	while k < changes.len() {
+		let start = changes[k].saturating_sub(CONTEXT);
+		let mut end = changes[k];
+		while k < changes.len() && changes[k] <= end + 2 * CONTEXT {
+			end = changes[k];
+			k += 1;
+		}
+		let end = (end + CONTEXT + 1).min(ops.len());
+
+		let hunk = &ops[start..end];
+		let old_count = hunk.iter().filter(|op| op.0 != '+').count();
+		let new_count = hunk.iter().filter(|op| op.0 != '-').count();
+		out.push_str(&format!(
+			"@@ -{},{} +{},{} @@\n",
+			hunk[0].1 + 1,
+			old_count,
+			hunk[0].2 + 1,
+			new_count
+		));
+		for (tag, _, _, line) in hunk {
+			out.push_str(&format!("{}{}\n", tag, line));
+		}
+	}
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::relay_config::find_relay_config;
+	use tempfile::tempdir;
+
+	#[test]
+	fn test_upsert_keeps_order_and_indentation() {
+		let text = "{\n    \"src\": \"./src\",\n    \"language\": \"typescript\"\n}\n";
+		let updated = upsert(
+			text,
+			&["featureFlags", DEDUP_FLAG],
+			&json!({ "kind": "enabled" }),
+		)
+		.unwrap();
+		assert_eq!(
+			updated,
+			"{\n    \"src\": \"./src\",\n    \"language\": \"typescript\",\n    \"featureFlags\": {\n        \"disable_deduping_common_structures_in_artifacts\": { \"kind\": \"enabled\" }\n    }\n}\n"
+		);
+
+		// Replacing a value leaves everything else alone
+		let flipped = upsert(
+			&updated,
+			&["featureFlags", DEDUP_FLAG],
+			&json!({ "kind": "disabled" }),
+		)
+		.unwrap();
+		assert_eq!(flipped, updated.replace("enabled", "disabled"));
+
+		// Tabs and empty objects
+		let text = "{\n\t\"featureFlags\": {}\n}";
+		let updated = upsert(text, &["featureFlags", "a"], &json!(true)).unwrap();
+		assert_eq!(updated, "{\n\t\"featureFlags\": {\n\t\t\"a\": true\n\t}\n}");
+	}
+
+	#[test]
+	fn test_plan_config_fixes() {
+		let temp = tempdir().unwrap();
+		fs::write(
+			temp.path().join("package.json"),
+			"{\n  \"name\": \"app\",\n  \"scripts\": {\n    \"relay\": \"relay-compiler\"\n  },\n  \"relay\": {\n    \"src\": \"./src\",\n    \"featureFlags\": {\n      \"enforce_fragment_alias_where_ambiguous\": { \"kind\": \"enabled\" }\n    }\n  }\n}\n",
+		)
+		.unwrap();
+
+		let config = find_relay_config(temp.path()).unwrap().unwrap();
+		let patches = plan_config_fixes(&config).unwrap();
+		assert_eq!(patches.len(), 1);
+
+		let updated = &patches[0].updated;
+		assert!(updated.contains(
+			"    \"relay\": \"relay-compiler\",\n    \"relay:dedup\": \"relay-dedup\"\n"
+		));
+		assert!(updated.contains(
+			"      \"enforce_fragment_alias_where_ambiguous\": { \"kind\": \"enabled\" },\n      \"disable_deduping_common_structures_in_artifacts\": { \"kind\": \"enabled\" }\n"
+		));
+		assert!(patches[0]
+			.diff()
+			.contains("+    \"relay:dedup\": \"relay-dedup\""));
+
+		// Once applied there is nothing left to do
+		patches[0].apply().unwrap();
+		let config = find_relay_config(temp.path()).unwrap().unwrap();
+		assert!(dedup_disabled(&config.projects[0]));
+		assert!(plan_config_fixes(&config).unwrap().is_empty());
+	}
+
+	#[test]
+	fn test_plan_multi_project_fixes() {
+		let temp = tempdir().unwrap();
+		fs::write(
+			temp.path().join("relay.config.json"),
+			r#"{
+  "sources": { "app": "app", "admin": "admin" },
+  "projects": {
+    "app": {
+      "featureFlags": { "disable_deduping_common_structures_in_artifacts": { "kind": "enabled" } }
+    },
+    "admin": { "language": "flow" }
+  }
+}"#,
+		)
+		.unwrap();
+
+		let config = find_relay_config(temp.path()).unwrap().unwrap();
+		let patches = plan_config_fixes(&config).unwrap();
+		assert_eq!(patches.len(), 1);
+		assert!(patches[0].updated.contains(
+			"    \"admin\": { \"language\": \"flow\", \"featureFlags\": { \"disable_deduping_common_structures_in_artifacts\": { \"kind\": \"enabled\" }, \"enforce_fragment_alias_where_ambiguous\": { \"kind\": \"disabled\" } } }\n"
+		));
+
+		patches[0].apply().unwrap();
+		let config = find_relay_config(temp.path()).unwrap().unwrap();
+		assert!(config.projects.iter().all(dedup_disabled));
+	}
+
+	#[test]
+	fn test_unified_diff() {
+		let diff = unified_diff("a\nb\nc\n", "a\nb\nx\nc\n", "file");
+		assert_eq!(
+			diff,
+			"--- file\n+++ file\n@@ -1,3 +1,4 @@\n a\n b\n+x\n c\n"
+		);
+	}
+}
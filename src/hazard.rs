@@ -0,0 +1,289 @@
+//! Mutation hazards for shared object identity.
+//!
+//! Extraction makes several artifacts point at the same object. That is only
+//! safe if nobody mutates it or relies on its identity: artifacts run
+//! `(node as any).hash = "..."` after the literal, and Relay keeps `WeakMap`
+//! caches keyed by some AST nodes. This module finds the objects an
+//! artifact's statements mutate; [`crate::tree::FileTree`] keeps them (and
+//! nodes of identity-sensitive kinds) out of extraction.
+
+use crate::lexer::{unquote, Token, TokenKind};
+use crate::statement::{statements, StatementKind};
+
+/// Node kinds Relay uses as cache keys by identity
+pub const IDENTITY_SENSITIVE_KINDS: &[&str] = &[
+	"Request",
+	"Fragment",
+	"Operation",
+	"SplitOperation",
+	"UpdatableQuery",
+	"InlineDataFragment",
+];
+
+/// Assignment operators (`==` and friends are separate tokens)
+const ASSIGNMENT_OPERATORS: &[&str] = &[
+	"=", "+=", "-=", "*=", "/=", "%=", "**=", "<<=", ">>=", ">>>=", "&=", "|=", "^=", "&&=", "||=",
+	"??=",
+];
+
+/// Methods that mutate the array, map or set they are called on
+const MUTATING_METHODS: &[&str] = &[
+	"push",
+	"pop",
+	"shift",
+	"unshift",
+	"splice",
+	"sort",
+	"reverse",
+	"fill",
+	"copyWithin",
+	"set",
+	"add",
+	"delete",
+	"clear",
+];
+
+/// `Object.*` / `Reflect.*` functions that mutate their first argument
+const MUTATING_FUNCTIONS: &[&str] = &[
+	"assign",
+	"defineProperty",
+	"defineProperties",
+	"setPrototypeOf",
+	"deleteProperty",
+	"set",
+	"freeze",
+	"seal",
+	"preventExtensions",
+];
+
+/// One step of a member chain
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+	/// `.name` or `["name"]`
+	Key(String),
+	/// `[expr]`: an index or a computed key that can't be resolved statically
+	Computed,
+}
+
+/// An object that a statement mutates: `binding` followed by `path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MutatedTarget {
+	pub binding: String,
+	pub path: Vec<Segment>,
+}
+
+impl std::fmt::Display for MutatedTarget {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}", self.binding)?;
+		for segment in &self.path {
+			match segment {
+				Segment::Key(key) => write!(f, ".{}", key)?,
+				Segment::Computed => write!(f, "[…]")?,
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Every object mutated by the statements in comment-free `tokens`.
+///
+/// Recognizes assignments (`(node as any).hash = ...`, `node.a.b += 1`),
+/// `++` / `--`, `delete`, mutating method calls (`node.selections.push(...)`)
+/// and `Object.assign(node.params, ...)`-style calls. Aliases (`const op =
+/// node.operation; op.x = 1`) are not followed.
+pub fn mutated_targets(src: &str, tokens: &[Token]) -> Vec<MutatedTarget> {
+	let mut result = Vec::new();
+	for statement in statements(src, tokens) {
+		if matches!(
+			statement.kind,
+			StatementKind::Module | StatementKind::TypeDeclaration
+		) {
+			continue;
+		}
+		let tokens = &tokens[statement.tokens];
+		for k in 0..tokens.len() {
+			if let Some(target) = target_at(src, tokens, k) {
+				if !result.contains(&target) {
+					result.push(target);
+				}
+			}
+		}
+	}
+	result
+}
+
+/// The object mutated by the member chain starting at `tokens[k]`, if any
+fn target_at(src: &str, tokens: &[Token], k: usize) -> Option<MutatedTarget> {
+	let token = &tokens[k];
+	if token.kind != TokenKind::Ident {
+		return None;
+	}
+	let prev = k.checked_sub(1).map(|i| &tokens[i]);
+	if prev.is_some_and(|p| p.is_punct(src, ".") || p.is_punct(src, "?.")) {
+		return None;
+	}
+
+	// `(node as any)` / `(node)` (Flow's `/*: any*/` is a comment)
+	let mut start = k;
+	let mut j = k + 1;
+	let is_call = k >= 2
+		&& (tokens[k - 2].kind == TokenKind::Ident
+			|| tokens[k - 2].is_punct(src, ")")
+			|| tokens[k - 2].is_punct(src, "]"));
+	if prev.is_some_and(|p| p.is_punct(src, "(")) && !is_call {
+		if let Some(close) = cast_end(src, tokens, j) {
+			start = k - 1;
+			j = close + 1;
+		}
+	}
+
+	let mut path = Vec::new();
+	while let Some(next) = tokens.get(j) {
+		if next.is_punct(src, "!") {
+			j += 1;
+		} else if next.is_punct(src, ".") || next.is_punct(src, "?.") {
+			let name = tokens.get(j + 1).filter(|t| t.kind == TokenKind::Ident)?;
+			path.push(Segment::Key(name.text(src).to_string()));
+			j += 2;
+		} else if next.is_punct(src, "[") {
+			let close = matching_bracket(src, tokens, j)?;
+			let segment = match &tokens[j + 1..close] {
+				[key] if key.kind == TokenKind::String => Segment::Key(unquote(key.text(src))),
+				_ => Segment::Computed,
+			};
+			path.push(segment);
+			j = close + 1;
+		} else {
+			break;
+		}
+	}
+
+	let before = start.checked_sub(1).map(|i| &tokens[i]);
+	let after = tokens.get(j);
+	let is = |token: Option<&Token>, texts: &[&str]| {
+		token.is_some_and(|t| t.kind != TokenKind::String && texts.contains(&t.text(src)))
+	};
+
+	let object_len = if is(after, ASSIGNMENT_OPERATORS)
+		|| is(after, &["++", "--"])
+		|| is(before, &["++", "--", "delete"])
+	{
+		// Writes a property of the object holding the last segment
+		path.len().checked_sub(1)?
+	} else if is(after, &["("])
+		&& matches!(path.last(), Some(Segment::Key(method)) if MUTATING_METHODS.contains(&method.as_str()))
+	{
+		path.len() - 1
+	} else if is_mutating_call_argument(src, tokens, start) && is(after, &[",", ")"]) {
+		path.len()
+	} else {
+		return None;
+	};
+
+	path.truncate(object_len);
+	Some(MutatedTarget {
+		binding: token.text(src).to_string(),
+		path,
+	})
+}
+
+/// Index of the `)` ending `(x as T)` or `(x)` when `tokens[j]` follows `x`
+fn cast_end(src: &str, tokens: &[Token], j: usize) -> Option<usize> {
+	let next = tokens.get(j)?;
+	if next.is_punct(src, ")") {
+		return Some(j);
+	}
+	if !(next.kind == TokenKind::Ident && next.text(src) == "as") {
+		return None;
+	}
+	let mut depth = 0i32;
+	for (i, token) in tokens.iter().enumerate().skip(j) {
+		match token.text(src) {
+			"(" | "[" | "{" if token.kind == TokenKind::Punct => depth += 1,
+			")" if token.kind == TokenKind::Punct && depth == 0 => return Some(i),
+			")" | "]" | "}" if token.kind == TokenKind::Punct => depth -= 1,
+			_ => {}
+		}
+	}
+	None
+}
+
+/// Whether `tokens[start]` is the first argument of `Object.assign(` or a
+/// similar mutating call
+fn is_mutating_call_argument(src: &str, tokens: &[Token], start: usize) -> bool {
+	let Some(callee) = start.checked_sub(4) else {
+		return false;
+	};
+	matches!(&tokens[callee..start], [object, dot, function, paren]
+		if matches!(object.text(src), "Object" | "Reflect")
+			&& dot.is_punct(src, ".")
+			&& function.kind == TokenKind::Ident
+			&& MUTATING_FUNCTIONS.contains(&function.text(src))
+			&& paren.is_punct(src, "("))
+}
+
+/// Index of the `]` matching the `[` at `open`
+fn matching_bracket(src: &str, tokens: &[Token], open: usize) -> Option<usize> {
+	let mut depth = 0;
+	for (i, token) in tokens.iter().enumerate().skip(open) {
+		if token.kind != TokenKind::Punct {
+			continue;
+		}
+		match token.text(src) {
+			"(" | "[" | "{" => depth += 1,
+			")" | "]" | "}" => {
+				depth -= 1;
+				if depth == 0 {
+					return Some(i);
+				}
+			}
+			_ => {}
+		}
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::lexer::tokenize;
+
+	#[test]
+	fn test_mutated_targets() {
+		let src = r#"import type { ConcreteRequest } from 'relay-runtime';
+const node: ConcreteRequest = {"params": {"id": null}, "fragment": {"selections": []}};
+(node as any).hash = "abc";
+(node/*: any*/).params["cacheID"] = "def";
+if (__DEV__) {
+  node.fragment.selections.push({});
+  delete node.fragment.metadata;
+}
+Object.assign(node.params.metadata, {});
+Object.freeze(node.operation);
+node.selections[0].x = 1;
+node = other;
+const same = node.params.id === null;
+export default node;
+"#;
+		let tokens: Vec<Token> = tokenize(src)
+			.into_iter()
+			.filter(|t| t.kind != TokenKind::Comment)
+			.collect();
+		let targets: Vec<String> = mutated_targets(src, &tokens)
+			.iter()
+			.map(|t| t.to_string())
+			.collect();
+		assert_eq!(
+			targets,
+			vec![
+				"node",
+				"node.params",
+				"node.fragment.selections",
+				"node.fragment",
+				"node.params.metadata",
+				"node.operation",
+				"node.selections[…]",
+			]
+		);
+	}
+}
@@ -0,0 +1,263 @@
+//! Inlining of Relay's own deduplication output.
+//!
+//! With `disable_deduping_common_structures_in_artifacts` off, relay-compiler
+//! hoists repeated values into local variables inside an IIFE:
+//!
+//! ```js
+//! const node = (function(){
+//! var v0 = {...},
+//! v1 = [v0];
+//! return {"selections": v1};
+//! })();
+//! ```
+//!
+//! `v0` isn't a ref this tool understands, so such literals were never leaves.
+//! Inlining the bindings turns the artifact back into a plain `node` literal
+//! that can be deduplicated like any other.
+
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::statement::{statements, StatementKind};
+use std::collections::HashMap;
+
+/// Replace every `(function(){ var vN = ...; return ...; })()` initializer
+/// with its return value, the `vN` bindings substituted.
+///
+/// Returns `None` when an IIFE doesn't have exactly that shape (or there is
+/// none), so the caller can refuse the artifact instead of guessing.
+pub fn inline_relay_dedup(content: &str) -> Option<String> {
+	let tokens: Vec<Token> = tokenize(content)
+		.into_iter()
+		.filter(|t| t.kind != TokenKind::Comment)
+		.collect();
+
+	let mut replacements: Vec<(usize, usize, String)> = Vec::new();
+	for statement in statements(content, &tokens) {
+		for initializer in statement.initializers {
+			let initializer = &tokens[initializer];
+			if !is_function_call(content, initializer) {
+				continue;
+			}
+			let (first, last) = (initializer.first()?, initializer.last()?);
+			let inlined = inline_iife(content, initializer)?;
+			replacements.push((first.start, last.end, inlined));
+		}
+	}
+	if replacements.is_empty() {
+		return None;
+	}
+
+	let mut result = String::with_capacity(content.len());
+	let mut last_end = 0;
+	for (start, end, text) in replacements {
+		result.push_str(&content[last_end..start]);
+		result.push_str(&text);
+		last_end = end;
+	}
+	result.push_str(&content[last_end..]);
+	Some(result)
+}
+
+/// Whether an initializer starts with `(function`
+fn is_function_call(src: &str, tokens: &[Token]) -> bool {
+	matches!(tokens, [open, function, ..]
+		if open.is_punct(src, "(")
+			&& function.kind == TokenKind::Ident
+			&& function.text(src) == "function")
+}
+
+/// Inline `(function(){ <var declarations> return <expr>; })()`
+fn inline_iife(src: &str, tokens: &[Token]) -> Option<String> {
+	let punct = |i: usize, p: &str| tokens.get(i).is_some_and(|t| t.is_punct(src, p));
+	let n = tokens.len();
+	let shape = n >= 9
+		&& punct(2, "(")
+		&& punct(3, ")")
+		&& punct(4, "{")
+		&& punct(n - 4, "}")
+		&& punct(n - 3, ")")
+		&& punct(n - 2, "(")
+		&& punct(n - 1, ")");
+	if !shape || closing_brace(src, tokens, 4)? != n - 4 {
+		return None;
+	}
+
+	let body = &tokens[5..n - 4];
+	let mut bindings: HashMap<&str, String> = HashMap::new();
+	let mut returned = None;
+	for statement in statements(src, body) {
+		let statement_tokens = &body[statement.tokens.clone()];
+		if returned.is_some() {
+			// Nothing may follow the return
+			return None;
+		}
+		match statement.kind {
+			StatementKind::Declaration => {
+				for initializer in statement.initializers {
+					// `name = value`
+					let name = body.get(initializer.start.checked_sub(2)?)?;
+					let name = name.text(src);
+					let value = &body[initializer];
+					if !is_hoisted_name(name) || value.is_empty() {
+						return None;
+					}
+					let expanded = substitute(src, value, &bindings);
+					bindings.insert(name, expanded);
+				}
+			}
+			_ if statement_tokens
+				.first()
+				.is_some_and(|t| t.kind == TokenKind::Ident && t.text(src) == "return") =>
+			{
+				let end = statement_tokens.len()
+					- usize::from(statement_tokens.last()?.is_punct(src, ";"));
+				let value = &statement_tokens[1..end];
+				if value.is_empty() {
+					return None;
+				}
+				returned = Some(substitute(src, value, &bindings));
+			}
+			_ => return None,
+		}
+	}
+	returned
+}
+
+/// Relay names its hoisted values `v0`, `v1`, ...
+fn is_hoisted_name(name: &str) -> bool {
+	name.len() > 1 && name.starts_with('v') && name[1..].bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Index of the `}` matching the `{` at `open`
+fn closing_brace(src: &str, tokens: &[Token], open: usize) -> Option<usize> {
+	let mut depth = 0;
+	for (k, token) in tokens.iter().enumerate().skip(open) {
+		if token.is_punct(src, "{") {
+			depth += 1;
+		} else if token.is_punct(src, "}") {
+			depth -= 1;
+			if depth == 0 {
+				return Some(k);
+			}
+		}
+	}
+	None
+}
+
+/// Source text of `tokens` (with the comments and whitespace between them),
+/// bound identifiers replaced by their values
+///
+/// Relay references its bindings as `(v0/*: any*/)`; the cast is dropped along
+/// with the identifier so the value is a plain literal again.
+fn substitute(src: &str, tokens: &[Token], bindings: &HashMap<&str, String>) -> String {
+	let (Some(first), Some(last)) = (tokens.first(), tokens.last()) else {
+		return String::new();
+	};
+	let mut result = String::new();
+	let mut last_end = first.start;
+	for (k, token) in tokens.iter().enumerate() {
+		if token.kind != TokenKind::Ident {
+			continue;
+		}
+		// `a.v0` is a property, not the binding
+		let is_property = k > 0 && tokens[k - 1].is_punct(src, ".");
+		if let Some(value) = bindings.get(token.text(src)).filter(|_| !is_property) {
+			let (start, end) = match cast_around(src, tokens, k) {
+				Some((open, close)) => (tokens[open].start, tokens[close].end),
+				None => (token.start, token.end),
+			};
+			result.push_str(&src[last_end..start]);
+			result.push_str(value);
+			last_end = end;
+		}
+	}
+	result.push_str(&src[last_end..last.end]);
+	result
+}
+
+/// Indices of the parentheses of a `(vN/*: any*/)` cast around `tokens[k]`
+///
+/// Parentheses that follow an operand are a call (`f(v0)`), not a cast.
+fn cast_around(src: &str, tokens: &[Token], k: usize) -> Option<(usize, usize)> {
+	let (open, close) = (k.checked_sub(1)?, k + 1);
+	if !tokens[open].is_punct(src, "(") || !tokens.get(close)?.is_punct(src, ")") {
+		return None;
+	}
+	let is_call = open > 0
+		&& matches!(tokens[open - 1].kind, TokenKind::Ident | TokenKind::Punct)
+		&& !matches!(
+			tokens[open - 1].text(src),
+			"(" | "[" | "{" | "," | ":" | "=" | "return"
+		);
+	if is_call {
+		return None;
+	}
+	// Only whitespace and comments (the type cast) between `vN` and `)`
+	let between = tokenize(&src[tokens[k].end..tokens[close].start]);
+	between
+		.iter()
+		.all(|t| t.kind == TokenKind::Comment)
+		.then_some((open, close))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_inline_relay_dedup() {
+		let content = r#"/** @generated */
+var node/*: ConcreteRequest*/ = (function(){
+var v0 = {"kind": "ScalarField", "name": "id"},
+v1 = [
+  (v0/*: any*/),
+  {"alias": "v0"}
+];
+return {
+  "fragment": {"selections": (v1/*: any*/)},
+  "operation": {"selections": [(v0/*: any*/)], "fields": [(v0/*: any*/)]}
+};
+})();
+
+(node/*: any*/).hash = "abc";
+module.exports = node;
+"#;
+		assert_eq!(
+			inline_relay_dedup(content).unwrap(),
+			r#"/** @generated */
+var node/*: ConcreteRequest*/ = {
+  "fragment": {"selections": [
+  {"kind": "ScalarField", "name": "id"},
+  {"alias": "v0"}
+]},
+  "operation": {"selections": [{"kind": "ScalarField", "name": "id"}], "fields": [{"kind": "ScalarField", "name": "id"}]}
+};
+
+(node/*: any*/).hash = "abc";
+module.exports = node;
+"#
+		);
+
+		// A call's parentheses stay, only casts are dropped
+		assert_eq!(
+			inline_relay_dedup(
+				"const node = (function(){\nvar v0 = 1;\nreturn [f(v0), (v0)];\n})();"
+			)
+			.unwrap(),
+			"const node = [f(1), 1];"
+		);
+
+		// Plain artifacts have nothing to inline
+		assert_eq!(inline_relay_dedup("const node = {\"a\": 1};"), None);
+		// Anything but bindings and a return is refused
+		assert_eq!(
+			inline_relay_dedup(
+				"const node = (function(){\nvar v0 = 1;\nsideEffect();\nreturn {\"a\": v0};\n})();"
+			),
+			None
+		);
+		assert_eq!(
+			inline_relay_dedup("const node = (function(){\nvar v0 = null;\nreturn {"),
+			None
+		);
+	}
+}
@@ -0,0 +1,905 @@
+//! Static extraction of JavaScript relay configs.
+//!
+//! Reads the object exported by `relay.config.js` / `.cjs` / `.mjs` without
+//! running Node. Only a small, side-effect free subset of JavaScript is
+//! understood:
+//!
+//! - `module.exports = ...` and `export default ...`
+//! - object, array, string, template, number, boolean and null literals
+//! - top-level `const` / `let` / `var` bindings of such values
+//! - string concatenation with `+`
+//! - `path.join`, `path.resolve` and `path.dirname` (via `require('path')` or
+//!   `import ... from 'path'`), `__dirname`, `__filename`, `import.meta.url`,
+//!   `import.meta.dirname` and `fileURLToPath`
+//!
+//! Anything else makes the config "too dynamic" and produces an error naming
+//! the offending construct and line.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Extract the exported config object from a JavaScript relay config.
+pub fn extract_config(source: &str, config_path: &Path) -> Result<Value> {
+	let config_path = std::path::absolute(config_path).unwrap_or(config_path.to_path_buf());
+	let mut parser = Parser {
+		src: source,
+		pos: 0,
+		config_path: &config_path,
+		bindings: HashMap::new(),
+	};
+
+	let exported = parser.parse_program().map_err(|e| {
+		anyhow!(
+			"{} is too dynamic to read statically: {}.\n\
+			 Use a plain object literal, move the config to relay.config.json,\n\
+			 or pass GENERATED_DIR explicitly.",
+			config_path.display(),
+			e
+		)
+	})?;
+
+	match exported {
+		Js::Value(value @ Value::Object(_)) => Ok(value),
+		_ => bail!(
+			"{} does not export an object literal",
+			config_path.display()
+		),
+	}
+}
+
+/// A statically evaluated JavaScript value
+#[derive(Debug, Clone)]
+enum Js {
+	Value(Value),
+	Undefined,
+	Module(Module),
+	Function(Builtin),
+}
+
+/// Node modules whose members we can evaluate
+#[derive(Debug, Clone, Copy)]
+enum Module {
+	Path,
+	Url,
+}
+
+/// Functions we can evaluate
+#[derive(Debug, Clone, Copy)]
+enum Builtin {
+	Join,
+	Resolve,
+	Dirname,
+	FileUrlToPath,
+}
+
+struct Parser<'a> {
+	src: &'a str,
+	pos: usize,
+	config_path: &'a Path,
+	bindings: HashMap<String, Js>,
+}
+
+type ParseResult<T> = std::result::Result<T, String>;
+
+impl Parser<'_> {
+	/// Parse top-level statements and return the exported value
+	fn parse_program(&mut self) -> ParseResult<Js> {
+		let mut exported = None;
+
+		loop {
+			self.skip_trivia();
+			if self.pos >= self.src.len() {
+				break;
+			}
+			if self.eat(";") {
+				continue;
+			}
+
+			let start = self.pos;
+			if self.eat_keyword("import") {
+				self.parse_import()?;
+			} else if self.eat_keyword("const")
+				|| self.eat_keyword("let")
+				|| self.eat_keyword("var")
+			{
+				self.parse_declaration()?;
+			} else if self.eat_keyword("export") {
+				if !self.eat_keyword("default") {
+					return Err(self.error_at(start, "only `export default` is supported"));
+				}
+				exported = Some(self.parse_expression()?);
+			} else if self.eat_word("module.exports") {
+				self.expect("=")?;
+				exported = Some(self.parse_expression()?);
+			} else if self.peek_char() == Some('\'') || self.peek_char() == Some('"') {
+				// Directive such as 'use strict'
+				self.parse_string()?;
+			} else {
+				return Err(self.error_at(start, "unsupported statement"));
+			}
+		}
+
+		exported.ok_or_else(|| "no `module.exports` or `export default` found".to_string())
+	}
+
+	/// `import X from 'm'`, `import * as X from 'm'`, `import { a, b as c } from 'm'`
+	fn parse_import(&mut self) -> ParseResult<()> {
+		let start = self.pos;
+		let mut default_name = None;
+		let mut named: Vec<(String, String)> = Vec::new();
+
+		self.skip_trivia();
+		if self.eat("*") {
+			self.expect_keyword("as")?;
+			default_name = Some(self.parse_ident()?);
+		} else if self.eat("{") {
+			loop {
+				self.skip_trivia();
+				if self.eat("}") {
+					break;
+				}
+				let imported = self.parse_ident()?;
+				let local = if self.eat_keyword("as") {
+					self.parse_ident()?
+				} else {
+					imported.clone()
+				};
+				named.push((imported, local));
+				self.skip_trivia();
+				if !self.eat(",") {
+					self.expect("}")?;
+					break;
+				}
+			}
+		} else {
+			default_name = Some(self.parse_ident()?);
+		}
+
+		self.expect_keyword("from")?;
+		let module = self.parse_string()?;
+		let module = self
+			.module(&module)
+			.ok_or_else(|| self.error_at(start, &format!("cannot evaluate module '{}'", module)))?;
+
+		if let Some(name) = default_name {
+			self.bindings.insert(name, Js::Module(module));
+		}
+		for (imported, local) in named {
+			let member = self.member(&Js::Module(module), &imported, start)?;
+			self.bindings.insert(local, member);
+		}
+		Ok(())
+	}
+
+	/// `const name = expr` or `const { a, b: c } = expr`
+	fn parse_declaration(&mut self) -> ParseResult<()> {
+		let start = self.pos;
+		self.skip_trivia();
+		if self.eat("{") {
+			let mut names: Vec<(String, String)> = Vec::new();
+			loop {
+				self.skip_trivia();
+				if self.eat("}") {
+					break;
+				}
+				let key = self.parse_ident()?;
+				let local = if self.eat(":") {
+					self.parse_ident()?
+				} else {
+					key.clone()
+				};
+				names.push((key, local));
+				self.skip_trivia();
+				if !self.eat(",") {
+					self.expect("}")?;
+					break;
+				}
+			}
+			self.expect("=")?;
+			let value = self.parse_expression()?;
+			for (key, local) in names {
+				let member = self.member(&value, &key, start)?;
+				self.bindings.insert(local, member);
+			}
+		} else {
+			let name = self.parse_ident()?;
+			self.expect("=")?;
+			let value = self.parse_expression()?;
+			self.bindings.insert(name, value);
+		}
+		Ok(())
+	}
+
+	/// Additive expressions (the only operator supported is `+`)
+	fn parse_expression(&mut self) -> ParseResult<Js> {
+		let start = self.pos;
+		let mut value = self.parse_postfix()?;
+		loop {
+			self.skip_trivia();
+			if self.src[self.pos..].starts_with("+") && !self.src[self.pos..].starts_with("++") {
+				self.pos += 1;
+				let rhs = self.parse_postfix()?;
+				value = add(value, rhs).map_err(|e| self.error_at(start, &e))?;
+			} else {
+				return Ok(value);
+			}
+		}
+	}
+
+	/// Member access and calls
+	fn parse_postfix(&mut self) -> ParseResult<Js> {
+		let mut value = self.parse_primary()?;
+		loop {
+			self.skip_trivia();
+			let start = self.pos;
+			if self.eat(".") {
+				let name = self.parse_ident()?;
+				value = self.member(&value, &name, start)?;
+			} else if self.eat("(") {
+				let mut args = Vec::new();
+				loop {
+					self.skip_trivia();
+					if self.eat(")") {
+						break;
+					}
+					args.push(self.parse_expression()?);
+					self.skip_trivia();
+					if !self.eat(",") {
+						self.expect(")")?;
+						break;
+					}
+				}
+				value = self.call(&value, args, start)?;
+			} else {
+				return Ok(value);
+			}
+		}
+	}
+
+	fn parse_primary(&mut self) -> ParseResult<Js> {
+		self.skip_trivia();
+		let start = self.pos;
+		match self.peek_char() {
+			Some('{') => self.parse_object(),
+			Some('[') => self.parse_array(),
+			Some('\'') | Some('"') => Ok(Js::Value(Value::String(self.parse_string()?))),
+			Some('`') => self.parse_template(),
+			Some('(') => {
+				self.pos += 1;
+				let value = self.parse_expression()?;
+				self.expect(")")?;
+				Ok(value)
+			}
+			Some(c) if c.is_ascii_digit() || c == '.' || c == '-' => self.parse_number(),
+			Some(c) if is_ident_start(c) => {
+				let name = self.parse_ident()?;
+				match name.as_str() {
+					"true" => Ok(Js::Value(Value::Bool(true))),
+					"false" => Ok(Js::Value(Value::Bool(false))),
+					"null" => Ok(Js::Value(Value::Null)),
+					"undefined" => Ok(Js::Undefined),
+					"__dirname" => Ok(self.dirname_value()),
+					"__filename" => Ok(Js::Value(Value::String(
+						self.config_path.to_string_lossy().into_owned(),
+					))),
+					"require" => {
+						self.expect("(")?;
+						let module = self.parse_string()?;
+						self.expect(")")?;
+						self.module(&module).map(Js::Module).ok_or_else(|| {
+							self.error_at(start, &format!("cannot evaluate require('{}')", module))
+						})
+					}
+					"import" => {
+						self.expect(".")?;
+						self.expect_keyword("meta")?;
+						self.expect(".")?;
+						match self.parse_ident()?.as_str() {
+							"url" => Ok(Js::Value(Value::String(format!(
+								"file://{}",
+								self.config_path.to_string_lossy()
+							)))),
+							"dirname" => Ok(self.dirname_value()),
+							other => Err(self.error_at(
+								start,
+								&format!("cannot evaluate import.meta.{}", other),
+							)),
+						}
+					}
+					_ => self.bindings.get(&name).cloned().ok_or_else(|| {
+						self.error_at(start, &format!("unknown identifier `{}`", name))
+					}),
+				}
+			}
+			_ => Err(self.error_at(start, "unsupported expression")),
+		}
+	}
+
+	fn parse_object(&mut self) -> ParseResult<Js> {
+		self.expect("{")?;
+		let mut map = Map::new();
+		loop {
+			self.skip_trivia();
+			if self.eat("}") {
+				break;
+			}
+			let start = self.pos;
+			if self.eat("...") {
+				match self.parse_expression()? {
+					Js::Value(Value::Object(spread)) => map.extend(spread),
+					_ => return Err(self.error_at(start, "can only spread object literals")),
+				}
+			} else {
+				let key = match self.peek_char() {
+					Some('\'') | Some('"') => self.parse_string()?,
+					Some(c) if c.is_ascii_digit() => match self.parse_number()? {
+						Js::Value(n) => n.to_string(),
+						_ => unreachable!(),
+					},
+					Some('[') => {
+						return Err(self.error_at(start, "computed keys are not supported"))
+					}
+					_ => self.parse_ident()?,
+				};
+				self.skip_trivia();
+				let value = if self.eat(":") {
+					self.parse_expression()?
+				} else if self.peek_char() == Some('(') {
+					return Err(self.error_at(start, "methods are not supported"));
+				} else {
+					// Shorthand property
+					self.bindings.get(&key).cloned().ok_or_else(|| {
+						self.error_at(start, &format!("unknown identifier `{}`", key))
+					})?
+				};
+				match value {
+					Js::Value(value) => {
+						map.insert(key, value);
+					}
+					Js::Undefined => {}
+					_ => {
+						return Err(self
+							.error_at(start, &format!("value of `{}` is not a plain value", key)))
+					}
+				}
+			}
+			self.skip_trivia();
+			if !self.eat(",") {
+				self.expect("}")?;
+				break;
+			}
+		}
+		Ok(Js::Value(Value::Object(map)))
+	}
+
+	fn parse_array(&mut self) -> ParseResult<Js> {
+		self.expect("[")?;
+		let mut items = Vec::new();
+		loop {
+			self.skip_trivia();
+			if self.eat("]") {
+				break;
+			}
+			let start = self.pos;
+			match self.parse_expression()? {
+				Js::Value(value) => items.push(value),
+				Js::Undefined => items.push(Value::Null),
+				_ => return Err(self.error_at(start, "array element is not a plain value")),
+			}
+			self.skip_trivia();
+			if !self.eat(",") {
+				self.expect("]")?;
+				break;
+			}
+		}
+		Ok(Js::Value(Value::Array(items)))
+	}
+
+	fn parse_string(&mut self) -> ParseResult<String> {
+		self.skip_trivia();
+		let start = self.pos;
+		let quote = self
+			.peek_char()
+			.filter(|&c| c == '\'' || c == '"')
+			.ok_or_else(|| self.error_at(start, "expected a string"))?;
+		self.pos += 1;
+
+		let mut out = String::new();
+		loop {
+			let c = self
+				.next_char()
+				.ok_or_else(|| self.error_at(start, "unterminated string"))?;
+			if c == quote {
+				return Ok(out);
+			}
+			if c == '\\' {
+				out.push(self.parse_escape(start)?);
+			} else {
+				out.push(c);
+			}
+		}
+	}
+
+	fn parse_template(&mut self) -> ParseResult<Js> {
+		let start = self.pos;
+		self.pos += 1; // opening backtick
+		let mut out = String::new();
+		loop {
+			let c = self
+				.next_char()
+				.ok_or_else(|| self.error_at(start, "unterminated template literal"))?;
+			match c {
+				'`' => return Ok(Js::Value(Value::String(out))),
+				'\\' => out.push(self.parse_escape(start)?),
+				'$' if self.peek_char() == Some('{') => {
+					self.pos += 1;
+					let expr_start = self.pos;
+					match self.parse_expression()? {
+						Js::Value(Value::String(s)) => out.push_str(&s),
+						Js::Value(Value::Number(n)) => out.push_str(&n.to_string()),
+						_ => {
+							return Err(self.error_at(
+								expr_start,
+								"template interpolation is not a string or number",
+							))
+						}
+					}
+					self.expect("}")?;
+				}
+				c => out.push(c),
+			}
+		}
+	}
+
+	fn parse_escape(&mut self, start: usize) -> ParseResult<char> {
+		let c = self
+			.next_char()
+			.ok_or_else(|| self.error_at(start, "unterminated escape"))?;
+		Ok(match c {
+			'n' => '\n',
+			't' => '\t',
+			'r' => '\r',
+			'b' => '\u{8}',
+			'f' => '\u{c}',
+			'v' => '\u{b}',
+			'0' => '\0',
+			'x' => self.parse_hex_escape(2, start)?,
+			'u' => {
+				if self.eat("{") {
+					let end = self.src[self.pos..]
+						.find('}')
+						.ok_or_else(|| self.error_at(start, "invalid unicode escape"))?;
+					let code = u32::from_str_radix(&self.src[self.pos..self.pos + end], 16)
+						.map_err(|_| self.error_at(start, "invalid unicode escape"))?;
+					self.pos += end + 1;
+					char::from_u32(code)
+						.ok_or_else(|| self.error_at(start, "invalid unicode escape"))?
+				} else {
+					self.parse_hex_escape(4, start)?
+				}
+			}
+			other => other,
+		})
+	}
+
+	fn parse_hex_escape(&mut self, len: usize, start: usize) -> ParseResult<char> {
+		let hex = self
+			.src
+			.get(self.pos..self.pos + len)
+			.ok_or_else(|| self.error_at(start, "invalid escape"))?;
+		let code =
+			u32::from_str_radix(hex, 16).map_err(|_| self.error_at(start, "invalid escape"))?;
+		self.pos += len;
+		char::from_u32(code).ok_or_else(|| self.error_at(start, "invalid escape"))
+	}
+
+	fn parse_number(&mut self) -> ParseResult<Js> {
+		let start = self.pos;
+		let len = self.src[self.pos..]
+			.char_indices()
+			.find(|&(i, c)| !(c.is_ascii_alphanumeric() || c == '.' || (i == 0 && c == '-')))
+			.map(|(i, _)| i)
+			.unwrap_or(self.src.len() - self.pos);
+		let text = &self.src[start..start + len];
+		self.pos += len;
+
+		let number: f64 = text
+			.parse()
+			.map_err(|_| self.error_at(start, &format!("unsupported number `{}`", text)))?;
+		Ok(Js::Value(number_value(number)))
+	}
+
+	fn parse_ident(&mut self) -> ParseResult<String> {
+		self.skip_trivia();
+		let start = self.pos;
+		let len = self.src[self.pos..]
+			.char_indices()
+			.find(|&(i, c)| !(is_ident_start(c) || (i > 0 && c.is_ascii_digit())))
+			.map(|(i, _)| i)
+			.unwrap_or(self.src.len() - self.pos);
+		if len == 0 {
+			return Err(self.error_at(start, "expected an identifier"));
+		}
+		self.pos += len;
+		Ok(self.src[start..self.pos].to_string())
+	}
+
+	/// Resolve `object.name`
+	fn member(&self, object: &Js, name: &str, at: usize) -> ParseResult<Js> {
+		match (object, name) {
+			(Js::Module(Module::Path), "join") => Ok(Js::Function(Builtin::Join)),
+			(Js::Module(Module::Path), "resolve") => Ok(Js::Function(Builtin::Resolve)),
+			(Js::Module(Module::Path), "dirname") => Ok(Js::Function(Builtin::Dirname)),
+			(Js::Module(Module::Url), "fileURLToPath") => Ok(Js::Function(Builtin::FileUrlToPath)),
+			(Js::Value(Value::Object(map)), _) => Ok(map
+				.get(name)
+				.cloned()
+				.map(Js::Value)
+				.unwrap_or(Js::Undefined)),
+			_ => Err(self.error_at(at, &format!("cannot evaluate `.{}`", name))),
+		}
+	}
+
+	/// Evaluate a call to one of the supported builtins
+	fn call(&self, callee: &Js, args: Vec<Js>, at: usize) -> ParseResult<Js> {
+		let Js::Function(builtin) = callee else {
+			return Err(self.error_at(at, "only path helpers can be called"));
+		};
+		let strings = args
+			.into_iter()
+			.map(|arg| match arg {
+				Js::Value(Value::String(s)) => Ok(s),
+				_ => Err(self.error_at(at, "path helpers only accept strings")),
+			})
+			.collect::<ParseResult<Vec<_>>>()?;
+
+		let result = match builtin {
+			Builtin::Join => normalize_path(&strings.join("/")),
+			Builtin::Resolve => {
+				let mut resolved = self.config_dir();
+				for segment in strings {
+					if segment.starts_with('/') {
+						resolved = segment;
+					} else {
+						resolved = format!("{}/{}", resolved, segment);
+					}
+				}
+				normalize_path(&resolved)
+			}
+			Builtin::Dirname => {
+				let [path] = strings.as_slice() else {
+					return Err(self.error_at(at, "path.dirname takes one argument"));
+				};
+				let path = normalize_path(path);
+				match path.rfind('/') {
+					Some(0) => "/".to_string(),
+					Some(i) => path[..i].to_string(),
+					None => ".".to_string(),
+				}
+			}
+			Builtin::FileUrlToPath => {
+				let [url] = strings.as_slice() else {
+					return Err(self.error_at(at, "fileURLToPath takes one argument"));
+				};
+				url.strip_prefix("file://")
+					.ok_or_else(|| self.error_at(at, "fileURLToPath expects a file:// URL"))?
+					.to_string()
+			}
+		};
+		Ok(Js::Value(Value::String(result)))
+	}
+
+	fn module(&self, name: &str) -> Option<Module> {
+		match name.trim_start_matches("node:") {
+			"path" => Some(Module::Path),
+			"url" => Some(Module::Url),
+			_ => None,
+		}
+	}
+
+	fn config_dir(&self) -> String {
+		self.config_path
+			.parent()
+			.unwrap_or(Path::new("/"))
+			.to_string_lossy()
+			.into_owned()
+	}
+
+	fn dirname_value(&self) -> Js {
+		Js::Value(Value::String(self.config_dir()))
+	}
+
+	fn skip_trivia(&mut self) {
+		loop {
+			let rest = &self.src[self.pos..];
+			let trimmed = rest.trim_start();
+			self.pos += rest.len() - trimmed.len();
+			if trimmed.starts_with("//") {
+				self.pos += trimmed.find('\n').unwrap_or(trimmed.len());
+			} else if trimmed.starts_with("/*") {
+				self.pos += trimmed.find("*/").map(|i| i + 2).unwrap_or(trimmed.len());
+			} else {
+				return;
+			}
+		}
+	}
+
+	fn peek_char(&self) -> Option<char> {
+		self.src[self.pos..].chars().next()
+	}
+
+	fn next_char(&mut self) -> Option<char> {
+		let c = self.peek_char()?;
+		self.pos += c.len_utf8();
+		Some(c)
+	}
+
+	fn eat(&mut self, token: &str) -> bool {
+		self.skip_trivia();
+		if self.src[self.pos..].starts_with(token) {
+			self.pos += token.len();
+			true
+		} else {
+			false
+		}
+	}
+
+	/// Consume a sequence such as `module.exports` if it is followed by a non-identifier char
+	fn eat_word(&mut self, word: &str) -> bool {
+		self.skip_trivia();
+		let rest = &self.src[self.pos..];
+		let followed_by_ident = rest[word.len().min(rest.len())..]
+			.chars()
+			.next()
+			.map(|c| is_ident_start(c) || c.is_ascii_digit())
+			.unwrap_or(false);
+		if rest.starts_with(word) && !followed_by_ident {
+			self.pos += word.len();
+			true
+		} else {
+			false
+		}
+	}
+
+	fn eat_keyword(&mut self, keyword: &str) -> bool {
+		self.eat_word(keyword)
+	}
+
+	fn expect(&mut self, token: &str) -> ParseResult<()> {
+		if self.eat(token) {
+			Ok(())
+		} else {
+			Err(self.error_at(self.pos, &format!("expected `{}`", token)))
+		}
+	}
+
+	fn expect_keyword(&mut self, keyword: &str) -> ParseResult<()> {
+		if self.eat_keyword(keyword) {
+			Ok(())
+		} else {
+			Err(self.error_at(self.pos, &format!("expected `{}`", keyword)))
+		}
+	}
+
+	fn error_at(&self, pos: usize, message: &str) -> String {
+		let line = self.src[..pos.min(self.src.len())].matches('\n').count() + 1;
+		format!("{} at line {}", message, line)
+	}
+}
+
+/// Evaluate `lhs + rhs`
+fn add(lhs: Js, rhs: Js) -> std::result::Result<Js, String> {
+	match (lhs, rhs) {
+		(Js::Value(Value::Number(a)), Js::Value(Value::Number(b))) => {
+			let sum = a.as_f64().unwrap_or(0.0) + b.as_f64().unwrap_or(0.0);
+			Ok(Js::Value(number_value(sum)))
+		}
+		(Js::Value(a), Js::Value(b)) if a.is_string() || b.is_string() => Ok(Js::Value(
+			Value::String(format!("{}{}", to_js_string(&a)?, to_js_string(&b)?)),
+		)),
+		_ => Err("`+` is only supported on strings and numbers".to_string()),
+	}
+}
+
+fn to_js_string(value: &Value) -> std::result::Result<String, String> {
+	match value {
+		Value::String(s) => Ok(s.clone()),
+		Value::Number(n) => Ok(n.to_string()),
+		Value::Bool(b) => Ok(b.to_string()),
+		Value::Null => Ok("null".to_string()),
+		_ => Err("cannot concatenate objects or arrays".to_string()),
+	}
+}
+
+fn number_value(number: f64) -> Value {
+	if number.fract() == 0.0 && number.abs() < i64::MAX as f64 {
+		Value::Number(Number::from(number as i64))
+	} else {
+		Number::from_f64(number)
+			.map(Value::Number)
+			.unwrap_or(Value::Null)
+	}
+}
+
+fn is_ident_start(c: char) -> bool {
+	c.is_ascii_alphabetic() || c == '_' || c == '$'
+}
+
+/// Collapse `.` and `..` segments and duplicate slashes, like `path.normalize`
+fn normalize_path(path: &str) -> String {
+	let absolute = path.starts_with('/');
+	let mut segments: Vec<&str> = Vec::new();
+	for segment in path.split('/') {
+		match segment {
+			"" | "." => {}
+			".." => {
+				if segments.last().is_some_and(|s| *s != "..") {
+					segments.pop();
+				} else if !absolute {
+					segments.push("..");
+				}
+			}
+			s => segments.push(s),
+		}
+	}
+	let joined = segments.join("/");
+	match (absolute, joined.is_empty()) {
+		(true, _) => format!("/{}", joined),
+		(false, true) => ".".to_string(),
+		(false, false) => joined,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+
+	fn extract(source: &str) -> Result<Value> {
+		extract_config(source, Path::new("/repo/relay.config.js"))
+	}
+
+	#[test]
+	fn test_module_exports_literal() {
+		let config = extract(
+			r#"
+			// @ts-check
+			'use strict';
+
+			/** @type {import('relay-compiler').Config} */
+			module.exports = {
+				src: './src',
+				schema: "./schema.graphql",
+				language: 'typescript',
+				eagerEsModules: true,
+				excludes: ['**/node_modules/**', `**/__mocks__/**`],
+				featureFlags: {
+					disable_deduping_common_structures_in_artifacts: { kind: 'enabled' },
+				},
+				persistConfig: undefined,
+			};
+			"#,
+		)
+		.unwrap();
+
+		assert_eq!(
+			config,
+			json!({
+				"src": "./src",
+				"schema": "./schema.graphql",
+				"language": "typescript",
+				"eagerEsModules": true,
+				"excludes": ["**/node_modules/**", "**/__mocks__/**"],
+				"featureFlags": {
+					"disable_deduping_common_structures_in_artifacts": { "kind": "enabled" }
+				}
+			})
+		);
+	}
+
+	#[test]
+	fn test_path_helpers_and_bindings() {
+		let config = extract(
+			r#"
+			const path = require('path');
+			const { join } = require("node:path");
+			const root = __dirname + '/app';
+
+			module.exports = {
+				src: path.resolve(__dirname, 'src'),
+				artifactDirectory: join(root, 'src', '..', 'src', '__generated__'),
+				schema: path.join('.', 'schema', 'schema' + '.graphql'),
+				root,
+			};
+			"#,
+		)
+		.unwrap();
+
+		assert_eq!(config["src"], "/repo/src");
+		assert_eq!(config["artifactDirectory"], "/repo/app/src/__generated__");
+		assert_eq!(config["schema"], "schema/schema.graphql");
+		assert_eq!(config["root"], "/repo/app");
+	}
+
+	#[test]
+	fn test_export_default_esm() {
+		let config = extract_config(
+			r#"
+			import path from 'node:path';
+			import { fileURLToPath } from 'node:url';
+
+			const dir = path.dirname(fileURLToPath(import.meta.url));
+			const shared = { language: "flow" };
+
+			export default {
+				...shared,
+				artifactDirectory: `${dir}/__generated__`,
+				src: path.join(import.meta.dirname, "src"),
+			};
+			"#,
+			Path::new("/repo/relay.config.mjs"),
+		)
+		.unwrap();
+
+		assert_eq!(config["language"], "flow");
+		assert_eq!(config["artifactDirectory"], "/repo/__generated__");
+		assert_eq!(config["src"], "/repo/src");
+	}
+
+	#[test]
+	fn test_too_dynamic() {
+		let err = extract(
+			r#"
+			module.exports = {
+				src: process.env.RELAY_SRC,
+			};
+			"#,
+		)
+		.unwrap_err()
+		.to_string();
+		assert!(err.contains("too dynamic"), "{}", err);
+		assert!(
+			err.contains("unknown identifier `process` at line 3"),
+			"{}",
+			err
+		);
+
+		let err = extract("const config = loadConfig();\nmodule.exports = config;")
+			.unwrap_err()
+			.to_string();
+		assert!(
+			err.contains("unknown identifier `loadConfig` at line 1"),
+			"{}",
+			err
+		);
+
+		let err = extract(
+			"const path = require('path');\nmodule.exports = { src: path.relative('a', 'b') };",
+		)
+		.unwrap_err()
+		.to_string();
+		assert!(
+			err.contains("cannot evaluate `.relative` at line 2"),
+			"{}",
+			err
+		);
+
+		let err = extract("if (x) { module.exports = {} }")
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("unsupported statement at line 1"), "{}", err);
+
+		let err = extract("const x = 1;").unwrap_err().to_string();
+		assert!(err.contains("no `module.exports`"), "{}", err);
+	}
+
+	#[test]
+	fn test_normalize_path() {
+		assert_eq!(normalize_path("/a/./b/../c//d"), "/a/c/d");
+		assert_eq!(normalize_path("./src/../gen"), "gen");
+		assert_eq!(normalize_path("../x"), "../x");
+		assert_eq!(normalize_path("."), ".");
+	}
+}
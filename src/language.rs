@@ -0,0 +1,147 @@
+//! Artifact language and module format detection.
+//!
+//! Relay emits TypeScript, Flow or plain JavaScript artifacts depending on the
+//! `language` setting in the relay config, as ES modules or CommonJS depending
+//! on `eagerEsModules`.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Language of the Relay artifacts being processed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Language {
+	#[default]
+	TypeScript,
+	Flow,
+	JavaScript,
+}
+
+impl Language {
+	/// File suffix of the artifacts Relay generates for this language
+	pub fn artifact_suffix(self) -> &'static str {
+		match self {
+			Language::TypeScript => ".graphql.ts",
+			Language::Flow | Language::JavaScript => ".graphql.js",
+		}
+	}
+
+	/// Default filename of the shared module
+	pub fn default_shared_module_name(self) -> &'static str {
+		match self {
+			Language::TypeScript => "__shared.ts",
+			Language::Flow | Language::JavaScript => "__shared.js",
+		}
+	}
+}
+
+impl FromStr for Language {
+	type Err = String;
+
+	/// Parse a relay config `language` value (or CLI flag)
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"typescript" | "ts" => Ok(Language::TypeScript),
+			"flow" => Ok(Language::Flow),
+			"javascript" | "js" => Ok(Language::JavaScript),
+			other => Err(format!(
+				"unknown language '{}' (expected typescript, flow or javascript)",
+				other
+			)),
+		}
+	}
+}
+
+impl fmt::Display for Language {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Language::TypeScript => "typescript",
+			Language::Flow => "flow",
+			Language::JavaScript => "javascript",
+		})
+	}
+}
+
+/// Module system used for artifact references and the shared module
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModuleFormat {
+	/// `import { ... } from` / `export const`
+	#[default]
+	Esm,
+	/// `const { ... } = require(...)` / `module.exports`
+	CommonJs,
+}
+
+impl ModuleFormat {
+	/// The format Relay uses for a language and `eagerEsModules` setting.
+	///
+	/// TypeScript artifacts are always ES modules; Flow and JavaScript
+	/// artifacts are CommonJS unless `eagerEsModules` is enabled.
+	pub fn for_relay(language: Language, eager_es_modules: bool) -> Self {
+		if language == Language::TypeScript || eager_es_modules {
+			ModuleFormat::Esm
+		} else {
+			ModuleFormat::CommonJs
+		}
+	}
+}
+
+impl FromStr for ModuleFormat {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s.to_ascii_lowercase().as_str() {
+			"esm" | "es" | "module" => Ok(ModuleFormat::Esm),
+			"commonjs" | "cjs" => Ok(ModuleFormat::CommonJs),
+			other => Err(format!(
+				"unknown module format '{}' (expected esm or commonjs)",
+				other
+			)),
+		}
+	}
+}
+
+impl fmt::Display for ModuleFormat {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			ModuleFormat::Esm => "esm",
+			ModuleFormat::CommonJs => "commonjs",
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_language() {
+		assert_eq!("typescript".parse(), Ok(Language::TypeScript));
+		assert_eq!("Flow".parse(), Ok(Language::Flow));
+		assert_eq!("javascript".parse(), Ok(Language::JavaScript));
+		assert!("coffeescript".parse::<Language>().is_err());
+	}
+
+	#[test]
+	fn test_artifact_suffix() {
+		assert_eq!(Language::TypeScript.artifact_suffix(), ".graphql.ts");
+		assert_eq!(Language::Flow.artifact_suffix(), ".graphql.js");
+		assert_eq!(Language::JavaScript.artifact_suffix(), ".graphql.js");
+	}
+
+	#[test]
+	fn test_module_format_for_relay() {
+		assert_eq!(
+			ModuleFormat::for_relay(Language::TypeScript, false),
+			ModuleFormat::Esm
+		);
+		assert_eq!(
+			ModuleFormat::for_relay(Language::Flow, false),
+			ModuleFormat::CommonJs
+		);
+		assert_eq!(
+			ModuleFormat::for_relay(Language::JavaScript, true),
+			ModuleFormat::Esm
+		);
+		assert_eq!("cjs".parse(), Ok(ModuleFormat::CommonJs));
+	}
+}
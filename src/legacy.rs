@@ -0,0 +1,275 @@
+//! Migration from the legacy `_xxxxxxxx` reference format.
+//!
+//! The old TypeScript implementation named shared structures `_` plus 8 hex
+//! characters and exported them from its own shared module:
+//!
+//! ```ts
+//! export const _1a2b3c4d = {"kind": "ScalarField", "name": "id"};
+//! ```
+//!
+//! Nothing in the current writer produces or imports those names. Migrating
+//! expands every legacy reference back into its structure (following legacy
+//! references inside definitions) and drops the legacy imports, so the
+//! artifact can be deduplicated into the current `x_` scheme.
+
+use crate::lexer::{tokenize, Token, TokenKind};
+use crate::statement::{declarator_name, statements, StatementKind};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Whether an identifier is a legacy reference (`_` followed by 8 hex digits)
+pub fn is_legacy_ref_name(ident: &str) -> bool {
+	ident.len() == 9 && ident.starts_with('_') && ident[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// A legacy reference that has no definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unresolved {
+	/// Byte offset of the reference in the artifact
+	pub offset: usize,
+	pub name: String,
+}
+
+/// Definitions read from a legacy shared module
+#[derive(Debug, Clone, Default)]
+pub struct LegacyModule {
+	/// Definition source by name, as written (may use other legacy names)
+	definitions: HashMap<String, String>,
+}
+
+impl LegacyModule {
+	/// Read the legacy shared module at `path`
+	pub fn read(path: &Path) -> Result<Self> {
+		let content = fs::read_to_string(path)
+			.with_context(|| format!("Failed to read legacy shared module {}", path.display()))?;
+		Ok(Self::parse(&content))
+	}
+
+	/// Collect every `const _xxxxxxxx = ...` declaration (exported or not)
+	pub fn parse(content: &str) -> Self {
+		let tokens = code_tokens(content);
+		let mut definitions = HashMap::new();
+		for statement in statements(content, &tokens) {
+			if statement.kind != StatementKind::Declaration {
+				continue;
+			}
+			for initializer in &statement.initializers {
+				let Some(name) = declarator_name(content, &tokens, initializer) else {
+					continue;
+				};
+				let value = &tokens[initializer.clone()];
+				if let (true, Some(first), Some(last)) =
+					(is_legacy_ref_name(name), value.first(), value.last())
+				{
+					definitions
+						.insert(name.to_string(), content[first.start..last.end].to_string());
+				}
+			}
+		}
+		Self { definitions }
+	}
+
+	pub fn len(&self) -> usize {
+		self.definitions.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.definitions.is_empty()
+	}
+
+	/// The definition of `name` with every legacy reference inside expanded
+	fn expand(
+		&self,
+		name: &str,
+		cache: &mut HashMap<String, Option<String>>,
+		visiting: &mut Vec<String>,
+	) -> Option<String> {
+		if let Some(expanded) = cache.get(name) {
+			return expanded.clone();
+		}
+		// A cycle can't be expanded
+		if visiting.iter().any(|n| n == name) {
+			return None;
+		}
+		let definition = self.definitions.get(name)?;
+		visiting.push(name.to_string());
+		let tokens = code_tokens(definition);
+		let mut result = String::with_capacity(definition.len());
+		let mut last_end = 0;
+		let mut complete = true;
+		for token in legacy_refs(definition, &tokens) {
+			match self.expand(token.text(definition), cache, visiting) {
+				Some(value) => {
+					result.push_str(&definition[last_end..token.start]);
+					result.push_str(&value);
+					last_end = token.end;
+				}
+				None => complete = false,
+			}
+		}
+		result.push_str(&definition[last_end..]);
+		visiting.pop();
+
+		let expanded = complete.then_some(result);
+		cache.insert(name.to_string(), expanded.clone());
+		expanded
+	}
+
+	/// Expand every legacy reference in an artifact and drop its legacy
+	/// imports (`import { _a, _b } from ...` / `const { _a } = require(...)`).
+	///
+	/// Fails with every reference that has no (complete) definition.
+	pub fn migrate_artifact(&self, content: &str) -> Result<String, Vec<Unresolved>> {
+		let tokens = code_tokens(content);
+		let mut replacements: Vec<(usize, usize, String)> = Vec::new();
+		let mut unresolved = Vec::new();
+		let mut cache = HashMap::new();
+
+		for statement in statements(content, &tokens) {
+			let statement_tokens = &tokens[statement.tokens];
+			if is_legacy_import(content, statement_tokens) {
+				let (Some(first), Some(last)) = (statement_tokens.first(), statement_tokens.last())
+				else {
+					continue;
+				};
+				// Take the line break with it
+				let end = if content[last.end..].starts_with('\n') {
+					last.end + 1
+				} else {
+					last.end
+				};
+				replacements.push((first.start, end, String::new()));
+				continue;
+			}
+			for token in legacy_refs(content, statement_tokens) {
+				let name = token.text(content);
+				match self.expand(name, &mut cache, &mut Vec::new()) {
+					Some(value) => replacements.push((token.start, token.end, value)),
+					None => unresolved.push(Unresolved {
+						offset: token.start,
+						name: name.to_string(),
+					}),
+				}
+			}
+		}
+		if !unresolved.is_empty() {
+			return Err(unresolved);
+		}
+
+		let mut result = String::with_capacity(content.len());
+		let mut last_end = 0;
+		for (start, end, text) in replacements {
+			result.push_str(&content[last_end..start]);
+			result.push_str(&text);
+			last_end = end;
+		}
+		result.push_str(&content[last_end..]);
+		Ok(result)
+	}
+}
+
+/// Whether the artifact refers to any legacy name
+pub fn has_legacy_refs(content: &str) -> bool {
+	first_legacy_ref(content).is_some()
+}
+
+/// Byte offset of the first legacy reference
+pub fn first_legacy_ref(content: &str) -> Option<usize> {
+	let tokens = code_tokens(content);
+	let first = legacy_refs(content, &tokens).next().map(|t| t.start);
+	first
+}
+
+fn code_tokens(content: &str) -> Vec<Token> {
+	tokenize(content)
+		.into_iter()
+		.filter(|t| t.kind != TokenKind::Comment)
+		.collect()
+}
+
+/// Identifier tokens that are legacy references (not `.property` names)
+fn legacy_refs<'a>(src: &'a str, tokens: &'a [Token]) -> impl Iterator<Item = &'a Token> + 'a {
+	tokens.iter().enumerate().filter_map(move |(k, token)| {
+		let is_property = k > 0 && tokens[k - 1].is_punct(src, ".");
+		(token.kind == TokenKind::Ident && !is_property && is_legacy_ref_name(token.text(src)))
+			.then_some(token)
+	})
+}
+
+/// `import { _a, _b } from "..."` or `const { _a, _b } = require("...")`
+/// importing nothing but legacy names
+fn is_legacy_import(src: &str, tokens: &[Token]) -> bool {
+	let word = |i: usize, w: &str| {
+		tokens
+			.get(i)
+			.is_some_and(|t| t.kind == TokenKind::Ident && t.text(src) == w)
+	};
+	let is_import = word(0, "import");
+	let is_require = matches!(tokens.first(), Some(t) if matches!(t.text(src), "const" | "var" | "let"))
+		&& tokens.iter().any(|t| t.text(src) == "require");
+	if !(is_import || is_require) || !tokens.get(1).is_some_and(|t| t.is_punct(src, "{")) {
+		return false;
+	}
+	let Some(close) = tokens.iter().position(|t| t.is_punct(src, "}")) else {
+		return false;
+	};
+	let names: Vec<&str> = tokens[2..close]
+		.iter()
+		.filter(|t| !t.is_punct(src, ","))
+		.map(|t| t.text(src))
+		.collect();
+	!names.is_empty() && names.iter().all(|name| is_legacy_ref_name(name))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_migrate_artifact() {
+		let module = LegacyModule::parse(
+			r#"/** @generated */
+type RelayNode = any;
+export const _0000000a: RelayNode = {"kind": "ScalarField", "name": "id"};
+export const _0000000b: RelayNode = [_0000000a, {"kind": "ScalarField", "name": "name"}];
+export const _0000000c = [_0000000d];
+export const _0000000d = [_0000000c];
+"#,
+		);
+		assert_eq!(module.len(), 4);
+
+		let artifact = r#"/** @generated */
+import type { ReaderFragment } from 'relay-runtime';
+import { _0000000a, _0000000b } from "./__shared";
+
+const node: ReaderFragment = {
+  "selections": _0000000b,
+  "other": [_0000000a]
+};
+"#;
+		assert_eq!(
+			module.migrate_artifact(artifact).unwrap(),
+			r#"/** @generated */
+import type { ReaderFragment } from 'relay-runtime';
+
+const node: ReaderFragment = {
+  "selections": [{"kind": "ScalarField", "name": "id"}, {"kind": "ScalarField", "name": "name"}],
+  "other": [{"kind": "ScalarField", "name": "id"}]
+};
+"#
+		);
+
+		// Missing and cyclic definitions are refused
+		let artifact = "const { _0000000a } = require(\"./__shared\");\n\
+			const node = {\"a\": _0000000e, \"b\": _0000000c};";
+		let unresolved: Vec<String> = module
+			.migrate_artifact(artifact)
+			.unwrap_err()
+			.into_iter()
+			.map(|u| u.name)
+			.collect();
+		assert_eq!(unresolved, vec!["_0000000e", "_0000000c"]);
+	}
+}
@@ -0,0 +1,345 @@
+//! JavaScript / TypeScript tokenizer.
+//!
+//! Splits source text into tokens with byte ranges so structural scans only
+//! ever look at real punctuation: braces inside strings, template literals,
+//! comments and regex literals are part of those tokens, never punctuation.
+//! Whitespace isn't reported.
+
+/// Kind of a token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+	/// Operator or punctuation (`{`, `=>`, `...`, `;`)
+	Punct,
+	/// Identifier or keyword
+	Ident,
+	Number,
+	/// Single- or double-quoted string
+	String,
+	/// A piece of a template literal: from `` ` `` or the `}` closing a
+	/// substitution up to and including the next `${` or closing `` ` ``
+	Template,
+	Regex,
+	/// `//` or `/* */` comment (including Flow annotations)
+	Comment,
+}
+
+/// A token and its byte range in the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token {
+	pub kind: TokenKind,
+	pub start: usize,
+	pub end: usize,
+}
+
+impl Token {
+	pub fn text<'a>(&self, src: &'a str) -> &'a str {
+		&src[self.start..self.end]
+	}
+
+	/// Whether this is the punctuation `p`
+	pub fn is_punct(&self, src: &str, p: &str) -> bool {
+		self.kind == TokenKind::Punct && self.text(src) == p
+	}
+}
+
+/// Multi-character operators, longest first
+const OPERATORS: &[&str] = &[
+	">>>=", "===", "!==", "**=", "<<=", ">>=", ">>>", "...", "&&=", "||=", "??=", "=>", "==", "!=",
+	"<=", ">=", "&&", "||", "??", "?.", "++", "--", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=",
+	"**", "<<", ">>",
+];
+
+/// Keywords after which a `/` starts a regex rather than a division
+const REGEX_KEYWORDS: &[&str] = &[
+	"return",
+	"typeof",
+	"instanceof",
+	"in",
+	"of",
+	"new",
+	"delete",
+	"void",
+	"throw",
+	"case",
+	"do",
+	"else",
+	"yield",
+	"await",
+];
+
+/// Tokenize `src`. Never fails: unterminated strings, comments and templates
+/// run to the end of their line or of the input.
+pub fn tokenize(src: &str) -> Vec<Token> {
+	let bytes = src.as_bytes();
+	let mut tokens: Vec<Token> = Vec::new();
+	// One entry per open `{`: true when it's a template substitution `${`
+	let mut braces: Vec<bool> = Vec::new();
+	let mut i = 0;
+
+	while i < bytes.len() {
+		let c = bytes[i];
+		if c.is_ascii_whitespace() {
+			i += 1;
+			continue;
+		}
+
+		let start = i;
+		let kind = match c {
+			b'/' if bytes.get(i + 1) == Some(&b'/') => {
+				i = line_end(bytes, i);
+				TokenKind::Comment
+			}
+			b'/' if bytes.get(i + 1) == Some(&b'*') => {
+				i = match src[i + 2..].find("*/") {
+					Some(offset) => i + 2 + offset + 2,
+					None => bytes.len(),
+				};
+				TokenKind::Comment
+			}
+			b'/' if regex_allowed(src, &tokens) => {
+				i = regex_end(bytes, i);
+				TokenKind::Regex
+			}
+			b'"' | b'\'' => {
+				i = string_end(bytes, i);
+				TokenKind::String
+			}
+			b'`' => {
+				let (end, substitution) = template_end(bytes, i + 1);
+				if substitution {
+					braces.push(true);
+				}
+				i = end;
+				TokenKind::Template
+			}
+			b'}' if braces.last() == Some(&true) => {
+				braces.pop();
+				let (end, substitution) = template_end(bytes, i + 1);
+				if substitution {
+					braces.push(true);
+				}
+				i = end;
+				TokenKind::Template
+			}
+			b'0'..=b'9' => {
+				i = number_end(bytes, i);
+				TokenKind::Number
+			}
+			b'.' if bytes.get(i + 1).is_some_and(u8::is_ascii_digit) => {
+				i = number_end(bytes, i);
+				TokenKind::Number
+			}
+			_ if is_ident_byte(c) => {
+				while i < bytes.len() && (is_ident_byte(bytes[i]) || bytes[i].is_ascii_digit()) {
+					i += 1;
+				}
+				TokenKind::Ident
+			}
+			_ => {
+				let len = OPERATORS
+					.iter()
+					.find(|op| src[i..].starts_with(*op))
+					.map_or(1, |op| op.len());
+				match c {
+					b'{' => braces.push(false),
+					b'}' => {
+						braces.pop();
+					}
+					_ => {}
+				}
+				i += len;
+				TokenKind::Punct
+			}
+		};
+		tokens.push(Token {
+			kind,
+			start,
+			end: i,
+		});
+	}
+
+	tokens
+}
+
+/// Bytes that can start an identifier (non-ASCII bytes are treated as
+/// identifier characters so multi-byte characters are never split)
+fn is_ident_byte(c: u8) -> bool {
+	c.is_ascii_alphabetic() || c == b'_' || c == b'$' || c >= 0x80
+}
+
+/// Whether a `/` after `tokens` starts a regex literal
+fn regex_allowed(src: &str, tokens: &[Token]) -> bool {
+	let Some(prev) = tokens.iter().rev().find(|t| t.kind != TokenKind::Comment) else {
+		return true;
+	};
+	match prev.kind {
+		TokenKind::Punct => !matches!(prev.text(src), ")" | "]" | "}" | "++" | "--"),
+		TokenKind::Ident => REGEX_KEYWORDS.contains(&prev.text(src)),
+		_ => false,
+	}
+}
+
+fn line_end(bytes: &[u8], mut i: usize) -> usize {
+	while i < bytes.len() && bytes[i] != b'\n' {
+		i += 1;
+	}
+	i
+}
+
+/// End (exclusive) of the string starting at `i`
+fn string_end(bytes: &[u8], i: usize) -> usize {
+	let quote = bytes[i];
+	let mut j = i + 1;
+	while j < bytes.len() {
+		match bytes[j] {
+			b'\\' => j += 2,
+			b'\n' => return j,
+			c if c == quote => return j + 1,
+			_ => j += 1,
+		}
+	}
+	bytes.len()
+}
+
+/// Scan template characters from `i`. Returns the end (exclusive) and whether
+/// the piece ends with a `${` substitution rather than the closing backtick.
+fn template_end(bytes: &[u8], mut i: usize) -> (usize, bool) {
+	while i < bytes.len() {
+		match bytes[i] {
+			b'\\' => i += 2,
+			b'`' => return (i + 1, false),
+			b'$' if bytes.get(i + 1) == Some(&b'{') => return (i + 2, true),
+			_ => i += 1,
+		}
+	}
+	(bytes.len(), false)
+}
+
+/// End (exclusive) of the regex literal starting at `i`, including flags
+fn regex_end(bytes: &[u8], i: usize) -> usize {
+	let mut j = i + 1;
+	let mut in_class = false;
+	while j < bytes.len() {
+		match bytes[j] {
+			b'\\' => j += 1,
+			b'\n' => return j,
+			b'[' => in_class = true,
+			b']' => in_class = false,
+			b'/' if !in_class => {
+				j += 1;
+				while j < bytes.len() && bytes[j].is_ascii_alphabetic() {
+					j += 1;
+				}
+				return j;
+			}
+			_ => {}
+		}
+		j += 1;
+	}
+	bytes.len()
+}
+
+/// End (exclusive) of the number starting at `i` (`1e-5`, `0xff`, `1_000n`)
+fn number_end(bytes: &[u8], mut i: usize) -> usize {
+	// Only decimal literals have an exponent: `0xE+1` is `0xE + 1`
+	let decimal = !(bytes[i] == b'0'
+		&& matches!(
+			bytes.get(i + 1),
+			Some(b'x' | b'X' | b'o' | b'O' | b'b' | b'B')
+		));
+	while i < bytes.len() {
+		let c = bytes[i];
+		if c.is_ascii_alphanumeric() || c == b'_' || c == b'.' {
+			i += 1;
+		} else if decimal && matches!(c, b'+' | b'-') && matches!(bytes[i - 1], b'e' | b'E') {
+			// Exponent sign
+			i += 1;
+		} else {
+			break;
+		}
+	}
+	i
+}
+
+/// Contents of a simple quoted string
+pub fn unquote(text: &str) -> String {
+	if text.starts_with('"') {
+		if let Ok(value) = serde_json::from_str(text) {
+			return value;
+		}
+	}
+	text.get(1..text.len().saturating_sub(1))
+		.unwrap_or_default()
+		.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn kinds(src: &str) -> Vec<(TokenKind, &str)> {
+		tokenize(src)
+			.iter()
+			.map(|t| (t.kind, t.text(src)))
+			.collect()
+	}
+
+	#[test]
+	fn test_strings_comments_and_regex() {
+		use TokenKind::*;
+		assert_eq!(
+			kinds(r#"a = '{' + "}[" // ]"#),
+			vec![
+				(Ident, "a"),
+				(Punct, "="),
+				(String, "'{'"),
+				(Punct, "+"),
+				(String, "\"}[\""),
+				(Comment, "// ]"),
+			]
+		);
+		assert_eq!(
+			kinds("x = /[/{]+/g.test(y) / 2 /* { */"),
+			vec![
+				(Ident, "x"),
+				(Punct, "="),
+				(Regex, "/[/{]+/g"),
+				(Punct, "."),
+				(Ident, "test"),
+				(Punct, "("),
+				(Ident, "y"),
+				(Punct, ")"),
+				(Punct, "/"),
+				(Number, "2"),
+				(Comment, "/* { */"),
+			]
+		);
+		assert_eq!(
+			kinds("f => 1e-5"),
+			vec![(Ident, "f"), (Punct, "=>"), (Number, "1e-5")]
+		);
+		assert_eq!(
+			kinds("0xE+1"),
+			vec![(Number, "0xE"), (Punct, "+"), (Number, "1")]
+		);
+	}
+
+	#[test]
+	fn test_template_literals() {
+		use TokenKind::*;
+		assert_eq!(
+			kinds("`a{${ {b: `[${c}`} }]`"),
+			vec![
+				(Template, "`a{${"),
+				(Punct, "{"),
+				(Ident, "b"),
+				(Punct, ":"),
+				(Template, "`[${"),
+				(Ident, "c"),
+				(Template, "}`"),
+				(Punct, "}"),
+				(Template, "}]`"),
+			]
+		);
+	}
+}
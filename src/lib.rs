@@ -3,37 +3,90 @@
 //! Provides functionality to deduplicate Relay-generated artifact files by
 //! extracting repeated structures into a shared module.
 
+pub mod artifact;
+pub mod atom;
+pub mod cost;
+pub mod diagnostics;
+pub mod doctor;
+pub mod factor;
+pub mod fix_config;
+pub mod hazard;
+pub mod inline;
+pub mod js_config;
+pub mod language;
+pub mod legacy;
+pub mod lexer;
+pub mod literal;
+pub mod lockfile;
 pub mod naming;
 pub mod normalize;
+pub mod options;
+pub mod order;
 pub mod relay_config;
+pub mod scope;
+pub mod statement;
 pub mod tree;
+pub mod value;
 pub mod writer;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use flate2::read::GzEncoder;
 use flate2::Compression;
 use rayon::prelude::*;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::{Duration, Instant};
-
-use naming::NameGenerator;
+use walkdir::WalkDir;
+
+use artifact::{prepare_artifact, problem_offset, ArtifactProblem};
+use cost::{Candidate, CostModel, ExtractionModel, THRESHOLD_MIN_CONTENT_LEN};
+use diagnostics::{Diagnostic, Diagnostics, Rule, Severity};
+pub use language::{Language, ModuleFormat};
+use legacy::LegacyModule;
+use lockfile::{lockfile_path, NameLock};
+use naming::{is_local_name, is_ref_name, NameGenerator};
+use order::OrderRules;
+use scope::{file_suppression, ProtectedPath, Suppression};
 use tree::FileTree;
 use writer::write_shared_module;
 
 /// Configuration for the deduplication process
 #[derive(Debug, Clone)]
 pub struct Config {
-	/// Path to the __generated__ directory
+	/// Path to the __generated__ directory (or the source root when `recursive` is set)
 	pub generated_dir: PathBuf,
 	/// Name of the shared module file (default: __shared.ts)
 	pub shared_module_name: String,
-	/// Minimum occurrences to extract (default: 2)
+	/// Explicit location of the shared module (overrides the default placement)
+	pub shared_module_path: Option<PathBuf>,
+	/// Walk `generated_dir` recursively and collect artifacts from every `__generated__` folder
+	pub recursive: bool,
+	/// Language of the artifacts (selects the file suffix and shared module syntax)
+	pub language: Language,
+	/// Module system for artifact references and the shared module
+	pub module_format: ModuleFormat,
+	/// Only process artifacts matching one of these globs (all if empty)
+	pub include_globs: Vec<String>,
+	/// Skip artifacts matching any of these globs
+	pub exclude_globs: Vec<String>,
+	/// Key paths (e.g. `params`, `metadata.refetch`) whose nodes are never extracted
+	pub protected_paths: Vec<String>,
+	/// Node kinds Relay caches by identity; nodes of these kinds are never extracted
+	pub identity_sensitive_kinds: Vec<String>,
+	/// How candidates are chosen (default: by net size benefit)
+	pub extraction_model: ExtractionModel,
+	/// Minimum occurrences to extract (default: 2). A lower bound only with
+	/// the cost model.
 	pub min_occurrences: usize,
-	/// Fields where array order doesn't matter
-	pub order_insensitive_fields: HashSet<String>,
+	/// Weight of the estimated gzip benefit in the cost model, from 0 to 1
+	pub gzip_weight: f64,
+	/// Factor near-duplicate objects and arrays into spreads of a shared
+	/// base (see [`factor`])
+	pub near_duplicates: bool,
+	/// Arrays whose element order doesn't matter (see [`order`])
+	pub order_rules: OrderRules,
 	/// Whether to actually write files
 	pub dry_run: bool,
 	/// Whether to print verbose output
@@ -42,28 +95,76 @@ pub struct Config {
 	pub max_passes: usize,
 	/// Whether to compute gzipped sizes
 	pub compute_gzip: bool,
+	/// Fail on warning diagnostics, before any file is written
+	pub strict: bool,
+	/// Write definitions in normalized form (keys sorted, no whitespace)
+	/// instead of as their first occurrence is written
+	pub compact_shared: bool,
+	/// Legacy shared module to migrate `_xxxxxxxx` references from (see [`legacy`])
+	pub legacy_shared_module: Option<PathBuf>,
 }
 
 impl Default for Config {
 	fn default() -> Self {
-		let mut order_insensitive = HashSet::new();
-		order_insensitive.insert("selections".to_string());
-		order_insensitive.insert("args".to_string());
-		order_insensitive.insert("argumentDefinitions".to_string());
-
 		Self {
 			generated_dir: PathBuf::new(),
 			shared_module_name: "__shared.ts".to_string(),
+			shared_module_path: None,
+			recursive: false,
+			language: Language::TypeScript,
+			module_format: ModuleFormat::Esm,
+			include_globs: Vec::new(),
+			exclude_globs: Vec::new(),
+			protected_paths: Vec::new(),
+			identity_sensitive_kinds: hazard::IDENTITY_SENSITIVE_KINDS
+				.iter()
+				.map(|k| k.to_string())
+				.collect(),
+			extraction_model: ExtractionModel::Cost,
 			min_occurrences: 2,
-			order_insensitive_fields: order_insensitive,
+			gzip_weight: 0.0,
+			near_duplicates: false,
+			order_rules: OrderRules::default(),
 			dry_run: false,
 			verbose: false,
 			max_passes: 50,
 			compute_gzip: false,
+			strict: false,
+			compact_shared: false,
+			legacy_shared_module: None,
 		}
 	}
 }
 
+impl Config {
+	/// Resolve where the shared module is written.
+	///
+	/// Uses `shared_module_path` if set. Otherwise the module lives next to the
+	/// artifacts in flat mode, or in `<root>/__generated__/` in recursive mode.
+	pub fn shared_module_path(&self) -> PathBuf {
+		if let Some(ref path) = self.shared_module_path {
+			return path.clone();
+		}
+		if self.recursive {
+			self.generated_dir
+				.join(GENERATED_DIR_NAME)
+				.join(&self.shared_module_name)
+		} else {
+			self.generated_dir.join(&self.shared_module_name)
+		}
+	}
+
+	/// Where the names of the shared module's exports are locked (see
+	/// [`lockfile`])
+	pub fn name_lock_path(&self) -> PathBuf {
+		lockfile_path(&self.shared_module_path())
+	}
+}
+
+/// Name of the directories Relay creates next to components when no
+/// `artifactDirectory` is configured
+pub const GENERATED_DIR_NAME: &str = "__generated__";
+
 /// Statistics about the deduplication process
 #[derive(Debug, Default)]
 pub struct Stats {
@@ -71,8 +172,15 @@ pub struct Stats {
 	pub raw_after: u64,
 	pub gzipped_before: u64,
 	pub gzipped_after: u64,
+	/// Structures moved to the shared module
 	pub total_extracted: usize,
+	/// Structures hoisted into a `const` of the only file they occur in
+	pub hoisted: usize,
+	/// Near-duplicates rewritten as spreads of a shared base
+	pub factored: usize,
 	pub passes: usize,
+	/// Number of candidates kept out of extraction, per scoping rule
+	pub suppressed: BTreeMap<Suppression, usize>,
 }
 
 impl Stats {
@@ -107,6 +215,23 @@ pub struct ExtractedEntry {
 	pub name: String,
 	pub hash: String,
 	pub count: usize,
+	/// Definition written to the shared module: the first occurrence as
+	/// written, or its normalized text with [`Config::compact_shared`]
+	/// (entries are keyed by the normalized form)
+	pub text: String,
+}
+
+/// What one extraction pass did
+#[derive(Debug, Default)]
+struct PassResult {
+	/// Structures moved to the shared module
+	extracted: usize,
+	/// Structures hoisted within their file
+	hoisted: usize,
+	/// Near-duplicates rewritten as spreads
+	factored: usize,
+	/// Structures kept inline for good (cost model only)
+	kept_inline: usize,
 }
 
 /// Timing stats for profiling
@@ -128,29 +253,75 @@ pub struct Deduplicator {
 	extracted: HashMap<String, ExtractedEntry>,
 	/// Name generator for short names
 	name_generator: NameGenerator,
+	/// Names read from the lockfile, by hash
+	locked_names: BTreeMap<String, String>,
+	/// Name generator for file-local names
+	local_names: NameGenerator,
+	/// Number of structures hoisted within their file
+	hoisted: usize,
+	/// Number of near-duplicates rewritten as spreads
+	factored: usize,
 	/// Tree representation of each file (parse once, mutate in place)
 	trees: BTreeMap<PathBuf, FileTree>,
+	/// Whether the shared module can't declare each atom seen so far without
+	/// a require cycle
+	cyclic_atoms: HashMap<String, bool>,
+	/// Parsed `Config::protected_paths`
+	protected_paths: Vec<ProtectedPath>,
+	/// Candidates suppressed per scoping rule
+	suppressed: BTreeMap<Suppression, usize>,
+	/// Protected nodes already counted in `suppressed`
+	suppressed_nodes: HashSet<(PathBuf, usize)>,
+	/// Problems found while loading and writing artifacts
+	pub diagnostics: Diagnostics,
 	/// Timing stats
 	pub timing: TimingStats,
 }
 
 impl Deduplicator {
 	pub fn new(config: Config) -> Self {
+		let protected_paths = config
+			.protected_paths
+			.iter()
+			.map(|p| ProtectedPath::parse(p))
+			.collect();
 		Self {
 			config,
 			extracted: HashMap::new(),
 			name_generator: NameGenerator::new(),
+			locked_names: BTreeMap::new(),
+			local_names: NameGenerator::local(),
+			hoisted: 0,
+			factored: 0,
 			trees: BTreeMap::new(),
+			cyclic_atoms: HashMap::new(),
+			protected_paths,
+			suppressed: BTreeMap::new(),
+			suppressed_nodes: HashSet::new(),
+			diagnostics: Diagnostics::default(),
 			timing: TimingStats::default(),
 		}
 	}
 
 	/// Run the full deduplication process
 	pub fn run(&mut self) -> Result<Stats> {
+		options::check_fraction(self.config.gzip_weight, "gzipWeight")?;
 		let mut stats = Stats::default();
 
 		// Load all files and build trees (parse ONCE)
 		self.load_files()?;
+		let lock = NameLock::read(&self.config.name_lock_path())?;
+		self.locked_names = lock.names.clone();
+		self.name_generator.lock(lock.names);
+
+		let warnings = self.diagnostics.count(Severity::Warning);
+		if self.config.strict && warnings > 0 {
+			bail!(
+				"{} warning(s) with --strict, no files written:\n{}",
+				warnings,
+				self.diagnostics.render(&self.config.generated_dir)
+			);
+		}
 
 		// Calculate initial size
 		let (raw, gzipped) = self.calculate_size();
@@ -173,13 +344,27 @@ impl Deduplicator {
 				println!("\n--- Pass {} ---", stats.passes);
 			}
 
-			let extracted = self.run_pass()?;
+			let pass = self.run_pass()?;
 
 			if self.config.verbose {
-				println!("  Extracted: {}", extracted);
+				println!("  Extracted: {}", pass.extracted);
+				if pass.hoisted > 0 {
+					println!("  Hoisted locally: {}", pass.hoisted);
+				}
+				if pass.factored > 0 {
+					println!("  Factored into spreads: {}", pass.factored);
+				}
+				if pass.kept_inline > 0 {
+					println!("  Kept inline: {}", pass.kept_inline);
+				}
 			}
 
-			if extracted == 0 {
+			// Nodes kept inline can make their parents candidates
+			if pass.extracted == 0
+				&& pass.hoisted == 0
+				&& pass.factored == 0
+				&& pass.kept_inline == 0
+			{
 				break;
 			}
 
@@ -192,10 +377,14 @@ impl Deduplicator {
 		}
 
 		stats.total_extracted = self.extracted.len();
+		stats.hoisted = self.hoisted;
+		stats.factored = self.factored;
+		stats.suppressed = self.suppressed.clone();
 
 		// Write all files to disk once at the end
 		if !self.config.dry_run {
 			self.write_all_files()?;
+			self.remove_legacy_module()?;
 		}
 
 		// Calculate final size
@@ -206,6 +395,10 @@ impl Deduplicator {
 		if self.config.verbose {
 			println!("\n============================");
 			println!("Total extracted: {}", stats.total_extracted);
+			println!("Hoisted locally: {}", stats.hoisted);
+			if self.config.near_duplicates {
+				println!("Factored into spreads: {}", stats.factored);
+			}
 			println!("\nRaw size:");
 			println!("  Before:  {}", format_bytes(stats.raw_before));
 			println!("  After:   {}", format_bytes(stats.raw_after));
@@ -222,68 +415,183 @@ impl Deduplicator {
 				format_bytes_signed(stats.gzipped_savings()),
 				stats.gzipped_savings_percent()
 			);
+			if !stats.suppressed.is_empty() {
+				println!("\nSuppressed candidates:");
+				for (rule, count) in &stats.suppressed {
+					println!("  {}: {}", rule, count);
+				}
+			}
 		}
 
 		Ok(stats)
 	}
 
-	/// Load all .graphql.ts files and build tree representations
+	/// Load all artifact files and build tree representations
 	fn load_files(&mut self) -> Result<()> {
 		self.trees.clear();
+		self.diagnostics = Diagnostics::default();
 
 		// Collect paths first (sequential - fast)
-		let paths: Vec<PathBuf> = fs::read_dir(&self.config.generated_dir)?
-			.filter_map(|e| e.ok())
-			.map(|e| e.path())
-			.filter(|p| {
-				p.file_name()
-					.and_then(|n| n.to_str())
-					.map(|n| n.ends_with(".graphql.ts"))
-					.unwrap_or(false)
-			})
-			.collect();
+		let paths = collect_artifacts(
+			&self.config.generated_dir,
+			self.config.recursive,
+			self.config.language,
+		)?;
+
+		let legacy_module = match self.config.legacy_shared_module {
+			Some(ref path) => Some(LegacyModule::read(path)?),
+			None => None,
+		};
 
 		// Parallel read and parse
-		let order_insensitive = &self.config.order_insensitive_fields;
+		let order_rules = &self.config.order_rules;
+		let root = &self.config.generated_dir;
+		let (include, exclude) = (&self.config.include_globs, &self.config.exclude_globs);
 		let results: Vec<_> = paths
 			.par_iter()
 			.map(|path| {
 				let t_read = Instant::now();
-				let content = fs::read_to_string(path).ok()?;
+				let content = match fs::read_to_string(path) {
+					Ok(content) => content,
+					Err(e) => {
+						let message = format!("can't read artifact: {}", e);
+						return Err(vec![Diagnostic::file(
+							Severity::Error,
+							Rule::ReadError,
+							path,
+							message,
+						)]);
+					}
+				};
 				let read_time = t_read.elapsed();
 
+				let relative = path.strip_prefix(root).unwrap_or(path);
+				let suppression = file_suppression(relative, &content, include, exclude);
+
+				// Legacy references are expanded before anything else looks at the file
+				let content = match (&legacy_module, &suppression) {
+					(Some(module), None) => migrate_legacy_refs(path, content, module)?,
+					_ => content,
+				};
+
+				// Only files we would rewrite need to look like plain Relay output;
+				// Relay's own dedup output is inlined first. When migrating, files
+				// left out keep importing the legacy module that is being replaced.
+				let (content, problem) = match suppression {
+					Some(_) if legacy_module.is_some() && legacy::has_legacy_refs(&content) => {
+						let problem = ArtifactProblem::LegacyRefs;
+						let diagnostic = artifact_problem_diagnostic(path, &content, problem);
+						(String::new(), Some((problem, diagnostic)))
+					}
+					Some(_) => (content, None),
+					None => match prepare_artifact(content.clone()) {
+						Ok(content) => (content, None),
+						Err(problem) => {
+							let diagnostic = artifact_problem_diagnostic(path, &content, problem);
+							(String::new(), Some((problem, diagnostic)))
+						}
+					},
+				};
+
 				let t_parse = Instant::now();
-				let tree = FileTree::new(content, order_insensitive);
+				let tree = FileTree::for_artifact(content, path, root, order_rules);
 				let parse_time = t_parse.elapsed();
 
-				Some((path.clone(), tree, read_time, parse_time))
+				Ok((
+					path.clone(),
+					tree,
+					suppression,
+					problem,
+					read_time,
+					parse_time,
+				))
 			})
 			.collect();
 
 		// Collect results and timing (sequential - fast)
-		for result in results.into_iter().flatten() {
-			let (path, tree, read_time, parse_time) = result;
+		let mut problems: Vec<(PathBuf, ArtifactProblem)> = Vec::new();
+		let mut unloaded = 0;
+		for result in results {
+			let (path, mut tree, suppression, problem, read_time, parse_time) = match result {
+				Ok(loaded) => loaded,
+				Err(diagnostics) => {
+					for diagnostic in diagnostics {
+						self.diagnostics.push(diagnostic);
+					}
+					unloaded += 1;
+					continue;
+				}
+			};
 			self.timing.file_read += read_time;
 			self.timing.tree_parse += parse_time;
+
+			if let Some((problem, diagnostic)) = problem {
+				self.diagnostics.push(diagnostic);
+				problems.push((path, problem));
+				continue;
+			}
+
+			// Out-of-scope files are left untouched; count what they would have offered
+			if let Some(rule) = suppression {
+				*self.suppressed.entry(rule).or_insert(0) += tree.find_leaves().len();
+				continue;
+			}
+
+			// Unclosed literals can't be replaced; they stay inline
+			for node in tree.nodes.iter().filter(|n| n.end == 0) {
+				let bracket = if node.is_array { '[' } else { '{' };
+				self.diagnostics.push(Diagnostic::at(
+					Severity::Warning,
+					Rule::UnbalancedLiteral,
+					&path,
+					&tree.original,
+					node.start,
+					format!("`{}` is never closed; the literal is left inline", bracket),
+				));
+			}
+
+			tree.protect_paths(&self.protected_paths);
+			tree.protect_kinds(&self.config.identity_sensitive_kinds);
 			self.trees.insert(path, tree);
 		}
 
+		if unloaded > 0 {
+			let mut message = format!(
+				"Couldn't load {} artifact(s):\n{}",
+				unloaded,
+				self.diagnostics.render(root)
+			);
+			if let Some(ref path) = self.config.legacy_shared_module {
+				message.push_str(&format!(
+					"\nEvery legacy reference must be defined in {}. Nothing was migrated.",
+					path.display()
+				));
+			}
+			bail!("{}", message);
+		}
+		if !problems.is_empty() {
+			problems.sort();
+			bail!("{}", artifact_problems_message(root, &problems));
+		}
+
 		Ok(())
 	}
 
 	/// Calculate total size (raw and gzipped) by serializing trees
 	fn calculate_size(&mut self) -> (u64, u64) {
-		let shared_module_name = &self.config.shared_module_name;
+		let shared_path = self.config.shared_module_path();
+		let module_format = self.config.module_format;
 		let compute_gzip = self.config.compute_gzip;
 
 		// Parallel: serialize and optionally gzip each tree
 		let results: Vec<_> = self
 			.trees
 			.par_iter_mut()
-			.map(|(_, tree)| {
+			.map(|(path, tree)| {
 				let t_ser = Instant::now();
 				let content = tree.serialize();
-				let content = writer::update_imports(&content, shared_module_name);
+				let import_source = writer::import_source(path, &shared_path);
+				let content = writer::update_imports(&content, &import_source, module_format);
 				let serialize_time = t_ser.elapsed();
 
 				let bytes = content.as_bytes();
@@ -332,7 +640,7 @@ impl Deduplicator {
 	}
 
 	/// Run a single pass of deduplication
-	fn run_pass(&mut self) -> Result<usize> {
+	fn run_pass(&mut self) -> Result<PassResult> {
 		// Parallel: collect all leaves from all trees
 		let t = Instant::now();
 		let leaves_by_file: Vec<_> = self
@@ -341,66 +649,345 @@ impl Deduplicator {
 			.map(|(path, tree)| (path.clone(), tree.find_leaves()))
 			.collect();
 
+		// Drop protected candidates (each node counted once)
+		let leaves_by_file: Vec<_> = leaves_by_file
+			.into_iter()
+			.map(|(path, leaves)| {
+				let tree = &self.trees[&path];
+				let (protected, leaves): (Vec<_>, Vec<_>) = leaves
+					.into_iter()
+					.partition(|(idx, _)| tree.nodes[*idx].protected_by.is_some());
+				for (idx, _) in protected {
+					if self.suppressed_nodes.insert((path.clone(), idx)) {
+						if let Some(rule) = &tree.nodes[idx].protected_by {
+							*self.suppressed.entry(rule.clone()).or_insert(0) += 1;
+						}
+					}
+				}
+				(path, leaves)
+			})
+			.collect();
+
 		// Merge counts (sequential - fast)
-		let mut counts: HashMap<String, usize> = HashMap::new();
-		for (_, leaves) in &leaves_by_file {
-			for (_, normalized) in leaves {
-				*counts.entry(normalized.clone()).or_insert(0) += 1;
+		let model = self.config.extraction_model;
+		let mut candidates: HashMap<String, Candidate> = HashMap::new();
+		// Files (indices into `leaves_by_file`) each structure occurs in
+		let mut files_of: HashMap<&str, Vec<usize>> = HashMap::new();
+		// The first occurrence of each structure represents it: its
+		// definition is emitted when extracted
+		let compact = self.config.compact_shared;
+		let mut firsts: HashMap<&str, (&FileTree, usize)> = HashMap::new();
+		let mut definitions: HashMap<&str, String> = HashMap::new();
+		let mut imports_shared = vec![false; leaves_by_file.len()];
+		for (file, (path, leaves)) in leaves_by_file.iter().enumerate() {
+			let tree = &self.trees[path];
+			imports_shared[file] = tree.nodes.iter().any(|n| n.extracted_as.is_some());
+			let mut seen: HashSet<&str> = HashSet::new();
+			for (idx, normalized) in leaves {
+				let len = tree.content_len(*idx);
+				if model == ExtractionModel::Threshold && len < THRESHOLD_MIN_CONTENT_LEN {
+					continue;
+				}
+				firsts.entry(normalized).or_insert((tree, *idx));
+				let definition = definitions.entry(normalized).or_insert_with(|| {
+					definition(tree, *idx, compact).unwrap_or_else(|| normalized.clone())
+				});
+				let candidate = candidates
+					.entry(normalized.clone())
+					.or_insert_with(|| Candidate {
+						definition_len: definition.len(),
+						replaced_len: 0,
+						occurrences: 0,
+						files: 0,
+						new_importers: 0,
+						overrides_len: 0,
+					});
+				candidate.replaced_len += len;
+				candidate.occurrences += 1;
+				if seen.insert(normalized) {
+					candidate.files += 1;
+					candidate.new_importers += usize::from(!imports_shared[file]);
+					files_of.entry(normalized).or_default().push(file);
+				}
 			}
 		}
 		self.timing.find_leaves += t.elapsed();
+		self.check_atoms();
 
 		// Find structures to extract (sequential - required for deterministic naming)
+		let cost_model = CostModel::new(
+			self.config.language,
+			self.config.module_format,
+			self.config.gzip_weight,
+		);
+		let worth_extracting = |candidate: &Candidate| {
+			candidate.occurrences >= self.config.min_occurrences
+				&& match model {
+					ExtractionModel::Cost => cost_model.worth_extracting(candidate),
+					ExtractionModel::Threshold => true,
+				}
+		};
+		let worth_hoisting = |candidate: &Candidate| {
+			candidate.occurrences >= self.config.min_occurrences
+				&& match model {
+					ExtractionModel::Cost => cost_model.worth_hoisting(candidate),
+					ExtractionModel::Threshold => true,
+				}
+		};
 		let mut to_extract: HashMap<String, String> = HashMap::new();
+		let mut to_keep_inline: HashSet<String> = HashSet::new();
+
+		// With the cost model every leaf is settled, so a structure that only
+		// becomes a leaf in a later pass reuses the existing name
+		if model == ExtractionModel::Cost {
+			for (normalized, candidate) in &candidates {
+				if let Some(entry) = self.extracted.get_mut(normalized) {
+					entry.count += candidate.occurrences;
+					to_extract.insert(normalized.clone(), entry.name.clone());
+				}
+			}
+		}
 
-		let mut normalized_list: Vec<_> = counts
+		let mut normalized_list: Vec<_> = candidates
 			.iter()
-			.filter(|(normalized, &count)| {
-				count >= self.config.min_occurrences && !self.extracted.contains_key(*normalized)
-			})
+			.filter(|(normalized, _)| !self.extracted.contains_key(*normalized))
 			.collect();
-		normalized_list.sort_by_key(|(normalized, _)| *normalized);
+		// Most beneficial first: an extraction gives its files the import
+		// statement, which the ones after it then don't pay for
+		normalized_list.sort_by(|(a, ca), (b, cb)| {
+			let benefit = |c: &Candidate| cost_model.net_benefit(c);
+			benefit(cb).total_cmp(&benefit(ca)).then_with(|| a.cmp(b))
+		});
+
+		let mut importing: HashSet<usize> = HashSet::new();
+		let mut chosen = Vec::new();
+		let mut hoisted = Vec::new();
+		for (normalized, candidate) in normalized_list {
+			// Duplicates within one file stay in it
+			if candidate.files == 1 {
+				if worth_hoisting(candidate) {
+					hoisted.push(normalized);
+				} else if model == ExtractionModel::Cost {
+					to_keep_inline.insert(normalized.clone());
+				}
+				continue;
+			}
+			// Local names can't be exported, and the shared module can't
+			// require what requires it
+			if identifiers(normalized).any(is_local_name) || self.refers_to_cyclic_atom(normalized)
+			{
+				if model == ExtractionModel::Cost {
+					to_keep_inline.insert(normalized.clone());
+				}
+				continue;
+			}
+			let files: Vec<usize> = files_of[normalized.as_str()]
+				.iter()
+				.copied()
+				.filter(|&f| !imports_shared[f] && !importing.contains(&f))
+				.collect();
+			let candidate = Candidate {
+				new_importers: files.len(),
+				..*candidate
+			};
+			if !worth_extracting(&candidate) {
+				// The threshold model leaves them as they are, like it always did
+				if model == ExtractionModel::Cost {
+					to_keep_inline.insert(normalized.clone());
+				}
+				continue;
+			}
+			importing.extend(files);
+			chosen.push((normalized, candidate));
+		}
 
-		for (normalized, &count) in normalized_list {
+		// Named in content order (deterministic, independent of the benefits)
+		chosen.sort_by_key(|(normalized, _)| *normalized);
+		for (normalized, candidate) in chosen {
 			let hash = hash_string(normalized);
 			let name = self.name_generator.next(&hash);
 			to_extract.insert(normalized.clone(), name.clone());
-			self.extracted
-				.insert(normalized.clone(), ExtractedEntry { name, hash, count });
+			self.extracted.insert(
+				normalized.clone(),
+				ExtractedEntry {
+					name,
+					hash,
+					count: candidate.occurrences,
+					text: definitions[normalized.as_str()].clone(),
+				},
+			);
 		}
 
-		if to_extract.is_empty() {
-			return Ok(0);
+		hoisted.sort();
+		for normalized in &hoisted {
+			let name = self.local_names.next(&hash_string(normalized));
+			to_extract.insert((*normalized).clone(), name);
+		}
+		self.hoisted += hoisted.len();
+
+		// What is left may still be near-duplicates of each other
+		let mut factored: HashMap<String, String> = HashMap::new();
+		if self.config.near_duplicates {
+			let mut forms: Vec<factor::Form> = candidates
+				.iter()
+				.filter(|(normalized, _)| !to_extract.contains_key(*normalized))
+				.filter(|(normalized, _)| !self.refers_to_cyclic_atom(normalized))
+				.map(|(normalized, candidate)| factor::Form {
+					normalized,
+					text: &definitions[normalized.as_str()],
+					candidate: *candidate,
+					files: &files_of[normalized.as_str()],
+				})
+				.collect();
+			forms.sort_by_key(|form| form.normalized);
+			for cluster in factor::select(&forms, &cost_model, &imports_shared, &mut importing) {
+				let name = match self.extracted.get_mut(&cluster.base) {
+					Some(entry) => {
+						entry.count += cluster.candidate.occurrences;
+						entry.name.clone()
+					}
+					None => {
+						let hash = hash_string(&cluster.base);
+						let name = self.name_generator.next(&hash);
+						self.extracted.insert(
+							cluster.base.clone(),
+							ExtractedEntry {
+								name: name.clone(),
+								hash,
+								count: cluster.candidate.occurrences,
+								text: cluster.base_text.clone(),
+							},
+						);
+						name
+					}
+				};
+				for member in &cluster.members {
+					let normalized = forms[member.form].normalized;
+					to_keep_inline.remove(normalized);
+					factored.insert(normalized.to_string(), cluster.spread(member, &name));
+				}
+			}
+		}
+		self.factored += factored.len();
+
+		let result = PassResult {
+			extracted: to_extract.len() - hoisted.len(),
+			hoisted: hoisted.len(),
+			factored: factored.len(),
+			kept_inline: to_keep_inline.len(),
+		};
+		if to_extract.is_empty() && factored.is_empty() && to_keep_inline.is_empty() {
+			return Ok(result);
 		}
 
-		// Parallel: mark nodes as extracted in trees
+		// Parallel: mark nodes as extracted (or kept inline) in trees
 		let t = Instant::now();
 		let leaves_map: HashMap<PathBuf, Vec<(usize, String)>> =
 			leaves_by_file.into_iter().collect();
-		let order_insensitive = &self.config.order_insensitive_fields;
+		let module_format = self.config.module_format;
 
 		self.trees.par_iter_mut().for_each(|(path, tree)| {
 			if let Some(leaves) = leaves_map.get(path) {
+				// Declared once, as the first occurrence in the file
+				let mut declared: HashSet<&str> = HashSet::new();
 				for (node_idx, normalized) in leaves {
 					if let Some(ref_name) = to_extract.get(normalized) {
-						tree.mark_extracted(*node_idx, ref_name.clone(), order_insensitive);
+						if is_local_name(ref_name) && declared.insert(ref_name) {
+							let text = definition(tree, *node_idx, compact)
+								.unwrap_or_else(|| normalized.clone());
+							let declarations =
+								local_declarations(tree, path, &text, ref_name, module_format);
+							tree.hoist(declarations);
+						}
+						tree.mark_extracted(*node_idx, ref_name.clone());
+					} else if let Some(spread) = factored.get(normalized) {
+						tree.hoist(file_atom_declarations(tree, path, spread, module_format));
+						let spread = tree.indented(*node_idx, spread);
+						tree.mark_extracted(*node_idx, spread);
+					} else if to_keep_inline.contains(normalized) {
+						tree.keep_inline(*node_idx);
 					}
 				}
 			}
 		});
 		self.timing.mark_extracted += t.elapsed();
 
-		Ok(to_extract.len())
+		Ok(result)
+	}
+
+	/// Check the atoms found since the last pass for references to a
+	/// processed artifact, or to any other module importing the shared module
+	fn check_atoms(&mut self) {
+		let artifacts: HashSet<PathBuf> = self
+			.trees
+			.keys()
+			.map(|path| atom::module_path(path))
+			.collect();
+		let shared_module = atom::module_path(&self.config.shared_module_path());
+		for (name, atom) in self.trees.values().flat_map(|tree| &tree.atoms) {
+			if !self.cyclic_atoms.contains_key(name) {
+				let cyclic = atom.is_cyclic(&artifacts, &shared_module);
+				self.cyclic_atoms.insert(name.clone(), cyclic);
+			}
+		}
+	}
+
+	/// Whether `normalized` refers to an atom the shared module can't declare
+	fn refers_to_cyclic_atom(&self, normalized: &str) -> bool {
+		identifiers(normalized).any(|word| self.cyclic_atoms.get(word) == Some(&true))
 	}
 
 	/// Generate the shared module content
 	fn generate_shared_module_content(&self) -> String {
-		writer::generate_shared_module_content(&self.extracted)
+		writer::generate_shared_module_content(
+			&self.extracted,
+			&self.atom_declarations(),
+			self.config.language,
+			self.config.module_format,
+		)
+	}
+
+	/// Declarations of the module atoms the extracted structures refer to,
+	/// sorted by name
+	fn atom_declarations(&self) -> Vec<String> {
+		let used: BTreeSet<&str> = self
+			.extracted
+			.values()
+			.flat_map(|entry| identifiers(&entry.text))
+			.filter(|word| atom::is_atom_name(word))
+			.collect();
+		if used.is_empty() {
+			return Vec::new();
+		}
+
+		let shared_path = self.config.shared_module_path();
+		let shared_dir = shared_path.parent().unwrap_or(Path::new(""));
+		used.into_iter()
+			.filter_map(|name| self.trees.values().find_map(|tree| tree.atoms.get(name)))
+			.map(|atom| atom.declaration(shared_dir, self.config.module_format))
+			.collect()
+	}
+
+	/// Delete the migrated legacy shared module unless the new shared module
+	/// was just written over it
+	fn remove_legacy_module(&self) -> Result<()> {
+		let Some(ref legacy) = self.config.legacy_shared_module else {
+			return Ok(());
+		};
+		let replaced = !self.extracted.is_empty() && *legacy == self.config.shared_module_path();
+		if !replaced && legacy.exists() {
+			fs::remove_file(legacy)?;
+			if self.config.verbose {
+				println!("Removed legacy shared module {}", legacy.display());
+			}
+		}
+		Ok(())
 	}
 
 	/// Write all files to disk (serialize trees)
 	fn write_all_files(&mut self) -> Result<()> {
-		let shared_module_name = &self.config.shared_module_name;
+		let shared_path = self.config.shared_module_path();
+		let module_format = self.config.module_format;
 
 		// Parallel: serialize and write each tree
 		let results: Vec<_> = self
@@ -409,37 +996,306 @@ impl Deduplicator {
 			.map(|(path, tree)| {
 				let t_ser = Instant::now();
 				let content = tree.serialize();
-				let content = writer::update_imports(&content, shared_module_name);
+				let import_source = writer::import_source(path, &shared_path);
+				let content = writer::update_imports(&content, &import_source, module_format);
 				let serialize_time = t_ser.elapsed();
 
+				let referenced: HashSet<String> = identifiers(&content)
+					.filter(|word| is_ref_name(word))
+					.map(str::to_string)
+					.collect();
+
 				let t_write = Instant::now();
 				let write_result = fs::write(path, content);
 				let write_time = t_write.elapsed();
 
-				(write_result, serialize_time, write_time)
+				(
+					path.clone(),
+					write_result,
+					serialize_time,
+					write_time,
+					referenced,
+				)
 			})
 			.collect();
 
-		// Check for errors and accumulate timing
-		for (result, ser_time, write_time) in results {
-			result?;
+		// Record every failed write and accumulate timing
+		let mut failed = 0;
+		let mut referenced = HashSet::new();
+		for (path, result, ser_time, write_time, names) in results {
+			referenced.extend(names);
+			if let Err(e) = result {
+				let message = format!("can't write artifact: {}", e);
+				self.diagnostics.push(Diagnostic::file(
+					Severity::Error,
+					Rule::WriteError,
+					&path,
+					message,
+				));
+				failed += 1;
+			}
 			self.timing.serialize += ser_time;
 			self.timing.file_write += write_time;
 		}
 
 		// Write shared module (single file, not parallelized)
 		if !self.extracted.is_empty() {
-			let shared_path = self
-				.config
-				.generated_dir
-				.join(&self.config.shared_module_name);
-			write_shared_module(&shared_path, &self.extracted)?;
+			let result = shared_path
+				.parent()
+				.map_or(Ok(()), fs::create_dir_all)
+				.map_err(anyhow::Error::from)
+				.and_then(|_| {
+					write_shared_module(
+						&shared_path,
+						&self.extracted,
+						&self.atom_declarations(),
+						self.config.language,
+						self.config.module_format,
+					)
+				});
+			if let Err(e) = result {
+				let message = format!("can't write shared module: {}", e);
+				self.diagnostics.push(Diagnostic::file(
+					Severity::Error,
+					Rule::WriteError,
+					&shared_path,
+					message,
+				));
+				failed += 1;
+			}
 		}
 
+		// The lockfile goes with the shared module: left alone when that isn't
+		// rewritten, else rewritten with this run's names and the locked names
+		// artifacts still use, which prunes only the ones nothing refers to
+		let lock_path = self.config.name_lock_path();
+		let result = if self.extracted.is_empty() {
+			Ok(())
+		} else {
+			let mut names: BTreeMap<String, String> = self
+				.extracted
+				.values()
+				.map(|entry| (entry.hash.clone(), entry.name.clone()))
+				.collect();
+			let taken: HashSet<&str> = names.values().map(String::as_str).collect();
+			let still_used: Vec<(String, String)> = self
+				.locked_names
+				.iter()
+				.filter(|(hash, name)| {
+					!names.contains_key(*hash)
+						&& !taken.contains(name.as_str())
+						&& referenced.contains(*name)
+				})
+				.map(|(hash, name)| (hash.clone(), name.clone()))
+				.collect();
+			names.extend(still_used);
+			NameLock { names }.write(&lock_path)
+		};
+		if let Err(e) = result {
+			let message = format!("can't write name lockfile: {:#}", e);
+			self.diagnostics.push(Diagnostic::file(
+				Severity::Error,
+				Rule::WriteError,
+				&lock_path,
+				message,
+			));
+			failed += 1;
+		}
+
+		if failed > 0 {
+			bail!(
+				"Couldn't write {} file(s):\n{}",
+				failed,
+				self.diagnostics.render(&self.config.generated_dir)
+			);
+		}
 		Ok(())
 	}
 }
 
+/// Identifiers in a normalized form
+pub(crate) fn identifiers(normalized: &str) -> impl Iterator<Item = &str> {
+	lexer::tokenize(normalized)
+		.into_iter()
+		.filter(|t| t.kind == lexer::TokenKind::Ident)
+		.map(move |t| t.text(normalized))
+}
+
+/// What a leaf is emitted as when extracted: its source, or its normalized
+/// text with `compact` (see [`Config::compact_shared`])
+fn definition(tree: &FileTree, node_idx: usize, compact: bool) -> Option<String> {
+	if compact {
+		tree.leaf_text(node_idx).map(str::to_string)
+	} else {
+		tree.leaf_source(node_idx)
+	}
+}
+
+/// Declarations hoisting `text` as `name` into the artifact at `path`: the
+/// module atoms it refers to, then `const name = ...;`
+fn local_declarations(
+	tree: &FileTree,
+	path: &Path,
+	text: &str,
+	name: &str,
+	format: ModuleFormat,
+) -> Vec<String> {
+	let mut declarations = file_atom_declarations(tree, path, text, format);
+	declarations.push(format!("const {} = {};", name, text));
+	declarations
+}
+
+/// Declarations of the module atoms `text` refers to, for the artifact at
+/// `path`
+fn file_atom_declarations(
+	tree: &FileTree,
+	path: &Path,
+	text: &str,
+	format: ModuleFormat,
+) -> Vec<String> {
+	let dir = path.parent().unwrap_or(Path::new(""));
+	let atoms: BTreeSet<&str> = identifiers(text)
+		.filter(|word| atom::is_atom_name(word))
+		.collect();
+	atoms
+		.into_iter()
+		.filter_map(|atom| tree.atoms.get(atom))
+		.map(|atom| atom.declaration(dir, format))
+		.collect()
+}
+
+/// Expand the legacy references of an artifact, or report every one that
+/// can't be resolved
+fn migrate_legacy_refs(
+	path: &Path,
+	content: String,
+	module: &LegacyModule,
+) -> Result<String, Vec<Diagnostic>> {
+	module.migrate_artifact(&content).map_err(|unresolved| {
+		unresolved
+			.into_iter()
+			.map(|u| {
+				let message = format!("legacy reference {} can't be resolved", u.name);
+				Diagnostic::at(
+					Severity::Error,
+					Rule::UnresolvedLegacyRef,
+					path,
+					&content,
+					u.offset,
+					message,
+				)
+			})
+			.collect()
+	})
+}
+
+/// Diagnostic for an artifact refused by the content checks
+fn artifact_problem_diagnostic(path: &Path, content: &str, problem: ArtifactProblem) -> Diagnostic {
+	let rule = match problem {
+		ArtifactProblem::RelayDedup => Rule::RelayDedup,
+		ArtifactProblem::NotGenerated => Rule::NotGenerated,
+		ArtifactProblem::LegacyRefs => Rule::LegacyRefs,
+	};
+	let offset = problem_offset(content, problem);
+	Diagnostic::at(
+		Severity::Error,
+		rule,
+		path,
+		content,
+		offset,
+		problem.to_string(),
+	)
+}
+
+/// Explain why a run was refused, naming every offending file
+fn artifact_problems_message(root: &Path, problems: &[(PathBuf, ArtifactProblem)]) -> String {
+	let mut lines = vec!["Refusing to process artifacts with unexpected contents:".to_string()];
+	for (path, problem) in problems {
+		let relative = path.strip_prefix(root).unwrap_or(path);
+		lines.push(format!("  {} ({})", relative.display(), problem));
+	}
+	if problems
+		.iter()
+		.any(|(_, p)| *p == ArtifactProblem::RelayDedup)
+	{
+		lines.push(
+			"Relay's built-in deduplication is enabled and its output couldn't be inlined. Set\n\
+			 featureFlags.disable_deduping_common_structures_in_artifacts = { \"kind\": \"enabled\" }\n\
+			 (or run `relay-dedup init`) and recompile."
+				.to_string(),
+		);
+	}
+	if problems
+		.iter()
+		.any(|(_, p)| *p == ArtifactProblem::LegacyRefs)
+	{
+		lines.push(
+			"Legacy _xxxxxxxx references come from an older relay-dedup. Convert them with \
+			 `relay-dedup migrate`; artifacts excluded from the run can't be migrated."
+				.to_string(),
+		);
+	}
+	if problems
+		.iter()
+		.any(|(_, p)| *p == ArtifactProblem::NotGenerated)
+	{
+		lines.push(
+			"Files without an @generated header weren't written by relay-compiler. \
+			 Move them out of the artifact directory or skip them with --exclude."
+				.to_string(),
+		);
+	}
+	lines.join("\n")
+}
+
+/// Check whether a path looks like a Relay artifact for the given language
+fn is_artifact(path: &Path, language: Language) -> bool {
+	path.file_name()
+		.and_then(|n| n.to_str())
+		.map(|n| n.ends_with(language.artifact_suffix()))
+		.unwrap_or(false)
+}
+
+/// Collect the artifacts to process: the files directly in `dir`, or with
+/// `recursive` every artifact in a `__generated__` folder below it.
+pub fn collect_artifacts(dir: &Path, recursive: bool, language: Language) -> Result<Vec<PathBuf>> {
+	if recursive {
+		return Ok(collect_colocated_artifacts(dir, language));
+	}
+	Ok(fs::read_dir(dir)?
+		.filter_map(|e| e.ok())
+		.map(|e| e.path())
+		.filter(|p| is_artifact(p, language))
+		.collect())
+}
+
+/// Collect artifacts from every `__generated__` folder below a source root.
+///
+/// `node_modules` and hidden directories are never entered.
+pub fn collect_colocated_artifacts(root: &Path, language: Language) -> Vec<PathBuf> {
+	let mut paths: Vec<PathBuf> = WalkDir::new(root)
+		.into_iter()
+		.filter_entry(|e| {
+			let name = e.file_name().to_string_lossy();
+			e.depth() == 0 || !(name == "node_modules" || name.starts_with('.'))
+		})
+		.filter_map(|e| e.ok())
+		.filter(|e| e.file_type().is_file())
+		.map(|e| e.into_path())
+		.filter(|p| {
+			is_artifact(p, language)
+				&& p.strip_prefix(root)
+					.map(|rel| {
+						rel.components()
+							.any(|c| c.as_os_str() == GENERATED_DIR_NAME)
+					})
+					.unwrap_or(false)
+		})
+		.collect();
+	paths.sort();
+	paths
+}
+
 /// Hash a string using MD5 and return full 32 hex chars
 pub fn hash_string(s: &str) -> String {
 	use md5::{Digest, Md5};
@@ -0,0 +1,213 @@
+//! Canonical spelling of string and number literals.
+//!
+//! The same value can be written many ways: `"A"`, `"\u0041"` and `"\x41"`,
+//! or `1`, `1.0`, `1e0` and `0x1`. Matching goes by the canonical spelling,
+//! so those count as one structure; the emitted code keeps the literals as
+//! written (see [`crate::normalize::Normalized`]).
+
+use std::borrow::Cow;
+use std::fmt::Write;
+
+/// The canonical spelling of a literal: strings re-escaped minimally,
+/// numbers in their shortest round-trip decimal form. Anything else (names,
+/// `null`, BigInts, malformed literals) is returned as is.
+pub fn canonical(literal: &str) -> Cow<'_, str> {
+	if literal.starts_with('"') {
+		return match decode_string(literal) {
+			Some(units) => Cow::Owned(encode_string(&units)),
+			None => Cow::Borrowed(literal),
+		};
+	}
+	match parse_number(literal) {
+		Some(value) => Cow::Owned(format_number(value)),
+		None => Cow::Borrowed(literal),
+	}
+}
+
+/// The property name an object key stands for, quoted canonically: `1`,
+/// `"1"` and `1.0` are all `"1"`
+pub fn canonical_key(key: &str) -> Cow<'_, str> {
+	if key.starts_with('"') {
+		return canonical(key);
+	}
+	match parse_number(key) {
+		Some(value) => {
+			let name: Vec<u16> = format_number(value).encode_utf16().collect();
+			Cow::Owned(encode_string(&name))
+		}
+		None => Cow::Borrowed(key),
+	}
+}
+
+/// UTF-16 code units of a double-quoted string literal (JSON and JS escapes)
+fn decode_string(literal: &str) -> Option<Vec<u16>> {
+	let inner = literal.strip_prefix('"')?.strip_suffix('"')?;
+	let mut units = Vec::with_capacity(inner.len());
+	let mut chars = inner.chars().peekable();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			let mut buf = [0u16; 2];
+			units.extend_from_slice(c.encode_utf16(&mut buf));
+			continue;
+		}
+		let unit = match chars.next()? {
+			'n' => 0x0a,
+			't' => 0x09,
+			'r' => 0x0d,
+			'b' => 0x08,
+			'f' => 0x0c,
+			'v' => 0x0b,
+			'0' if !chars.peek().is_some_and(|c| c.is_ascii_digit()) => 0,
+			'x' => hex(&mut chars, 2)? as u16,
+			'u' if chars.peek() == Some(&'{') => {
+				chars.next();
+				let mut code = 0u32;
+				loop {
+					match chars.next()? {
+						'}' => break,
+						c => code = code.checked_mul(16)?.checked_add(c.to_digit(16)?)?,
+					}
+				}
+				let mut buf = [0u16; 2];
+				units.extend_from_slice(char::from_u32(code)?.encode_utf16(&mut buf));
+				continue;
+			}
+			'u' => hex(&mut chars, 4)? as u16,
+			// Line continuation
+			'\n' => continue,
+			'\r' => {
+				if chars.peek() == Some(&'\n') {
+					chars.next();
+				}
+				continue;
+			}
+			// Octal escapes are errors in strict mode; leave them alone
+			'1'..='9' => return None,
+			// `\"`, `\\`, `\/` and any other escaped character
+			c => {
+				let mut buf = [0u16; 2];
+				units.extend_from_slice(c.encode_utf16(&mut buf));
+				continue;
+			}
+		};
+		units.push(unit);
+	}
+	Some(units)
+}
+
+/// `digits` hex digits
+fn hex(chars: &mut impl Iterator<Item = char>, digits: usize) -> Option<u32> {
+	let mut value = 0;
+	for _ in 0..digits {
+		value = value * 16 + chars.next()?.to_digit(16)?;
+	}
+	Some(value)
+}
+
+/// A string literal with only `"`, `\` and control characters escaped.
+/// Lone surrogates can't be written literally and stay `\uXXXX` escapes.
+fn encode_string(units: &[u16]) -> String {
+	let mut result = String::with_capacity(units.len() + 2);
+	result.push('"');
+	for decoded in char::decode_utf16(units.iter().copied()) {
+		match decoded {
+			Ok('"') => result.push_str("\\\""),
+			Ok('\\') => result.push_str("\\\\"),
+			Ok('\n') => result.push_str("\\n"),
+			Ok('\t') => result.push_str("\\t"),
+			Ok('\r') => result.push_str("\\r"),
+			Ok(c) if (c as u32) < 0x20 => {
+				let _ = write!(result, "\\u{:04x}", c as u32);
+			}
+			Ok(c) => result.push(c),
+			Err(e) => {
+				let _ = write!(result, "\\u{:04x}", e.unpaired_surrogate());
+			}
+		}
+	}
+	result.push('"');
+	result
+}
+
+/// The value of a numeric literal (decimal, hex, octal or binary, with `_`
+/// separators and an optional leading `-`). BigInts aren't numbers.
+fn parse_number(literal: &str) -> Option<f64> {
+	let (negative, digits) = match literal.strip_prefix('-') {
+		Some(rest) => (true, rest),
+		None => (false, literal),
+	};
+	let digits = digits.replace('_', "");
+	let radix = match digits.get(..2) {
+		Some("0x" | "0X") => 16,
+		Some("0o" | "0O") => 8,
+		Some("0b" | "0B") => 2,
+		_ => 10,
+	};
+	let value = if radix == 10 {
+		// `0123` is a legacy octal literal
+		let legacy_octal = digits.len() > 1
+			&& digits.starts_with('0')
+			&& digits[1..].starts_with(|c: char| c.is_ascii_digit());
+		if legacy_octal
+			|| !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.')
+			|| digits.contains(|c: char| c.is_ascii_alphabetic() && c != 'e' && c != 'E')
+		{
+			return None;
+		}
+		digits.parse::<f64>().ok()?
+	} else {
+		u128::from_str_radix(&digits[2..], radix).ok()? as f64
+	};
+	Some(if negative { -value } else { value })
+}
+
+/// Shortest decimal spelling that reads back as `value`
+fn format_number(value: f64) -> String {
+	if value == 0.0 && value.is_sign_negative() {
+		return "-0".to_string();
+	}
+	format!("{}", value)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_canonical_strings() {
+		assert_eq!(canonical(r#""\u0041""#), r#""A""#);
+		assert_eq!(canonical(r#""\x41\u{42}""#), r#""AB""#);
+		assert_eq!(canonical(r#""a\/b""#), r#""a/b""#);
+		assert_eq!(canonical(r#""\"q\" \\ \n""#), r#""\"q\" \\ \n""#);
+		assert_eq!(canonical(r#""\u000A\u0001""#), r#""\n\u0001""#);
+		assert_eq!(canonical(r#""\u00e9""#), "\"é\"");
+		// Surrogate pairs are one character; lone surrogates stay escaped
+		assert_eq!(canonical(r#""\ud83d\ude00""#), "\"😀\"");
+		assert_eq!(canonical(r#""\u{1F600}""#), "\"😀\"");
+		assert_eq!(canonical(r#""\uD83D!""#), r#""\ud83d!""#);
+		assert_eq!(canonical(r#""\1""#), r#""\1""#);
+	}
+
+	#[test]
+	fn test_canonical_numbers() {
+		for (literal, expected) in [
+			("1", "1"),
+			("1.0", "1"),
+			("1e3", "1000"),
+			("1E+3", "1000"),
+			("-0.50", "-0.5"),
+			(".5", "0.5"),
+			("0x10", "16"),
+			("0b101", "5"),
+			("1_000", "1000"),
+			("-0", "-0"),
+			("10n", "10n"),
+			("017", "017"),
+			("null", "null"),
+		] {
+			assert_eq!(canonical(literal), expected, "{}", literal);
+		}
+		assert_eq!(canonical_key("1.0"), r#""1""#);
+		assert_eq!(canonical_key(r#""a""#), r#""a""#);
+	}
+}
@@ -0,0 +1,132 @@
+//! Name lockfile.
+//!
+//! A structure's name is the shortest prefix of its hash not taken yet, so
+//! which of two colliding structures gets `x_abc` depends on everything else
+//! extracted in the run: adding one query could rename unrelated exports and
+//! touch hundreds of artifacts. The names given out are kept in a lockfile
+//! next to the shared module (`__shared.lock.json` for `__shared.ts`):
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "names": {
+//!     "4a7f9c...": "x_4a7"
+//!   }
+//! }
+//! ```
+//!
+//! Later runs give each hash its locked name back, and new names never take
+//! one held by a locked entry (see [`crate::naming::NameGenerator::lock`]).
+//! The lockfile is rewritten along with the shared module, keeping the names
+//! of the run and the locked names artifacts still refer to, so only entries
+//! nothing uses are pruned. A run that doesn't rewrite the shared module (a
+//! rerun over deduplicated artifacts) leaves it alone. Commit it along with
+//! the artifacts.
+
+use crate::naming::is_ref_name;
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Format version written to the lockfile
+const VERSION: u64 = 1;
+
+/// Where the lockfile of the shared module at `shared_module` lives
+pub fn lockfile_path(shared_module: &Path) -> PathBuf {
+	let stem = shared_module
+		.file_stem()
+		.map(|stem| stem.to_string_lossy())
+		.unwrap_or_default();
+	shared_module.with_file_name(format!("{}.lock.json", stem))
+}
+
+/// Shared export names by content hash
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NameLock {
+	pub names: BTreeMap<String, String>,
+}
+
+impl NameLock {
+	/// Read the lockfile at `path`; a missing one is empty
+	pub fn read(path: &Path) -> Result<Self> {
+		if !path.exists() {
+			return Ok(Self::default());
+		}
+		let content = fs::read_to_string(path)
+			.with_context(|| format!("Failed to read name lockfile {}", path.display()))?;
+		Self::parse(&content).with_context(|| {
+			format!(
+				"Invalid name lockfile {} (delete it to start over; exports may be renamed)",
+				path.display()
+			)
+		})
+	}
+
+	fn parse(content: &str) -> Result<Self> {
+		let json: Value = serde_json::from_str(content)?;
+		match json.get("version").and_then(Value::as_u64) {
+			Some(VERSION) => {}
+			Some(version) => bail!("unsupported version {}", version),
+			None => bail!("missing \"version\""),
+		}
+		let Some(names) = json.get("names").and_then(Value::as_object) else {
+			bail!("\"names\" must be an object");
+		};
+
+		let mut lock = Self::default();
+		let mut holders: HashMap<&str, &str> = HashMap::new();
+		for (hash, name) in names {
+			let Some(name) = name.as_str().filter(|name| is_ref_name(name)) else {
+				bail!("invalid name {} for {}", name, hash);
+			};
+			if let Some(other) = holders.insert(name, hash) {
+				bail!("{} is held by both {} and {}", name, other, hash);
+			}
+			lock.names.insert(hash.clone(), name.to_string());
+		}
+		Ok(lock)
+	}
+
+	/// Write the lockfile to `path`, names sorted by hash
+	pub fn write(&self, path: &Path) -> Result<()> {
+		let names: Map<String, Value> = self
+			.names
+			.iter()
+			.map(|(hash, name)| (hash.clone(), Value::from(name.as_str())))
+			.collect();
+		let json = json!({ "version": VERSION, "names": names });
+		let content = format!("{}\n", serde_json::to_string_pretty(&json)?);
+		fs::write(path, content)
+			.with_context(|| format!("Failed to write name lockfile {}", path.display()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_and_write() {
+		let temp = tempfile::tempdir().unwrap();
+		let path = lockfile_path(&temp.path().join("__shared.ts"));
+		assert_eq!(path, temp.path().join("__shared.lock.json"));
+		assert_eq!(NameLock::read(&path).unwrap(), NameLock::default());
+
+		let mut lock = NameLock::default();
+		lock.names
+			.insert("abcd12".to_string(), "x_abcd".to_string());
+		lock.names.insert("abc123".to_string(), "x_abc".to_string());
+		lock.write(&path).unwrap();
+		assert_eq!(NameLock::read(&path).unwrap(), lock);
+
+		let parse = |content: &str| NameLock::parse(content).unwrap_err().to_string();
+		assert!(parse(r#"{"version": 2, "names": {}}"#).contains("unsupported version 2"));
+		assert!(parse(r#"{"version": 1, "names": {"abc": "_abc"}}"#).contains("invalid name"));
+		assert!(
+			parse(r#"{"version": 1, "names": {"abc1": "x_abc", "abc2": "x_abc"}}"#)
+				.contains("x_abc is held by both abc1 and abc2")
+		);
+	}
+}
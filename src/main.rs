@@ -3,13 +3,26 @@
 //! A tool to deduplicate Relay-generated artifact files by extracting
 //! repeated structures into a shared module.
 
-use anyhow::{bail, Result};
-use clap::Parser;
-use relay_dedup::relay_config::{find_relay_config, validate_relay_config};
-use relay_dedup::{Config, Deduplicator};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use relay_dedup::cost::ExtractionModel;
+use relay_dedup::diagnostics::Diagnostics;
+use relay_dedup::doctor::{self, Report, Status};
+use relay_dedup::fix_config::plan_config_fixes;
+use relay_dedup::options::{
+	check_fraction, find_options_file, load_options_file, OptionLayer, Options,
+};
+use relay_dedup::order::OrderRules;
+use relay_dedup::relay_config::{
+	dedup_disabled, find_relay_config, find_workspace_configs, validate_project,
+	validate_relay_config, RelayConfig, RelayProject,
+};
+use relay_dedup::{collect_artifacts, Config, Deduplicator, Language, ModuleFormat, Stats};
 use std::collections::HashSet;
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 #[derive(Parser, Debug)]
@@ -17,60 +30,661 @@ use std::time::Instant;
 #[command(author = "Fellow <engineering@fellow.app>")]
 #[command(version)]
 #[command(about = "Deduplicate Relay-generated artifact files", long_about = None)]
+#[command(
+	after_help = "On/off flags also have a --no-<flag> form (e.g. --no-dry-run) to override the options file and RELAY_DEDUP_* variables."
+)]
 struct Args {
-	/// Path to the __generated__ directory (optional if relay config has artifactDirectory)
+	#[command(subcommand)]
+	command: Option<Command>,
+
+	/// Path to the __generated__ directory, or the source root with --recursive
+	/// (optional if relay config has artifactDirectory / src)
 	#[arg(value_name = "GENERATED_DIR")]
 	generated_dir: Option<PathBuf>,
 
-	/// Shared module filename
-	#[arg(short, long, default_value = "__shared.ts")]
-	output: String,
+	/// Shared module filename [default: __shared.ts, or __shared.js for flow/javascript]
+	#[arg(short, long, global = true)]
+	output: Option<String>,
+
+	/// Artifact language: typescript, flow or javascript (default: relay config's
+	/// language, else typescript)
+	#[arg(long, global = true)]
+	language: Option<Language>,
+
+	/// Module format of artifact references and the shared module: esm or
+	/// commonjs (default: derived from the relay config's language and
+	/// eagerEsModules, else esm)
+	#[arg(long, value_name = "FORMAT", global = true)]
+	module_format: Option<ModuleFormat>,
+
+	/// Collect artifacts from every colocated __generated__ folder below the source root
+	#[arg(short, long, overrides_with = "no_recursive", global = true)]
+	recursive: bool,
+
+	/// Turn off --recursive set by the options file or environment
+	#[arg(long, overrides_with = "recursive", hide = true, global = true)]
+	no_recursive: bool,
+
+	/// Location of the shared module (default: next to the artifacts, or
+	/// <root>/__generated__/ with --recursive)
+	#[arg(long, value_name = "PATH", global = true)]
+	shared_module: Option<PathBuf>,
 
 	/// Show what would change without writing files
-	#[arg(short = 'n', long)]
+	#[arg(short = 'n', long, global = true, overrides_with = "no_dry_run")]
 	dry_run: bool,
 
+	/// Turn off --dry-run set by the options file or environment
+	#[arg(long, overrides_with = "dry_run", hide = true, global = true)]
+	no_dry_run: bool,
+
 	/// Print detailed progress and statistics
-	#[arg(short, long)]
+	#[arg(short, long, overrides_with = "no_verbose", global = true)]
 	verbose: bool,
 
-	/// Minimum occurrences to extract a structure
-	#[arg(long, default_value = "2")]
-	min_occurrences: usize,
+	/// Turn off --verbose set by the options file or environment
+	#[arg(long, overrides_with = "verbose", hide = true, global = true)]
+	no_verbose: bool,
+
+	/// How structures are chosen for extraction: cost (only when the output
+	/// gets smaller) or threshold (15+ bytes seen --min-occurrences times)
+	/// [default: cost]
+	#[arg(long, value_name = "MODEL", global = true)]
+	extraction_model: Option<ExtractionModel>,
+
+	/// Minimum occurrences to extract a structure; a lower bound with the cost
+	/// model [default: 2]
+	#[arg(long, global = true)]
+	min_occurrences: Option<usize>,
+
+	/// Weight of the estimated gzip benefit in the cost model, from 0 (raw
+	/// size only) to 1 [default: 0]
+	#[arg(long, value_name = "WEIGHT", value_parser = parse_gzip_weight, global = true)]
+	gzip_weight: Option<f64>,
+
+	/// Also factor objects differing in one or two keys into
+	/// `{...x_base, "name": ...}` and arrays with a common prefix into
+	/// `[...x_prefix, ...]`, when that makes the output smaller
+	#[arg(long, overrides_with = "no_near_duplicates", global = true)]
+	near_duplicates: bool,
+
+	/// Turn off --near-duplicates set by the options file or environment
+	#[arg(long, overrides_with = "near_duplicates", hide = true, global = true)]
+	no_near_duplicates: bool,
 
-	/// Comma-separated list of order-insensitive field names
-	#[arg(long, default_value = "selections,args,argumentDefinitions")]
-	order_insensitive: String,
+	/// Comma-separated arrays whose element order doesn't matter: `key` for
+	/// any parent kind or `Kind.key`, e.g. `LinkedField.selections,args`
+	/// [default: the rules for the installed relay-compiler]
+	#[arg(long, global = true)]
+	order_insensitive: Option<String>,
 
-	/// Maximum number of passes to run
-	#[arg(long, default_value = "50")]
-	max_passes: usize,
+	/// Read order-insensitivity rules from this JSON file (`orderInsensitive`
+	/// and `orderSensitiveKinds` lists)
+	#[arg(long, value_name = "PATH", global = true)]
+	order_rules: Option<PathBuf>,
+
+	/// Maximum number of passes to run [default: 50]
+	#[arg(long, global = true)]
+	max_passes: Option<usize>,
 
 	/// Show gzipped size savings in output
-	#[arg(long)]
+	#[arg(long, overrides_with = "no_show_gzip", global = true)]
 	show_gzip: bool,
 
+	/// Turn off --show-gzip set by the options file or environment
+	#[arg(long, overrides_with = "show_gzip", hide = true, global = true)]
+	no_show_gzip: bool,
+
 	/// Show timing breakdown
-	#[arg(long)]
+	#[arg(long, overrides_with = "no_show_timing", global = true)]
 	show_timing: bool,
 
+	/// Turn off --show-timing set by the options file or environment
+	#[arg(long, overrides_with = "show_timing", hide = true, global = true)]
+	no_show_timing: bool,
+
 	/// Skip relay config validation (use with caution)
-	#[arg(long)]
+	#[arg(long, overrides_with = "no_skip_config_check", global = true)]
 	skip_config_check: bool,
+
+	/// Turn off --skip-config-check set by the options file or environment
+	#[arg(long, overrides_with = "skip_config_check", hide = true, global = true)]
+	no_skip_config_check: bool,
+
+	/// Fail on warnings (e.g. unbalanced literals) before writing any file
+	#[arg(long, overrides_with = "no_strict", global = true)]
+	strict: bool,
+
+	/// Turn off --strict set by the options file or environment
+	#[arg(long, overrides_with = "strict", hide = true, global = true)]
+	no_strict: bool,
+
+	/// Write shared definitions in normalized form (keys sorted, no
+	/// whitespace) instead of as their first occurrence is written
+	#[arg(long, overrides_with = "no_compact_shared", global = true)]
+	compact_shared: bool,
+
+	/// Turn off --compact-shared set by the options file or environment
+	#[arg(long, overrides_with = "compact_shared", hide = true, global = true)]
+	no_compact_shared: bool,
+
+	/// Write diagnostics as a SARIF 2.1.0 log to this file (for CI code scanning)
+	#[arg(long, value_name = "PATH", global = true)]
+	sarif: Option<PathBuf>,
+
+	/// Only process artifacts matching this glob, relative to the directory (repeatable)
+	#[arg(long = "include", value_name = "GLOB", global = true)]
+	include: Vec<String>,
+
+	/// Skip artifacts matching this glob, relative to the directory (repeatable)
+	#[arg(long = "exclude", value_name = "GLOB", global = true)]
+	exclude: Vec<String>,
+
+	/// Never extract nodes at or below this key path, e.g. `params` or
+	/// `metadata.refetch` (repeatable, comma-separated)
+	#[arg(
+		long = "protect",
+		value_name = "KEY_PATH",
+		value_delimiter = ',',
+		global = true
+	)]
+	protect: Vec<String>,
+
+	/// Never extract nodes with this `"kind"` (Relay caches them by identity)
+	/// [default: Request,Fragment,Operation,SplitOperation,UpdatableQuery,InlineDataFragment]
+	#[arg(
+		long = "protect-kind",
+		value_name = "KIND",
+		value_delimiter = ',',
+		global = true
+	)]
+	protect_kind: Vec<String>,
+
+	/// Deduplicate every relay project found below this workspace root
+	#[arg(
+		long,
+		value_name = "ROOT",
+		conflicts_with = "generated_dir",
+		global = true
+	)]
+	workspace: Option<PathBuf>,
+
+	/// Only process the named relay project (repeatable)
+	#[arg(long = "project", value_name = "NAME", global = true)]
+	projects: Vec<String>,
+
+	/// List the relay projects that would be processed and exit
+	#[arg(long, global = true)]
+	list_projects: bool,
+
+	/// Read options from this file instead of searching for relay-dedup.config.json
+	/// or a "relayDedup" key in package.json
+	#[arg(long, value_name = "PATH", global = true)]
+	config: Option<PathBuf>,
+
+	/// Add the required feature flags to the relay config and exit (same as `init`)
+	#[arg(long, global = true)]
+	fix_config: bool,
+
+	/// Apply `init` / `--fix-config` changes without asking for confirmation
+	#[arg(short, long, global = true)]
+	yes: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+	/// Add the required feature flags to the relay config and a relay:dedup
+	/// script to package.json
+	Init,
+	/// Check relay-compiler / relay-runtime versions, config flags and artifacts
+	Doctor {
+		/// Same as GENERATED_DIR before the subcommand
+		#[arg(value_name = "GENERATED_DIR")]
+		generated_dir: Option<PathBuf>,
+	},
+	/// Expand legacy `_xxxxxxxx` references and deduplicate into the current format
+	Migrate {
+		/// Same as GENERATED_DIR before the subcommand
+		#[arg(value_name = "GENERATED_DIR")]
+		generated_dir: Option<PathBuf>,
+
+		/// Legacy shared module defining the references (default: the shared
+		/// module path)
+		#[arg(long, value_name = "PATH")]
+		from: Option<PathBuf>,
+	},
+}
+
+impl Args {
+	/// The directory given before or after the subcommand
+	fn generated_dir(&self) -> Result<Option<PathBuf>> {
+		let after = match self.command {
+			Some(Command::Doctor { ref generated_dir })
+			| Some(Command::Migrate {
+				ref generated_dir, ..
+			}) => generated_dir.clone(),
+			_ => None,
+		};
+		match (&self.generated_dir, after) {
+			(Some(_), Some(_)) => {
+				bail!("GENERATED_DIR can be given before or after the subcommand, not both")
+			}
+			(_, Some(_)) if self.workspace.is_some() => {
+				bail!("GENERATED_DIR can't be used with --workspace")
+			}
+			(before, after) => Ok(before.clone().or(after)),
+		}
+	}
+
+	/// Options given on the command line. Flags count only when passed,
+	/// `--no-<flag>` turns one off.
+	fn option_layer(&self) -> Result<OptionLayer> {
+		let flag = |set: bool, unset: bool| match (set, unset) {
+			(true, _) => Some(true),
+			(_, true) => Some(false),
+			_ => None,
+		};
+		let list = |values: &[String]| (!values.is_empty()).then(|| values.to_vec());
+		Ok(OptionLayer {
+			generated_dir: self.generated_dir()?,
+			output: self.output.clone(),
+			language: self.language,
+			module_format: self.module_format,
+			recursive: flag(self.recursive, self.no_recursive),
+			shared_module: self.shared_module.clone(),
+			dry_run: flag(self.dry_run, self.no_dry_run),
+			verbose: flag(self.verbose, self.no_verbose),
+			extraction_model: self.extraction_model,
+			min_occurrences: self.min_occurrences,
+			gzip_weight: self.gzip_weight,
+			near_duplicates: flag(self.near_duplicates, self.no_near_duplicates),
+			order_insensitive: self.order_insensitive.as_ref().map(|fields| {
+				fields
+					.split(',')
+					.map(|s| s.trim().to_string())
+					.filter(|s| !s.is_empty())
+					.collect()
+			}),
+			order_rules: self.order_rules.clone(),
+			max_passes: self.max_passes,
+			show_gzip: flag(self.show_gzip, self.no_show_gzip),
+			show_timing: flag(self.show_timing, self.no_show_timing),
+			skip_config_check: flag(self.skip_config_check, self.no_skip_config_check),
+			strict: flag(self.strict, self.no_strict),
+			compact_shared: flag(self.compact_shared, self.no_compact_shared),
+			sarif: self.sarif.clone(),
+			include: list(&self.include),
+			exclude: list(&self.exclude),
+			protect: list(&self.protect),
+			protect_kinds: list(&self.protect_kind),
+			workspace: self.workspace.clone(),
+			projects: list(&self.projects),
+		})
+	}
+}
+
+/// `--gzip-weight`: a finite number from 0 to 1
+fn parse_gzip_weight(s: &str) -> Result<f64> {
+	let value: f64 = s
+		.trim()
+		.parse()
+		.context("option 'gzipWeight' must be a number")?;
+	check_fraction(value, "gzipWeight")
+}
+
+/// One directory to deduplicate, optionally tied to a relay project
+struct Target {
+	/// Project label used in per-project summaries
+	label: Option<String>,
+	generated_dir: PathBuf,
+	recursive: bool,
+	language: Option<Language>,
+	/// `eagerEsModules` of the relay project this target belongs to (Relay
+	/// defaults it to false); `None` without a relay config
+	eager_es_modules: Option<bool>,
 }
 
 fn main() -> Result<()> {
 	let args = Args::parse();
-
-	// Find relay config
 	let cwd = env::current_dir()?;
-	let relay_config = find_relay_config(&cwd);
 
-	// Determine the generated directory
-	let generated_dir = if let Some(dir) = args.generated_dir.clone() {
+	if matches!(args.command, Some(Command::Init)) || args.fix_config {
+		return init(&cwd, args.yes, args.dry_run);
+	}
+	let list_only = args.list_projects;
+	let run_doctor = matches!(args.command, Some(Command::Doctor { .. }));
+	let options = resolve_options(&args, &cwd)?;
+
+	// Find relay config(s)
+	let relay_configs: Vec<RelayConfig> = if let Some(ref root) = options.workspace {
+		let configs = find_workspace_configs(root)?;
+		if configs.is_empty() {
+			bail!("No relay configs found below {}", root.display());
+		}
+		configs
+	} else {
+		find_relay_config(&cwd)?.into_iter().collect()
+	};
+
+	if list_only {
+		list_projects(&relay_configs, &cwd);
+		return Ok(());
+	}
+
+	// Process relay projects when asked to, or when no single directory can be derived
+	let project_mode = options.generated_dir.is_none()
+		&& (options.workspace.is_some()
+			|| !options.projects.is_empty()
+			|| relay_configs.iter().any(|c| c.is_multi_project()));
+
+	if run_doctor {
+		return doctor(&options, &relay_configs, project_mode, &cwd);
+	}
+
+	let targets = if project_mode {
+		project_targets(&options, &relay_configs, &cwd)?
+	} else {
+		vec![single_target(&options, relay_configs.first())?]
+	};
+
+	// Check the relay config's feature flags (unless skipped). Relay's own
+	// dedup output is inlined, so these are recommendations.
+	if !options.skip_config_check {
+		if project_mode {
+			for (config, project) in selected_projects(&options, &relay_configs) {
+				if let Err(e) = validate_project(config, project) {
+					eprintln!("Warning: {}", e.to_string().trim_start());
+				}
+			}
+		} else if let Some(config) = relay_configs.first() {
+			if let Err(e) = validate_relay_config(&config.config_path) {
+				eprintln!("Warning: {}", e.to_string().trim_start());
+			}
+		} else {
+			eprintln!(
+				"Warning: No relay config found (relay.config.json/.js/.cjs/.mjs or package.json with 'relay' key).\n\
+				 Make sure Relay's built-in deduplication is disabled:\n\
+				 featureFlags.disable_deduping_common_structures_in_artifacts = {{ \"kind\": \"enabled\" }}\n"
+			);
+		}
+	}
+
+	// Verify directories exist
+	for target in &targets {
+		if !target.generated_dir.exists() {
+			bail!(
+				"Generated directory does not exist: {}\n\
+				 Run relay-compiler first to generate artifacts.",
+				target.generated_dir.display()
+			);
+		}
+	}
+
+	let start_time = Instant::now();
+	let mut diagnostics = Diagnostics::default();
+	let migrate_from = match args.command {
+		Some(Command::Migrate { ref from, .. }) => Some(from.as_deref()),
+		_ => None,
+	};
+	if let [target] = targets.as_slice() {
+		let result = run_target(&options, target, migrate_from, &mut diagnostics);
+		report_diagnostics(&options, &diagnostics, &cwd, result.is_ok())?;
+		return result.map(|_| ());
+	}
+
+	// Every project runs, even after one fails, so the summary covers them all
+	let results: Vec<Result<Stats>> = targets
+		.iter()
+		.map(|target| run_target(&options, target, migrate_from, &mut diagnostics))
+		.collect();
+	let failed = results.iter().filter(|r| r.is_err()).count();
+	for (target, result) in targets.iter().zip(&results) {
+		if let Err(e) = result {
+			eprintln!(
+				"Error: {}: {:#}",
+				target.label.as_deref().unwrap_or_default(),
+				e
+			);
+		}
+	}
+	report_diagnostics(&options, &diagnostics, &cwd, failed == 0)?;
+
+	print_project_summary(&targets, &results);
+	println!(
+		"Processed {} projects in {:.2}s",
+		targets.len(),
+		start_time.elapsed().as_secs_f64()
+	);
+	if failed > 0 {
+		bail!("{} of {} projects failed", failed, targets.len());
+	}
+	Ok(())
+}
+
+/// Print one row per project: what was extracted, or why it failed
+fn print_project_summary(targets: &[Target], results: &[Result<Stats>]) {
+	let labels: Vec<&str> = targets
+		.iter()
+		.map(|t| t.label.as_deref().unwrap_or_default())
+		.collect();
+	let width = labels.iter().map(|l| l.len()).max().unwrap_or(0).max(7);
+
+	println!("\n{:<width$}  Result", "Project");
+	for (label, result) in labels.iter().zip(results) {
+		let outcome = match result {
+			Ok(stats) => format!(
+				"ok: {} extracted, saved {} ({:.1}%)",
+				stats.total_extracted + stats.hoisted,
+				relay_dedup::format_bytes_signed(stats.raw_savings()),
+				stats.raw_savings_percent()
+			),
+			Err(e) => {
+				let message = e.to_string();
+				format!("FAILED: {}", message.lines().next().unwrap_or_default())
+			}
+		};
+		println!("{:<width$}  {}", label, outcome);
+	}
+}
+
+/// Print the warnings of a successful run (a failed run's error lists its
+/// diagnostics) and write the SARIF log if one was asked for
+fn report_diagnostics(
+	options: &Options,
+	diagnostics: &Diagnostics,
+	cwd: &Path,
+	succeeded: bool,
+) -> Result<()> {
+	if succeeded && !diagnostics.is_empty() {
+		eprintln!("{}", diagnostics.render(cwd));
+	}
+	if let Some(ref path) = options.sarif {
+		let log = serde_json::to_string_pretty(&diagnostics.to_sarif(cwd))?;
+		fs::write(path, log + "\n")
+			.with_context(|| format!("Failed to write SARIF log {}", path.display()))?;
+	}
+	Ok(())
+}
+
+/// Patch the detected relay config (and package.json) after showing the diff
+fn init(cwd: &Path, yes: bool, dry_run: bool) -> Result<()> {
+	let Some(relay_config) = find_relay_config(cwd)? else {
+		bail!(
+			"No relay config found (relay.config.json/.js/.cjs/.mjs or package.json with 'relay' key).\n\
+			 Create one first, e.g. with relay-compiler's own setup."
+		);
+	};
+
+	let patches = plan_config_fixes(&relay_config)?;
+	if patches.is_empty() {
+		println!(
+			"{} is already set up for relay-dedup.",
+			display_path(&relay_config.config_path, cwd)
+		);
+		return Ok(());
+	}
+
+	for patch in &patches {
+		print!("{}", patch.diff());
+	}
+	if dry_run || !(yes || confirm("Apply these changes?")?) {
+		println!("No files changed.");
+		return Ok(());
+	}
+
+	for patch in &patches {
+		patch.apply()?;
+		println!("Updated {}", display_path(&patch.path, cwd));
+	}
+	Ok(())
+}
+
+/// Report version, flag and artifact problems without changing anything
+fn doctor(
+	options: &Options,
+	relay_configs: &[RelayConfig],
+	project_mode: bool,
+	cwd: &Path,
+) -> Result<()> {
+	let mut report = Report::default();
+
+	let start_dir = relay_configs
+		.first()
+		.and_then(|c| c.config_path.parent())
+		.unwrap_or(cwd);
+	let compiler = doctor::check_versions(start_dir, &mut report);
+
+	if relay_configs.is_empty() {
+		report.push(
+			Status::Warning,
+			"No relay config found; feature flags can't be checked",
+		);
+	}
+	let projects: Vec<(&RelayConfig, &RelayProject)> = if project_mode {
+		selected_projects(options, relay_configs).collect()
+	} else {
+		relay_configs
+			.first()
+			.map(|c| c.projects.iter().map(|p| (c, p)).collect())
+			.unwrap_or_default()
+	};
+	for (config, project) in projects {
+		doctor::check_project_flags(config, project, compiler, &mut report);
+	}
+
+	let targets = if project_mode {
+		project_targets(options, relay_configs, cwd)
+	} else {
+		single_target(options, relay_configs.first()).map(|t| vec![t])
+	};
+	match targets {
+		Ok(targets) => {
+			for target in targets {
+				let label = target
+					.label
+					.clone()
+					.unwrap_or_else(|| display_path(&target.generated_dir, cwd));
+				let language = options.language.or(target.language).unwrap_or_default();
+				match collect_artifacts(&target.generated_dir, target.recursive, language) {
+					Ok(paths) => doctor::check_artifacts(&label, &paths, &mut report),
+					Err(e) => report.push(Status::Error, format!("{}: {}", label, e)),
+				}
+			}
+		}
+		Err(e) => report.push(Status::Error, e.to_string()),
+	}
+
+	for finding in &report.findings {
+		println!("[{}] {}", finding.status, finding.message);
+	}
+
+	// Unset, the suggested rules apply already
+	if let (Some(suggested), Some(current)) = (
+		&report.suggested_order_insensitive,
+		&options.order_insensitive,
+	) {
+		if suggested.iter().collect::<HashSet<_>>() != current.iter().collect() {
+			println!(
+				"\nSuggested for relay-compiler {}: --order-insensitive {}",
+				compiler.map(|v| v.to_string()).unwrap_or_default(),
+				suggested.join(",")
+			);
+		}
+	}
+
+	let errors = report.count(Status::Error);
+	if errors > 0 {
+		bail!("doctor found {} problem(s)", errors);
+	}
+	println!("\nNo problems found.");
+	Ok(())
+}
+
+/// Ask a yes/no question on stdin (default: no)
+fn confirm(question: &str) -> Result<bool> {
+	print!("{} [y/N] ", question);
+	io::stdout().flush()?;
+	let mut answer = String::new();
+	io::stdin().read_line(&mut answer)?;
+	Ok(matches!(
+		answer.trim().to_ascii_lowercase().as_str(),
+		"y" | "yes"
+	))
+}
+
+/// Merge defaults, the options file, `RELAY_DEDUP_*` variables and the command line
+fn resolve_options(args: &Args, cwd: &Path) -> Result<Options> {
+	let options_file = match args.config {
+		Some(ref path) => Some(path.clone()),
+		None => find_options_file(cwd),
+	};
+
+	let mut layers = Vec::new();
+	if let Some(ref path) = options_file {
+		layers.push(load_options_file(path)?);
+	}
+	layers.push(OptionLayer::from_env(env::vars(), cwd)?);
+	let cli = args.option_layer()?;
+
+	// A directory on the command line replaces any configured workspace, and vice versa
+	let cli_generated_dir = cli.generated_dir.is_some();
+	let cli_workspace = cli.workspace.is_some();
+	layers.push(cli);
+
+	let mut options = Options::default();
+	for layer in layers {
+		layer.apply_to(&mut options);
+	}
+	if cli_generated_dir {
+		options.workspace = None;
+	} else if cli_workspace {
+		options.generated_dir = None;
+	}
+
+	if options.verbose {
+		if let Some(ref path) = options_file {
+			println!("Using options from {}", display_path(path, cwd));
+		}
+	}
+	Ok(options)
+}
+
+/// Determine the directory to process for a plain (non-project) run
+fn single_target(options: &Options, relay_config: Option<&RelayConfig>) -> Result<Target> {
+	let generated_dir = if let Some(dir) = options.generated_dir.clone() {
 		// CLI arg provided - use it
 		dir
-	} else if let Some(ref config) = relay_config {
+	} else if options.recursive {
+		// Recursive mode walks the relay config's source root
+		relay_config
+			.and_then(|config| config.src.clone())
+			.ok_or_else(|| {
+				anyhow::anyhow!(
+					"No source root specified and no relay config with a src key found.\n\
+					 Usage: relay-dedup --recursive ./src"
+				)
+			})?
+	} else if let Some(config) = relay_config {
 		// No CLI arg - try to get from relay config
 		config.artifact_directory.clone().ok_or_else(|| {
 			anyhow::anyhow!(
@@ -89,63 +703,255 @@ fn main() -> Result<()> {
 		);
 	};
 
-	// Validate relay config (unless skipped)
-	if !args.skip_config_check {
-		if let Some(ref config) = relay_config {
-			validate_relay_config(&config.config_path)?;
-		} else {
-			eprintln!(
-				"Warning: No relay config found (relay.config.json or package.json with 'relay' key).\n\
-				 Make sure Relay's built-in deduplication is disabled:\n\
-				 featureFlags.disable_deduping_common_structures_in_artifacts = {{ \"kind\": \"enabled\" }}\n"
+	Ok(Target {
+		label: None,
+		generated_dir,
+		recursive: options.recursive,
+		language: relay_config.and_then(|c| c.language),
+		eager_es_modules: relay_config.map(|c| c.eager_es_modules.unwrap_or(false)),
+	})
+}
+
+/// Iterate the projects selected by `--project` (all projects if none given)
+fn selected_projects<'a>(
+	options: &'a Options,
+	relay_configs: &'a [RelayConfig],
+) -> impl Iterator<Item = (&'a RelayConfig, &'a RelayProject)> {
+	relay_configs.iter().flat_map(move |config| {
+		config
+			.projects
+			.iter()
+			.filter(move |p| options.projects.is_empty() || options.projects.contains(&p.name))
+			.map(move |p| (config, p))
+	})
+}
+
+/// Build one target per selected project.
+///
+/// Projects with an `output` directory are processed flat; projects with
+/// colocated artifacts are processed recursively per source root.
+fn project_targets(
+	options: &Options,
+	relay_configs: &[RelayConfig],
+	cwd: &Path,
+) -> Result<Vec<Target>> {
+	if relay_configs.is_empty() {
+		bail!("No relay config found.");
+	}
+	for name in &options.projects {
+		if !relay_configs
+			.iter()
+			.any(|c| c.projects.iter().any(|p| &p.name == name))
+		{
+			bail!(
+				"Unknown relay project '{}'. Use --list-projects to see available projects.",
+				name
 			);
 		}
 	}
 
-	// Verify directory exists
-	if !generated_dir.exists() {
-		bail!(
-			"Generated directory does not exist: {}\n\
-			 Run relay-compiler first to generate artifacts.",
-			generated_dir.display()
+	let mut targets: Vec<Target> = Vec::new();
+	for (config, project) in selected_projects(options, relay_configs) {
+		let label = format!(
+			"{} ({})",
+			project.name,
+			display_path(&config.config_path, cwd)
 		);
+		let mut push = |generated_dir: &PathBuf, recursive: bool| {
+			// Projects sharing a source root (or output directory) are one target
+			let existing = targets.iter_mut().find(|t| {
+				t.generated_dir == *generated_dir
+					&& t.recursive == recursive
+					&& t.language == project.language
+			});
+			match existing {
+				Some(target) => {
+					let merged =
+						format!("{}, {}", target.label.as_deref().unwrap_or_default(), label);
+					target.label = Some(merged);
+				}
+				None => targets.push(Target {
+					label: Some(label.clone()),
+					generated_dir: generated_dir.clone(),
+					recursive,
+					language: project.language,
+					eager_es_modules: Some(project.eager_es_modules.unwrap_or(false)),
+				}),
+			}
+		};
+		if let Some(ref output) = project.output {
+			push(output, false);
+		} else if !project.sources.is_empty() {
+			for source in &project.sources {
+				push(source, true);
+			}
+		} else {
+			bail!(
+				"Relay project {} has neither an output directory nor sources.",
+				label
+			);
+		}
 	}
 
-	// Parse order-insensitive fields
-	let order_insensitive_fields: HashSet<String> = args
-		.order_insensitive
-		.split(',')
-		.map(|s| s.trim().to_string())
-		.filter(|s| !s.is_empty())
-		.collect();
+	// One shared module path for every project would have each overwrite the last
+	if targets.len() > 1 {
+		if let Some(ref shared_module) = options.shared_module {
+			bail!(
+				"--shared-module {} can't be used with {} projects: each project writes its own shared module.\n\
+				 Select one with --project, or drop --shared-module to keep each next to its artifacts.",
+				shared_module.display(),
+				targets.len()
+			);
+		}
+	}
+	Ok(targets)
+}
+
+/// Print every project of the given configs
+fn list_projects(relay_configs: &[RelayConfig], cwd: &Path) {
+	if relay_configs.is_empty() {
+		println!("No relay config found.");
+		return;
+	}
+	for config in relay_configs {
+		println!("{}", display_path(&config.config_path, cwd));
+		for project in &config.projects {
+			let location = match project.output {
+				Some(ref output) => format!("output {}", display_path(output, cwd)),
+				None => {
+					let sources: Vec<_> = project
+						.sources
+						.iter()
+						.map(|s| display_path(s, cwd))
+						.collect();
+					format!("colocated in {}", sources.join(", "))
+				}
+			};
+			let language = project
+				.language
+				.map(|l| l.to_string())
+				.unwrap_or_else(|| "default language".to_string());
+			let flags = if dedup_disabled(project) {
+				"flags ok"
+			} else {
+				"built-in dedup NOT disabled"
+			};
+			println!(
+				"  {:<20} {} [{}, {}]",
+				project.name, location, language, flags
+			);
+		}
+	}
+}
+
+fn display_path(path: &Path, cwd: &Path) -> String {
+	path.strip_prefix(cwd).unwrap_or(path).display().to_string()
+}
+
+/// Deduplicate one target, print its summary and return its statistics. `migrate_from` is set when
+/// migrating (`Some(None)`: the legacy module is at the shared module path).
+fn run_target(
+	options: &Options,
+	target: &Target,
+	migrate_from: Option<Option<&Path>>,
+	diagnostics: &mut Diagnostics,
+) -> Result<Stats> {
+	// The installed relay-compiler's rules, unless given
+	let compiler = doctor::installed_version(&target.generated_dir, "relay-compiler");
+	let mut order_rules = OrderRules::for_relay(compiler.map(|(_, version)| version));
+	if let Some(ref path) = options.order_rules {
+		order_rules = OrderRules::load(path, order_rules)?;
+	}
+	if let Some(ref rules) = options.order_insensitive {
+		order_rules.rules = OrderRules::parse(rules)
+			.context("Invalid --order-insensitive")?
+			.rules;
+	}
 
 	// Compute gzip if we need to display it (verbose always shows gzip, or explicit --show-gzip)
-	let compute_gzip = args.verbose || args.show_gzip;
+	let compute_gzip = options.verbose || options.show_gzip;
 
-	let config = Config {
-		generated_dir,
-		shared_module_name: args.output,
-		min_occurrences: args.min_occurrences,
-		order_insensitive_fields,
-		dry_run: args.dry_run,
-		verbose: args.verbose,
-		max_passes: args.max_passes,
+	// CLI language wins over the relay config's
+	let language = options.language.or(target.language).unwrap_or_default();
+	let shared_module_name = options
+		.output
+		.clone()
+		.unwrap_or_else(|| language.default_shared_module_name().to_string());
+
+	// Follow Relay's own module format unless overridden
+	let module_format = options.module_format.unwrap_or_else(|| {
+		target
+			.eager_es_modules
+			.map(|eager| ModuleFormat::for_relay(language, eager))
+			.unwrap_or_default()
+	});
+
+	let mut config = Config {
+		generated_dir: target.generated_dir.clone(),
+		shared_module_name,
+		shared_module_path: options.shared_module.clone(),
+		recursive: target.recursive,
+		language,
+		module_format,
+		include_globs: options.include.clone(),
+		exclude_globs: options.exclude.clone(),
+		protected_paths: options.protect.clone(),
+		identity_sensitive_kinds: options.protect_kinds.clone(),
+		extraction_model: options.extraction_model,
+		min_occurrences: options.min_occurrences,
+		gzip_weight: options.gzip_weight,
+		near_duplicates: options.near_duplicates,
+		order_rules,
+		dry_run: options.dry_run,
+		verbose: options.verbose,
+		max_passes: options.max_passes,
 		compute_gzip,
+		strict: options.strict,
+		compact_shared: options.compact_shared,
+		legacy_shared_module: None,
 	};
+	if let Some(from) = migrate_from {
+		let legacy = from.map_or_else(|| config.shared_module_path(), Path::to_path_buf);
+		config.legacy_shared_module = Some(legacy);
+	}
+
+	if options.verbose {
+		if let Some(ref label) = target.label {
+			println!("\n=== {} ===", label);
+		}
+	}
 
 	let start_time = Instant::now();
 	let mut deduplicator = Deduplicator::new(config);
-	let stats = deduplicator.run()?;
+	let result = deduplicator.run();
+	diagnostics.extend(std::mem::take(&mut deduplicator.diagnostics));
+	let stats = result?;
 	let total_time = start_time.elapsed();
 
 	let time_str = format!("{:.2}s", total_time.as_secs_f64());
+	let prefix = target
+		.label
+		.as_ref()
+		.map(|l| format!("{}: ", l))
+		.unwrap_or_default();
 
 	// Always print summary (even if not verbose)
-	if !args.verbose {
-		if args.show_gzip {
+	if !options.verbose {
+		let mut extracted = match stats.hoisted {
+			0 => format!("Extracted {} structures", stats.total_extracted),
+			hoisted => format!(
+				"Extracted {} shared and {} file-local structures",
+				stats.total_extracted, hoisted
+			),
+		};
+		if stats.factored > 0 {
+			extracted.push_str(&format!(" ({} near-duplicates as spreads)", stats.factored));
+		}
+		if options.show_gzip {
 			println!(
-				"Extracted {} structures, saved {} raw ({:.1}%), {} gzipped ({:.1}%) in {}",
-				stats.total_extracted,
+				"{}{}, saved {} raw ({:.1}%), {} gzipped ({:.1}%) in {}",
+				prefix,
+				extracted,
 				relay_dedup::format_bytes_signed(stats.raw_savings()),
 				stats.raw_savings_percent(),
 				relay_dedup::format_bytes_signed(stats.gzipped_savings()),
@@ -154,20 +960,29 @@ fn main() -> Result<()> {
 			);
 		} else {
 			println!(
-				"Extracted {} structures, saved {} raw ({:.1}%) in {}",
-				stats.total_extracted,
+				"{}{}, saved {} raw ({:.1}%) in {}",
+				prefix,
+				extracted,
 				relay_dedup::format_bytes_signed(stats.raw_savings()),
 				stats.raw_savings_percent(),
 				time_str
 			);
 		}
+		if !stats.suppressed.is_empty() {
+			let rules: Vec<String> = stats
+				.suppressed
+				.iter()
+				.map(|(rule, count)| format!("{} ({})", rule, count))
+				.collect();
+			println!("{}Suppressed candidates: {}", prefix, rules.join(", "));
+		}
 	} else {
 		// Verbose mode prints its own detailed output, just add total time
 		println!("\nTotal time: {}", time_str);
 	}
 
 	// Print timing breakdown if requested
-	if args.show_timing {
+	if options.show_timing {
 		let t = &deduplicator.timing;
 		let total_io = t.file_read.as_secs_f64() + t.file_write.as_secs_f64();
 		let mut total_cpu = t.tree_parse.as_secs_f64()
@@ -212,5 +1027,5 @@ fn main() -> Result<()> {
 		eprintln!("  --- total CPU:  {:>7.1}ms", total_cpu * 1000.0);
 	}
 
-	Ok(())
+	Ok(stats)
 }
@@ -2,20 +2,57 @@
 //!
 //! Generates short, unique names in the format `x_XXX` where XXX is
 //! the minimum number of hex characters from the hash needed to be unique.
+//! Structures hoisted within a single artifact are named `l_XXX` instead.
+//! Names from a previous run can be locked in (see [`crate::lockfile`]).
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+
+/// Prefix of the names exported by the shared module
+pub const REF_PREFIX: &str = "x_";
+
+/// Prefix of the names declared inside one artifact
+pub const LOCAL_PREFIX: &str = "l_";
 
 /// Generator for unique short names.
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct NameGenerator {
+	prefix: &'static str,
 	used: HashSet<String>,
+	/// Names by hash, given back to the same hash only
+	locked: HashMap<String, String>,
+}
+
+impl Default for NameGenerator {
+	fn default() -> Self {
+		Self::new()
+	}
 }
 
 impl NameGenerator {
-	/// Create a new name generator.
+	/// Create a new name generator for shared refs.
 	pub fn new() -> Self {
+		Self::with_prefix(REF_PREFIX)
+	}
+
+	/// Create a new name generator for file-local names.
+	pub fn local() -> Self {
+		Self::with_prefix(LOCAL_PREFIX)
+	}
+
+	fn with_prefix(prefix: &'static str) -> Self {
 		Self {
+			prefix,
 			used: HashSet::new(),
+			locked: HashMap::new(),
+		}
+	}
+
+	/// Reserve names given out before, by hash: each goes to its hash
+	/// again and never to another one
+	pub fn lock(&mut self, names: impl IntoIterator<Item = (String, String)>) {
+		for (hash, name) in names {
+			self.used.insert(name.clone());
+			self.locked.insert(hash, name);
 		}
 	}
 
@@ -23,9 +60,13 @@ impl NameGenerator {
 	///
 	/// Format: `x_XXX` where XXX is at least 3 hex chars, extended on collision.
 	pub fn next(&mut self, hash: &str) -> String {
+		if let Some(name) = self.locked.get(hash) {
+			return name.clone();
+		}
+
 		// Start with 3 chars, extend if collision
 		for len in 3..=hash.len() {
-			let name = format!("x_{}", &hash[..len]);
+			let name = format!("{}{}", self.prefix, &hash[..len]);
 			if !self.used.contains(&name) {
 				self.used.insert(name.clone());
 				return name;
@@ -33,12 +74,28 @@ impl NameGenerator {
 		}
 
 		// Fallback: use full hash (shouldn't happen)
-		let name = format!("x_{}", hash);
+		let name = format!("{}{}", self.prefix, hash);
 		self.used.insert(name.clone());
 		name
 	}
 }
 
+/// Whether an identifier is one of our refs (`x_` followed by hex digits)
+pub fn is_ref_name(ident: &str) -> bool {
+	has_hex_suffix(ident, REF_PREFIX)
+}
+
+/// Whether an identifier is a file-local name (`l_` followed by hex digits)
+pub fn is_local_name(ident: &str) -> bool {
+	has_hex_suffix(ident, LOCAL_PREFIX)
+}
+
+fn has_hex_suffix(ident: &str, prefix: &str) -> bool {
+	ident.len() >= prefix.len() + 2
+		&& ident.starts_with(prefix)
+		&& ident[prefix.len()..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -74,4 +131,23 @@ mod tests {
 		let name = gen.next("abcdf789");
 		assert_eq!(name, "x_abcdf");
 	}
+
+	#[test]
+	fn test_locked_names() {
+		let mut gen = NameGenerator::new();
+		gen.lock([("abce5678".to_string(), "x_abc".to_string())]);
+
+		// The locked name goes to its hash only, whatever comes first
+		assert_eq!(gen.next("abcd1234"), "x_abcd");
+		assert_eq!(gen.next("abce5678"), "x_abc");
+	}
+
+	#[test]
+	fn test_local_names() {
+		let mut gen = NameGenerator::local();
+		assert_eq!(gen.next("abcd1234"), "l_abc");
+		assert!(is_local_name("l_abc"));
+		assert!(!is_ref_name("l_abc"));
+		assert!(!is_local_name("x_abc"));
+	}
 }
@@ -1,157 +1,91 @@
 //! Normalization module for consistent structure comparison.
 //!
-//! Handles whitespace stripping and array element sorting for order-insensitive fields.
+//! Handles whitespace stripping, object key ordering and array element
+//! sorting for order-insensitive fields.
 
-/// Strip all non-essential whitespace from content (outside string literals).
+use crate::tree::comment_end;
+use crate::value::Value;
+
+/// Strip all non-essential whitespace and comments from content (outside string literals).
+///
+/// Comments only carry annotations (e.g. Flow's `/*: any*/`), so they never
+/// affect whether two structures match.
 fn strip_whitespace(content: &str) -> String {
 	let mut result = String::with_capacity(content.len());
 	let mut in_string = false;
 	let mut escape = false;
 
-	for c in content.chars() {
+	let mut rest = content;
+	while let Some(c) = rest.chars().next() {
+		let len = c.len_utf8();
 		if escape {
 			result.push(c);
 			escape = false;
-			continue;
-		}
-		if c == '\\' {
+		} else if c == '\\' {
 			result.push(c);
 			escape = true;
-			continue;
-		}
-		if c == '"' {
+		} else if c == '"' {
 			result.push(c);
 			in_string = !in_string;
-			continue;
-		}
-		if in_string {
+		} else if in_string {
 			result.push(c);
+		} else if let Some(end) = comment_end(rest.as_bytes(), 0) {
+			// Skip comments outside strings
+			rest = &rest[end..];
 			continue;
+		} else if !c.is_whitespace() {
+			// Skip whitespace outside strings
+			result.push(c);
 		}
-		// Skip whitespace outside strings
-		if c.is_whitespace() {
-			continue;
-		}
-		result.push(c);
+		rest = &rest[len..];
 	}
 
 	result
 }
 
+/// A structure normalized for comparison
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Normalized {
+	/// What structures are matched by: literals in their canonical spelling
+	/// (see [`crate::literal`])
+	pub key: String,
+	/// The same structure with its literals as written, in the same order
+	pub text: String,
+}
+
 /// Normalize content for comparison.
 ///
 /// - Strips whitespace
+/// - Sorts object keys at every depth (see [`Value::sort_keys`])
 /// - For arrays in order-insensitive fields: sorts elements
-/// - For objects: tries to sort keys (if valid JSON), else just strips whitespace
-pub fn normalize(content: &str, can_sort_array: bool) -> String {
+///
+/// Content that isn't a literal is only stripped.
+pub fn normalize(content: &str, can_sort_array: bool) -> Normalized {
 	let stripped = strip_whitespace(content);
-
-	if stripped.starts_with('[') && can_sort_array {
-		normalize_array(&stripped)
-	} else if stripped.starts_with('{') {
-		// Try to sort object keys (like TS does via JSON.parse)
-		normalize_object(&stripped)
-	} else {
-		stripped
+	let Some(mut value) = Value::parse(&stripped) else {
+		return Normalized {
+			key: stripped.clone(),
+			text: stripped,
+		};
+	};
+	value.sort_keys();
+	if let (Value::Array(elements), true) = (&mut value, can_sort_array) {
+		elements.sort_by_cached_key(Value::canonical);
 	}
-}
-
-/// Normalize an object by sorting its keys.
-/// Matches TS behavior: try JSON.parse, if it fails (due to x_ refs, v0/v1 refs,
-/// or any non-JSON identifier), just return the stripped content as-is.
-fn normalize_object(content: &str) -> String {
-	// Try to parse as JSON and sort keys - exactly like TS does
-	// If parsing fails for any reason (refs like x_abc, v0, v1, etc.), return as-is
-	match serde_json::from_str::<serde_json::Value>(content) {
-		Ok(serde_json::Value::Object(map)) => {
-			let mut keys: Vec<_> = map.keys().collect();
-			keys.sort();
-			let pairs: Vec<String> = keys
-				.iter()
-				.map(|k| format!("\"{}\":{}", k, map.get(*k).unwrap()))
-				.collect();
-			format!("{{{}}}", pairs.join(","))
-		}
-		_ => content.to_string(),
-	}
-}
-
-/// Normalize an array by sorting its elements.
-fn normalize_array(content: &str) -> String {
-	let inner = &content[1..content.len() - 1];
-	if inner.is_empty() {
-		return "[]".to_string();
+	Normalized {
+		key: value.canonical(),
+		text: value.to_string(),
 	}
-
-	// Split carefully (not inside nested structures)
-	let mut elements = split_array_elements(inner);
-	elements.sort();
-	format!("[{}]", elements.join(","))
-}
-
-/// Split array elements, respecting nested structures.
-fn split_array_elements(inner: &str) -> Vec<String> {
-	let mut elements = Vec::new();
-	let mut depth = 0;
-	let mut current = String::new();
-	let mut in_string = false;
-	let mut escape = false;
-
-	for c in inner.chars() {
-		if escape {
-			current.push(c);
-			escape = false;
-			continue;
-		}
-		if c == '\\' {
-			current.push(c);
-			escape = true;
-			continue;
-		}
-		if c == '"' {
-			current.push(c);
-			in_string = !in_string;
-			continue;
-		}
-		if in_string {
-			current.push(c);
-			continue;
-		}
-
-		match c {
-			'{' | '[' => {
-				depth += 1;
-				current.push(c);
-			}
-			'}' | ']' => {
-				depth -= 1;
-				current.push(c);
-			}
-			',' if depth == 0 => {
-				let trimmed = current.trim().to_string();
-				if !trimmed.is_empty() {
-					elements.push(trimmed);
-				}
-				current.clear();
-			}
-			_ => {
-				current.push(c);
-			}
-		}
-	}
-
-	let trimmed = current.trim().to_string();
-	if !trimmed.is_empty() {
-		elements.push(trimmed);
-	}
-
-	elements
 }
 
 #[cfg(test)]
 mod tests {
 	use super::*;
 
+	fn text(content: &str, can_sort_array: bool) -> String {
+		normalize(content, can_sort_array).text
+	}
+
 	#[test]
 	fn test_strip_whitespace() {
 		assert_eq!(strip_whitespace("{ }"), "{}");
@@ -162,48 +96,73 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_strip_comments() {
+		assert_eq!(
+			strip_whitespace(r#"{ "a": (v0/*: any*/), "b": "/* kept */" }"#),
+			r#"{"a":(v0),"b":"/* kept */"}"#
+		);
+		assert_eq!(strip_whitespace("[1, // trailing\n 2]"), "[1,2]");
+	}
+
 	#[test]
 	fn test_normalize_array_no_sort() {
-		assert_eq!(normalize("[3, 1, 2]", false), "[3,1,2]");
+		assert_eq!(text("[3, 1, 2]", false), "[3,1,2]");
 	}
 
 	#[test]
 	fn test_normalize_array_with_sort() {
-		assert_eq!(normalize("[3, 1, 2]", true), "[1,2,3]");
-		assert_eq!(normalize(r#"["c", "a", "b"]"#, true), r#"["a","b","c"]"#);
+		assert_eq!(text("[3, 1, 2]", true), "[1,2,3]");
+		assert_eq!(text(r#"["c", "a", "b"]"#, true), r#"["a","b","c"]"#);
 	}
 
 	#[test]
 	fn test_normalize_object_sorts_keys() {
 		// Objects ARE sorted (keys sorted alphabetically) - matches TS JSON.parse behavior
-		assert_eq!(normalize(r#"{"z": 1, "a": 2}"#, false), r#"{"a":2,"z":1}"#);
+		assert_eq!(text(r#"{"z": 1, "a": 2}"#, false), r#"{"a":2,"z":1}"#);
 	}
 
 	#[test]
-	fn test_normalize_object_with_refs_no_sort() {
-		// Objects with refs (x_abc, v0, v1) can't be parsed as JSON, so just strip whitespace
+	fn test_normalize_object_with_refs() {
+		// Refs (x_abc) and atoms are leaves of their own: keys are sorted at
+		// every depth, so these match what they'd be written in other orders
 		assert_eq!(
-			normalize(r#"{"z": x_abc, "a": 2}"#, false),
-			r#"{"z":x_abc,"a":2}"#
+			text(r#"{"z": x_abc, "a": 2}"#, false),
+			r#"{"a":2,"z":x_abc}"#
 		);
 		assert_eq!(
-			normalize(r#"{"items": [v0, v1]}"#, false),
-			r#"{"items":[v0,v1]}"#
+			text(r#"{"b": 2, "a": x_1}"#, false),
+			text(r#"{"a": x_1, "b": 2}"#, false)
+		);
+		assert_eq!(
+			text(r#"[{"s": [x_2, x_1], "k": m_1a2b3c4d}, x_3]"#, true),
+			r#"[x_3,{"k":m_1a2b3c4d,"s":[x_2,x_1]}]"#
 		);
 	}
 
+	#[test]
+	fn test_normalize_matches_equivalent_literals() {
+		let a = normalize(r#"{"b": 1.0, "a": "\u0041\/"}"#, false);
+		let b = normalize(r#"{"a": "A/", "b": 1e0}"#, false);
+		assert_eq!(a.key, b.key);
+		assert_eq!(a.key, r#"{"a":"A/","b":1}"#);
+		// Literals are kept as written, in the canonical order
+		assert_eq!(a.text, r#"{"a":"\u0041\/","b":1.0}"#);
+
+		let a = normalize(r#"["\u0062", "a", 0x10]"#, true);
+		let b = normalize(r#"[16, "b", "a"]"#, true);
+		assert_eq!(a.key, b.key);
+		assert_eq!(a.text, r#"["a","\u0062",0x10]"#);
+	}
+
 	#[test]
 	fn test_normalize_empty() {
-		assert_eq!(normalize("[]", false), "[]");
-		assert_eq!(normalize("{}", false), "{}");
+		assert_eq!(text("[]", false), "[]");
+		assert_eq!(text("{}", false), "{}");
 	}
 
 	#[test]
-	fn test_split_array_elements() {
-		assert_eq!(split_array_elements("1, 2, 3"), vec!["1", "2", "3"]);
-		assert_eq!(
-			split_array_elements(r#"{"a": 1}, {"b": 2}"#),
-			vec![r#"{"a": 1}"#, r#"{"b": 2}"#]
-		);
+	fn test_normalize_not_a_literal() {
+		assert_eq!(text("[1, {", false), "[1,{");
 	}
 }
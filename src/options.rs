@@ -0,0 +1,620 @@
+//! Layered CLI options.
+//!
+//! Options can come from (highest precedence first) the command line,
+//! `RELAY_DEDUP_*` environment variables, a project config file
+//! (`relay-dedup.config.json` or a `"relayDedup"` key in `package.json`) and
+//! built-in defaults. Each source is read into an [`OptionLayer`] and the
+//! layers are applied in order onto [`Options::default`].
+
+use crate::cost::ExtractionModel;
+use crate::hazard::IDENTITY_SENSITIVE_KINDS;
+use crate::{Language, ModuleFormat};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the standalone options file
+pub const OPTIONS_FILE_NAME: &str = "relay-dedup.config.json";
+
+/// Key holding the options inside `package.json`
+pub const PACKAGE_JSON_KEY: &str = "relayDedup";
+
+/// Prefix of the environment variables that override options
+pub const ENV_PREFIX: &str = "RELAY_DEDUP_";
+
+/// Option keys as written in config files (camelCase). The matching
+/// environment variable is `RELAY_DEDUP_` plus the key in SCREAMING_SNAKE_CASE.
+pub const KNOWN_KEYS: &[&str] = &[
+	"generatedDir",
+	"output",
+	"language",
+	"moduleFormat",
+	"recursive",
+	"sharedModule",
+	"dryRun",
+	"verbose",
+	"extractionModel",
+	"minOccurrences",
+	"gzipWeight",
+	"nearDuplicates",
+	"orderInsensitive",
+	"orderRules",
+	"maxPasses",
+	"showGzip",
+	"showTiming",
+	"skipConfigCheck",
+	"strict",
+	"compactShared",
+	"sarif",
+	"include",
+	"exclude",
+	"protect",
+	"protectKinds",
+	"workspace",
+	"projects",
+];
+
+/// Fully resolved options
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+	pub generated_dir: Option<PathBuf>,
+	pub output: Option<String>,
+	pub language: Option<Language>,
+	pub module_format: Option<ModuleFormat>,
+	pub recursive: bool,
+	pub shared_module: Option<PathBuf>,
+	pub dry_run: bool,
+	pub verbose: bool,
+	pub extraction_model: ExtractionModel,
+	pub min_occurrences: usize,
+	pub gzip_weight: f64,
+	pub near_duplicates: bool,
+	pub order_insensitive: Option<Vec<String>>,
+	pub order_rules: Option<PathBuf>,
+	pub max_passes: usize,
+	pub show_gzip: bool,
+	pub show_timing: bool,
+	pub skip_config_check: bool,
+	pub strict: bool,
+	pub compact_shared: bool,
+	pub sarif: Option<PathBuf>,
+	pub include: Vec<String>,
+	pub exclude: Vec<String>,
+	pub protect: Vec<String>,
+	pub protect_kinds: Vec<String>,
+	pub workspace: Option<PathBuf>,
+	pub projects: Vec<String>,
+}
+
+impl Default for Options {
+	fn default() -> Self {
+		Self {
+			generated_dir: None,
+			output: None,
+			language: None,
+			module_format: None,
+			recursive: false,
+			shared_module: None,
+			dry_run: false,
+			verbose: false,
+			extraction_model: ExtractionModel::Cost,
+			min_occurrences: 2,
+			gzip_weight: 0.0,
+			near_duplicates: false,
+			order_insensitive: None,
+			order_rules: None,
+			max_passes: 50,
+			show_gzip: false,
+			show_timing: false,
+			skip_config_check: false,
+			strict: false,
+			compact_shared: false,
+			sarif: None,
+			include: Vec::new(),
+			exclude: Vec::new(),
+			protect: Vec::new(),
+			protect_kinds: IDENTITY_SENSITIVE_KINDS
+				.iter()
+				.map(|k| k.to_string())
+				.collect(),
+			workspace: None,
+			projects: Vec::new(),
+		}
+	}
+}
+
+/// A partial set of options from one source
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OptionLayer {
+	pub generated_dir: Option<PathBuf>,
+	pub output: Option<String>,
+	pub language: Option<Language>,
+	pub module_format: Option<ModuleFormat>,
+	pub recursive: Option<bool>,
+	pub shared_module: Option<PathBuf>,
+	pub dry_run: Option<bool>,
+	pub verbose: Option<bool>,
+	pub extraction_model: Option<ExtractionModel>,
+	pub min_occurrences: Option<usize>,
+	pub gzip_weight: Option<f64>,
+	pub near_duplicates: Option<bool>,
+	pub order_insensitive: Option<Vec<String>>,
+	pub order_rules: Option<PathBuf>,
+	pub max_passes: Option<usize>,
+	pub show_gzip: Option<bool>,
+	pub show_timing: Option<bool>,
+	pub skip_config_check: Option<bool>,
+	pub strict: Option<bool>,
+	pub compact_shared: Option<bool>,
+	pub sarif: Option<PathBuf>,
+	pub include: Option<Vec<String>>,
+	pub exclude: Option<Vec<String>>,
+	pub protect: Option<Vec<String>>,
+	pub protect_kinds: Option<Vec<String>>,
+	pub workspace: Option<PathBuf>,
+	pub projects: Option<Vec<String>>,
+}
+
+/// A raw option value before conversion
+enum Raw<'a> {
+	Json(&'a Value),
+	Env(&'a str),
+}
+
+impl OptionLayer {
+	/// Read options from a config object. Relative paths resolve against `base_dir`.
+	pub fn from_json(value: &Value, base_dir: &Path) -> Result<Self> {
+		let map = value
+			.as_object()
+			.ok_or_else(|| anyhow!("options must be a JSON object"))?;
+
+		let mut layer = Self::default();
+		for (key, value) in map {
+			layer.set(key, Raw::Json(value), base_dir)?;
+		}
+		Ok(layer)
+	}
+
+	/// Read options from `RELAY_DEDUP_*` environment variables.
+	///
+	/// Relative paths resolve against `base_dir`. Other variables are ignored.
+	pub fn from_env<I>(vars: I, base_dir: &Path) -> Result<Self>
+	where
+		I: IntoIterator<Item = (String, String)>,
+	{
+		let mut vars: Vec<_> = vars
+			.into_iter()
+			.filter(|(name, _)| name.starts_with(ENV_PREFIX))
+			.collect();
+		vars.sort();
+
+		let mut layer = Self::default();
+		for (name, value) in &vars {
+			let suffix = &name[ENV_PREFIX.len()..];
+			let key = KNOWN_KEYS
+				.iter()
+				.find(|k| env_suffix(k) == suffix)
+				.ok_or_else(|| {
+					anyhow!(
+						"Unknown environment variable {}.\nKnown variables: {}",
+						name,
+						KNOWN_KEYS
+							.iter()
+							.map(|k| format!("{}{}", ENV_PREFIX, env_suffix(k)))
+							.collect::<Vec<_>>()
+							.join(", ")
+					)
+				})?;
+			layer
+				.set(key, Raw::Env(value), base_dir)
+				.with_context(|| format!("Invalid value for {}", name))?;
+		}
+		Ok(layer)
+	}
+
+	fn set(&mut self, key: &str, raw: Raw, base_dir: &Path) -> Result<()> {
+		match key {
+			"generatedDir" => self.generated_dir = Some(base_dir.join(string(&raw, key)?)),
+			"output" => self.output = Some(string(&raw, key)?),
+			"language" => {
+				self.language = Some(string(&raw, key)?.parse().map_err(|e| anyhow!("{}", e))?)
+			}
+			"moduleFormat" => {
+				self.module_format = Some(string(&raw, key)?.parse().map_err(|e| anyhow!("{}", e))?)
+			}
+			"recursive" => self.recursive = Some(boolean(&raw, key)?),
+			"sharedModule" => self.shared_module = Some(base_dir.join(string(&raw, key)?)),
+			"dryRun" => self.dry_run = Some(boolean(&raw, key)?),
+			"verbose" => self.verbose = Some(boolean(&raw, key)?),
+			"extractionModel" => {
+				self.extraction_model =
+					Some(string(&raw, key)?.parse().map_err(|e| anyhow!("{}", e))?)
+			}
+			"minOccurrences" => self.min_occurrences = Some(number(&raw, key)?),
+			"gzipWeight" => self.gzip_weight = Some(fraction(&raw, key)?),
+			"nearDuplicates" => self.near_duplicates = Some(boolean(&raw, key)?),
+			"orderInsensitive" => self.order_insensitive = Some(list(&raw, key)?),
+			"orderRules" => self.order_rules = Some(base_dir.join(string(&raw, key)?)),
+			"maxPasses" => self.max_passes = Some(number(&raw, key)?),
+			"showGzip" => self.show_gzip = Some(boolean(&raw, key)?),
+			"showTiming" => self.show_timing = Some(boolean(&raw, key)?),
+			"skipConfigCheck" => self.skip_config_check = Some(boolean(&raw, key)?),
+			"strict" => self.strict = Some(boolean(&raw, key)?),
+			"compactShared" => self.compact_shared = Some(boolean(&raw, key)?),
+			"sarif" => self.sarif = Some(base_dir.join(string(&raw, key)?)),
+			"include" => self.include = Some(list(&raw, key)?),
+			"exclude" => self.exclude = Some(list(&raw, key)?),
+			"protect" => self.protect = Some(list(&raw, key)?),
+			"protectKinds" => self.protect_kinds = Some(list(&raw, key)?),
+			"workspace" => self.workspace = Some(base_dir.join(string(&raw, key)?)),
+			"projects" => self.projects = Some(list(&raw, key)?),
+			_ => bail!(
+				"Unknown option '{}'.\nKnown options: {}",
+				key,
+				KNOWN_KEYS.join(", ")
+			),
+		}
+		Ok(())
+	}
+
+	/// Apply every option set in this layer on top of `options`
+	pub fn apply_to(self, options: &mut Options) {
+		fn set<T>(target: &mut T, value: Option<T>) {
+			if let Some(value) = value {
+				*target = value;
+			}
+		}
+		set(&mut options.generated_dir, self.generated_dir.map(Some));
+		set(&mut options.output, self.output.map(Some));
+		set(&mut options.language, self.language.map(Some));
+		set(&mut options.module_format, self.module_format.map(Some));
+		set(&mut options.recursive, self.recursive);
+		set(&mut options.shared_module, self.shared_module.map(Some));
+		set(&mut options.dry_run, self.dry_run);
+		set(&mut options.verbose, self.verbose);
+		set(&mut options.extraction_model, self.extraction_model);
+		set(&mut options.min_occurrences, self.min_occurrences);
+		set(&mut options.gzip_weight, self.gzip_weight);
+		set(&mut options.near_duplicates, self.near_duplicates);
+		set(
+			&mut options.order_insensitive,
+			self.order_insensitive.map(Some),
+		);
+		set(&mut options.order_rules, self.order_rules.map(Some));
+		set(&mut options.max_passes, self.max_passes);
+		set(&mut options.show_gzip, self.show_gzip);
+		set(&mut options.show_timing, self.show_timing);
+		set(&mut options.skip_config_check, self.skip_config_check);
+		set(&mut options.strict, self.strict);
+		set(&mut options.compact_shared, self.compact_shared);
+		set(&mut options.sarif, self.sarif.map(Some));
+		set(&mut options.include, self.include);
+		set(&mut options.exclude, self.exclude);
+		set(&mut options.protect, self.protect);
+		set(&mut options.protect_kinds, self.protect_kinds);
+		set(&mut options.workspace, self.workspace.map(Some));
+		set(&mut options.projects, self.projects);
+	}
+}
+
+/// `minOccurrences` → `MIN_OCCURRENCES`
+fn env_suffix(key: &str) -> String {
+	let mut out = String::new();
+	for c in key.chars() {
+		if c.is_ascii_uppercase() {
+			out.push('_');
+		}
+		out.push(c.to_ascii_uppercase());
+	}
+	out
+}
+
+fn string(raw: &Raw, key: &str) -> Result<String> {
+	match raw {
+		Raw::Json(Value::String(s)) => Ok(s.clone()),
+		Raw::Env(s) => Ok(s.to_string()),
+		Raw::Json(_) => bail!("option '{}' must be a string", key),
+	}
+}
+
+fn boolean(raw: &Raw, key: &str) -> Result<bool> {
+	match raw {
+		Raw::Json(Value::Bool(b)) => Ok(*b),
+		Raw::Env(s) => match s.to_ascii_lowercase().as_str() {
+			"1" | "true" | "yes" => Ok(true),
+			"0" | "false" | "no" | "" => Ok(false),
+			_ => bail!("option '{}' must be true or false, got '{}'", key, s),
+		},
+		Raw::Json(_) => bail!("option '{}' must be a boolean", key),
+	}
+}
+
+fn number(raw: &Raw, key: &str) -> Result<usize> {
+	match raw {
+		Raw::Json(Value::Number(n)) => n
+			.as_u64()
+			.map(|n| n as usize)
+			.ok_or_else(|| anyhow!("option '{}' must be a non-negative integer", key)),
+		Raw::Env(s) => s.trim().parse().map_err(|_| {
+			anyhow!(
+				"option '{}' must be a non-negative integer, got '{}'",
+				key,
+				s
+			)
+		}),
+		Raw::Json(_) => bail!("option '{}' must be a number", key),
+	}
+}
+
+/// A number from 0 to 1
+fn fraction(raw: &Raw, key: &str) -> Result<f64> {
+	let value = match raw {
+		Raw::Json(Value::Number(n)) => n.as_f64(),
+		Raw::Env(s) => s.trim().parse().ok(),
+		Raw::Json(_) => bail!("option '{}' must be a number", key),
+	};
+	value
+		.ok_or_else(|| anyhow!("option '{}' must be a number from 0 to 1", key))
+		.and_then(|v| check_fraction(v, key))
+}
+
+/// Accept a finite `value` from 0 to 1. NaN would make every cost comparison
+/// false, so it is an error like any other out-of-range value.
+pub fn check_fraction(value: f64, key: &str) -> Result<f64> {
+	if value.is_finite() && (0.0..=1.0).contains(&value) {
+		Ok(value)
+	} else {
+		bail!(
+			"option '{}' must be a number from 0 to 1, got {}",
+			key,
+			value
+		)
+	}
+}
+
+/// Lists are JSON arrays of strings (or a comma-separated string in either source)
+fn list(raw: &Raw, key: &str) -> Result<Vec<String>> {
+	let split = |s: &str| {
+		s.split(',')
+			.map(|s| s.trim().to_string())
+			.filter(|s| !s.is_empty())
+			.collect()
+	};
+	match raw {
+		Raw::Json(Value::Array(items)) => items
+			.iter()
+			.map(|item| {
+				item.as_str()
+					.map(|s| s.to_string())
+					.ok_or_else(|| anyhow!("option '{}' must be a list of strings", key))
+			})
+			.collect(),
+		Raw::Json(Value::String(s)) => Ok(split(s)),
+		Raw::Env(s) => Ok(split(s)),
+		Raw::Json(_) => bail!("option '{}' must be a list of strings", key),
+	}
+}
+
+/// Find an options file by searching upward from a starting directory.
+///
+/// Uses the same upward search as [`crate::relay_config::find_relay_config`]:
+/// `relay-dedup.config.json` first, then `package.json` with a `"relayDedup"` key.
+pub fn find_options_file(start_dir: &Path) -> Option<PathBuf> {
+	for dir in start_dir.ancestors() {
+		let options_path = dir.join(OPTIONS_FILE_NAME);
+		if options_path.exists() {
+			return Some(options_path);
+		}
+
+		let package_json_path = dir.join("package.json");
+		let has_key = fs::read_to_string(&package_json_path)
+			.ok()
+			.and_then(|content| serde_json::from_str::<Value>(&content).ok())
+			.map(|json| json.get(PACKAGE_JSON_KEY).is_some())
+			.unwrap_or(false);
+		if has_key {
+			return Some(package_json_path);
+		}
+	}
+	None
+}
+
+/// Load options from a file (`relay-dedup.config.json` or `package.json`).
+pub fn load_options_file(path: &Path) -> Result<OptionLayer> {
+	let content =
+		fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+	let json: Value = serde_json::from_str(&content)
+		.with_context(|| format!("Failed to parse {}", path.display()))?;
+
+	let is_package_json = path
+		.file_name()
+		.map(|n| n == "package.json")
+		.unwrap_or(false);
+	let options = if is_package_json {
+		json.get(PACKAGE_JSON_KEY)
+			.ok_or_else(|| anyhow!("No '{}' key found in {}", PACKAGE_JSON_KEY, path.display()))?
+	} else {
+		&json
+	};
+
+	let base_dir = path.parent().unwrap_or(Path::new(""));
+	OptionLayer::from_json(options, base_dir)
+		.with_context(|| format!("Invalid options in {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use serde_json::json;
+	use tempfile::tempdir;
+
+	fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+		pairs
+			.iter()
+			.map(|(k, v)| (k.to_string(), v.to_string()))
+			.collect()
+	}
+
+	#[test]
+	fn test_from_json() {
+		let layer = OptionLayer::from_json(
+			&json!({
+				"generatedDir": "./src/__generated__",
+				"minOccurrences": 3,
+				"orderInsensitive": ["selections"],
+				"exclude": "*Test*.graphql.ts, *Mock*.graphql.ts",
+				"language": "flow",
+				"extractionModel": "threshold",
+				"gzipWeight": 0.5,
+				"nearDuplicates": true,
+				"verbose": true
+			}),
+			Path::new("/repo"),
+		)
+		.unwrap();
+
+		assert_eq!(
+			layer.generated_dir,
+			Some(PathBuf::from("/repo/./src/__generated__"))
+		);
+		assert_eq!(layer.min_occurrences, Some(3));
+		assert_eq!(
+			layer.order_insensitive,
+			Some(vec!["selections".to_string()])
+		);
+		assert_eq!(
+			layer.exclude,
+			Some(vec![
+				"*Test*.graphql.ts".to_string(),
+				"*Mock*.graphql.ts".to_string()
+			])
+		);
+		assert_eq!(layer.language, Some(Language::Flow));
+		assert_eq!(layer.extraction_model, Some(ExtractionModel::Threshold));
+		assert_eq!(layer.gzip_weight, Some(0.5));
+		assert_eq!(layer.near_duplicates, Some(true));
+		assert_eq!(layer.verbose, Some(true));
+		assert_eq!(layer.max_passes, None);
+	}
+
+	#[test]
+	fn test_gzip_weight_must_be_a_fraction() {
+		for value in ["NaN", "inf", "-0.5", "1.5", "half"] {
+			let err =
+				OptionLayer::from_env(vars(&[("RELAY_DEDUP_GZIP_WEIGHT", value)]), Path::new("/"))
+					.unwrap_err();
+			assert!(
+				format!("{:#}", err).contains("option 'gzipWeight' must be a number from 0 to 1"),
+				"{}: {:#}",
+				value,
+				err
+			);
+		}
+		let err = OptionLayer::from_json(&json!({ "gzipWeight": 2 }), Path::new("/")).unwrap_err();
+		assert!(err.to_string().contains("'gzipWeight'"), "{}", err);
+		assert!(check_fraction(f64::NAN, "gzipWeight").is_err());
+		assert_eq!(check_fraction(1.0, "gzipWeight").unwrap(), 1.0);
+	}
+
+	#[test]
+	fn test_unknown_and_invalid_keys() {
+		let err = OptionLayer::from_json(&json!({ "minOccurences": 3 }), Path::new("/"))
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("Unknown option 'minOccurences'"), "{}", err);
+
+		let err = OptionLayer::from_json(&json!({ "maxPasses": "ten" }), Path::new("/"))
+			.unwrap_err()
+			.to_string();
+		assert!(err.contains("'maxPasses' must be a number"), "{}", err);
+
+		let err = OptionLayer::from_env(vars(&[("RELAY_DEDUP_VERBOSITY", "1")]), Path::new("/"))
+			.unwrap_err()
+			.to_string();
+		assert!(
+			err.contains("Unknown environment variable RELAY_DEDUP_VERBOSITY"),
+			"{}",
+			err
+		);
+	}
+
+	#[test]
+	fn test_from_env() {
+		let layer = OptionLayer::from_env(
+			vars(&[
+				("RELAY_DEDUP_MIN_OCCURRENCES", "4"),
+				("RELAY_DEDUP_DRY_RUN", "true"),
+				("RELAY_DEDUP_ORDER_INSENSITIVE", "selections,args"),
+				("RELAY_DEDUP_SHARED_MODULE", "lib/__shared.ts"),
+				("PATH", "/usr/bin"),
+			]),
+			Path::new("/repo"),
+		)
+		.unwrap();
+
+		assert_eq!(layer.min_occurrences, Some(4));
+		assert_eq!(layer.dry_run, Some(true));
+		assert_eq!(
+			layer.order_insensitive,
+			Some(vec!["selections".to_string(), "args".to_string()])
+		);
+		assert_eq!(
+			layer.shared_module,
+			Some(PathBuf::from("/repo/lib/__shared.ts"))
+		);
+	}
+
+	#[test]
+	fn test_precedence() {
+		let file = OptionLayer::from_json(
+			&json!({ "minOccurrences": 3, "maxPasses": 10, "verbose": true }),
+			Path::new("/"),
+		)
+		.unwrap();
+		let env = OptionLayer::from_env(
+			vars(&[("RELAY_DEDUP_MIN_OCCURRENCES", "4")]),
+			Path::new("/"),
+		)
+		.unwrap();
+		let cli = OptionLayer {
+			max_passes: Some(20),
+			..OptionLayer::default()
+		};
+
+		let mut options = Options::default();
+		for layer in [file, env, cli] {
+			layer.apply_to(&mut options);
+		}
+
+		assert_eq!(options.min_occurrences, 4); // env over file
+		assert_eq!(options.max_passes, 20); // cli over file
+		assert!(options.verbose); // file over default
+		assert_eq!(options.order_insensitive, None); // relay-compiler defaults
+	}
+
+	#[test]
+	fn test_find_options_file() {
+		let temp = tempdir().unwrap();
+		let nested = temp.path().join("packages/app");
+		fs::create_dir_all(&nested).unwrap();
+
+		assert!(find_options_file(&nested).is_none());
+
+		fs::write(
+			temp.path().join("package.json"),
+			r#"{ "relayDedup": { "exclude": ["*Test*"] } }"#,
+		)
+		.unwrap();
+		let found = find_options_file(&nested).unwrap();
+		assert!(found.ends_with("package.json"));
+		let layer = load_options_file(&found).unwrap();
+		assert_eq!(layer.exclude, Some(vec!["*Test*".to_string()]));
+
+		// A standalone file closer to the start wins
+		fs::write(nested.join(OPTIONS_FILE_NAME), r#"{ "maxPasses": 5 }"#).unwrap();
+		let found = find_options_file(&nested).unwrap();
+		assert!(found.ends_with(OPTIONS_FILE_NAME));
+		assert_eq!(load_options_file(&found).unwrap().max_passes, Some(5));
+	}
+}
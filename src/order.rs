@@ -0,0 +1,232 @@
+//! Order-insensitivity rules.
+//!
+//! Sorting the elements of an array before comparison catches duplicates
+//! that only differ in element order, but only some arrays allow it. A rule
+//! names an array by its key and the `"kind"` of the object holding it:
+//! `LinkedField.selections`, or just `args` for any kind. Arrays held by or
+//! holding a node of an order-sensitive kind (`Defer`, `Stream`, `Condition`,
+//! `ClientExtension`, `ModuleImport`) are never sorted, whatever the rules
+//! say.
+//!
+//! Rules come from `--order-insensitive`, a rule file (`--order-rules`) or
+//! the built-in defaults for the installed relay-compiler (see
+//! [`crate::doctor::COMPATIBILITY`]). A rule file is a JSON object:
+//!
+//! ```json
+//! {
+//!   "orderInsensitive": ["LinkedField.selections", "args"],
+//!   "orderSensitiveKinds": ["MyDirective"]
+//! }
+//! ```
+
+use crate::doctor::{compatibility, Version, COMPATIBILITY, CONSERVATIVE_ORDER_INSENSITIVE};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Node kinds whose children are ordered: incremental delivery, conditional
+/// inclusion and client-only or lazily loaded selections
+pub const ORDER_SENSITIVE_KINDS: &[&str] = &[
+	"Defer",
+	"Stream",
+	"Condition",
+	"ClientExtension",
+	"ModuleImport",
+];
+
+/// One order-insensitive array: `Kind.key`, or `key` for any parent kind
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderRule {
+	/// `"kind"` of the object holding the array (`None`: any)
+	pub kind: Option<String>,
+	pub key: String,
+}
+
+impl FromStr for OrderRule {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let s = s.trim();
+		let (kind, key) = match s.split_once('.') {
+			Some(("*", key)) => (None, key),
+			Some((kind, key)) => (Some(kind.to_string()), key),
+			None => (None, s),
+		};
+		let is_name = |name: &str| !name.is_empty() && !name.contains(['.', '*']);
+		if !is_name(key) || kind.as_deref().is_some_and(|kind| !is_name(kind)) {
+			return Err(format!(
+				"invalid order-insensitive rule '{}' (expected key or Kind.key)",
+				s
+			));
+		}
+		Ok(Self {
+			kind,
+			key: key.to_string(),
+		})
+	}
+}
+
+impl fmt::Display for OrderRule {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.kind {
+			Some(ref kind) => write!(f, "{}.{}", kind, self.key),
+			None => f.write_str(&self.key),
+		}
+	}
+}
+
+/// Which arrays may be sorted for comparison
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OrderRules {
+	pub rules: Vec<OrderRule>,
+	/// [`ORDER_SENSITIVE_KINDS`] and any added by a rule file
+	pub sensitive_kinds: Vec<String>,
+}
+
+impl Default for OrderRules {
+	/// The defaults for the newest relay-compiler checked against
+	fn default() -> Self {
+		let newest = COMPATIBILITY.last().map(|c| c.order_insensitive);
+		Self::parse(newest.unwrap_or(CONSERVATIVE_ORDER_INSENSITIVE))
+			.expect("built-in order rules are valid")
+	}
+}
+
+impl OrderRules {
+	/// Rules from `Kind.key` / `key` strings, with the built-in
+	/// order-sensitive kinds
+	pub fn parse<S: AsRef<str>>(rules: &[S]) -> Result<Self> {
+		let rules = rules
+			.iter()
+			.map(|rule| rule.as_ref().parse().map_err(|e| anyhow!("{}", e)))
+			.collect::<Result<_>>()?;
+		Ok(Self {
+			rules,
+			sensitive_kinds: ORDER_SENSITIVE_KINDS
+				.iter()
+				.map(|k| k.to_string())
+				.collect(),
+		})
+	}
+
+	/// The built-in defaults for a relay-compiler version: the compatibility
+	/// table's rules, conservative ones for versions newer than the table,
+	/// and the newest table entry's when the version is unknown
+	pub fn for_relay(version: Option<Version>) -> Self {
+		let Some(version) = version else {
+			return Self::default();
+		};
+		let rules = compatibility(version)
+			.map(|c| c.order_insensitive)
+			.unwrap_or(CONSERVATIVE_ORDER_INSENSITIVE);
+		Self::parse(rules).expect("built-in order rules are valid")
+	}
+
+	/// Read a rule file. Its `orderInsensitive` list replaces `defaults`'
+	/// rules; `orderSensitiveKinds` add to the built-in kinds.
+	pub fn load(path: &Path, defaults: OrderRules) -> Result<Self> {
+		let content = fs::read_to_string(path)
+			.with_context(|| format!("Failed to read order rules {}", path.display()))?;
+		let json: Value = serde_json::from_str(&content)
+			.with_context(|| format!("Failed to parse order rules {}", path.display()))?;
+		Self::from_json(&json, defaults)
+			.with_context(|| format!("Invalid order rules {}", path.display()))
+	}
+
+	fn from_json(json: &Value, mut defaults: OrderRules) -> Result<Self> {
+		let map = json
+			.as_object()
+			.ok_or_else(|| anyhow!("order rules must be a JSON object"))?;
+		for (key, value) in map {
+			let list = value
+				.as_array()
+				.and_then(|items| items.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+				.ok_or_else(|| anyhow!("'{}' must be a list of strings", key))?;
+			match key.as_str() {
+				"orderInsensitive" => defaults.rules = Self::parse(&list)?.rules,
+				"orderSensitiveKinds" => {
+					for kind in list {
+						if !defaults.sensitive_kinds.iter().any(|k| k == kind) {
+							defaults.sensitive_kinds.push(kind.to_string());
+						}
+					}
+				}
+				_ => bail!(
+					"Unknown key '{}' (expected orderInsensitive or orderSensitiveKinds)",
+					key
+				),
+			}
+		}
+		Ok(defaults)
+	}
+
+	/// Whether the array at `key` of an object of kind `parent_kind` may be
+	/// sorted. `element_kinds` are the kinds of its object elements: an
+	/// order-sensitive one among them, or as the parent, forbids sorting.
+	pub fn allows<'a>(
+		&self,
+		parent_kind: Option<&str>,
+		key: &str,
+		mut element_kinds: impl Iterator<Item = &'a str>,
+	) -> bool {
+		let is_sensitive = |kind: &str| self.sensitive_kinds.iter().any(|k| k == kind);
+		if parent_kind.is_some_and(is_sensitive) {
+			return false;
+		}
+		let matches = self.rules.iter().any(|rule| {
+			rule.key == key
+				&& rule
+					.kind
+					.as_deref()
+					.is_none_or(|kind| parent_kind == Some(kind))
+		});
+		matches && !element_kinds.any(is_sensitive)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_allows() {
+		let rules = OrderRules::parse(&["LinkedField.selections", "*.args"]).unwrap();
+		let none = std::iter::empty;
+		assert!(rules.allows(Some("LinkedField"), "selections", none()));
+		assert!(!rules.allows(Some("InlineFragment"), "selections", none()));
+		assert!(rules.allows(Some("ScalarField"), "args", none()));
+		assert!(rules.allows(None, "args", none()));
+		// Order-sensitive contexts win over any rule
+		let any = OrderRules::parse(&["selections"]).unwrap();
+		assert!(!any.allows(Some("Defer"), "selections", none()));
+		assert!(!any.allows(Some("Condition"), "selections", none()));
+		assert!(!any.allows(
+			Some("LinkedField"),
+			"selections",
+			["ScalarField", "Stream"].into_iter()
+		));
+
+		assert!("Fragment.".parse::<OrderRule>().is_err());
+		assert!("a.b.c".parse::<OrderRule>().is_err());
+		assert_eq!(
+			"Fragment.argumentDefinitions"
+				.parse::<OrderRule>()
+				.unwrap()
+				.to_string(),
+			"Fragment.argumentDefinitions"
+		);
+
+		let json = serde_json::json!({
+			"orderInsensitive": ["Operation.selections"],
+			"orderSensitiveKinds": ["Custom"],
+		});
+		let loaded = OrderRules::from_json(&json, OrderRules::default()).unwrap();
+		assert_eq!(loaded.rules.len(), 1);
+		assert!(!loaded.allows(Some("Custom"), "selections", none()));
+		assert!(!loaded.allows(Some("Operation"), "selections", ["Custom"].into_iter()));
+		assert!(OrderRules::from_json(&serde_json::json!({ "rules": [] }), loaded).is_err());
+	}
+}
@@ -1,126 +1,346 @@
 //! Relay configuration detection and validation.
 //!
-//! Finds and validates relay.config.json or package.json with relay config.
+//! Finds and validates relay.config.json, relay.config.js (`.cjs` / `.mjs`)
+//! or package.json with relay config. Both Relay's single-project format and its multi-project format
+//! (`sources` plus `projects`) are understood.
 
+use crate::language::ModuleFormat;
+use crate::{js_config, Language};
 use anyhow::{bail, Context, Result};
 use serde_json::Value;
 use std::fs;
 use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Relay config filenames, in search order (package.json is checked last)
+pub const CONFIG_FILE_NAMES: &[&str] = &[
+	"relay.config.json",
+	"relay.config.js",
+	"relay.config.cjs",
+	"relay.config.mjs",
+];
+
+/// Project name Relay uses for single-project configs
+pub const DEFAULT_PROJECT_NAME: &str = "default";
 
 /// Result of finding and parsing relay config
 #[derive(Debug, Clone)]
 pub struct RelayConfig {
 	/// The artifact directory from config (if specified)
 	pub artifact_directory: Option<PathBuf>,
+	/// The source root from config (if specified)
+	pub src: Option<PathBuf>,
+	/// The artifact language from config (if specified and recognized)
+	pub language: Option<Language>,
+	/// The `eagerEsModules` setting (if specified)
+	pub eager_es_modules: Option<bool>,
 	/// Path to the config file that was found
 	pub config_path: PathBuf,
+	/// Every project in the config (a single `default` project for
+	/// single-project configs)
+	pub projects: Vec<RelayProject>,
+}
+
+impl RelayConfig {
+	/// Whether this config uses Relay's multi-project format
+	pub fn is_multi_project(&self) -> bool {
+		!(self.projects.len() == 1 && self.projects[0].name == DEFAULT_PROJECT_NAME)
+	}
+}
+
+/// A single project within a relay config
+#[derive(Debug, Clone)]
+pub struct RelayProject {
+	/// Project name (`default` for single-project configs)
+	pub name: String,
+	/// Artifact directory (`output` / `artifactDirectory`), if set
+	pub output: Option<PathBuf>,
+	/// Source roots belonging to this project
+	pub sources: Vec<PathBuf>,
+	/// The artifact language (if specified and recognized)
+	pub language: Option<Language>,
+	/// The project's `eagerEsModules` setting, falling back to the top-level one
+	pub eager_es_modules: Option<bool>,
+	/// Effective feature flags: the project's own flags over top-level flags
+	pub feature_flags: Option<Value>,
 }
 
 /// Find relay config by searching upward from a starting directory.
 ///
 /// Searches for:
-/// 1. `relay.config.json` in the directory or any parent
+/// 1. `relay.config.json`, `relay.config.js`, `.cjs` or `.mjs` in the directory or any parent
 /// 2. `package.json` with a `"relay"` key in the directory or any parent
 ///
-/// Returns `None` if no config is found.
-pub fn find_relay_config(start_dir: &Path) -> Option<RelayConfig> {
+/// Returns `Ok(None)` if no config is found, and an error if a config file
+/// exists but can't be read (e.g. a JavaScript config that is too dynamic).
+pub fn find_relay_config(start_dir: &Path) -> Result<Option<RelayConfig>> {
 	for dir in start_dir.ancestors() {
-		// Check for relay.config.json
-		let relay_config_path = dir.join("relay.config.json");
-		if relay_config_path.exists() {
-			if let Ok(content) = fs::read_to_string(&relay_config_path) {
-				if let Ok(json) = serde_json::from_str::<Value>(&content) {
-					let artifact_dir = json
-						.get("artifactDirectory")
-						.and_then(|v| v.as_str())
-						.map(|s| dir.join(s));
-
-					return Some(RelayConfig {
-						artifact_directory: artifact_dir,
-						config_path: relay_config_path,
-					});
-				}
+		// Check for relay.config.*
+		for name in CONFIG_FILE_NAMES {
+			let relay_config_path = dir.join(name);
+			if relay_config_path.exists() {
+				return load_relay_config(&relay_config_path).map(Some);
 			}
 		}
 
 		// Check for package.json with "relay" key
 		let package_json_path = dir.join("package.json");
 		if package_json_path.exists() {
-			if let Ok(content) = fs::read_to_string(&package_json_path) {
-				if let Ok(json) = serde_json::from_str::<Value>(&content) {
-					if let Some(relay) = json.get("relay") {
-						let artifact_dir = relay
-							.get("artifactDirectory")
-							.and_then(|v| v.as_str())
-							.map(|s| dir.join(s));
-
-						return Some(RelayConfig {
-							artifact_directory: artifact_dir,
-							config_path: package_json_path,
-						});
-					}
-				}
+			if let Ok(config) = load_relay_config(&package_json_path) {
+				return Ok(Some(config));
 			}
 		}
 	}
 
-	None
+	Ok(None)
 }
 
-/// Validate that required feature flags are set in relay config.
+/// Find every relay config below a workspace root.
 ///
-/// Required flags:
-/// - `disable_deduping_common_structures_in_artifacts`: must be `{ "kind": "enabled" }`
+/// `node_modules` and hidden directories are skipped, as are `package.json`
+/// files without a `"relay"` key. Results are sorted by path.
+pub fn find_workspace_configs(root: &Path) -> Result<Vec<RelayConfig>> {
+	let mut configs = Vec::new();
+
+	let entries = WalkDir::new(root)
+		.sort_by_file_name()
+		.into_iter()
+		.filter_entry(|e| {
+			let name = e.file_name().to_string_lossy();
+			e.depth() == 0 || !(name == "node_modules" || name.starts_with('.'))
+		})
+		.filter_map(|e| e.ok())
+		.filter(|e| e.file_type().is_file());
+
+	for entry in entries {
+		match entry.file_name().to_str() {
+			Some(name) if CONFIG_FILE_NAMES.contains(&name) => {
+				configs.push(load_relay_config(entry.path())?)
+			}
+			Some("package.json") => {
+				// Most package.json files in a workspace aren't relay configs
+				if let Ok(config) = load_relay_config(entry.path()) {
+					configs.push(config);
+				}
+			}
+			_ => {}
+		}
+	}
+
+	Ok(configs)
+}
+
+/// Read the relay config object from a config file.
 ///
-/// Returns `Ok(())` if valid, `Err` with detailed message if not.
-pub fn validate_relay_config(config_path: &Path) -> Result<()> {
+/// For `package.json` this is the value of the `"relay"` key. JavaScript
+/// configs are read statically (see [`crate::js_config`]).
+pub fn read_config_value(config_path: &Path) -> Result<Value> {
 	let content = fs::read_to_string(config_path)
 		.with_context(|| format!("Failed to read {}", config_path.display()))?;
 
+	if is_javascript_config(config_path) {
+		return js_config::extract_config(&content, config_path);
+	}
+
 	let json: Value = serde_json::from_str(&content)
 		.with_context(|| format!("Failed to parse {}", config_path.display()))?;
 
 	// If this is package.json, look under "relay" key
-	let config = if config_path
-		.file_name()
-		.map(|n| n == "package.json")
-		.unwrap_or(false)
-	{
+	if is_package_json(config_path) {
 		json.get("relay")
-			.ok_or_else(|| anyhow::anyhow!("No 'relay' key found in package.json"))?
+			.cloned()
+			.ok_or_else(|| anyhow::anyhow!("No 'relay' key found in package.json"))
 	} else {
-		&json
+		Ok(json)
+	}
+}
+
+/// Whether a config file is JavaScript (`relay.config.js` / `.cjs` / `.mjs`)
+pub fn is_javascript_config(path: &Path) -> bool {
+	matches!(
+		path.extension().and_then(|e| e.to_str()),
+		Some("js" | "cjs" | "mjs")
+	)
+}
+
+pub(crate) fn is_package_json(path: &Path) -> bool {
+	path.file_name()
+		.map(|n| n == "package.json")
+		.unwrap_or(false)
+}
+
+/// Load and parse a relay config file.
+pub fn load_relay_config(config_path: &Path) -> Result<RelayConfig> {
+	let config = read_config_value(config_path)?;
+	let dir = config_path.parent().unwrap_or(Path::new(""));
+	Ok(parse_relay_config(&config, dir, config_path))
+}
+
+/// Build a [`RelayConfig`] from a parsed config object.
+fn parse_relay_config(config: &Value, dir: &Path, config_path: &Path) -> RelayConfig {
+	let top_level_flags = config.get("featureFlags");
+
+	let projects = match config.get("projects").and_then(|p| p.as_object()) {
+		Some(projects) => {
+			// Multi-project paths are relative to `root` (itself relative to the config)
+			let root = config_dir_path(config, "root", dir).unwrap_or_else(|| dir.to_path_buf());
+			let sources = config.get("sources").and_then(|s| s.as_object());
+
+			projects
+				.iter()
+				.map(|(name, project)| {
+					// `sources` maps a directory to one project name or a list of them
+					let project_sources = sources
+						.into_iter()
+						.flatten()
+						.filter(|(_, owner)| match owner {
+							Value::String(owner) => owner == name,
+							Value::Array(owners) => owners.iter().any(|o| o.as_str() == Some(name)),
+							_ => false,
+						})
+						.map(|(source, _)| root.join(source))
+						.collect();
+
+					RelayProject {
+						name: name.clone(),
+						output: config_dir_path(project, "output", &root),
+						sources: project_sources,
+						language: config_language(project),
+						eager_es_modules: config_bool(project, "eagerEsModules")
+							.or_else(|| config_bool(config, "eagerEsModules")),
+						feature_flags: merge_feature_flags(
+							top_level_flags,
+							project.get("featureFlags"),
+						),
+					}
+				})
+				.collect()
+		}
+		None => vec![RelayProject {
+			name: DEFAULT_PROJECT_NAME.to_string(),
+			output: config_dir_path(config, "artifactDirectory", dir),
+			sources: config_dir_path(config, "src", dir).into_iter().collect(),
+			language: config_language(config),
+			eager_es_modules: config_bool(config, "eagerEsModules"),
+			feature_flags: top_level_flags.cloned(),
+		}],
 	};
 
-	let feature_flags = config.get("featureFlags");
+	RelayConfig {
+		artifact_directory: config_dir_path(config, "artifactDirectory", dir),
+		src: config_dir_path(config, "src", dir),
+		language: config_language(config),
+		eager_es_modules: config_bool(config, "eagerEsModules"),
+		config_path: config_path.to_path_buf(),
+		projects,
+	}
+}
 
-	// Check disable_deduping_common_structures_in_artifacts
-	let dedup_disabled = feature_flags
+/// Overlay a project's feature flags on the top-level ones
+fn merge_feature_flags(top_level: Option<&Value>, project: Option<&Value>) -> Option<Value> {
+	match (top_level, project) {
+		(Some(Value::Object(top)), Some(Value::Object(own))) => {
+			let mut merged = top.clone();
+			for (key, value) in own {
+				merged.insert(key.clone(), value.clone());
+			}
+			Some(Value::Object(merged))
+		}
+		(top, own) => own.or(top).cloned(),
+	}
+}
+
+/// Read a path-valued key from a relay config, resolved against the config's directory
+fn config_dir_path(config: &Value, key: &str, dir: &Path) -> Option<PathBuf> {
+	config
+		.get(key)
+		.and_then(|v| v.as_str())
+		.map(|s| dir.join(s))
+}
+
+/// Read the `language` key from a relay config
+fn config_language(config: &Value) -> Option<Language> {
+	config
+		.get("language")
+		.and_then(|v| v.as_str())
+		.and_then(|s| s.parse().ok())
+}
+
+/// Read a boolean key from a relay config
+fn config_bool(config: &Value, key: &str) -> Option<bool> {
+	config.get(key).and_then(|v| v.as_bool())
+}
+
+impl RelayProject {
+	/// The module format Relay uses for this project's artifacts, given the
+	/// effective language
+	pub fn module_format(&self, language: Language) -> ModuleFormat {
+		ModuleFormat::for_relay(language, self.eager_es_modules.unwrap_or(false))
+	}
+}
+
+/// Check whether a project has Relay's built-in deduplication disabled.
+pub fn dedup_disabled(project: &RelayProject) -> bool {
+	project
+		.feature_flags
+		.as_ref()
 		.and_then(|ff| ff.get("disable_deduping_common_structures_in_artifacts"))
 		.and_then(|v| v.get("kind"))
 		.and_then(|v| v.as_str())
-		== Some("enabled");
+		== Some("enabled")
+}
 
-	if !dedup_disabled {
-		bail!(
-			r#"
-ERROR: Relay's built-in deduplication must be disabled.
+/// Validate that recommended feature flags are set in relay config.
+///
+/// Recommended flags:
+/// - `disable_deduping_common_structures_in_artifacts`: `{ "kind": "enabled" }`
+///
+/// Every project of a multi-project config is checked.
+///
+/// Returns `Ok(())` if valid, `Err` with detailed message if not.
+pub fn validate_relay_config(config_path: &Path) -> Result<()> {
+	let config = load_relay_config(config_path)?;
+	for project in &config.projects {
+		validate_project(&config, project)?;
+	}
+	Ok(())
+}
 
-Add the following to your relay config ({}):
+/// Validate the feature flags of a single project.
+pub fn validate_project(config: &RelayConfig, project: &RelayProject) -> Result<()> {
+	if dedup_disabled(project) {
+		return Ok(());
+	}
+
+	let location = if config.is_multi_project() {
+		format!(
+			"project '{}' in your relay config ({}), under \"projects\": {{ \"{}\": ... }}",
+			project.name,
+			config.config_path.display(),
+			project.name
+		)
+	} else {
+		format!("your relay config ({})", config.config_path.display())
+	};
+
+	bail!(
+		r#"
+Relay's built-in deduplication is enabled in {}.
+
+relay-dedup inlines Relay's hoisted `v0` variables before deduplicating, but
+that's slower and Relay's dedup gains nothing on top of this tool's. Add:
 
   "featureFlags": {{
     "disable_deduping_common_structures_in_artifacts": {{ "kind": "enabled" }},
     "enforce_fragment_alias_where_ambiguous": {{ "kind": "disabled" }}
   }}
 
-The first flag is REQUIRED - Relay's dedup conflicts with this tool.
 The second flag works around a Relay bug where any feature flags enable strict alias checking.
-"#,
-			config_path.display()
-		);
-	}
 
-	Ok(())
+Run `relay-dedup init` to add them automatically.
+"#,
+		location
+	);
 }
 
 #[cfg(test)]
@@ -134,21 +354,23 @@ mod tests {
 		let temp = tempdir().unwrap();
 
 		// No config → None
-		assert!(find_relay_config(temp.path()).is_none());
+		assert!(find_relay_config(temp.path()).unwrap().is_none());
 
 		// relay.config.json works
 		fs::write(
 			temp.path().join("relay.config.json"),
-			r#"{ "artifactDirectory": "./src/__generated__" }"#,
+			r#"{ "artifactDirectory": "./src/__generated__", "language": "flow" }"#,
 		)
 		.unwrap();
 
-		let config = find_relay_config(temp.path()).unwrap();
+		let config = find_relay_config(temp.path()).unwrap().unwrap();
 		assert!(config.config_path.ends_with("relay.config.json"));
 		assert_eq!(
 			config.artifact_directory,
 			Some(temp.path().join("./src/__generated__"))
 		);
+		assert_eq!(config.src, None);
+		assert_eq!(config.language, Some(Language::Flow));
 	}
 
 	#[test]
@@ -157,12 +379,14 @@ mod tests {
 
 		fs::write(
 			temp.path().join("package.json"),
-			r#"{ "relay": { "artifactDirectory": "./gen" } }"#,
+			r#"{ "relay": { "src": "./src", "artifactDirectory": "./gen" } }"#,
 		)
 		.unwrap();
 
-		let config = find_relay_config(temp.path()).unwrap();
+		let config = find_relay_config(temp.path()).unwrap().unwrap();
 		assert!(config.config_path.ends_with("package.json"));
+		assert_eq!(config.src, Some(temp.path().join("./src")));
+		assert_eq!(config.language, None);
 	}
 
 	#[test]
@@ -186,4 +410,192 @@ mod tests {
 		.unwrap();
 		assert!(validate_relay_config(&path).is_ok());
 	}
+
+	#[test]
+	fn test_find_javascript_config() {
+		let temp = tempdir().unwrap();
+
+		fs::write(
+			temp.path().join("relay.config.cjs"),
+			r#"
+			const path = require("path");
+			module.exports = {
+				src: "./src",
+				artifactDirectory: path.join(__dirname, "src", "__generated__"),
+				language: "typescript",
+				featureFlags: {
+					disable_deduping_common_structures_in_artifacts: { kind: "enabled" },
+				},
+			};
+			"#,
+		)
+		.unwrap();
+
+		let config = find_relay_config(temp.path()).unwrap().unwrap();
+		assert!(config.config_path.ends_with("relay.config.cjs"));
+		let expected = std::path::absolute(temp.path().join("src/__generated__")).unwrap();
+		assert_eq!(config.artifact_directory, Some(expected));
+		assert_eq!(config.language, Some(Language::TypeScript));
+		assert!(validate_relay_config(&config.config_path).is_ok());
+	}
+
+	#[test]
+	fn test_dynamic_javascript_config_is_an_error() {
+		let temp = tempdir().unwrap();
+
+		fs::write(
+			temp.path().join("relay.config.js"),
+			"module.exports = require('./base.config');",
+		)
+		.unwrap();
+
+		let err = find_relay_config(temp.path()).unwrap_err().to_string();
+		assert!(err.contains("too dynamic"), "{}", err);
+	}
+
+	#[test]
+	fn test_multi_project_config() {
+		let temp = tempdir().unwrap();
+		let path = temp.path().join("relay.config.json");
+
+		fs::write(
+			&path,
+			r#"{
+				"root": "./packages",
+				"sources": {
+					"app/src": "app",
+					"admin/src": "admin",
+					"shared/src": ["app", "admin"]
+				},
+				"projects": {
+					"app": {
+						"output": "app/src/__generated__",
+						"language": "typescript",
+						"featureFlags": {
+							"disable_deduping_common_structures_in_artifacts": { "kind": "enabled" }
+						}
+					},
+					"admin": { "language": "flow" }
+				}
+			}"#,
+		)
+		.unwrap();
+
+		let config = find_relay_config(temp.path()).unwrap().unwrap();
+		assert!(config.is_multi_project());
+		assert_eq!(config.projects.len(), 2);
+
+		let root = temp.path().join("./packages");
+		let app = config.projects.iter().find(|p| p.name == "app").unwrap();
+		assert_eq!(app.output, Some(root.join("app/src/__generated__")));
+		assert_eq!(
+			app.sources,
+			vec![root.join("app/src"), root.join("shared/src")]
+		);
+		assert_eq!(app.language, Some(Language::TypeScript));
+		assert!(validate_project(&config, app).is_ok());
+
+		let admin = config.projects.iter().find(|p| p.name == "admin").unwrap();
+		assert_eq!(admin.output, None);
+		assert_eq!(admin.language, Some(Language::Flow));
+		let err = validate_project(&config, admin).unwrap_err().to_string();
+		assert!(err.contains("project 'admin'"));
+
+		// One bad project fails the whole config
+		assert!(validate_relay_config(&path).is_err());
+	}
+
+	#[test]
+	fn test_module_format_from_config() {
+		let temp = tempdir().unwrap();
+		fs::write(
+			temp.path().join("relay.config.json"),
+			r#"{
+				"sources": { "app": "app", "native": "native", "web": "web" },
+				"eagerEsModules": true,
+				"projects": {
+					"app": { "language": "typescript", "eagerEsModules": false },
+					"native": { "language": "flow", "eagerEsModules": false },
+					"web": { "language": "javascript" }
+				}
+			}"#,
+		)
+		.unwrap();
+
+		let config = find_relay_config(temp.path()).unwrap().unwrap();
+		let format = |name: &str| {
+			let project = config.projects.iter().find(|p| p.name == name).unwrap();
+			project.module_format(project.language.unwrap_or_default())
+		};
+		assert_eq!(format("app"), ModuleFormat::Esm);
+		assert_eq!(format("native"), ModuleFormat::CommonJs);
+		assert_eq!(format("web"), ModuleFormat::Esm);
+	}
+
+	#[test]
+	fn test_project_flags_override_top_level() {
+		let temp = tempdir().unwrap();
+		let path = temp.path().join("relay.config.json");
+
+		fs::write(
+			&path,
+			r#"{
+				"featureFlags": {
+					"disable_deduping_common_structures_in_artifacts": { "kind": "enabled" }
+				},
+				"sources": { "a": "a", "b": "b" },
+				"projects": {
+					"a": {},
+					"b": {
+						"featureFlags": {
+							"disable_deduping_common_structures_in_artifacts": { "kind": "disabled" }
+						}
+					}
+				}
+			}"#,
+		)
+		.unwrap();
+
+		let config = load_relay_config(&path).unwrap();
+		let a = config.projects.iter().find(|p| p.name == "a").unwrap();
+		let b = config.projects.iter().find(|p| p.name == "b").unwrap();
+		assert!(dedup_disabled(a));
+		assert!(!dedup_disabled(b));
+	}
+
+	#[test]
+	fn test_find_workspace_configs() {
+		let temp = tempdir().unwrap();
+		let root = temp.path();
+
+		fs::create_dir_all(root.join("apps/web")).unwrap();
+		fs::create_dir_all(root.join("apps/mobile")).unwrap();
+		fs::create_dir_all(root.join("node_modules/dep")).unwrap();
+		fs::write(root.join("package.json"), r#"{ "private": true }"#).unwrap();
+		fs::write(
+			root.join("apps/web/relay.config.json"),
+			r#"{ "artifactDirectory": "./src/__generated__" }"#,
+		)
+		.unwrap();
+		fs::write(
+			root.join("apps/mobile/package.json"),
+			r#"{ "relay": { "src": "./src" } }"#,
+		)
+		.unwrap();
+		fs::write(
+			root.join("node_modules/dep/relay.config.json"),
+			r#"{ "artifactDirectory": "./gen" }"#,
+		)
+		.unwrap();
+
+		let configs = find_workspace_configs(root).unwrap();
+		let paths: Vec<_> = configs.iter().map(|c| c.config_path.clone()).collect();
+		assert_eq!(
+			paths,
+			vec![
+				root.join("apps/mobile/package.json"),
+				root.join("apps/web/relay.config.json"),
+			]
+		);
+	}
 }
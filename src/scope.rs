@@ -0,0 +1,225 @@
+//! Extraction scoping rules.
+//!
+//! Decides which artifacts take part in deduplication (include/exclude globs
+//! and the `@relay-dedup-ignore` pragma) and which key paths inside an
+//! artifact must never be extracted. Nodes kept inline for safety (see
+//! [`crate::hazard`]) are reported with the same [`Suppression`] counts.
+
+use std::path::Path;
+
+/// Pragma that opts an artifact out of deduplication when found in its header
+pub const IGNORE_PRAGMA: &str = "@relay-dedup-ignore";
+
+/// Why a candidate was kept out of extraction.
+///
+/// Used as the key of the per-rule suppression counts in [`crate::Stats`].
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Suppression {
+	/// The file matched no `--include` glob
+	NotIncluded,
+	/// The file matched an `--exclude` glob
+	Excluded(String),
+	/// The file carries the `@relay-dedup-ignore` pragma
+	Pragma,
+	/// The node lies at or below a protected key path
+	ProtectedPath(String),
+	/// The artifact's own statements mutate the node (e.g. `node.params`)
+	MutatedTarget(String),
+	/// The node's `"kind"` is used by Relay as an identity cache key
+	IdentitySensitive(String),
+}
+
+impl std::fmt::Display for Suppression {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Suppression::NotIncluded => write!(f, "not matched by --include"),
+			Suppression::Excluded(glob) => write!(f, "excluded by \"{}\"", glob),
+			Suppression::Pragma => write!(f, "{} pragma", IGNORE_PRAGMA),
+			Suppression::ProtectedPath(path) => write!(f, "protected path \"{}\"", path),
+			Suppression::MutatedTarget(target) => write!(f, "mutated target \"{}\"", target),
+			Suppression::IdentitySensitive(kind) => {
+				write!(f, "identity-sensitive kind \"{}\"", kind)
+			}
+		}
+	}
+}
+
+/// Decide whether a whole file is out of scope.
+///
+/// `relative_path` is the artifact's path relative to the directory being
+/// processed. Returns the first rule that suppresses the file.
+pub fn file_suppression(
+	relative_path: &Path,
+	content: &str,
+	include: &[String],
+	exclude: &[String],
+) -> Option<Suppression> {
+	let path = relative_path.to_string_lossy().replace('\\', "/");
+
+	if !include.is_empty() && !include.iter().any(|g| path_matches(g, &path)) {
+		return Some(Suppression::NotIncluded);
+	}
+	if let Some(glob) = exclude.iter().find(|g| path_matches(g, &path)) {
+		return Some(Suppression::Excluded(glob.clone()));
+	}
+	if has_ignore_pragma(content) {
+		return Some(Suppression::Pragma);
+	}
+	None
+}
+
+/// Check whether the file header (leading comments, before any code) has the ignore pragma.
+pub fn has_ignore_pragma(content: &str) -> bool {
+	header_contains(content, IGNORE_PRAGMA)
+}
+
+/// Check whether the file header (leading comments, before any code) contains `needle`.
+pub fn header_contains(content: &str, needle: &str) -> bool {
+	let mut in_block_comment = false;
+	for line in content.lines() {
+		let trimmed = line.trim();
+		let is_comment = in_block_comment || trimmed.starts_with("/*") || trimmed.starts_with("//");
+		if is_comment {
+			if trimmed.contains(needle) {
+				return true;
+			}
+			if in_block_comment || trimmed.starts_with("/*") {
+				in_block_comment = !trimmed.contains("*/");
+			}
+		} else if !(trimmed.is_empty() || matches!(trimmed, "'use strict';" | "\"use strict\";")) {
+			return false;
+		}
+	}
+	false
+}
+
+/// Match a path against a glob.
+///
+/// Supports `*` (within a segment), `**` (any number of segments) and `?`.
+/// A pattern without a `/` matches against the file name only.
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+	if pattern.contains('/') {
+		let pattern: Vec<&str> = pattern.trim_start_matches("./").split('/').collect();
+		let path: Vec<&str> = path.split('/').collect();
+		match_segments(&pattern, &path)
+	} else {
+		let name = path.rsplit('/').next().unwrap_or(path);
+		match_segment(pattern.as_bytes(), name.as_bytes())
+	}
+}
+
+fn match_segments(pattern: &[&str], path: &[&str]) -> bool {
+	match pattern.split_first() {
+		None => path.is_empty(),
+		Some((&"**", rest)) => (0..=path.len()).any(|skip| match_segments(rest, &path[skip..])),
+		Some((first, rest)) => match path.split_first() {
+			Some((segment, path_rest)) => {
+				match_segment(first.as_bytes(), segment.as_bytes())
+					&& match_segments(rest, path_rest)
+			}
+			None => false,
+		},
+	}
+}
+
+fn match_segment(pattern: &[u8], text: &[u8]) -> bool {
+	match pattern.split_first() {
+		None => text.is_empty(),
+		Some((b'*', rest)) => (0..=text.len()).any(|skip| match_segment(rest, &text[skip..])),
+		Some((b'?', rest)) => !text.is_empty() && match_segment(rest, &text[1..]),
+		Some((c, rest)) => text.first() == Some(c) && match_segment(rest, &text[1..]),
+	}
+}
+
+/// A parsed protected key path such as `metadata.refetch`
+#[derive(Debug, Clone)]
+pub struct ProtectedPath {
+	pub source: String,
+	segments: Vec<String>,
+}
+
+impl ProtectedPath {
+	pub fn parse(source: &str) -> Self {
+		Self {
+			source: source.to_string(),
+			segments: source
+				.split('.')
+				.filter(|s| !s.is_empty())
+				.map(|s| s.to_string())
+				.collect(),
+		}
+	}
+
+	/// Whether a node at `key_path` (object keys from the root literal) is at
+	/// or below this path. A `*` segment matches any key.
+	pub fn covers(&self, key_path: &[&str]) -> bool {
+		!self.segments.is_empty()
+			&& key_path.len() >= self.segments.len()
+			&& self
+				.segments
+				.iter()
+				.zip(key_path)
+				.all(|(segment, key)| segment == "*" || segment == key)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_path_matches() {
+		assert!(path_matches("*Query.graphql.ts", "FooQuery.graphql.ts"));
+		assert!(path_matches("*Query.graphql.ts", "a/b/FooQuery.graphql.ts"));
+		assert!(!path_matches("*Query.graphql.ts", "FooFragment.graphql.ts"));
+		assert!(path_matches("admin/**", "admin/__generated__/A.graphql.ts"));
+		assert!(path_matches(
+			"**/__generated__/*.graphql.ts",
+			"__generated__/A.graphql.ts"
+		));
+		assert!(!path_matches("admin/*", "admin/__generated__/A.graphql.ts"));
+		assert!(path_matches("File?.graphql.ts", "File1.graphql.ts"));
+	}
+
+	#[test]
+	fn test_ignore_pragma_only_in_header() {
+		assert!(has_ignore_pragma(
+			"/**\n * @generated\n * @relay-dedup-ignore\n */\nconst node = {};"
+		));
+		assert!(has_ignore_pragma(
+			"'use strict';\n// @relay-dedup-ignore\nvar node = {};"
+		));
+		assert!(!has_ignore_pragma(
+			"/** @generated */\nconst node = {\"text\": \"// @relay-dedup-ignore\"};"
+		));
+	}
+
+	#[test]
+	fn test_file_suppression_order() {
+		let path = Path::new("admin/AdminQuery.graphql.ts");
+		let include = vec!["admin/**".to_string()];
+		let exclude = vec!["*Query.graphql.ts".to_string()];
+
+		assert_eq!(file_suppression(path, "", &include, &[]), None);
+		assert_eq!(
+			file_suppression(Path::new("Other.graphql.ts"), "", &include, &[]),
+			Some(Suppression::NotIncluded)
+		);
+		assert_eq!(
+			file_suppression(path, "", &include, &exclude),
+			Some(Suppression::Excluded("*Query.graphql.ts".to_string()))
+		);
+	}
+
+	#[test]
+	fn test_protected_path_covers() {
+		let path = ProtectedPath::parse("metadata.refetch");
+		assert!(path.covers(&["metadata", "refetch"]));
+		assert!(path.covers(&["metadata", "refetch", "operation"]));
+		assert!(!path.covers(&["metadata"]));
+		assert!(!path.covers(&["fragment", "metadata", "refetch"]));
+
+		assert!(ProtectedPath::parse("*.argumentDefinitions")
+			.covers(&["fragment", "argumentDefinitions"]));
+	}
+}
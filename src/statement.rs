@@ -0,0 +1,295 @@
+//! Top-level statement classification.
+//!
+//! Splits a token stream into top-level statements and finds their runtime
+//! initializers: the values of `const` / `let` / `var` declarators, the
+//! right-hand side of assignments like `module.exports = ...` and the
+//! expression after `export default`. Only those are candidates for
+//! extraction; imports and type declarations are never looked into.
+
+use crate::lexer::{Token, TokenKind};
+use std::ops::Range;
+
+/// What a top-level statement is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatementKind {
+	/// `import ...`, `export { ... }`, `export * from ...`
+	Module,
+	/// `type`, `interface`, `declare`, `enum` and their `export` forms
+	TypeDeclaration,
+	/// `const` / `let` / `var`, optionally exported
+	Declaration,
+	/// `export default ...`
+	ExportDefault,
+	/// An expression statement with a top-level `=` (e.g. `module.exports = node`)
+	Assignment,
+	/// Anything else (`if` blocks, calls, functions, ...)
+	Other,
+}
+
+/// A top-level statement
+#[derive(Debug, Clone)]
+pub struct Statement {
+	pub kind: StatementKind,
+	/// Token indices of the whole statement
+	pub tokens: Range<usize>,
+	/// Token indices of each runtime initializer
+	pub initializers: Vec<Range<usize>>,
+}
+
+/// Keywords that can only start a statement, so one on a new line ends the
+/// previous statement even without a `;`
+const STATEMENT_KEYWORDS: &[&str] = &[
+	"import",
+	"export",
+	"const",
+	"var",
+	"interface",
+	"declare",
+	"if",
+];
+
+/// Keywords that start a statement on a new line after a complete expression
+/// (they can also be identifiers or expressions)
+const CONTEXTUAL_KEYWORDS: &[&str] = &["let", "type", "function", "class"];
+
+/// Split comment-free `tokens` into top-level statements
+pub fn statements(src: &str, tokens: &[Token]) -> Vec<Statement> {
+	let mut result = Vec::new();
+	let mut start = 0;
+	let mut depth = 0i32;
+
+	for (k, token) in tokens.iter().enumerate() {
+		if depth == 0 && k > start && starts_new_line_statement(src, &tokens[k - 1], token) {
+			result.push(classify(src, tokens, start..k));
+			start = k;
+		}
+		if token.kind != TokenKind::Punct {
+			continue;
+		}
+		match token.text(src) {
+			"{" | "[" | "(" => depth += 1,
+			"}" if depth == 1 && is_block_statement(src, &tokens[start]) => {
+				// `if (...) { ... }` and friends end with their block
+				depth = 0;
+				result.push(classify(src, tokens, start..k + 1));
+				start = k + 1;
+			}
+			"}" | "]" | ")" => depth = (depth - 1).max(0),
+			";" if depth == 0 => {
+				result.push(classify(src, tokens, start..k + 1));
+				start = k + 1;
+			}
+			_ => {}
+		}
+	}
+	if start < tokens.len() {
+		result.push(classify(src, tokens, start..tokens.len()));
+	}
+	result
+}
+
+/// Whether a statement starting with `token` ends with a block
+fn is_block_statement(src: &str, token: &Token) -> bool {
+	token.kind == TokenKind::Ident
+		&& matches!(
+			token.text(src),
+			"if" | "else" | "function" | "class" | "for" | "while" | "try" | "switch"
+		)
+}
+
+/// Whether `token` begins a new statement by automatic semicolon insertion
+fn starts_new_line_statement(src: &str, prev: &Token, token: &Token) -> bool {
+	if token.kind != TokenKind::Ident || !src[prev.end..token.start].contains('\n') {
+		return false;
+	}
+	let word = token.text(src);
+	let prev_ends_expression = match prev.kind {
+		// `>` closes type arguments (`ReadonlyArray<{ ... }>`)
+		TokenKind::Punct => matches!(prev.text(src), ")" | "]" | "}" | ">"),
+		_ => true,
+	};
+	STATEMENT_KEYWORDS.contains(&word)
+		|| (prev_ends_expression && CONTEXTUAL_KEYWORDS.contains(&word))
+}
+
+fn classify(src: &str, tokens: &[Token], range: Range<usize>) -> Statement {
+	let statement = &tokens[range.clone()];
+	let word = |i: usize| {
+		statement
+			.get(i)
+			.filter(|t| t.kind == TokenKind::Ident)
+			.map(|t| t.text(src))
+	};
+	let exported = word(0) == Some("export");
+	let head = if exported { 1 } else { 0 };
+
+	let kind = match (word(0), word(head)) {
+		(Some("import"), _) => StatementKind::Module,
+		(Some("export"), Some("default")) => StatementKind::ExportDefault,
+		(_, Some("type" | "interface" | "declare" | "enum" | "opaque")) => {
+			StatementKind::TypeDeclaration
+		}
+		(_, Some("const" | "let" | "var")) => StatementKind::Declaration,
+		(Some("export"), _) => StatementKind::Module,
+		_ if top_level_assignment(src, statement).is_some() => StatementKind::Assignment,
+		_ => StatementKind::Other,
+	};
+
+	let offset = range.start;
+	let shift = |r: Range<usize>| r.start + offset..r.end + offset;
+	let end = statement.len() - usize::from(statement.last().is_some_and(|t| t.is_punct(src, ";")));
+	let initializers = match kind {
+		StatementKind::Declaration => declarator_initializers(src, &statement[..end])
+			.into_iter()
+			.map(shift)
+			.collect(),
+		StatementKind::ExportDefault => vec![shift(end.min(2)..end)],
+		StatementKind::Assignment => top_level_assignment(src, statement)
+			.map(|eq| vec![shift(eq + 1..end)])
+			.unwrap_or_default(),
+		_ => Vec::new(),
+	};
+
+	Statement {
+		kind,
+		tokens: range,
+		initializers,
+	}
+}
+
+/// Index of the first `=` outside any bracket
+fn top_level_assignment(src: &str, statement: &[Token]) -> Option<usize> {
+	let mut depth = 0i32;
+	for (k, token) in statement.iter().enumerate() {
+		if token.kind != TokenKind::Punct {
+			continue;
+		}
+		match token.text(src) {
+			"{" | "[" | "(" => depth += 1,
+			"}" | "]" | ")" => depth -= 1,
+			"=" if depth == 0 => return Some(k),
+			_ => {}
+		}
+	}
+	None
+}
+
+/// Initializer ranges of `a = ..., b = ...`: from each top-level `=` to the
+/// next top-level `,`
+fn declarator_initializers(src: &str, statement: &[Token]) -> Vec<Range<usize>> {
+	let mut result = Vec::new();
+	let mut depth = 0i32;
+	let mut current: Option<usize> = None;
+	for (k, token) in statement.iter().enumerate() {
+		if token.kind != TokenKind::Punct {
+			continue;
+		}
+		match token.text(src) {
+			"{" | "[" | "(" => depth += 1,
+			"}" | "]" | ")" => depth -= 1,
+			"=" if depth == 0 && current.is_none() => current = Some(k + 1),
+			"," if depth == 0 => {
+				if let Some(start) = current.take() {
+					result.push(start..k);
+				}
+			}
+			_ => {}
+		}
+	}
+	if let Some(start) = current {
+		result.push(start..statement.len());
+	}
+	result
+}
+
+/// Name bound by the declarator whose initializer is `initializer`
+/// (`node` in `const node: ConcreteRequest = ...`)
+pub fn declarator_name<'a>(
+	src: &'a str,
+	tokens: &[Token],
+	initializer: &Range<usize>,
+) -> Option<&'a str> {
+	// Walk back from the `=` over any type annotation
+	let mut depth = 0i32;
+	let mut k = initializer.start.checked_sub(1)?;
+	while k > 0 {
+		k -= 1;
+		let token = &tokens[k];
+		match token.text(src) {
+			")" | "]" | "}" | ">" if token.kind == TokenKind::Punct => depth += 1,
+			"(" | "[" | "{" | "<" if token.kind == TokenKind::Punct => depth -= 1,
+			"," if token.kind == TokenKind::Punct && depth == 0 => break,
+			"const" | "let" | "var" if token.kind == TokenKind::Ident && depth == 0 => break,
+			_ => {}
+		}
+	}
+	tokens
+		.get(k + 1)
+		.filter(|t| t.kind == TokenKind::Ident)
+		.map(|t| t.text(src))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::lexer::tokenize;
+
+	#[test]
+	fn test_statements() {
+		let src = r#"import type { ConcreteRequest } from 'relay-runtime';
+export type Foo$data = { readonly id: string };
+export type Foo$key = { readonly " $fragmentType": "Foo" }
+const providedVariablesDefinition = { "a": 1 }, other = [2];
+const node: ConcreteRequest = (function () { return {}; })();
+(node as any).hash = "abc";
+if (__DEV__) {
+  (node as any).params = {};
+}
+module.exports = node;
+export default node;
+"#;
+		let tokens: Vec<Token> = tokenize(src)
+			.into_iter()
+			.filter(|t| t.kind != TokenKind::Comment)
+			.collect();
+		let statements = statements(src, &tokens);
+		let text = |r: &Range<usize>| &src[tokens[r.start].start..tokens[r.end - 1].end];
+
+		let kinds: Vec<StatementKind> = statements.iter().map(|s| s.kind).collect();
+		assert_eq!(
+			kinds,
+			vec![
+				StatementKind::Module,
+				StatementKind::TypeDeclaration,
+				StatementKind::TypeDeclaration,
+				StatementKind::Declaration,
+				StatementKind::Declaration,
+				StatementKind::Assignment,
+				StatementKind::Other,
+				StatementKind::Assignment,
+				StatementKind::ExportDefault,
+			]
+		);
+		let initializers: Vec<&str> = statements
+			.iter()
+			.flat_map(|s| s.initializers.iter().map(text))
+			.collect();
+		assert_eq!(
+			initializers,
+			vec![
+				r#"{ "a": 1 }"#,
+				"[2]",
+				"(function () { return {}; })()",
+				r#""abc""#,
+				"node",
+				"node",
+			]
+		);
+		let names: Vec<&str> = statements[3..5]
+			.iter()
+			.flat_map(|s| &s.initializers)
+			.filter_map(|r| declarator_name(src, &tokens, r))
+			.collect();
+		assert_eq!(names, vec!["providedVariablesDefinition", "other", "node"]);
+	}
+}
@@ -2,8 +2,16 @@
 //!
 //! Instead of repeatedly parsing strings, we build a tree once and mutate it.
 
-use crate::normalize::normalize;
-use std::collections::HashSet;
+use crate::atom::{is_atom_name, Atom, ModuleScope};
+use crate::hazard::{mutated_targets, MutatedTarget, Segment};
+use crate::lexer::{tokenize, unquote, Token, TokenKind};
+use crate::naming::{is_local_name, is_ref_name};
+use crate::normalize::{normalize, Normalized};
+use crate::order::OrderRules;
+use crate::scope::{ProtectedPath, Suppression};
+use crate::statement::{declarator_name, statements, StatementKind};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 
 /// A node in the structure tree
 #[derive(Debug, Clone)]
@@ -11,10 +19,15 @@ pub struct Node {
 	pub start: usize,
 	pub end: usize,
 	pub is_array: bool,
-	pub parent: Option<usize>,        // parent node index (for O(1) lookup)
-	pub children: Vec<usize>,         // indices of child nodes
-	pub extracted_as: Option<String>, // ref name if extracted (e.g., "x_abc")
-	pub normalized: Option<String>,   // cached normalized form
+	pub parent: Option<usize>,          // parent node index (for O(1) lookup)
+	pub children: Vec<usize>,           // indices of child nodes
+	pub extracted_as: Option<String>,   // ref name (e.g., "x_abc") or spread text if extracted
+	pub normalized: Option<Normalized>, // cached normalized form
+	pub key: Option<String>,            // object key this node is the value of
+	pub kind: Option<String>,           // an object's own `"kind"` property
+	pub sortable: bool,                 // array whose element order doesn't matter
+	pub protected_by: Option<Suppression>, // rule keeping this node out of extraction
+	pub kept_inline: bool,              // not worth extracting; its parent may still be
 }
 
 /// Tree representation of a file's structure
@@ -22,101 +35,159 @@ pub struct Node {
 pub struct FileTree {
 	pub original: String,
 	pub nodes: Vec<Node>,
-	pub root_nodes: Vec<usize>,       // top-level structure indices
-	serialized_cache: Option<String>, // cached serialization
+	pub root_nodes: Vec<usize>,        // top-level structure indices
+	pub atoms: BTreeMap<String, Atom>, // module references used by normalized forms, by name
+	modules: ModuleScope,              // resolves `require()` calls and imported bindings
+	hoist_offset: Option<usize>,       // start of the first statement holding a root literal
+	locals: Vec<String>,               // file-local declarations, inserted at `hoist_offset`
+	serialized_cache: Option<String>,  // cached serialization
 }
 
 impl FileTree {
-	/// Build a tree from file content (parse once)
-	pub fn new(content: String, order_insensitive_fields: &HashSet<String>) -> Self {
-		let mut nodes: Vec<Node> = Vec::new();
-		let mut stack: Vec<usize> = Vec::new();
-		let mut root_nodes: Vec<usize> = Vec::new();
+	/// Build a tree from file content (parse once).
+	///
+	/// Nodes are built only from the object and array literals of runtime
+	/// initializers (see [`statements`]): imports and type declarations are
+	/// never looked into, and braces in strings, template literals, comments
+	/// and regexes are never punctuation.
+	///
+	/// Relative module references can't be resolved without the artifact's
+	/// path; use [`FileTree::for_artifact`] for files on disk.
+	pub fn new(content: String, order_rules: &OrderRules) -> Self {
+		Self::build(content, None, order_rules)
+	}
 
-		// Find where imports end
-		let import_end = Self::find_import_end(&content);
+	/// Build the tree of the artifact at `path`, resolving module references
+	/// relative to it (`root` keeps their identity machine-independent)
+	pub fn for_artifact(
+		content: String,
+		path: &Path,
+		root: &Path,
+		order_rules: &OrderRules,
+	) -> Self {
+		Self::build(content, Some((path, root)), order_rules)
+	}
 
-		let bytes = content.as_bytes();
-		let mut in_string = false;
-		let mut escape = false;
+	fn build(content: String, location: Option<(&Path, &Path)>, order_rules: &OrderRules) -> Self {
+		let tokens: Vec<Token> = tokenize(&content)
+			.into_iter()
+			.filter(|t| t.kind != TokenKind::Comment)
+			.collect();
+		let modules = location
+			.map(|(path, root)| ModuleScope::new(&content, &tokens, path, root))
+			.unwrap_or_default();
 
-		for (i, &c) in bytes.iter().enumerate().skip(import_end) {
-			if escape {
-				escape = false;
-				continue;
-			}
-			if c == b'\\' {
-				escape = true;
-				continue;
-			}
-			if c == b'"' {
-				in_string = !in_string;
-				continue;
-			}
-			if in_string {
-				continue;
-			}
-
-			if c == b'{' || c == b'[' {
-				let node_idx = nodes.len();
-				let parent_idx = stack.last().copied();
-				nodes.push(Node {
-					start: i,
-					end: 0,
-					is_array: c == b'[',
-					parent: parent_idx,
-					children: Vec::new(),
-					extracted_as: None,
-					normalized: None,
-				});
-
-				if let Some(parent_idx) = parent_idx {
-					nodes[parent_idx].children.push(node_idx);
-				} else {
-					root_nodes.push(node_idx);
+		let mut nodes: Vec<Node> = Vec::new();
+		let mut root_nodes: Vec<usize> = Vec::new();
+		let mut hoist_offset = None;
+
+		// Root literal bound by each declared name
+		let mut bindings: HashMap<String, usize> = HashMap::new();
+
+		let initializers = statements(&content, &tokens).into_iter().flat_map(|s| {
+			let is_declaration = s.kind == StatementKind::Declaration;
+			let statement_start = tokens.get(s.tokens.start).map_or(0, |t| t.start);
+			s.initializers
+				.into_iter()
+				.map(move |r| (r, is_declaration, statement_start))
+		});
+		for (initializer, is_declaration, statement_start) in initializers {
+			let binding = is_declaration
+				.then(|| declarator_name(&content, &tokens, &initializer))
+				.flatten();
+			let tokens = &tokens[initializer];
+			// Open brackets, with the node they started if they opened a literal
+			let mut brackets: Vec<(u8, Option<usize>)> = Vec::new();
+
+			for (k, token) in tokens.iter().enumerate() {
+				if token.kind != TokenKind::Punct {
+					// `"kind": "..."` directly inside an object literal
+					if let (TokenKind::String, [.., open, key, colon], Some((b'{', Some(node)))) =
+						(token.kind, &tokens[..k], brackets.last())
+					{
+						let is_kind = matches!(key.text(&content), "\"kind\"" | "'kind'" | "kind");
+						if is_kind
+							&& colon.is_punct(&content, ":")
+							&& (open.is_punct(&content, "{") || open.is_punct(&content, ","))
+						{
+							nodes[*node]
+								.kind
+								.get_or_insert_with(|| unquote(token.text(&content)));
+						}
+					}
+					continue;
 				}
-				stack.push(node_idx);
-			} else if c == b'}' || c == b']' {
-				if let Some(node_idx) = stack.pop() {
-					nodes[node_idx].end = i + 1;
+				let c = content.as_bytes()[token.start];
+				match token.text(&content) {
+					"{" | "[" => {
+						if !starts_literal(&content, &tokens[..k], &brackets) {
+							brackets.push((c, None));
+							continue;
+						}
+						let node_idx = nodes.len();
+						let parent_idx = brackets.iter().rev().find_map(|(_, node)| *node);
+						nodes.push(Node {
+							start: token.start,
+							end: 0,
+							is_array: c == b'[',
+							parent: parent_idx,
+							children: Vec::new(),
+							extracted_as: None,
+							normalized: None,
+							key: literal_key(&content, &tokens[..k]),
+							kind: None,
+							sortable: false,
+							protected_by: None,
+							kept_inline: false,
+						});
+
+						if let Some(parent_idx) = parent_idx {
+							nodes[parent_idx].children.push(node_idx);
+						} else {
+							root_nodes.push(node_idx);
+							hoist_offset.get_or_insert(statement_start);
+							if let Some(name) = binding.filter(|_| k == 0) {
+								bindings.insert(name.to_string(), node_idx);
+							}
+						}
+						brackets.push((c, Some(node_idx)));
+					}
+					"(" => brackets.push((c, None)),
+					"}" | "]" | ")" => {
+						if let Some((_, Some(node_idx))) = brackets.pop() {
+							nodes[node_idx].end = token.end;
+						}
+					}
+					_ => {}
 				}
 			}
 		}
 
+		let mutated = mutated_targets(&content, &tokens);
 		let mut tree = FileTree {
 			original: content,
 			nodes,
 			root_nodes,
+			atoms: BTreeMap::new(),
+			modules,
+			hoist_offset,
+			locals: Vec::new(),
 			serialized_cache: None,
 		};
 
+		tree.protect_mutated(&mutated, &bindings);
+		for i in 0..tree.nodes.len() {
+			tree.nodes[i].sortable = tree.is_order_insensitive(i, order_rules);
+		}
+
 		// Pre-compute normalized forms for valid leaves
-		tree.compute_normalized_forms(order_insensitive_fields);
+		tree.compute_normalized_forms();
 
 		tree
 	}
 
-	fn find_import_end(content: &str) -> usize {
-		let mut pos = 0;
-		for line in content.lines() {
-			let trimmed = line.trim();
-			if trimmed.is_empty()
-				|| trimmed.starts_with("//")
-				|| trimmed.starts_with("/*")
-				|| trimmed.starts_with("*")
-				|| trimmed.starts_with("*/")
-				|| trimmed.starts_with("import ")
-			{
-				pos += line.len() + 1;
-			} else {
-				break;
-			}
-		}
-		pos
-	}
-
 	/// Pre-compute normalized forms for nodes that could be leaves
-	fn compute_normalized_forms(&mut self, order_insensitive_fields: &HashSet<String>) {
+	fn compute_normalized_forms(&mut self) {
 		for i in 0..self.nodes.len() {
 			// Only compute for potential leaves (no children, valid content)
 			if !self.nodes[i].children.is_empty() {
@@ -127,115 +198,181 @@ impl FileTree {
 			}
 
 			let content = &self.original[self.nodes[i].start..self.nodes[i].end];
-			if content.len() < 15 {
+			let Some((content, atoms)) = self.leaf_content(content) else {
 				continue;
-			}
-
-			if !self.is_valid_leaf_content(content) {
-				continue;
-			}
+			};
+			self.atoms.extend(atoms);
 
-			let can_sort = self.nodes[i].is_array
-				&& self.is_order_insensitive(self.nodes[i].start, order_insensitive_fields);
-			let normalized = normalize(content, can_sort);
+			let normalized = normalize(&content, self.nodes[i].sortable);
 			self.nodes[i].normalized = Some(normalized);
 		}
 	}
 
-	/// Check if content is a valid leaf (no invalid identifiers)
-	fn is_valid_leaf_content(&self, content: &str) -> bool {
-		let inner = &content[1..content.len() - 1];
-		let inner = inner.trim();
+	/// Replace module references in `content` by atoms and check it's a
+	/// valid leaf. Returns the content and the atoms it refers to.
+	fn leaf_content(&self, content: &str) -> Option<(String, Vec<(String, Atom)>)> {
+		let tokens: Vec<Token> = tokenize(content)
+			.into_iter()
+			.filter(|t| t.kind != TokenKind::Comment)
+			.collect();
+		let (content, atoms) = self.modules.atomize(content, &tokens);
+		Self::is_valid_leaf_content(&content).then_some((content, atoms))
+	}
 
-		if inner.is_empty() {
+	/// Check if content is a valid leaf: a JSON-like literal whose only
+	/// identifiers are our refs and `null` / `true` / `false` (spreads of a
+	/// ref are ours too, see [`crate::factor`])
+	fn is_valid_leaf_content(content: &str) -> bool {
+		let tokens: Vec<Token> = tokenize(content)
+			.into_iter()
+			.filter(|t| t.kind != TokenKind::Comment)
+			.collect();
+
+		// Empty literals aren't worth extracting
+		if tokens.len() <= 2 {
 			return false;
 		}
 
-		let mut in_string = false;
-		let mut escape = false;
-		let mut ident = String::new();
+		tokens.iter().all(|t| match t.kind {
+			TokenKind::Punct => matches!(
+				t.text(content),
+				"{" | "}" | "[" | "]" | ":" | "," | "-" | "..."
+			),
+			// Only double-quoted strings survive normalization unchanged
+			TokenKind::String => content[t.start..].starts_with('"'),
+			TokenKind::Number => true,
+			TokenKind::Ident => Self::is_valid_identifier(t.text(content)),
+			TokenKind::Template | TokenKind::Regex | TokenKind::Comment => false,
+		})
+	}
 
-		for c in inner.chars() {
-			if escape {
-				escape = false;
-				continue;
-			}
-			if c == '\\' {
-				escape = true;
-				continue;
-			}
-			if c == '"' {
-				in_string = !in_string;
-				continue;
-			}
-			if in_string {
-				continue;
-			}
+	fn is_valid_identifier(ident: &str) -> bool {
+		// Our refs (x_XXX, 3+ hex) and module atoms. Legacy `_XXXXXXXX` refs
+		// are expanded by `relay-dedup migrate` before anything is parsed.
+		is_ref_name(ident)
+			|| is_local_name(ident)
+			|| is_atom_name(ident)
+			|| matches!(ident, "null" | "true" | "false")
+	}
+
+	/// Whether an array's elements may be sorted: it is the value of a key
+	/// `rules` allow for the kind of object holding it
+	fn is_order_insensitive(&self, node_idx: usize, rules: &OrderRules) -> bool {
+		let node = &self.nodes[node_idx];
+		let (true, Some(key), Some(parent)) = (node.is_array, &node.key, node.parent) else {
+			return false;
+		};
+		let element_kinds = node
+			.children
+			.iter()
+			.filter_map(|&c| self.nodes[c].kind.as_deref());
+		rules.allows(self.nodes[parent].kind.as_deref(), key, element_kinds)
+	}
 
-			if c.is_ascii_alphanumeric() || c == '_' {
-				ident.push(c);
-			} else if !ident.is_empty() {
-				if !Self::is_valid_identifier(&ident) {
-					return false;
+	/// Object keys leading from the root literal to a node (array levels add no key)
+	pub fn key_path(&self, node_idx: usize) -> Vec<&str> {
+		let mut path = Vec::new();
+		let mut current = Some(node_idx);
+		while let Some(idx) = current {
+			let node = &self.nodes[idx];
+			// Root literals are values of declarations, not of object keys
+			if node.parent.is_some() {
+				if let Some(ref key) = node.key {
+					path.push(key.as_str());
 				}
-				ident.clear();
 			}
+			current = node.parent;
 		}
-
-		ident.is_empty() || Self::is_valid_identifier(&ident)
+		path.reverse();
+		path
 	}
 
-	fn is_valid_identifier(ident: &str) -> bool {
-		// Our refs: x_XXX (3+ hex) or _XXXXXXXX (8 hex)
-		if ident.starts_with("x_") && ident.len() >= 4 {
-			return ident[2..].chars().all(|c| c.is_ascii_hexdigit());
+	/// Mark every node at or below one of the protected key paths.
+	///
+	/// `protected_by` records the first matching path (earlier protection wins).
+	pub fn protect_paths(&mut self, paths: &[ProtectedPath]) {
+		if paths.is_empty() {
+			return;
 		}
-		if ident.starts_with('_') && ident.len() == 9 {
-			return ident[1..].chars().all(|c| c.is_ascii_hexdigit());
+		for idx in 0..self.nodes.len() {
+			let key_path = self.key_path(idx);
+			if let Some(path) = paths.iter().find(|p| p.covers(&key_path)) {
+				let rule = Suppression::ProtectedPath(path.source.clone());
+				self.protect(idx, rule);
+			}
 		}
-		matches!(ident, "null" | "true" | "false") || ident.chars().all(|c| c.is_ascii_digit())
 	}
 
-	fn is_order_insensitive(&self, pos: usize, fields: &HashSet<String>) -> bool {
-		// Look backwards for field name
-		let bytes = self.original.as_bytes();
-		let mut i = pos.saturating_sub(1);
-
-		// Skip whitespace
-		while i > 0 && bytes[i].is_ascii_whitespace() {
-			i -= 1;
-		}
-		// Should be colon
-		if i == 0 || bytes[i] != b':' {
-			return false;
-		}
-		i -= 1;
-		// Skip whitespace
-		while i > 0 && bytes[i].is_ascii_whitespace() {
-			i -= 1;
+	/// Mark object nodes whose `"kind"` is one of the identity-sensitive
+	/// `kinds`: Relay uses them as cache keys, so they must stay distinct
+	pub fn protect_kinds(&mut self, kinds: &[String]) {
+		if kinds.is_empty() {
+			return;
 		}
-		// Should be quote
-		if i == 0 || bytes[i] != b'"' {
-			return false;
+		for idx in 0..self.nodes.len() {
+			if let Some(kind) = self.nodes[idx].kind.clone().filter(|k| kinds.contains(k)) {
+				self.protect(idx, Suppression::IdentitySensitive(kind));
+			}
 		}
-		i -= 1;
-		// Read field name backwards
-		let mut name = String::new();
-		while i > 0 && bytes[i] != b'"' {
-			name.insert(0, bytes[i] as char);
-			i -= 1;
+	}
+
+	/// Mark the objects the artifact's own statements mutate.
+	///
+	/// A target that resolves to a node protects just that node (its
+	/// ancestors can't be extracted without it). One that can't be followed
+	/// all the way (computed keys, array indices, values that aren't
+	/// literals) protects everything below the deepest node it reaches.
+	fn protect_mutated(&mut self, targets: &[MutatedTarget], bindings: &HashMap<String, usize>) {
+		for target in targets {
+			let Some(&root) = bindings.get(&target.binding) else {
+				continue;
+			};
+			let rule = Suppression::MutatedTarget(target.to_string());
+			let mut idx = root;
+			let mut resolved = true;
+			for segment in &target.path {
+				let node = &self.nodes[idx];
+				let child = match segment {
+					Segment::Key(key) if !node.is_array => node
+						.children
+						.iter()
+						.copied()
+						.find(|&c| self.nodes[c].key.as_ref() == Some(key)),
+					_ => None,
+				};
+				match child {
+					Some(child) => idx = child,
+					None => {
+						resolved = false;
+						break;
+					}
+				}
+			}
+			if resolved {
+				self.protect(idx, rule);
+			} else {
+				let (start, end) = (self.nodes[idx].start, self.nodes[idx].end);
+				for i in 0..self.nodes.len() {
+					if self.nodes[i].start >= start && self.nodes[i].end <= end {
+						self.protect(i, rule.clone());
+					}
+				}
+			}
 		}
+	}
 
-		fields.contains(&name)
+	fn protect(&mut self, idx: usize, rule: Suppression) {
+		self.nodes[idx].protected_by.get_or_insert(rule);
 	}
 
-	/// Find current leaves (nodes where all children are extracted OR no children)
+	/// Find current leaves (nodes where all children are settled OR no
+	/// children), with their matching keys
 	pub fn find_leaves(&self) -> Vec<(usize, String)> {
 		let mut leaves = Vec::new();
 
 		for (idx, node) in self.nodes.iter().enumerate() {
-			// Skip already extracted
-			if node.extracted_as.is_some() {
+			// Skip already extracted or kept inline
+			if self.is_settled(idx) {
 				continue;
 			}
 			// Must have normalized form (valid leaf)
@@ -243,66 +380,149 @@ impl FileTree {
 				continue;
 			};
 
-			// All children must be extracted
-			let all_children_extracted = node
-				.children
-				.iter()
-				.all(|&child_idx| self.nodes[child_idx].extracted_as.is_some());
+			// All children must be extracted or kept inline
+			let all_children_settled = node.children.iter().all(|&c| self.is_settled(c));
 
-			if all_children_extracted {
-				leaves.push((idx, normalized.clone()));
+			if all_children_settled {
+				leaves.push((idx, normalized.key.clone()));
 			}
 		}
 
 		leaves
 	}
 
-	/// Mark a node as extracted
-	pub fn mark_extracted(
-		&mut self,
-		node_idx: usize,
-		ref_name: String,
-		order_insensitive_fields: &HashSet<String>,
-	) {
+	/// A leaf's normalized text with its literals as written, keys sorted
+	/// and without whitespace
+	pub fn leaf_text(&self, node_idx: usize) -> Option<&str> {
+		self.nodes[node_idx]
+			.normalized
+			.as_ref()
+			.map(|normalized| normalized.text.as_str())
+	}
+
+	/// A leaf as written in the file: its keys and elements in their
+	/// original order, settled children replaced by their names and module
+	/// references by atoms. Lines after the first lose the indentation of
+	/// the line the leaf starts on.
+	pub fn leaf_source(&self, node_idx: usize) -> Option<String> {
+		self.nodes[node_idx].normalized.as_ref()?;
+		let (content, _) = self.leaf_content(&self.get_current_content(node_idx))?;
+		let indent = self.line_indent(self.nodes[node_idx].start);
+		if indent.is_empty() || !content.contains('\n') {
+			return Some(content);
+		}
+		let lines: Vec<&str> = content
+			.split('\n')
+			.map(|l| l.strip_prefix(indent).unwrap_or(l))
+			.collect();
+		Some(lines.join("\n"))
+	}
+
+	/// `text` indented to replace the node at `node_idx`: lines after the
+	/// first get the indentation of the line the node starts on (the
+	/// reverse of [`FileTree::leaf_source`])
+	pub fn indented(&self, node_idx: usize, text: &str) -> String {
+		let indent = self.line_indent(self.nodes[node_idx].start);
+		if indent.is_empty() || !text.contains('\n') {
+			return text.to_string();
+		}
+		text.split('\n')
+			.enumerate()
+			.map(|(i, line)| match i {
+				0 => line.to_string(),
+				_ if line.is_empty() => String::new(),
+				_ => format!("{}{}", indent, line),
+			})
+			.collect::<Vec<_>>()
+			.join("\n")
+	}
+
+	/// Indentation of the line holding `offset`
+	fn line_indent(&self, offset: usize) -> &str {
+		let line = &self.original[self.original[..offset].rfind('\n').map_or(0, |i| i + 1)..];
+		&line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+	}
+
+	/// Mark a node as extracted. `ref_name` replaces it in the output: a
+	/// name, or the spread of a shared base written for a near-duplicate
+	/// (`{...x_abc,"name":"id"}`).
+	pub fn mark_extracted(&mut self, node_idx: usize, ref_name: String) {
 		self.nodes[node_idx].extracted_as = Some(ref_name);
 		self.serialized_cache = None; // invalidate cache
 
 		// Parent might now be a valid leaf - recompute its normalized form
-		self.update_parent_normalized(node_idx, order_insensitive_fields);
+		self.update_parent_normalized(node_idx);
+	}
+
+	/// Add file-local declarations (e.g. `const l_abc = ...;`), inserted in
+	/// order before the first statement holding a root literal. Declarations
+	/// already present are skipped.
+	pub fn hoist(&mut self, declarations: impl IntoIterator<Item = String>) {
+		for declaration in declarations {
+			if !self.locals.contains(&declaration) {
+				self.locals.push(declaration);
+			}
+		}
+		self.serialized_cache = None;
+	}
+
+	/// Keep a node inline for good: it is never extracted on its own, but
+	/// its parent may be extracted with it
+	pub fn keep_inline(&mut self, node_idx: usize) {
+		self.nodes[node_idx].kept_inline = true;
+		self.update_parent_normalized(node_idx);
+	}
+
+	/// Whether a node has been extracted or kept inline
+	fn is_settled(&self, node_idx: usize) -> bool {
+		let node = &self.nodes[node_idx];
+		node.extracted_as.is_some() || node.kept_inline
+	}
+
+	/// Current length of a node's content (extracted children count as their names)
+	pub fn content_len(&self, node_idx: usize) -> usize {
+		let node = &self.nodes[node_idx];
+		let children: usize = node
+			.children
+			.iter()
+			.map(|&c| {
+				let child = &self.nodes[c];
+				match child.extracted_as {
+					Some(ref name) => child.end - child.start - name.len(),
+					None => child.end - child.start - self.content_len(c),
+				}
+			})
+			.sum();
+		node.end - node.start - children
 	}
 
-	/// After extracting a child, parent might become a valid leaf
-	fn update_parent_normalized(
-		&mut self,
-		child_idx: usize,
-		order_insensitive_fields: &HashSet<String>,
-	) {
+	/// After settling a child, parent might become a valid leaf
+	fn update_parent_normalized(&mut self, child_idx: usize) {
 		// O(1) parent lookup
 		let Some(parent_idx) = self.nodes[child_idx].parent else {
 			return;
 		};
 
-		// Recompute normalized form for parent if not already extracted
-		if self.nodes[parent_idx].extracted_as.is_some() {
+		// Recompute normalized form for parent if not already settled (and
+		// balanced)
+		if self.is_settled(parent_idx) || self.nodes[parent_idx].end == 0 {
 			return;
 		}
 
-		// Check if all children are now extracted
-		let all_extracted = self.nodes[parent_idx]
+		// Check if all children are now settled
+		let all_settled = self.nodes[parent_idx]
 			.children
 			.iter()
-			.all(|&c| self.nodes[c].extracted_as.is_some());
+			.all(|&c| self.is_settled(c));
 
-		if !all_extracted {
+		if !all_settled {
 			return;
 		}
 
 		let content = self.get_current_content(parent_idx);
-		if content.len() >= 15 && self.is_valid_leaf_content(&content) {
-			let can_sort = self.nodes[parent_idx].is_array
-				&& self
-					.is_order_insensitive(self.nodes[parent_idx].start, order_insensitive_fields);
-			let normalized = normalize(&content, can_sort);
+		if let Some((content, atoms)) = self.leaf_content(&content) {
+			self.atoms.extend(atoms);
+			let normalized = normalize(&content, self.nodes[parent_idx].sortable);
 			self.nodes[parent_idx].normalized = Some(normalized);
 		}
 	}
@@ -387,6 +607,16 @@ impl FileTree {
 
 		replacements.sort_by_key(|(start, _, _)| *start);
 
+		// Local declarations go before every replacement
+		if let (Some(offset), false) = (self.hoist_offset, self.locals.is_empty()) {
+			result.push_str(&self.original[..offset]);
+			for declaration in &self.locals {
+				result.push_str(declaration);
+				result.push('\n');
+			}
+			last_end = offset;
+		}
+
 		for (start, end, ref_name) in replacements {
 			result.push_str(&self.original[last_end..start]);
 			result.push_str(ref_name);
@@ -398,6 +628,81 @@ impl FileTree {
 	}
 }
 
+/// Whether a `{` / `[` following `before` (within one initializer) opens an
+/// object or array literal.
+///
+/// The initializer's first token always does. Otherwise it's decided by the
+/// preceding token: a literal follows `=`, `(`, `return` and the like, while
+/// a block follows `)` / `=>`. Inside a literal every `:`, `,` and `[`
+/// introduces a value; outside one `:` is a type annotation and `,` only
+/// separates call arguments.
+fn starts_literal(src: &str, before: &[Token], brackets: &[(u8, Option<usize>)]) -> bool {
+	let Some(prev) = before.last() else {
+		return true;
+	};
+	let innermost = brackets.last();
+	let in_literal = matches!(innermost, Some((_, Some(_))));
+	match (prev.kind, prev.text(src)) {
+		(TokenKind::Punct, "=" | "(" | "?" | "??" | "||" | "&&" | "...") => true,
+		(TokenKind::Punct, ":" | "[") => in_literal,
+		(TokenKind::Punct, ",") => in_literal || matches!(innermost, Some((b'(', None))),
+		(TokenKind::Ident, "return" | "yield") => true,
+		_ => false,
+	}
+}
+
+/// The object key (`"name":` or `name:`) in front of a literal, if any
+fn literal_key(src: &str, before: &[Token]) -> Option<String> {
+	let [.., key, colon] = before else {
+		return None;
+	};
+	if !colon.is_punct(src, ":") {
+		return None;
+	}
+	let text = key.text(src);
+	match key.kind {
+		TokenKind::String if text.len() >= 2 => Some(text[1..text.len() - 1].to_string()),
+		TokenKind::Ident | TokenKind::Number => Some(text.to_string()),
+		_ => None,
+	}
+}
+
+/// Whether a (trimmed) line is a top-level `import` or a CommonJS
+/// `const { ... } = require(...)` statement.
+pub(crate) fn is_module_import(trimmed: &str) -> bool {
+	if trimmed.starts_with("import ") {
+		return true;
+	}
+	let is_declaration = ["const ", "var ", "let "]
+		.iter()
+		.any(|kw| trimmed.starts_with(kw));
+	is_declaration && trimmed.contains("= require(") && trimmed.ends_with(");")
+}
+
+/// If a comment starts at `i`, return the index just past its end.
+pub(crate) fn comment_end(bytes: &[u8], i: usize) -> Option<usize> {
+	if bytes.get(i) != Some(&b'/') {
+		return None;
+	}
+	match bytes.get(i + 1) {
+		Some(b'*') => {
+			let mut j = i + 2;
+			while j + 1 < bytes.len() && !(bytes[j] == b'*' && bytes[j + 1] == b'/') {
+				j += 1;
+			}
+			Some((j + 2).min(bytes.len()))
+		}
+		Some(b'/') => {
+			let mut j = i + 2;
+			while j < bytes.len() && bytes[j] != b'\n' {
+				j += 1;
+			}
+			Some(j)
+		}
+		_ => None,
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
@@ -405,7 +710,7 @@ mod tests {
 	#[test]
 	fn test_build_tree() {
 		let content = r#"const x = {"a": 1, "b": [{"c": 2}]};"#.to_string();
-		let tree = FileTree::new(content, &HashSet::new());
+		let tree = FileTree::new(content, &OrderRules::default());
 
 		assert!(!tree.nodes.is_empty());
 	}
@@ -413,9 +718,231 @@ mod tests {
 	#[test]
 	fn test_find_leaves() {
 		let content = r#"const x = {"kind": "Field", "name": "id"};"#.to_string();
-		let tree = FileTree::new(content, &HashSet::new());
+		let tree = FileTree::new(content, &OrderRules::default());
 
 		let leaves = tree.find_leaves();
 		assert!(!leaves.is_empty());
 	}
+
+	#[test]
+	fn test_leaf_source() {
+		let content = "const node = {\n  \"b\": {\n    \"name\": \"id\",\n    \"kind\": \"Field\"\n  },\n  \"a\": 1\n};"
+			.to_string();
+		let mut tree = FileTree::new(content, &OrderRules::default());
+		let inner = tree.nodes[0].children[0];
+		assert_eq!(
+			tree.leaf_source(inner).unwrap(),
+			"{\n  \"name\": \"id\",\n  \"kind\": \"Field\"\n}"
+		);
+		assert_eq!(
+			tree.leaf_text(inner),
+			Some(r#"{"kind":"Field","name":"id"}"#)
+		);
+		tree.mark_extracted(inner, "x_abc".to_string());
+		assert_eq!(
+			tree.leaf_source(0).unwrap(),
+			"{\n  \"b\": x_abc,\n  \"a\": 1\n}"
+		);
+	}
+
+	#[test]
+	fn test_key_path_and_protection() {
+		let content = r#"const node = {
+  "fragment": {"selections": [{"kind": "ScalarField", "name": "id"}]},
+  "params": {"metadata": {"refetch": {"operation": "Foo"}}}
+};"#
+		.to_string();
+		let mut tree = FileTree::new(content, &OrderRules::default());
+
+		let field = tree
+			.nodes
+			.iter()
+			.position(|n| n.key.is_none() && n.parent.is_some())
+			.unwrap();
+		assert_eq!(tree.key_path(field), vec!["fragment", "selections"]);
+
+		tree.protect_paths(&[
+			ProtectedPath::parse("fragment.argumentDefinitions"),
+			ProtectedPath::parse("params.metadata"),
+		]);
+		let protected: Vec<_> = tree
+			.nodes
+			.iter()
+			.enumerate()
+			.filter(|(_, n)| n.protected_by.is_some())
+			.map(|(i, _)| tree.key_path(i).join("."))
+			.collect();
+		assert_eq!(
+			protected,
+			vec!["params.metadata", "params.metadata.refetch"]
+		);
+	}
+
+	#[test]
+	fn test_mutated_and_identity_sensitive_nodes() {
+		let content = r#"const node: ConcreteRequest = {
+  "fragment": {"kind": "Fragment", "selections": [{"kind": "ScalarField", "name": "id"}]},
+  "params": {"metadata": {"refetch": {"operation": "Foo"}}, "text": null},
+  "items": [{"a": {"b": 1}}]
+};
+(node as any).hash = "abc";
+if (__DEV__) {
+  (node as any).params.text = "query Foo { id }";
+}
+node.items[0].a.b = 2;
+"#
+		.to_string();
+		let mut tree = FileTree::new(content, &OrderRules::default());
+		tree.protect_kinds(&["Fragment".to_string()]);
+
+		let mut protected: Vec<_> = tree
+			.nodes
+			.iter()
+			.enumerate()
+			.filter_map(|(i, n)| {
+				let rule = n.protected_by.as_ref()?;
+				Some(format!("{} ({})", tree.key_path(i).join("."), rule))
+			})
+			.collect();
+		protected.sort();
+		assert_eq!(
+			protected,
+			vec![
+				" (mutated target \"node\")",
+				"fragment (identity-sensitive kind \"Fragment\")",
+				// Array indices aren't followed: everything below is kept
+				"items (mutated target \"node.items[…].a\")",
+				"items (mutated target \"node.items[…].a\")",
+				"items.a (mutated target \"node.items[…].a\")",
+				"params (mutated target \"node.params\")",
+			]
+		);
+	}
+
+	#[test]
+	fn test_sortable_arrays() {
+		let content = r#"const node = {"kind": "Fragment", "selections": [
+			{"kind": "LinkedField", "args": [{"b": 2}, {"a": 1}], "selections": [{"x": 1}, {"y": 2}]},
+			{"kind": "Defer", "selections": [{"y": 2}, {"x": 1}]},
+			{"kind": "InlineFragment", "selections": [{"kind": "Stream", "x": 1}, {"y": 2}]}
+		]};"#
+			.to_string();
+		let tree = FileTree::new(content, &OrderRules::default());
+
+		let mut sortable: Vec<String> = (0..tree.nodes.len())
+			.filter(|&i| tree.nodes[i].sortable)
+			.map(|i| tree.key_path(i).join("."))
+			.collect();
+		sortable.sort();
+		// Neither the Defer's selections nor those holding a Stream
+		assert_eq!(sortable, vec!["selections.args", "selections.selections"]);
+		let defer = tree
+			.nodes
+			.iter()
+			.position(|n| n.kind.as_deref() == Some("Defer"))
+			.unwrap();
+		assert!(!tree.nodes[tree.nodes[defer].children[0]].sortable);
+	}
+
+	#[test]
+	fn test_flow_annotation_comments() {
+		let content = r#"/**
+ * @flow
+ */
+
+'use strict';
+
+/*::
+import type { ConcreteRequest } from 'relay-runtime';
+export type Foo$data = {|
+  +id: string,
+|};
+*/
+
+var node/*: ConcreteRequest*/ = {
+  "kind": "Fragment",
+  "selections": [
+    {"kind": "ScalarField", "name": "id"/*: string*/}
+  ]
+};
+
+module.exports = ((node/*: any*/)/*: Fragment<{| +id: string |}>*/);
+"#
+		.to_string();
+		let tree = FileTree::new(content, &OrderRules::default());
+
+		// Only the runtime literal's object/array/object are nodes - nothing
+		// from the type block or the trailing annotation
+		assert_eq!(tree.nodes.len(), 3);
+		assert!(tree.nodes.iter().all(|n| n.end != 0));
+
+		// Annotation comments are ignored for matching
+		let leaves = tree.find_leaves();
+		assert_eq!(leaves.len(), 1);
+		assert_eq!(leaves[0].1, r#"{"kind":"ScalarField","name":"id"}"#);
+	}
+
+	#[test]
+	fn test_hostile_artifact() {
+		let content = r#"// { unbalanced [ in a comment
+import type { ConcreteRequest } from 'relay-runtime';
+import { x_abc } from "./__shared";
+
+const pattern = /[{(]/g;
+const label = '{ "kind": "Fake" }' + `[${ {a: [1]}.a }`;
+export type Foo$data = { readonly id: string; readonly list: ReadonlyArray<{ readonly n: number }> };
+
+const node: ConcreteRequest = {
+  "name": "x_abc has { braces [",
+  "selections": [x_abc, {"kind": "ScalarField", "name": "id"}], /* } */
+  "text": 'single { quoted'
+};
+export default node;
+"#
+		.to_string();
+		let tree = FileTree::new(content, &OrderRules::default());
+
+		// The node literal, its selections array and the field object
+		assert_eq!(tree.nodes.len(), 3);
+		assert_eq!(tree.root_nodes.len(), 1);
+		assert!(tree.nodes.iter().all(|n| n.end != 0));
+		assert_eq!(tree.nodes[1].key.as_deref(), Some("selections"));
+
+		let leaves = tree.find_leaves();
+		assert_eq!(leaves.len(), 1);
+		assert_eq!(leaves[0].1, r#"{"kind":"ScalarField","name":"id"}"#);
+	}
+
+	#[test]
+	fn test_leaf_content_must_be_json_like() {
+		for literal in [
+			r#"{"name": 'single quoted'}"#,
+			r#"{"name": `template ${x}`}"#,
+			r#"{"pattern": /[a-z]+/g}"#,
+			r#"{"kind": "Field", "name": someVariable}"#,
+		] {
+			let tree = FileTree::new(format!("const node = {};", literal), &OrderRules::default());
+			assert!(tree.find_leaves().is_empty(), "{}", literal);
+		}
+	}
+
+	#[test]
+	fn test_type_declarations_are_never_nodes() {
+		// Type bodies made only of string literal types look like JSON
+		let content = r#"import type { ReaderFragment } from 'relay-runtime';
+export type Foo$fragmentType = { "kind": "ScalarField", "name": "id" };
+export interface Foo$data { "kind": "ScalarField", "name": "id" }
+type Foo$key = ReadonlyArray<{ "kind": "ScalarField", "name": "id" }>
+const node: ReaderFragment = {
+  "selections": [{"kind": "ScalarField", "name": "id"}]
+};
+export default node;
+"#
+		.to_string();
+		let tree = FileTree::new(content, &OrderRules::default());
+
+		let body_start = tree.original.find("const node").unwrap();
+		assert_eq!(tree.nodes.len(), 3);
+		assert!(tree.nodes.iter().all(|n| n.start > body_start));
+	}
 }
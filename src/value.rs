@@ -0,0 +1,335 @@
+//! Value AST of leaf content.
+//!
+//! Leaves are JSON-like literals that may also hold names: `x_` refs to the
+//! shared module, `l_` file-local names and `m_` module atoms. Parsing them
+//! into a [`Value`] with names as leaves of their own lets objects be put in
+//! canonical key order at every depth, whatever has been extracted below.
+
+use crate::literal::{canonical, canonical_key};
+use std::fmt;
+
+/// A parsed literal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value<'a> {
+	/// `null`, `true`, `false`, a number or a string, as written
+	Literal(&'a str),
+	/// A shared ref, file-local name or module atom
+	Ref(&'a str),
+	Array(Vec<Value<'a>>),
+	Object(Vec<Member<'a>>),
+	/// `...value` as an array element
+	Spread(Box<Value<'a>>),
+}
+
+/// One member of an object literal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Member<'a> {
+	/// `key: value`, the key as written (quoted or a number)
+	Pair(&'a str, Value<'a>),
+	/// `...value`
+	Spread(Value<'a>),
+}
+
+impl<'a> Value<'a> {
+	/// Parse a whole literal. Every identifier but `null`, `true` and `false`
+	/// is taken for a name. Returns `None` for anything else, e.g. unquoted
+	/// keys or unbalanced brackets.
+	pub fn parse(text: &'a str) -> Option<Self> {
+		let mut parser = Parser { text, pos: 0 };
+		let value = parser.value()?;
+		parser.skip_whitespace();
+		(parser.pos == text.len()).then_some(value)
+	}
+
+	/// Sort the members of every object by the property name of their key.
+	/// Objects with spreads keep their order: a later member overrides what
+	/// the spread brings in.
+	pub fn sort_keys(&mut self) {
+		match self {
+			Value::Array(elements) => elements.iter_mut().for_each(Value::sort_keys),
+			Value::Spread(value) => value.sort_keys(),
+			Value::Object(members) => {
+				let mut has_spread = false;
+				for member in members.iter_mut() {
+					match member {
+						Member::Pair(_, value) => value.sort_keys(),
+						Member::Spread(value) => {
+							value.sort_keys();
+							has_spread = true;
+						}
+					}
+				}
+				if !has_spread {
+					// Stable: of repeated keys, the last one still wins
+					members.sort_by_cached_key(|member| match member {
+						Member::Pair(key, _) => canonical_key(key).into_owned(),
+						Member::Spread(_) => String::new(),
+					});
+				}
+			}
+			Value::Literal(_) | Value::Ref(_) => {}
+		}
+	}
+}
+
+impl Value<'_> {
+	/// Compact form with every literal and key in its canonical spelling
+	/// (see [`crate::literal`]): equal for values that only differ in how
+	/// their literals are written
+	pub fn canonical(&self) -> String {
+		Canonical(self).to_string()
+	}
+
+	fn write(&self, f: &mut fmt::Formatter<'_>, canonical_literals: bool) -> fmt::Result {
+		match self {
+			Value::Literal(text) if canonical_literals => f.write_str(&canonical(text)),
+			Value::Literal(text) | Value::Ref(text) => f.write_str(text),
+			Value::Spread(value) => {
+				f.write_str("...")?;
+				value.write(f, canonical_literals)
+			}
+			Value::Array(elements) => {
+				f.write_str("[")?;
+				for (i, element) in elements.iter().enumerate() {
+					if i > 0 {
+						f.write_str(",")?;
+					}
+					element.write(f, canonical_literals)?;
+				}
+				f.write_str("]")
+			}
+			Value::Object(members) => {
+				f.write_str("{")?;
+				for (i, member) in members.iter().enumerate() {
+					if i > 0 {
+						f.write_str(",")?;
+					}
+					member.write(f, canonical_literals)?;
+				}
+				f.write_str("}")
+			}
+		}
+	}
+}
+
+impl Member<'_> {
+	fn write(&self, f: &mut fmt::Formatter<'_>, canonical_literals: bool) -> fmt::Result {
+		match self {
+			Member::Pair(key, value) if canonical_literals => {
+				write!(f, "{}:", canonical_key(key))?;
+				value.write(f, true)
+			}
+			Member::Pair(key, value) => {
+				write!(f, "{}:", key)?;
+				value.write(f, false)
+			}
+			Member::Spread(value) => {
+				f.write_str("...")?;
+				value.write(f, canonical_literals)
+			}
+		}
+	}
+}
+
+/// Compact form, without whitespace, literals as written
+impl fmt::Display for Value<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.write(f, false)
+	}
+}
+
+impl fmt::Display for Member<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.write(f, false)
+	}
+}
+
+/// Display adapter for [`Value::canonical`]
+struct Canonical<'v, 'a>(&'v Value<'a>);
+
+impl fmt::Display for Canonical<'_, '_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.0.write(f, true)
+	}
+}
+
+struct Parser<'a> {
+	text: &'a str,
+	pos: usize,
+}
+
+impl<'a> Parser<'a> {
+	fn peek(&self) -> Option<u8> {
+		self.text.as_bytes().get(self.pos).copied()
+	}
+
+	fn skip_whitespace(&mut self) {
+		while self.peek().is_some_and(|b| b.is_ascii_whitespace()) {
+			self.pos += 1;
+		}
+	}
+
+	/// Consume `token` if it comes next
+	fn eat(&mut self, token: &str) -> bool {
+		self.skip_whitespace();
+		let found = self.text[self.pos..].starts_with(token);
+		if found {
+			self.pos += token.len();
+		}
+		found
+	}
+
+	fn value(&mut self) -> Option<Value<'a>> {
+		self.skip_whitespace();
+		match self.peek()? {
+			b'{' => self.object(),
+			b'[' => self.array(),
+			b'"' => self.string().map(Value::Literal),
+			b'-' | b'0'..=b'9' | b'.' => self.number().map(Value::Literal),
+			_ => {
+				let word = self.word()?;
+				Some(match word {
+					"null" | "true" | "false" => Value::Literal(word),
+					_ => Value::Ref(word),
+				})
+			}
+		}
+	}
+
+	fn array(&mut self) -> Option<Value<'a>> {
+		self.pos += 1;
+		let mut elements = Vec::new();
+		if self.eat("]") {
+			return Some(Value::Array(elements));
+		}
+		loop {
+			let element = if self.eat("...") {
+				Value::Spread(Box::new(self.value()?))
+			} else {
+				self.value()?
+			};
+			elements.push(element);
+			if self.eat("]") {
+				return Some(Value::Array(elements));
+			}
+			if !self.eat(",") {
+				return None;
+			}
+		}
+	}
+
+	fn object(&mut self) -> Option<Value<'a>> {
+		self.pos += 1;
+		let mut members = Vec::new();
+		if self.eat("}") {
+			return Some(Value::Object(members));
+		}
+		loop {
+			let member = if self.eat("...") {
+				Member::Spread(self.value()?)
+			} else {
+				self.skip_whitespace();
+				let key = match self.peek()? {
+					b'"' => self.string()?,
+					b'0'..=b'9' => self.number()?,
+					_ => return None,
+				};
+				if !self.eat(":") {
+					return None;
+				}
+				Member::Pair(key, self.value()?)
+			};
+			members.push(member);
+			if self.eat("}") {
+				return Some(Value::Object(members));
+			}
+			if !self.eat(",") {
+				return None;
+			}
+		}
+	}
+
+	/// A double-quoted string, quotes included
+	fn string(&mut self) -> Option<&'a str> {
+		let start = self.pos;
+		let bytes = self.text.as_bytes();
+		let mut i = start + 1;
+		while i < bytes.len() {
+			match bytes[i] {
+				b'\\' => i += 2,
+				b'"' => {
+					self.pos = i + 1;
+					return Some(&self.text[start..self.pos]);
+				}
+				_ => i += 1,
+			}
+		}
+		None
+	}
+
+	fn number(&mut self) -> Option<&'a str> {
+		let start = self.pos;
+		let bytes = self.text.as_bytes();
+		let mut i = start;
+		while i < bytes.len() {
+			let b = bytes[i];
+			let sign =
+				(b == b'-' || b == b'+') && (i == start || matches!(bytes[i - 1], b'e' | b'E'));
+			if !(b.is_ascii_alphanumeric() || b == b'.' || sign) {
+				break;
+			}
+			i += 1;
+		}
+		self.pos = i;
+		(i > start).then(|| &self.text[start..i])
+	}
+
+	/// An identifier
+	fn word(&mut self) -> Option<&'a str> {
+		let start = self.pos;
+		let bytes = self.text.as_bytes();
+		let mut i = start;
+		while i < bytes.len()
+			&& (bytes[i].is_ascii_alphanumeric() || matches!(bytes[i], b'_' | b'$'))
+		{
+			i += 1;
+		}
+		if i == start || bytes[start].is_ascii_digit() {
+			return None;
+		}
+		self.pos = i;
+		Some(&self.text[start..i])
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_and_sort_keys() {
+		let text = r#"{"b":[2,{"d":x_ab1,"c":-1.5e3}],"a":m_1a2b3c4d,"e":"x\"y"}"#;
+		let mut value = Value::parse(text).unwrap();
+		assert_eq!(value.to_string(), text);
+		value.sort_keys();
+		assert_eq!(
+			value.to_string(),
+			r#"{"a":m_1a2b3c4d,"b":[2,{"c":-1.5e3,"d":x_ab1}],"e":"x\"y"}"#
+		);
+
+		// Spreads keep the members after them in place
+		let mut value = Value::parse(r#"[...x_ab2,{...x_ab1,"b":1,"a":2}]"#).unwrap();
+		value.sort_keys();
+		assert_eq!(value.to_string(), r#"[...x_ab2,{...x_ab1,"b":1,"a":2}]"#);
+
+		// Literals only differing in spelling have the same canonical form
+		let a = Value::parse(r#"{"\u0061":[1.0,"\/"],2:x_ab1}"#).unwrap();
+		let b = Value::parse(r#"{"a":[1,"/"],"2":x_ab1}"#).unwrap();
+		assert_ne!(a.to_string(), b.to_string());
+		assert_eq!(a.canonical(), b.canonical());
+
+		assert_eq!(Value::parse("[1,2"), None);
+		assert_eq!(Value::parse(r#"{"a":1}}"#), None);
+		assert_eq!(Value::parse("{a:1}"), None);
+	}
+}
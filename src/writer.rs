@@ -2,47 +2,80 @@
 //!
 //! Handles replacing structures with references and managing imports.
 
-use crate::ExtractedEntry;
+use crate::language::ModuleFormat;
+use crate::lexer::{tokenize, TokenKind};
+use crate::naming::is_ref_name;
+use crate::tree::is_module_import;
+use crate::{ExtractedEntry, Language};
 use anyhow::Result;
 use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+/// Compute the import specifier an artifact uses to reach the shared module.
+///
+/// The result is relative to the artifact's directory, always starts with
+/// `./` or `../`, and has the module's extension stripped.
+pub fn import_source(artifact_path: &Path, shared_path: &Path) -> String {
+	let from_dir = absolute(artifact_path.parent().unwrap_or(Path::new("")));
+	relative_specifier(&from_dir, &absolute(shared_path).with_extension(""))
+}
+
+/// Relative specifier (`./x` or `../x`) from an absolute directory to an
+/// absolute path, kept as written (no extension handling)
+pub fn relative_specifier(from_dir: &Path, target: &Path) -> String {
+	let from: Vec<_> = from_dir.components().collect();
+	let to: Vec<_> = target.components().collect();
+	let common = from
+		.iter()
+		.zip(to.iter())
+		.take_while(|(a, b)| a == b)
+		.count();
+
+	let mut parts: Vec<String> = Vec::new();
+	for _ in common..from.len() {
+		parts.push("..".to_string());
+	}
+	for component in &to[common..] {
+		parts.push(component.as_os_str().to_string_lossy().into_owned());
+	}
+
+	let joined = parts.join("/");
+	if joined.starts_with("..") {
+		joined
+	} else {
+		format!("./{}", joined)
+	}
+}
+
+fn absolute(path: &Path) -> PathBuf {
+	std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
 
 /// Update imports in the file content.
-pub fn update_imports(content: &str, shared_module_name: &str) -> String {
-	let import_source = format!("./{}", shared_module_name.trim_end_matches(".ts"));
+///
+/// `import_source` is the specifier to import from (see [`import_source`]).
+/// ES modules get an `import { ... } from` line, CommonJS artifacts a
+/// `const { ... } = require(...)` statement. A stale reference to the shared
+/// module in either format is replaced.
+pub fn update_imports(content: &str, import_source: &str, format: ModuleFormat) -> String {
 	let import_marker = format!("from \"{}\"", import_source);
+	let require_marker = format!("require(\"{}\")", import_source);
 
 	// Remove all existing shared module imports
 	let mut lines: Vec<&str> = content.lines().collect();
-	lines.retain(|line| !line.contains(&import_marker));
+	lines.retain(|line| {
+		!(is_module_import(line.trim())
+			&& (line.contains(&import_marker) || line.contains(&require_marker)))
+	});
 
 	// Find all x_XXX refs in content (exclude import lines we just removed)
-	let mut used_refs: HashSet<String> = HashSet::new();
-	for line in &lines {
-		// Skip import lines for ref scanning
-		if line.starts_with("import ") {
-			continue;
-		}
-		let bytes = line.as_bytes();
-		let mut i = 0;
-		while i + 3 < bytes.len() {
-			if bytes[i] == b'x' && bytes[i + 1] == b'_' {
-				let mut ref_name = String::from("x_");
-				let mut j = i + 2;
-				while j < bytes.len() && bytes[j].is_ascii_hexdigit() {
-					ref_name.push(bytes[j] as char);
-					j += 1;
-				}
-				if ref_name.len() >= 4 {
-					used_refs.insert(ref_name);
-				}
-				i = j;
-			} else {
-				i += 1;
-			}
-		}
-	}
+	let code: Vec<&str> = lines
+		.iter()
+		.copied()
+		.filter(|line| !is_module_import(line))
+		.collect();
+	let used_refs: HashSet<String> = ref_names(&code.join("\n")).collect();
 
 	if used_refs.is_empty() {
 		let mut result = lines.join("\n");
@@ -53,21 +86,50 @@ pub fn update_imports(content: &str, shared_module_name: &str) -> String {
 	// Create import line
 	let mut refs: Vec<_> = used_refs.into_iter().collect();
 	refs.sort();
-	let import_line = format!(
-		"import {{ {} }} from \"{}\";",
-		refs.join(", "),
-		import_source
-	);
-
-	// Find insert position (after other imports, before exports/code)
-	let mut insert_idx = 0;
+	let import_line = match format {
+		ModuleFormat::Esm => format!(
+			"import {{ {} }} from \"{}\";",
+			refs.join(", "),
+			import_source
+		),
+		ModuleFormat::CommonJs => format!(
+			"const {{ {} }} = require(\"{}\");",
+			refs.join(", "),
+			import_source
+		),
+	};
+
+	// Find insert position: after other imports, otherwise before the first
+	// line of code. Leading comments (including Flow's `/*:: ... */` type
+	// import blocks) and the `'use strict'` directive are never split.
+	let mut insert_idx = None;
+	let mut in_block_comment = false;
 	for (i, line) in lines.iter().enumerate() {
-		if line.starts_with("import ") {
-			insert_idx = i + 1;
-		} else if line.starts_with("export ") || line.starts_with("const ") {
-			break;
+		let trimmed = line.trim();
+		if in_block_comment {
+			in_block_comment = !trimmed.contains("*/");
+			continue;
+		}
+		if trimmed.starts_with("/*") {
+			in_block_comment = !trimmed.contains("*/");
+			continue;
 		}
+		if trimmed.is_empty()
+			|| trimmed.starts_with("//")
+			|| matches!(trimmed, "'use strict';" | "\"use strict\";")
+		{
+			continue;
+		}
+		if is_module_import(line) {
+			insert_idx = Some(i + 1);
+			continue;
+		}
+		if insert_idx.is_none() {
+			insert_idx = Some(i);
+		}
+		break;
 	}
+	let insert_idx = insert_idx.unwrap_or(lines.len());
 
 	// Insert and join (with trailing newline)
 	lines.insert(insert_idx, &import_line);
@@ -77,25 +139,83 @@ pub fn update_imports(content: &str, shared_module_name: &str) -> String {
 }
 
 /// Generate the shared module content as a string (no I/O).
-pub fn generate_shared_module_content(extracted: &HashMap<String, ExtractedEntry>) -> String {
+///
+/// TypeScript modules annotate each export with a `RelayNode` alias, Flow
+/// modules use comment annotations (like Relay's own Flow artifacts) so they
+/// run without a Flow transform, and plain JavaScript modules are untyped.
+/// CommonJS modules declare plain constants and export them all through a
+/// single `module.exports` object.
+pub fn generate_shared_module_content(
+	extracted: &HashMap<String, ExtractedEntry>,
+	atom_declarations: &[String],
+	language: Language,
+	format: ModuleFormat,
+) -> String {
 	let mut lines = vec![
 		"/**".to_string(),
 		" * @generated - Do not edit manually".to_string(),
-		" * Shared Relay structures".to_string(),
-		" */".to_string(),
-		"// eslint-disable-next-line @typescript-eslint/no-explicit-any".to_string(),
-		"type RelayNode = any;".to_string(),
-		String::new(),
 	];
+	if language == Language::Flow {
+		lines.push(" * @flow".to_string());
+	}
+	lines.push(" * Shared Relay structures".to_string());
+	lines.push(" */".to_string());
+	if format == ModuleFormat::CommonJs {
+		lines.push(String::new());
+		lines.push("'use strict';".to_string());
+		lines.push(String::new());
+	}
+	// Modules referenced by the structures (see `atom`)
+	if !atom_declarations.is_empty() {
+		if format == ModuleFormat::Esm {
+			lines.push(String::new());
+		}
+		lines.extend(atom_declarations.iter().cloned());
+		lines.push(String::new());
+	}
+	match language {
+		Language::TypeScript => {
+			lines
+				.push("// eslint-disable-next-line @typescript-eslint/no-explicit-any".to_string());
+			lines.push("type RelayNode = any;".to_string());
+		}
+		Language::Flow => lines.push("/*:: type RelayNode = any; */".to_string()),
+		Language::JavaScript => {}
+	}
+	lines.push(String::new());
 
 	// Topologically sort entries
 	let sorted = topo_sort(extracted);
 
-	for (normalized, entry) in sorted {
-		lines.push(format!(
-			"export const {}: RelayNode = {};",
-			entry.name, normalized
-		));
+	let export = match format {
+		ModuleFormat::Esm => "export ",
+		ModuleFormat::CommonJs => "",
+	};
+	for (_, entry) in &sorted {
+		lines.push(match language {
+			Language::TypeScript => {
+				format!(
+					"{}const {}: RelayNode = {};",
+					export, entry.name, entry.text
+				)
+			}
+			Language::Flow => format!(
+				"{}const {}/*: RelayNode*/ = {};",
+				export, entry.name, entry.text
+			),
+			Language::JavaScript => format!("{}const {} = {};", export, entry.name, entry.text),
+		});
+	}
+
+	if format == ModuleFormat::CommonJs {
+		let mut names: Vec<&str> = sorted.iter().map(|(_, e)| e.name.as_str()).collect();
+		names.sort();
+		lines.push(String::new());
+		lines.push("module.exports = {".to_string());
+		for name in names {
+			lines.push(format!("\t{},", name));
+		}
+		lines.push("};".to_string());
 	}
 
 	lines.push(String::new());
@@ -106,35 +226,29 @@ pub fn generate_shared_module_content(extracted: &HashMap<String, ExtractedEntry
 pub fn write_shared_module(
 	shared_path: &Path,
 	extracted: &HashMap<String, ExtractedEntry>,
+	atom_declarations: &[String],
+	language: Language,
+	format: ModuleFormat,
 ) -> Result<()> {
-	let content = generate_shared_module_content(extracted);
+	let content = generate_shared_module_content(extracted, atom_declarations, language, format);
 	fs::write(shared_path, content)?;
 	Ok(())
 }
 
-/// Get dependency names from a normalized string.
-fn get_deps(normalized: &str) -> Vec<String> {
-	let mut deps = Vec::new();
-
-	let mut chars = normalized.chars().peekable();
-	while let Some(c) = chars.next() {
-		if c == 'x' && chars.peek() == Some(&'_') {
-			chars.next(); // consume _
-			let mut ref_name = String::from("x_");
-			while let Some(&c) = chars.peek() {
-				if c.is_ascii_hexdigit() {
-					ref_name.push(chars.next().unwrap());
-				} else {
-					break;
-				}
-			}
-			if ref_name.len() >= 4 {
-				deps.push(ref_name);
-			}
-		}
-	}
+/// Get dependency names from a definition.
+fn get_deps(text: &str) -> Vec<String> {
+	ref_names(text).collect()
+}
 
-	deps
+/// `x_XXX` refs used as identifiers in `content` (mentions inside strings
+/// and comments don't count)
+fn ref_names(content: &str) -> impl Iterator<Item = String> + '_ {
+	tokenize(content)
+		.into_iter()
+		.filter(|t| t.kind == TokenKind::Ident)
+		.map(|t| t.text(content))
+		.filter(|name| is_ref_name(name))
+		.map(str::to_string)
 }
 
 /// Topologically sort extracted entries for proper dependency order.
@@ -163,7 +277,7 @@ fn topo_sort(extracted: &HashMap<String, ExtractedEntry>) -> Vec<(String, Extrac
 		};
 
 		// Visit dependencies first
-		for dep in get_deps(normalized) {
+		for dep in get_deps(&entry.text) {
 			if name_to_entry.contains_key(dep.as_str()) {
 				visit(&dep, name_to_entry, visited, result);
 			}
@@ -194,6 +308,47 @@ mod tests {
 		assert!(get_deps(r#"{"key": "value"}"#).is_empty());
 	}
 
+	#[test]
+	fn test_import_source() {
+		assert_eq!(
+			import_source(
+				Path::new("/app/src/__generated__/A.graphql.ts"),
+				Path::new("/app/src/__generated__/__shared.ts")
+			),
+			"./__shared"
+		);
+		assert_eq!(
+			import_source(
+				Path::new("/app/src/components/user/__generated__/A.graphql.ts"),
+				Path::new("/app/src/__generated__/__shared.ts")
+			),
+			"../../../__generated__/__shared"
+		);
+	}
+
+	#[test]
+	fn test_update_imports_ignores_strings_and_comments() {
+		let content =
+			"const node = {\"name\": \"x_abc\", \"ref\": x_def}; // x_123\nexport default node;\n";
+		let updated = update_imports(content, "./__shared", ModuleFormat::Esm);
+		assert!(updated.starts_with("import { x_def } from \"./__shared\";\n"));
+	}
+
+	#[test]
+	fn test_update_imports_commonjs() {
+		let content = "'use strict';\n\nvar node = {\"a\": x_abc, \"b\": x_def};\n";
+		let updated = update_imports(content, "./__shared", ModuleFormat::CommonJs);
+		assert_eq!(
+			updated,
+			"'use strict';\n\nconst { x_abc, x_def } = require(\"./__shared\");\nvar node = {\"a\": x_abc, \"b\": x_def};\n"
+		);
+
+		// Switching formats replaces the stale reference
+		let esm = update_imports(&updated, "./__shared", ModuleFormat::Esm);
+		assert!(esm.contains("import { x_abc, x_def } from \"./__shared\";"));
+		assert!(!esm.contains("require("));
+	}
+
 	#[test]
 	fn test_topo_sort() {
 		let mut extracted = HashMap::new();
@@ -205,6 +360,7 @@ mod tests {
 				name: "x_aaa".to_string(),
 				hash: "aaa12345".to_string(),
 				count: 2,
+				text: r#"{"kind":"Literal"}"#.to_string(),
 			},
 		);
 
@@ -215,6 +371,7 @@ mod tests {
 				name: "x_bbb".to_string(),
 				hash: "bbb12345".to_string(),
 				count: 2,
+				text: "[x_aaa]".to_string(),
 			},
 		);
 
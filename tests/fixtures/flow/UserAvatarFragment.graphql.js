@@ -0,0 +1,66 @@
+/**
+ * @generated SignedSource<<flow2>>
+ * @flow
+ * @lightSyntaxTransform
+ * @nogrep
+ */
+
+/* eslint-disable */
+
+'use strict';
+
+/*::
+import type { Fragment, ReaderFragment } from 'relay-runtime';
+import type { FragmentType } from "relay-runtime";
+declare export opaque type UserAvatarFragment$fragmentType: FragmentType;
+export type UserAvatarFragment$data = {|
+  +id: string,
+  +name: ?string,
+  +avatarUrl: ?string,
+  +$fragmentType: UserAvatarFragment$fragmentType,
+|};
+export type UserAvatarFragment$key = {
+  +$data?: UserAvatarFragment$data,
+  +$fragmentSpreads: UserAvatarFragment$fragmentType,
+  ...
+};
+*/
+
+var node/*: ReaderFragment*/ = {
+  "argumentDefinitions": [],
+  "kind": "Fragment",
+  "metadata": null,
+  "name": "UserAvatarFragment",
+  "selections": [
+    {
+      "alias": null,
+      "args": null,
+      "kind": "ScalarField",
+      "name": "id",
+      "storageKey": null
+    },
+    {
+      "alias": null,
+      "args": null,
+      "kind": "ScalarField",
+      "name": "name"/*: string*/,
+      "storageKey": null
+    },
+    {
+      "alias": "avatarUrl",
+      "args": null,
+      "kind": "ScalarField",
+      "name": "avatar",
+      "storageKey": null
+    }
+  ],
+  "type": "User",
+  "abstractKey": null
+};
+
+(node/*: any*/).hash = "flowhash2";
+
+module.exports = ((node/*: any*/)/*: Fragment<
+  UserAvatarFragment$fragmentType,
+  UserAvatarFragment$data,
+>*/);
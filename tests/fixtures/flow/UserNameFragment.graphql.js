@@ -0,0 +1,58 @@
+/**
+ * @generated SignedSource<<flow1>>
+ * @flow
+ * @lightSyntaxTransform
+ * @nogrep
+ */
+
+/* eslint-disable */
+
+'use strict';
+
+/*::
+import type { Fragment, ReaderFragment } from 'relay-runtime';
+import type { FragmentType } from "relay-runtime";
+declare export opaque type UserNameFragment$fragmentType: FragmentType;
+export type UserNameFragment$data = {|
+  +id: string,
+  +name: ?string,
+  +$fragmentType: UserNameFragment$fragmentType,
+|};
+export type UserNameFragment$key = {
+  +$data?: UserNameFragment$data,
+  +$fragmentSpreads: UserNameFragment$fragmentType,
+  ...
+};
+*/
+
+var node/*: ReaderFragment*/ = {
+  "argumentDefinitions": [],
+  "kind": "Fragment",
+  "metadata": null,
+  "name": "UserNameFragment",
+  "selections": [
+    {
+      "alias": null,
+      "args": null,
+      "kind": "ScalarField",
+      "name": "id",
+      "storageKey": null
+    },
+    {
+      "alias": null,
+      "args": null,
+      "kind": "ScalarField",
+      "name": "name",
+      "storageKey": null
+    }
+  ],
+  "type": "User",
+  "abstractKey": null
+};
+
+(node/*: any*/).hash = "flowhash1";
+
+module.exports = ((node/*: any*/)/*: Fragment<
+  UserNameFragment$fragmentType,
+  UserNameFragment$data,
+>*/);
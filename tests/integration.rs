@@ -1,7 +1,10 @@
 //! Integration tests for relay-dedup
 
 use pretty_assertions::assert_eq;
-use relay_dedup::{Config, Deduplicator};
+use relay_dedup::diagnostics::Severity;
+use relay_dedup::order::OrderRules;
+use relay_dedup::scope::Suppression;
+use relay_dedup::{Config, Deduplicator, Language, ModuleFormat};
 use std::collections::HashSet;
 use std::fs;
 use std::path::PathBuf;
@@ -69,20 +72,18 @@ fn setup_test_dir_copy(suffix: &str) -> PathBuf {
 fn test_full_dedup_cycle() {
 	let test_dir = setup_test_dir();
 
-	let mut order_insensitive = HashSet::new();
-	order_insensitive.insert("selections".to_string());
-	order_insensitive.insert("args".to_string());
-	order_insensitive.insert("argumentDefinitions".to_string());
+	let order_rules = OrderRules::parse(&["selections", "args", "argumentDefinitions"]).unwrap();
 
 	let config = Config {
 		generated_dir: test_dir.clone(),
 		shared_module_name: "__shared.ts".to_string(),
 		min_occurrences: 2,
-		order_insensitive_fields: order_insensitive,
+		order_rules,
 		dry_run: false,
 		verbose: true,
 		max_passes: 50,
 		compute_gzip: false,
+		..Config::default()
 	};
 
 	let mut deduplicator = Deduplicator::new(config);
@@ -131,21 +132,19 @@ fn test_deterministic_across_runs() {
 	let test_dir1 = setup_test_dir_copy("run1");
 	let test_dir2 = setup_test_dir_copy("run2");
 
-	let mut order_insensitive = HashSet::new();
-	order_insensitive.insert("selections".to_string());
-	order_insensitive.insert("args".to_string());
-	order_insensitive.insert("argumentDefinitions".to_string());
+	let order_rules = OrderRules::parse(&["selections", "args", "argumentDefinitions"]).unwrap();
 
 	// Run first time
 	let config1 = Config {
 		generated_dir: test_dir1.clone(),
 		shared_module_name: "__shared.ts".to_string(),
 		min_occurrences: 2,
-		order_insensitive_fields: order_insensitive.clone(),
+		order_rules: order_rules.clone(),
 		dry_run: false,
 		verbose: false,
 		max_passes: 50,
 		compute_gzip: false,
+		..Config::default()
 	};
 	let mut deduplicator1 = Deduplicator::new(config1);
 	deduplicator1.run().unwrap();
@@ -155,11 +154,12 @@ fn test_deterministic_across_runs() {
 		generated_dir: test_dir2.clone(),
 		shared_module_name: "__shared.ts".to_string(),
 		min_occurrences: 2,
-		order_insensitive_fields: order_insensitive,
+		order_rules,
 		dry_run: false,
 		verbose: false,
 		max_passes: 50,
 		compute_gzip: false,
+		..Config::default()
 	};
 	let mut deduplicator2 = Deduplicator::new(config2);
 	deduplicator2.run().unwrap();
@@ -195,18 +195,18 @@ fn test_dry_run_no_modifications() {
 		original_contents.push((entry.path(), content));
 	}
 
-	let mut order_insensitive = HashSet::new();
-	order_insensitive.insert("selections".to_string());
+	let order_rules = OrderRules::parse(&["selections"]).unwrap();
 
 	let config = Config {
 		generated_dir: test_dir.clone(),
 		shared_module_name: "__shared.ts".to_string(),
 		min_occurrences: 2,
-		order_insensitive_fields: order_insensitive,
+		order_rules,
 		dry_run: true, // DRY RUN
 		verbose: false,
 		max_passes: 50,
 		compute_gzip: false,
+		..Config::default()
 	};
 
 	let mut deduplicator = Deduplicator::new(config);
@@ -241,19 +241,19 @@ fn test_dry_run_no_modifications() {
 fn test_min_occurrences_respected() {
 	let test_dir = setup_test_dir_copy("min_occ");
 
-	let mut order_insensitive = HashSet::new();
-	order_insensitive.insert("selections".to_string());
+	let order_rules = OrderRules::parse(&["selections"]).unwrap();
 
 	// Set min_occurrences to 50 (higher than any structure appears)
 	let config = Config {
 		generated_dir: test_dir.clone(),
 		shared_module_name: "__shared.ts".to_string(),
 		min_occurrences: 50,
-		order_insensitive_fields: order_insensitive,
+		order_rules,
 		dry_run: false,
 		verbose: false,
 		max_passes: 50,
 		compute_gzip: false,
+		..Config::default()
 	};
 
 	let mut deduplicator = Deduplicator::new(config);
@@ -271,19 +271,18 @@ fn test_single_child_arrays_are_extracted() {
 	// NOTE: Single-child arrays ARE now extracted (gives +11% more savings)
 	let test_dir = setup_test_dir_copy("single_child");
 
-	let mut order_insensitive = HashSet::new();
-	order_insensitive.insert("selections".to_string());
-	order_insensitive.insert("args".to_string());
+	let order_rules = OrderRules::parse(&["selections", "args"]).unwrap();
 
 	let config = Config {
 		generated_dir: test_dir.clone(),
 		shared_module_name: "__shared.ts".to_string(),
 		min_occurrences: 2,
-		order_insensitive_fields: order_insensitive,
+		order_rules,
 		dry_run: false,
 		verbose: false,
 		max_passes: 50,
 		compute_gzip: false,
+		..Config::default()
 	};
 
 	let mut deduplicator = Deduplicator::new(config);
@@ -313,19 +312,18 @@ fn test_single_child_arrays_are_extracted() {
 fn test_unique_items_not_extracted() {
 	let test_dir = setup_test_dir_copy("unique");
 
-	let mut order_insensitive = HashSet::new();
-	order_insensitive.insert("selections".to_string());
-	order_insensitive.insert("args".to_string());
+	let order_rules = OrderRules::parse(&["selections", "args"]).unwrap();
 
 	let config = Config {
 		generated_dir: test_dir.clone(),
 		shared_module_name: "__shared.ts".to_string(),
 		min_occurrences: 2,
-		order_insensitive_fields: order_insensitive,
+		order_rules,
 		dry_run: false,
 		verbose: false,
 		max_passes: 50,
 		compute_gzip: false,
+		..Config::default()
 	};
 
 	let mut deduplicator = Deduplicator::new(config);
@@ -366,3 +364,1550 @@ fn test_unique_items_not_extracted() {
 	// Cleanup
 	fs::remove_dir_all(&test_dir).ok();
 }
+
+#[test]
+fn test_recursive_colocated_artifacts() {
+	let fixtures_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+		.join("tests")
+		.join("fixtures")
+		.join("input");
+	let temp = tempfile::tempdir().unwrap();
+	let src = temp.path().join("src");
+
+	// Spread the fixtures over colocated __generated__ folders
+	let placements = [
+		("FileOne.graphql.ts", "components/__generated__"),
+		(
+			"FileTwo.graphql.ts",
+			"components/user/profile/__generated__",
+		),
+		("FileThree.graphql.ts", "__generated__"),
+	];
+	for (file, dir) in placements {
+		let dir = src.join(dir);
+		fs::create_dir_all(&dir).unwrap();
+		fs::copy(fixtures_dir.join(file), dir.join(file)).unwrap();
+	}
+
+	// Artifacts outside __generated__ and inside node_modules are ignored
+	fs::create_dir_all(src.join("node_modules/pkg/__generated__")).unwrap();
+	fs::copy(
+		fixtures_dir.join("FileOne.graphql.ts"),
+		src.join("node_modules/pkg/__generated__/FileOne.graphql.ts"),
+	)
+	.unwrap();
+	fs::copy(
+		fixtures_dir.join("FileOne.graphql.ts"),
+		src.join("components/Stray.graphql.ts"),
+	)
+	.unwrap();
+
+	let config = Config {
+		generated_dir: src.clone(),
+		recursive: true,
+		..Config::default()
+	};
+	let mut deduplicator = Deduplicator::new(config);
+	let stats = deduplicator.run().unwrap();
+	assert!(stats.total_extracted > 0);
+
+	// Shared module defaults to <root>/__generated__/
+	assert!(src.join("__generated__/__shared.ts").exists());
+
+	let file_one =
+		fs::read_to_string(src.join("components/__generated__/FileOne.graphql.ts")).unwrap();
+	assert!(file_one.contains("from \"../../__generated__/__shared\""));
+
+	let file_two =
+		fs::read_to_string(src.join("components/user/profile/__generated__/FileTwo.graphql.ts"))
+			.unwrap();
+	assert!(file_two.contains("from \"../../../../__generated__/__shared\""));
+
+	let file_three = fs::read_to_string(src.join("__generated__/FileThree.graphql.ts")).unwrap();
+	assert!(file_three.contains("from \"./__shared\""));
+
+	// Untouched files
+	let stray = fs::read_to_string(src.join("components/Stray.graphql.ts")).unwrap();
+	assert!(!stray.contains("__shared"));
+	let vendored =
+		fs::read_to_string(src.join("node_modules/pkg/__generated__/FileOne.graphql.ts")).unwrap();
+	assert!(!vendored.contains("__shared"));
+}
+
+#[test]
+fn test_custom_shared_module_path() {
+	let test_dir = setup_test_dir_copy("shared_path");
+	let shared_path = test_dir.join("lib").join("relay-shared.ts");
+
+	let config = Config {
+		generated_dir: test_dir.clone(),
+		shared_module_path: Some(shared_path.clone()),
+		..Config::default()
+	};
+	let mut deduplicator = Deduplicator::new(config);
+	deduplicator.run().unwrap();
+
+	assert!(shared_path.exists());
+	let file_one = fs::read_to_string(test_dir.join("FileOne.graphql.ts")).unwrap();
+	assert!(file_one.contains("from \"./lib/relay-shared\""));
+
+	fs::remove_dir_all(&test_dir).ok();
+}
+
+/// Copy a fixture set into a fresh temp directory
+fn copy_fixtures(set: &str) -> tempfile::TempDir {
+	let fixtures_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+		.join("tests")
+		.join("fixtures")
+		.join(set);
+	let temp = tempfile::tempdir().unwrap();
+	for entry in fs::read_dir(&fixtures_dir).unwrap() {
+		let entry = entry.unwrap();
+		fs::copy(entry.path(), temp.path().join(entry.file_name())).unwrap();
+	}
+	temp
+}
+
+#[test]
+fn test_flow_artifacts() {
+	let temp = copy_fixtures("flow");
+
+	let config = Config {
+		generated_dir: temp.path().to_path_buf(),
+		shared_module_name: Language::Flow.default_shared_module_name().to_string(),
+		language: Language::Flow,
+		..Config::default()
+	};
+	let mut deduplicator = Deduplicator::new(config);
+	let stats = deduplicator.run().unwrap();
+	assert!(stats.total_extracted > 0);
+
+	// Shared module is a @flow module with comment annotations
+	let shared = fs::read_to_string(temp.path().join("__shared.js")).unwrap();
+	assert!(shared.contains(" * @flow"));
+	assert!(shared.contains("/*:: type RelayNode = any; */"));
+	assert!(shared.contains("export const x_"));
+	assert!(shared.contains("/*: RelayNode*/ = "));
+	assert!(!shared.contains(": RelayNode ="));
+
+	// The "name" field annotated with a Flow comment still matches the plain one
+	assert_eq!(strip_ws(&shared).matches(r#""name":"name""#).count(), 1);
+
+	let name = fs::read_to_string(temp.path().join("UserNameFragment.graphql.js")).unwrap();
+	// Import goes after the header, directive and type block - not into a comment
+	let import_pos = name.find("import { x_").unwrap();
+	assert!(import_pos > name.find("'use strict';").unwrap());
+	assert!(import_pos > name.find("\n*/\n").unwrap());
+	assert!(import_pos < name.find("var node").unwrap());
+	assert!(name.contains("from \"./__shared\";"));
+	// Type declarations inside the Flow comment are untouched
+	assert!(name.contains("+$fragmentType: UserNameFragment$fragmentType,"));
+	assert!(name.contains("UserNameFragment$fragmentType,\n  UserNameFragment$data,\n>*/);"));
+}
+
+#[test]
+fn test_commonjs_module_format() {
+	let temp = copy_fixtures("flow");
+
+	let config = Config {
+		generated_dir: temp.path().to_path_buf(),
+		shared_module_name: "__shared.js".to_string(),
+		language: Language::Flow,
+		module_format: ModuleFormat::CommonJs,
+		..Config::default()
+	};
+	Deduplicator::new(config.clone()).run().unwrap();
+
+	let shared = fs::read_to_string(temp.path().join("__shared.js")).unwrap();
+	assert!(shared.contains("'use strict';"));
+	assert!(!shared.contains("export "));
+	assert!(shared.contains("const x_"));
+	assert!(shared.contains("module.exports = {\n\tx_"));
+
+	let name = fs::read_to_string(temp.path().join("UserNameFragment.graphql.js")).unwrap();
+	assert!(!name.contains("import { x_"));
+	let require_pos = name.find("const { x_").unwrap();
+	assert!(name[require_pos..].starts_with("const { x_"));
+	assert!(name.contains("} = require(\"./__shared\");"));
+	assert!(require_pos > name.find("\n*/\n").unwrap());
+	assert!(require_pos < name.find("var node").unwrap());
+
+	// Running again leaves a single require and keeps the artifact intact
+	Deduplicator::new(config).run().unwrap();
+	let rerun = fs::read_to_string(temp.path().join("UserNameFragment.graphql.js")).unwrap();
+	assert_eq!(rerun.matches("require(\"./__shared\")").count(), 1);
+	assert_eq!(rerun, name);
+}
+
+#[test]
+fn test_inlines_relay_deduplicated_artifacts() {
+	let temp = copy_fixtures("flow");
+	let id_field = "{\n      \"alias\": null,\n      \"args\": null,\n      \"kind\": \"ScalarField\",\n      \"name\": \"id\",\n      \"storageKey\": null\n    }";
+
+	// Rewrite both artifacts the way relay-compiler does with its own dedup on
+	let mut plain = Vec::new();
+	for name in [
+		"UserAvatarFragment.graphql.js",
+		"UserNameFragment.graphql.js",
+	] {
+		let path = temp.path().join(name);
+		// Repeat the field so relay-compiler would hoist it
+		let content = fs::read_to_string(&path).unwrap().replacen(
+			"\"type\": \"User\",",
+			&format!("\"type\": \"User\",\n  \"fields\": [{}],", id_field),
+			1,
+		);
+		assert_eq!(content.matches(id_field).count(), 2);
+		let deduped = content
+			.replace(id_field, "(v0/*: any*/)")
+			.replacen(
+				"var node/*: ReaderFragment*/ = {",
+				&format!(
+					"var node/*: ReaderFragment*/ = (function(){{\nvar v0 = {};\nreturn {{",
+					id_field
+				),
+				1,
+			)
+			.replacen("};\n\n(node", "};\n})();\n\n(node", 1);
+		assert!(deduped.contains("})();"));
+		fs::write(&path, deduped).unwrap();
+		plain.push((path, content));
+	}
+
+	let config = Config {
+		generated_dir: temp.path().to_path_buf(),
+		shared_module_name: "__shared.js".to_string(),
+		language: Language::Flow,
+		..Config::default()
+	};
+	let stats = Deduplicator::new(config.clone()).run().unwrap();
+	assert!(stats.total_extracted > 0);
+	let inlined: Vec<String> = plain
+		.iter()
+		.map(|(path, _)| fs::read_to_string(path).unwrap())
+		.collect();
+	for content in &inlined {
+		assert!(!content.contains("function()"), "{}", content);
+		assert!(!content.contains("v0"), "{}", content);
+		// No cast is left around inlined or extracted values
+		assert!(!content.contains("(x_"), "{}", content);
+		assert!(!content.contains("({"), "{}", content);
+		assert!(!content.contains("(["), "{}", content);
+	}
+
+	// Same result as deduplicating the plain artifacts
+	for (path, content) in &plain {
+		fs::write(path, content).unwrap();
+	}
+	fs::remove_file(temp.path().join("__shared.js")).unwrap();
+	Deduplicator::new(config).run().unwrap();
+	for ((path, _), content) in plain.iter().zip(&inlined) {
+		let expected = fs::read_to_string(path).unwrap();
+		assert_eq!(strip_ws(content), strip_ws(&expected));
+	}
+}
+
+/// Compare artifacts regardless of the indentation inlined values keep
+fn strip_ws(content: &str) -> String {
+	content.chars().filter(|c| !c.is_whitespace()).collect()
+}
+
+#[test]
+fn test_module_references_are_shared() {
+	let temp = tempfile::tempdir().unwrap();
+	let artifact = |name: &str, import: &str, operation: &str| {
+		format!(
+			r#"/**
+ * @generated SignedSource<<{name}>>
+ */
+
+import type {{ ReaderFragment }} from 'relay-runtime';
+{import}
+const node: ReaderFragment = {{
+  "kind": "Fragment",
+  "name": "{name}",
+  "selections": [
+    {{
+      "kind": "ModuleImport",
+      "documentName": "Avatar",
+      "fragmentName": "Avatar_user",
+      "fragmentPropName": "user",
+      "operation": {operation}
+    }}
+  ]
+}};
+
+export default node;
+"#
+		)
+	};
+	// Artifacts colocated in different directories
+	let one_dir = temp.path().join("a").join("__generated__");
+	let two_dir = temp.path().join("b").join("c").join("__generated__");
+	fs::create_dir_all(&one_dir).unwrap();
+	fs::create_dir_all(&two_dir).unwrap();
+	fs::write(
+		one_dir.join("One.graphql.ts"),
+		artifact("One", "", "require('./Avatar_user$normalization.graphql')"),
+	)
+	.unwrap();
+	fs::write(
+		two_dir.join("Two.graphql.ts"),
+		artifact(
+			"Two",
+			"",
+			"require(\"./../../../a/__generated__/Avatar_user$normalization.graphql\")",
+		),
+	)
+	.unwrap();
+	// An imported binding is a different atom (default import, not require)
+	fs::write(
+		two_dir.join("Three.graphql.ts"),
+		artifact(
+			"Three",
+			"import AvatarNormalization from '../../../a/__generated__/Avatar_user$normalization.graphql';",
+			"AvatarNormalization",
+		),
+	)
+	.unwrap();
+	fs::write(
+		two_dir.join("Four.graphql.ts"),
+		artifact(
+			"Four",
+			"import Normalization from '../../../a/__generated__/Avatar_user$normalization.graphql';",
+			"Normalization",
+		),
+	)
+	.unwrap();
+
+	let shared_path = temp.path().join("lib").join("__shared.ts");
+	let config = Config {
+		generated_dir: temp.path().to_path_buf(),
+		recursive: true,
+		shared_module_path: Some(shared_path.clone()),
+		..Config::default()
+	};
+	Deduplicator::new(config).run().unwrap();
+
+	let shared = fs::read_to_string(&shared_path).unwrap();
+	// Both spellings of the require resolve to one atom, declared once
+	assert_eq!(
+		shared
+			.matches("= require(\"../a/__generated__/Avatar_user$normalization.graphql\");")
+			.count(),
+		1,
+		"{}",
+		shared
+	);
+	assert_eq!(
+		shared
+			.matches(" from \"../a/__generated__/Avatar_user$normalization.graphql\";")
+			.count(),
+		1,
+		"{}",
+		shared
+	);
+	for path in [
+		one_dir.join("One.graphql.ts"),
+		two_dir.join("Two.graphql.ts"),
+	] {
+		let content = fs::read_to_string(path).unwrap();
+		assert!(!content.contains("require("), "{}", content);
+		assert!(
+			strip_ws(&content).contains("\"selections\":[x_"),
+			"{}",
+			content
+		);
+	}
+}
+
+/// JSON of the modules at `paths`, each loaded by a fresh `node` process in
+/// order. `None` if node isn't installed.
+fn evaluate_with_node(paths: &[PathBuf]) -> Option<String> {
+	let script = "const replacer = (k, v) => typeof v === 'function' ? v.name : v;\n\
+		for (const path of process.argv.slice(1)) {\n\
+		\tconsole.log(JSON.stringify(require(path), replacer));\n\
+		}";
+	let output = std::process::Command::new("node")
+		.arg("-e")
+		.arg(script)
+		.args(paths)
+		.output()
+		.ok()?;
+	assert!(
+		output.status.success(),
+		"{}",
+		String::from_utf8_lossy(&output.stderr)
+	);
+	Some(String::from_utf8(output.stdout).unwrap())
+}
+
+#[test]
+fn test_references_to_shared_importers_stay_out_of_the_shared_module() {
+	let temp = tempfile::tempdir().unwrap();
+	let dir = temp.path();
+	let field = |name: &str| {
+		format!(
+			"{{\"alias\": null, \"args\": null, \"kind\": \"ScalarField\", \"name\": \"{}\", \"storageKey\": null}}",
+			name
+		)
+	};
+	let artifact = |extra: &str| {
+		format!(
+			"/**\n * @generated\n */\n\n'use strict';\n\nvar node = {{\n  \"selections\": [{id}, {name}, {avatar}],\n  \"metadata\": {extra}\n}};\n\nmodule.exports = node;\n",
+			id = field("id"),
+			name = field("name"),
+			avatar = field("avatarUrl"),
+			extra = extra,
+		)
+	};
+	// An artifact both fragments refer to, which imports the shared module
+	// itself once rewritten
+	let refetch = "{\"refetch\": {\"operation\": require('./UserRefetchQuery.graphql'), \"fragmentPathInResult\": [\"node\"], \"identifierInfo\": {\"identifierField\": \"id\"}}}";
+	// A resolver module that imports the shared module
+	let resolver = "{\"resolver\": {\"kind\": \"RelayResolver\", \"fragment\": null, \"resolverModule\": require('./userResolver')}}";
+	fs::write(dir.join("UserRefetchQuery.graphql.js"), artifact("{}")).unwrap();
+	fs::write(dir.join("A_user.graphql.js"), artifact(refetch)).unwrap();
+	fs::write(dir.join("B_user.graphql.js"), artifact(refetch)).unwrap();
+	fs::write(dir.join("C_user.graphql.js"), artifact(resolver)).unwrap();
+	fs::write(dir.join("D_user.graphql.js"), artifact(resolver)).unwrap();
+	fs::write(
+		dir.join("userResolver.js"),
+		"'use strict';\n\nmodule.exports = function userResolver() {\n  return require('./__shared');\n};\n",
+	)
+	.unwrap();
+
+	let paths: Vec<PathBuf> = ["A_user", "B_user", "C_user", "D_user", "UserRefetchQuery"]
+		.iter()
+		.map(|name| dir.join(format!("{}.graphql.js", name)))
+		.collect();
+	let Some(before) = evaluate_with_node(&paths) else {
+		eprintln!("node not found, skipping evaluation");
+		return;
+	};
+
+	let stats = Deduplicator::new(Config {
+		generated_dir: dir.to_path_buf(),
+		shared_module_name: "__shared.js".to_string(),
+		language: Language::JavaScript,
+		module_format: ModuleFormat::CommonJs,
+		..Config::default()
+	})
+	.run()
+	.unwrap();
+	assert!(stats.total_extracted > 0);
+	// Loaded in an order that would see a half-initialized shared module
+	assert_eq!(evaluate_with_node(&paths).unwrap(), before);
+
+	let shared = fs::read_to_string(dir.join("__shared.js")).unwrap();
+	assert!(!shared.contains("UserRefetchQuery"), "{}", shared);
+	assert!(!shared.contains("userResolver"), "{}", shared);
+	for path in &paths {
+		let content = fs::read_to_string(path).unwrap();
+		assert!(content.contains("require(\"./__shared\")"), "{}", content);
+	}
+}
+
+#[test]
+fn test_javascript_shared_module_is_untyped() {
+	let temp = copy_fixtures("flow");
+
+	let config = Config {
+		generated_dir: temp.path().to_path_buf(),
+		shared_module_name: "__shared.js".to_string(),
+		language: Language::JavaScript,
+		..Config::default()
+	};
+	Deduplicator::new(config).run().unwrap();
+
+	let shared = fs::read_to_string(temp.path().join("__shared.js")).unwrap();
+	assert!(!shared.contains("RelayNode"));
+	assert!(shared.contains("export const x_"));
+}
+
+#[test]
+fn test_scoping_rules() {
+	let temp = copy_fixtures("input");
+	let dir = temp.path();
+
+	// Opt FileTwo out with the header pragma
+	let file_two_path = dir.join("FileTwo.graphql.ts");
+	let file_two = fs::read_to_string(&file_two_path).unwrap();
+	let file_two = file_two.replacen(" * @nogrep\n", " * @nogrep\n * @relay-dedup-ignore\n", 1);
+	fs::write(&file_two_path, &file_two).unwrap();
+
+	// Mark FileThree as something we exclude by glob
+	fs::rename(
+		dir.join("FileThree.graphql.ts"),
+		dir.join("FileThreeQuery.graphql.ts"),
+	)
+	.unwrap();
+	fs::copy(
+		dir.join("FileOne.graphql.ts"),
+		dir.join("FileFour.graphql.ts"),
+	)
+	.unwrap();
+
+	let config = Config {
+		generated_dir: dir.to_path_buf(),
+		exclude_globs: vec!["*Query.graphql.ts".to_string()],
+		protected_paths: vec!["fragment.argumentDefinitions".to_string()],
+		..Config::default()
+	};
+	let mut deduplicator = Deduplicator::new(config);
+	let stats = deduplicator.run().unwrap();
+
+	// Pragma and glob files are untouched
+	assert_eq!(fs::read_to_string(&file_two_path).unwrap(), file_two);
+	let excluded = fs::read_to_string(dir.join("FileThreeQuery.graphql.ts")).unwrap();
+	assert!(!excluded.contains("__shared"));
+
+	// Each rule reports what it suppressed
+	assert!(stats.suppressed[&Suppression::Pragma] > 0);
+	assert!(stats.suppressed[&Suppression::Excluded("*Query.graphql.ts".to_string())] > 0);
+	let protected = Suppression::ProtectedPath("fragment.argumentDefinitions".to_string());
+	assert_eq!(stats.suppressed[&protected], 2);
+	// FileFour's copy of FileOne's operation stays a distinct object
+	let operation = Suppression::IdentitySensitive("Operation".to_string());
+	assert_eq!(stats.suppressed[&operation], 2);
+
+	// The protected structure stays inline even though it repeats
+	let file_one = fs::read_to_string(dir.join("FileOne.graphql.ts")).unwrap();
+	assert!(
+		file_one.contains("\"argumentDefinitions\": [\n      {\n        \"defaultValue\": null")
+	);
+	assert!(stats.total_extracted > 0);
+}
+
+#[test]
+fn test_diagnostics_and_strict_mode() {
+	let temp = copy_fixtures("input");
+	let dir = temp.path();
+
+	// A literal that is never closed, at the end of FileThree
+	let path = dir.join("FileThree.graphql.ts");
+	let content = fs::read_to_string(&path).unwrap() + "\nexport const broken = {\"a\": [1, 2]";
+	let line = content.lines().count();
+	fs::write(&path, &content).unwrap();
+	let before = fs::read_to_string(dir.join("FileOne.graphql.ts")).unwrap();
+
+	let config = Config {
+		generated_dir: dir.to_path_buf(),
+		strict: true,
+		..Config::default()
+	};
+	let mut deduplicator = Deduplicator::new(config.clone());
+	let err = deduplicator.run().unwrap_err().to_string();
+	let expected = format!(
+		"FileThree.graphql.ts:{}:23: warning: `{{` is never closed; the literal is left inline",
+		line
+	);
+	assert!(err.contains("--strict"), "{}", err);
+	assert!(err.contains(&expected), "{}", err);
+	assert_eq!(deduplicator.diagnostics.count(Severity::Warning), 1);
+	// Nothing was written
+	assert_eq!(
+		fs::read_to_string(dir.join("FileOne.graphql.ts")).unwrap(),
+		before
+	);
+
+	// Without --strict the rest is still deduplicated and the warning is kept
+	let mut deduplicator = Deduplicator::new(Config {
+		strict: false,
+		..config
+	});
+	let stats = deduplicator.run().unwrap();
+	assert!(stats.total_extracted > 0);
+	let after = fs::read_to_string(&path).unwrap();
+	assert!(after.contains("__shared"));
+	assert!(after
+		.trim_end()
+		.ends_with("export const broken = {\"a\": [1, 2]"));
+
+	let sarif = deduplicator.diagnostics.to_sarif(dir);
+	let result = &sarif["runs"][0]["results"][0];
+	assert_eq!(result["ruleId"], "unbalanced-literal");
+	let location = &result["locations"][0]["physicalLocation"];
+	assert_eq!(location["artifactLocation"]["uri"], "FileThree.graphql.ts");
+	assert_eq!(location["region"]["startLine"], line);
+}
+
+#[test]
+fn test_refuses_unexpected_artifact_contents() {
+	let temp = copy_fixtures("input");
+	let dir = temp.path();
+
+	// Relay's own dedup output, and a hand-written file in the artifact directory
+	let file_two = fs::read_to_string(dir.join("FileTwo.graphql.ts")).unwrap();
+	let relay_deduped = file_two.replacen(
+		"const node: ConcreteRequest = {",
+		"const node: ConcreteRequest = (function(){\nvar v0 = null;\nreturn {",
+		1,
+	);
+	assert_ne!(relay_deduped, file_two);
+	fs::write(dir.join("FileTwo.graphql.ts"), relay_deduped).unwrap();
+	fs::write(
+		dir.join("Handwritten.graphql.ts"),
+		"export const node = { \"kind\": \"Fragment\" };\n",
+	)
+	.unwrap();
+	let before = fs::read_to_string(dir.join("FileOne.graphql.ts")).unwrap();
+
+	let config = Config {
+		generated_dir: dir.to_path_buf(),
+		..Config::default()
+	};
+	let err = Deduplicator::new(config.clone())
+		.run()
+		.unwrap_err()
+		.to_string();
+	assert!(
+		err.contains("FileTwo.graphql.ts (already deduplicated by Relay"),
+		"{}",
+		err
+	);
+	assert!(
+		err.contains("Handwritten.graphql.ts (no @generated header)"),
+		"{}",
+		err
+	);
+	assert!(err.contains("relay-dedup init"), "{}", err);
+
+	// Nothing was written
+	assert_eq!(
+		fs::read_to_string(dir.join("FileOne.graphql.ts")).unwrap(),
+		before
+	);
+	assert!(!dir.join("__shared.ts").exists());
+
+	// Excluded files don't block the run
+	let config = Config {
+		exclude_globs: vec![
+			"FileTwo.graphql.ts".to_string(),
+			"Handwritten.graphql.ts".to_string(),
+		],
+		..config
+	};
+	Deduplicator::new(config).run().unwrap();
+}
+
+/// Rewrite the input fixtures in `dir` to the legacy `_xxxxxxxx` format,
+/// returning the legacy shared module
+fn write_legacy_artifacts(dir: &std::path::Path) -> PathBuf {
+	let argument = "{\n        \"defaultValue\": null,\n        \"kind\": \"LocalArgument\",\n        \"name\": \"id\"\n      }";
+	let legacy_path = dir.join("legacy").join("__shared.ts");
+	fs::create_dir_all(legacy_path.parent().unwrap()).unwrap();
+	fs::write(
+		&legacy_path,
+		"/** @generated */\n\
+		 export const _0000000b = \"id\";\n\
+		 export const _0000000a = {\"defaultValue\": null, \"kind\": \"LocalArgument\", \"name\": _0000000b};\n",
+	)
+	.unwrap();
+	for name in ["FileOne", "FileTwo", "FileThree"] {
+		let path = dir.join(format!("{}.graphql.ts", name));
+		let content = fs::read_to_string(&path).unwrap();
+		assert!(content.contains(argument));
+		let content = content.replace(argument, "_0000000a").replacen(
+			"import type",
+			"import { _0000000a } from \"./legacy/__shared\";\nimport type",
+			1,
+		);
+		fs::write(&path, content).unwrap();
+	}
+	legacy_path
+}
+
+#[test]
+fn test_migrate_legacy_references() {
+	let temp = copy_fixtures("input");
+	let dir = temp.path();
+
+	// Artifacts in the legacy format import their shared structures
+	let legacy_path = write_legacy_artifacts(dir);
+
+	// A normal run refuses the legacy format
+	let config = Config {
+		generated_dir: dir.to_path_buf(),
+		..Config::default()
+	};
+	let err = Deduplicator::new(config.clone())
+		.run()
+		.unwrap_err()
+		.to_string();
+	assert!(err.contains("legacy _xxxxxxxx references"), "{}", err);
+	assert!(err.contains("relay-dedup migrate"), "{}", err);
+
+	// An unresolvable reference stops the migration before anything is written
+	let config = Config {
+		legacy_shared_module: Some(legacy_path.clone()),
+		..config
+	};
+	let path = dir.join("FileThree.graphql.ts");
+	let content = fs::read_to_string(&path).unwrap();
+	let unresolved = content.replace("\n      _0000000a", "\n      _0000000f");
+	fs::write(&path, unresolved).unwrap();
+	let before = fs::read_to_string(dir.join("FileOne.graphql.ts")).unwrap();
+	let mut deduplicator = Deduplicator::new(config.clone());
+	let err = deduplicator.run().unwrap_err().to_string();
+	assert!(
+		err.contains("error: legacy reference _0000000f can't be resolved"),
+		"{}",
+		err
+	);
+	assert_eq!(deduplicator.diagnostics.count(Severity::Error), 2);
+	assert_eq!(
+		fs::read_to_string(dir.join("FileOne.graphql.ts")).unwrap(),
+		before
+	);
+	assert!(legacy_path.exists());
+	assert!(!dir.join("__shared.ts").exists());
+
+	// Once every reference resolves, the artifacts move to the x_ format
+	fs::write(&path, content).unwrap();
+	let stats = Deduplicator::new(config).run().unwrap();
+	assert!(stats.total_extracted > 0);
+	for name in ["FileOne", "FileTwo", "FileThree"] {
+		let content = fs::read_to_string(dir.join(format!("{}.graphql.ts", name))).unwrap();
+		assert!(!content.contains("_0000000"), "{}", content);
+		assert!(!content.contains("legacy"), "{}", content);
+		assert!(content.contains("from \"./__shared\""), "{}", content);
+	}
+	let shared = fs::read_to_string(dir.join("__shared.ts")).unwrap();
+	assert!(shared.contains("\"LocalArgument\""), "{}", shared);
+	assert!(!legacy_path.exists());
+}
+
+#[test]
+fn test_cost_model_and_threshold_mode() {
+	use relay_dedup::cost::ExtractionModel;
+
+	// A short path repeated in every artifact, next to a long field
+	let temp = tempfile::tempdir().unwrap();
+	for i in 0..20 {
+		fs::write(
+			temp.path().join(format!("Query{}.graphql.ts", i)),
+			format!(
+				r#"/** @generated */
+const node = {{
+  "name": "Query{}",
+  "field": {{"alias": null, "args": null, "kind": "ScalarField", "name": "viewer", "storageKey": null}},
+  "a": ["node", "id"],
+  "b": ["node", "id"],
+  "c": ["node", "id"]
+}};
+export default node;
+"#,
+				i
+			),
+		)
+		.unwrap();
+	}
+	let config = Config {
+		generated_dir: temp.path().to_path_buf(),
+		protected_paths: Vec::new(),
+		..Config::default()
+	};
+	let threshold = Deduplicator::new(Config {
+		extraction_model: ExtractionModel::Threshold,
+		dry_run: true,
+		..config.clone()
+	})
+	.run()
+	.unwrap();
+	// The path is shorter than 15 bytes
+	assert_eq!(threshold.total_extracted, 1);
+
+	let stats = Deduplicator::new(config).run().unwrap();
+	assert_eq!(stats.total_extracted, 2);
+	assert!(stats.raw_after < stats.raw_before);
+	let content = fs::read_to_string(temp.path().join("Query7.graphql.ts")).unwrap();
+	assert!(strip_ws(&content).contains("\"a\":x_"), "{}", content);
+
+	// Extraction never costs more than it saves, unlike fixed thresholds
+	for set in ["input", "flow"] {
+		let temp = copy_fixtures(set);
+		let config = Config {
+			generated_dir: temp.path().to_path_buf(),
+			language: if set == "flow" {
+				Language::Flow
+			} else {
+				Language::TypeScript
+			},
+			dry_run: true,
+			..Config::default()
+		};
+		let cost = Deduplicator::new(config.clone()).run().unwrap();
+		let threshold = Deduplicator::new(Config {
+			extraction_model: ExtractionModel::Threshold,
+			..config
+		})
+		.run()
+		.unwrap();
+		assert!(
+			cost.raw_after <= threshold.raw_after,
+			"{}: {} > {}",
+			set,
+			cost.raw_after,
+			threshold.raw_after
+		);
+	}
+}
+
+#[test]
+fn test_file_local_duplicates_are_hoisted() {
+	let temp = tempfile::tempdir().unwrap();
+	let dir = temp.path();
+	let field = |name: &str| {
+		format!(
+			"{{\"alias\": null, \"args\": null, \"kind\": \"ScalarField\", \"name\": \"{}\", \"storageKey\": null}}",
+			name
+		)
+	};
+	let refetch = "{\"kind\": \"Refetch\", \"operation\": require('./RefetchQuery.graphql'), \"path\": [\"viewer\"]}";
+	fs::write(
+		dir.join("Big.graphql.ts"),
+		format!(
+			"/** @generated */\nimport type {{ ConcreteRequest }} from 'relay-runtime';\n\nconst node: ConcreteRequest = {{\n  \"a\": [{local}, {shared}],\n  \"b\": [{local}],\n  \"c\": {local},\n  \"r1\": {refetch},\n  \"r2\": {refetch}\n}};\n\nexport default node;\n",
+			local = field("onlyInBig"),
+			shared = field("id"),
+			refetch = refetch,
+		),
+	)
+	.unwrap();
+	fs::write(
+		dir.join("Small.graphql.ts"),
+		format!(
+			"/** @generated */\nconst node = {{\"a\": {id}, \"b\": {id}, \"c\": {id}}};\nexport default node;\n",
+			id = field("id")
+		),
+	)
+	.unwrap();
+
+	let stats = Deduplicator::new(Config {
+		generated_dir: dir.to_path_buf(),
+		..Config::default()
+	})
+	.run()
+	.unwrap();
+	assert_eq!(stats.hoisted, 2);
+
+	let big = fs::read_to_string(dir.join("Big.graphql.ts")).unwrap();
+	let shared = fs::read_to_string(dir.join("__shared.ts")).unwrap();
+	assert!(!shared.contains("onlyInBig"), "{}", shared);
+	assert!(!shared.contains("Refetch"), "{}", shared);
+	assert!(strip_ws(&shared).contains("\"name\":\"id\""), "{}", shared);
+
+	// Declared once, before the node, with the module it refers to
+	assert_eq!(big.matches("onlyInBig").count(), 1, "{}", big);
+	let local = big.find("const l_").unwrap();
+	assert!(local < big.find("const node").unwrap(), "{}", big);
+	assert!(
+		big.contains("= require(\"./RefetchQuery.graphql\");\n"),
+		"{}",
+		big
+	);
+	assert_eq!(big.matches("RefetchQuery").count(), 1, "{}", big);
+	assert!(strip_ws(&big).contains("\"c\":l_"), "{}", big);
+	assert!(big.contains("import { x_"), "{}", big);
+}
+
+#[test]
+fn test_near_duplicates_become_spreads() {
+	let temp = tempfile::tempdir().unwrap();
+	let dir = temp.path();
+	let scalar = |name: &str| {
+		format!(
+			"{{\"alias\": null, \"args\": null, \"kind\": \"ScalarField\", \"name\": \"{}\", \"storageKey\": null}}",
+			name
+		)
+	};
+	for (file, field) in [("A", "author"), ("B", "editor"), ("C", "owner")] {
+		fs::write(
+			dir.join(format!("{}.graphql.ts", file)),
+			format!(
+				"/** @generated */\nconst node = {{\n  \"field\": {{\"alias\": null, \"args\": null, \"concreteType\": \"User\", \"kind\": \"LinkedField\", \"name\": \"{field}\", \"plural\": false, \"selections\": [{id}, {name}], \"storageKey\": null}},\n  \"path\": [\"viewer\", \"accountSettings\", \"privacySettings\", \"notificationPreferences\", \"{field}\"]\n}};\nexport default node;\n",
+				field = field,
+				id = scalar("id"),
+				name = scalar("name"),
+			),
+		)
+		.unwrap();
+	}
+
+	let config = Config {
+		generated_dir: dir.to_path_buf(),
+		..Config::default()
+	};
+	let exact = Deduplicator::new(Config {
+		dry_run: true,
+		..config.clone()
+	})
+	.run()
+	.unwrap();
+	assert_eq!(exact.factored, 0);
+
+	let stats = Deduplicator::new(Config {
+		near_duplicates: true,
+		..config
+	})
+	.run()
+	.unwrap();
+	assert_eq!(stats.factored, 6);
+	assert!(stats.raw_after < exact.raw_after);
+
+	let content = fs::read_to_string(dir.join("A.graphql.ts")).unwrap();
+	let a = strip_ws(&content);
+	let shared = strip_ws(&fs::read_to_string(dir.join("__shared.ts")).unwrap());
+	assert!(a.contains("\"field\":{...x_"), "{}", a);
+	assert!(a.contains(",\"name\":\"author\"}"), "{}", a);
+	assert!(a.contains("\"path\":[...x_"), "{}", a);
+	assert!(a.contains(",\"author\"]"), "{}", a);
+	assert!(!shared.contains("author"), "{}", shared);
+	assert!(
+		shared.contains(
+			"[\"viewer\",\"accountSettings\",\"privacySettings\",\"notificationPreferences\"];"
+		),
+		"{}",
+		shared
+	);
+	// Every base is imported where it is spread
+	let import = content.lines().find(|l| l.starts_with("import {")).unwrap();
+	for name in a.split("...").skip(1).map(|s| &s[..s.find(',').unwrap()]) {
+		assert!(import.contains(name), "{} not in {}", name, import);
+	}
+}
+
+#[test]
+fn test_factored_base_keeps_source_formatting() {
+	for compact in [false, true] {
+		let temp = tempfile::tempdir().unwrap();
+		let dir = temp.path();
+		for (file, field) in [("A", "author"), ("B", "editor"), ("C", "owner")] {
+			fs::write(
+				dir.join(format!("{}.graphql.ts", file)),
+				format!(
+					"/** @generated */\nconst node = {{\n  \"selections\": [\n    {{\n      \"alias\": null,\n      \"args\": null,\n      \"concreteType\": \"User\",\n      \"kind\": \"LinkedField\",\n      \"name\": \"{field}\",\n      \"plural\": false,\n      \"storageKey\": null,\n      \"extra\": \"{field}-more\"\n    }}\n  ]\n}};\nexport default node;\n",
+					field = field
+				),
+			)
+			.unwrap();
+		}
+
+		Deduplicator::new(Config {
+			generated_dir: dir.to_path_buf(),
+			near_duplicates: true,
+			compact_shared: compact,
+			..Config::default()
+		})
+		.run()
+		.unwrap();
+
+		let content = fs::read_to_string(dir.join("A.graphql.ts")).unwrap();
+		let shared = fs::read_to_string(dir.join("__shared.ts")).unwrap();
+		let start = content.find("...").unwrap() + 3;
+		let name = &content[start..start + content[start..].find(',').unwrap()];
+		let (base, spread) = if compact {
+			(
+				r#"{"alias":null,"args":null,"concreteType":"User","kind":"LinkedField","plural":false,"storageKey":null}"#.to_string(),
+				format!(r#"{{...{},"extra":"author-more","name":"author"}}"#, name),
+			)
+		} else {
+			(
+				"{\n  \"alias\": null,\n  \"args\": null,\n  \"concreteType\": \"User\",\n  \"kind\": \"LinkedField\",\n  \"plural\": false,\n  \"storageKey\": null\n}".to_string(),
+				format!(
+					"    {{\n      ...{},\n      \"name\": \"author\",\n      \"extra\": \"author-more\"\n    }}\n",
+					name
+				),
+			)
+		};
+		assert!(
+			shared.contains(&format!("export const {}: RelayNode = {};", name, base)),
+			"{}",
+			shared
+		);
+		assert!(content.contains(&spread), "{}", content);
+	}
+}
+
+#[test]
+fn test_key_order_ignored_after_extraction() {
+	let temp = tempfile::tempdir().unwrap();
+	let edges = r#"{"alias": null, "args": null, "concreteType": "UserEdge", "kind": "LinkedField", "name": "edges", "plural": true, "storageKey": null}"#;
+	let page_info = r#"{"alias": null, "args": null, "concreteType": "PageInfo", "kind": "LinkedField", "name": "pageInfo", "plural": false, "storageKey": null}"#;
+	let connections = [
+		format!(
+			r#"{{"edges": {}, "pageInfo": {}, "name": "friendsConnection"}}"#,
+			edges, page_info
+		),
+		format!(
+			r#"{{"pageInfo": {}, "name": "friendsConnection", "edges": {}}}"#,
+			page_info, edges
+		),
+		format!(
+			r#"{{"name": "friendsConnection", "edges": {}, "pageInfo": {}}}"#,
+			edges, page_info
+		),
+	];
+	for (i, connection) in connections.iter().enumerate() {
+		fs::write(
+			temp.path().join(format!("Query{}.graphql.ts", i)),
+			format!(
+				"/** @generated */\nconst node = {{\"name\": \"Query{}\", \"connection\": {}}};\nexport default node;\n",
+				i, connection
+			),
+		)
+		.unwrap();
+	}
+
+	let stats = Deduplicator::new(Config {
+		generated_dir: temp.path().to_path_buf(),
+		..Config::default()
+	})
+	.run()
+	.unwrap();
+
+	// The connections only match once their children are names
+	assert_eq!(stats.total_extracted, 3);
+	let shared = strip_ws(&fs::read_to_string(temp.path().join("__shared.ts")).unwrap());
+	assert!(
+		shared.contains("={\"edges\":x_"),
+		"connection not extracted: {}",
+		shared
+	);
+	for i in 0..connections.len() {
+		let content =
+			fs::read_to_string(temp.path().join(format!("Query{}.graphql.ts", i))).unwrap();
+		assert!(
+			strip_ws(&content).contains("\"connection\":x_"),
+			"{}",
+			content
+		);
+	}
+}
+
+#[test]
+fn test_equivalent_literals_match() {
+	let temp = tempfile::tempdir().unwrap();
+	let spellings = [
+		r#"{"alias": null, "args": null, "concreteType": "User", "kind": "LinkedField", "name": "author", "plural": false, "storageKey": null, "count": 1.0}"#,
+		r#"{"alias": null, "args": null, "concreteType": "User", "kind": "LinkedField", "name": "author", "plural": false, "storageKey": null, "count": 1}"#,
+		r#"{"alias": null, "args": null, "concreteType": "User", "kind": "Linked\x46ield", "name": "author", "plural": false, "storageKey": null, "count": 1e0}"#,
+		r#"{"alias": null, "args": null, "concreteType": "User", "kind": "LinkedField", "name": "\/author", "plural": false, "storageKey": null, "count": 1}"#,
+	];
+	for (i, field) in spellings.iter().enumerate() {
+		fs::write(
+			temp.path().join(format!("Query{}.graphql.ts", i)),
+			format!(
+				"/** @generated */\nconst node = {{\"name\": \"Query{}\", \"field\": {}}};\nexport default node;\n",
+				i, field
+			),
+		)
+		.unwrap();
+	}
+
+	let stats = Deduplicator::new(Config {
+		generated_dir: temp.path().to_path_buf(),
+		..Config::default()
+	})
+	.run()
+	.unwrap();
+
+	// `"\/author"` is `"/author"`; the others only differ in spelling
+	assert_eq!(stats.total_extracted, 1);
+	let shared = strip_ws(&fs::read_to_string(temp.path().join("__shared.ts")).unwrap());
+	// The first occurrence's spelling is kept
+	assert!(
+		shared.contains("\"concreteType\":\"User\"") && shared.contains("\"count\":1.0"),
+		"{}",
+		shared
+	);
+	for i in 0..spellings.len() {
+		let content = strip_ws(
+			&fs::read_to_string(temp.path().join(format!("Query{}.graphql.ts", i))).unwrap(),
+		);
+		assert_eq!(content.contains("\"field\":x_"), i < 3, "{}", content);
+	}
+}
+
+#[test]
+fn test_order_sensitive_contexts_never_sorted() {
+	use relay_dedup::cost::ExtractionModel;
+
+	let temp = tempfile::tempdir().unwrap();
+	let field = |name: &str| {
+		format!(
+			r#"{{"alias": null, "args": null, "kind": "ScalarField", "name": "{}", "storageKey": null}}"#,
+			name
+		)
+	};
+	let (a, b, c) = (field("firstName"), field("lastName"), field("email"));
+	let selections = [
+		format!("[{}, {}, {}]", a, b, c),
+		format!("[{}, {}, {}]", c, b, a),
+	];
+	for (i, selections) in selections.iter().enumerate() {
+		fs::write(
+			temp.path().join(format!("Query{}.graphql.ts", i)),
+			format!(
+				"/** @generated */\nconst node = {{\"kind\": \"Fragment\", \"selections\": [\
+				 {{\"kind\": \"LinkedField\", \"name\": \"viewer\", \"selections\": {}}}, \
+				 {{\"kind\": \"Defer\", \"label\": \"Query{}$defer\", \"selections\": {}}}]}};\n\
+				 export default node;\n",
+				selections, i, selections
+			),
+		)
+		.unwrap();
+	}
+
+	Deduplicator::new(Config {
+		generated_dir: temp.path().to_path_buf(),
+		order_rules: OrderRules::parse(&["selections"]).unwrap(),
+		extraction_model: ExtractionModel::Threshold,
+		..Config::default()
+	})
+	.run()
+	.unwrap();
+
+	let mut viewers = HashSet::new();
+	for i in 0..selections.len() {
+		let content = strip_ws(
+			&fs::read_to_string(temp.path().join(format!("Query{}.graphql.ts", i))).unwrap(),
+		);
+		// The field matches whatever its selections' order...
+		let viewer = content
+			.split("\"kind\":\"Fragment\",\"selections\":[")
+			.nth(1)
+			.and_then(|rest| rest.split(',').next())
+			.unwrap_or_default();
+		assert!(viewer.starts_with("x_"), "{}", content);
+		viewers.insert(viewer.to_string());
+		// ...but the Defer's selections keep theirs, and so stay apart
+		assert!(
+			content.contains(&format!("\"Query{}$defer\",\"selections\":[x_", i)),
+			"{}",
+			content
+		);
+	}
+	assert_eq!(viewers.len(), 1);
+}
+
+#[test]
+fn test_shared_definitions_keep_source_order() {
+	let field = [
+		r#"{"name": "author", "kind": "LinkedField", "alias": null, "args": null, "concreteType": "User", "plural": false, "storageKey": null}"#,
+		r#"{"alias": null, "args": null, "concreteType": "User", "kind": "LinkedField", "name": "author", "plural": false, "storageKey": null}"#,
+	];
+	let run = |compact_shared: bool| {
+		let temp = tempfile::tempdir().unwrap();
+		for (i, field) in field.iter().enumerate() {
+			fs::write(
+				temp.path().join(format!("Query{}.graphql.ts", i)),
+				format!(
+					"/** @generated */\nconst node = {{\"name\": \"Query{}\", \"field\": {}}};\nexport default node;\n",
+					i, field
+				),
+			)
+			.unwrap();
+		}
+		let stats = Deduplicator::new(Config {
+			generated_dir: temp.path().to_path_buf(),
+			compact_shared,
+			..Config::default()
+		})
+		.run()
+		.unwrap();
+		assert_eq!(stats.total_extracted, 1);
+		fs::read_to_string(temp.path().join("__shared.ts")).unwrap()
+	};
+
+	// The first occurrence, as written
+	let shared = run(false);
+	assert!(shared.contains(field[0]), "{}", shared);
+	// Normalized: keys sorted, no whitespace
+	let shared = run(true);
+	assert!(shared.contains(&strip_ws(field[1])), "{}", shared);
+}
+
+#[test]
+fn test_name_lockfile() {
+	use relay_dedup::lockfile::NameLock;
+
+	let run = |temp: &tempfile::TempDir| {
+		// Every run starts from freshly compiled artifacts
+		for entry in fs::read_dir(temp.path()).unwrap() {
+			let path = entry.unwrap().path();
+			if path.to_string_lossy().ends_with(".graphql.ts") {
+				fs::remove_file(path).unwrap();
+			}
+		}
+		let fixtures = copy_fixtures("input");
+		for entry in fs::read_dir(fixtures.path()).unwrap() {
+			let entry = entry.unwrap();
+			fs::copy(entry.path(), temp.path().join(entry.file_name())).unwrap();
+		}
+		Deduplicator::new(Config {
+			generated_dir: temp.path().to_path_buf(),
+			..Config::default()
+		})
+		.run()
+		.unwrap();
+		let lock = NameLock::read(&temp.path().join("__shared.lock.json")).unwrap();
+		let shared = fs::read_to_string(temp.path().join("__shared.ts")).unwrap();
+		(lock, shared)
+	};
+
+	let temp = tempfile::tempdir().unwrap();
+	let (lock, shared) = run(&temp);
+	assert!(!lock.names.is_empty());
+	for name in lock.names.values() {
+		assert!(
+			shared.contains(&format!("export const {}:", name)),
+			"{}",
+			name
+		);
+	}
+
+	// A locked name is kept, and a name held by an entry that is gone is
+	// never given to another one; that entry is pruned afterwards
+	let (hash, name) = lock.names.iter().next().unwrap();
+	let (other_hash, other_name) = lock.names.iter().nth(1).unwrap();
+	let mut edited = lock.clone();
+	edited.names.insert(hash.clone(), format!("{}0", name));
+	edited.names.remove(other_hash);
+	edited.names.insert("0".repeat(32), other_name.clone());
+	edited
+		.write(&temp.path().join("__shared.lock.json"))
+		.unwrap();
+
+	let (lock, shared) = run(&temp);
+	assert_eq!(lock.names[hash], format!("{}0", name));
+	assert!(shared.contains(&format!("export const {}0:", name)));
+	assert_ne!(&lock.names[other_hash], other_name);
+	assert!(!lock.names.contains_key(&"0".repeat(32)));
+	assert!(!shared.contains(&format!("export const {}:", other_name)));
+}
+
+/// Run the relay-dedup binary in `dir` without inherited `RELAY_DEDUP_*` variables
+fn run_cli(dir: &std::path::Path, args: &[&str], envs: &[(&str, &str)]) -> std::process::Output {
+	let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_relay-dedup"));
+	command.current_dir(dir).args(args);
+	for (key, _) in std::env::vars().filter(|(k, _)| k.starts_with("RELAY_DEDUP_")) {
+		command.env_remove(key);
+	}
+	command.envs(envs.iter().copied());
+	command.output().unwrap()
+}
+
+#[test]
+fn test_cli_flags_override_options_file_and_env() {
+	let temp = copy_fixtures("flow");
+	fs::write(
+		temp.path().join("relay-dedup.config.json"),
+		r#"{"generatedDir": ".", "language": "flow", "dryRun": true, "verbose": true, "skipConfigCheck": true}"#,
+	)
+	.unwrap();
+	let artifact = temp.path().join("UserNameFragment.graphql.js");
+	let original = fs::read_to_string(&artifact).unwrap();
+
+	// The options file turns on dry run and verbose output
+	let output = run_cli(temp.path(), &[], &[]);
+	assert!(output.status.success(), "{:?}", output);
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert!(
+		stdout.contains("Using options from relay-dedup.config.json"),
+		"{}",
+		stdout
+	);
+	assert_eq!(fs::read_to_string(&artifact).unwrap(), original);
+
+	// The last of a flag and its negation wins; the environment also keeps dry run on
+	let output = run_cli(
+		temp.path(),
+		&["--no-dry-run", "--dry-run"],
+		&[("RELAY_DEDUP_DRY_RUN", "true")],
+	);
+	assert!(output.status.success(), "{:?}", output);
+	assert_eq!(fs::read_to_string(&artifact).unwrap(), original);
+
+	// --no-<flag> beats both the environment and the file
+	let output = run_cli(
+		temp.path(),
+		&["--no-dry-run", "--no-verbose"],
+		&[("RELAY_DEDUP_VERBOSE", "1")],
+	);
+	assert!(output.status.success(), "{:?}", output);
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert!(!stdout.contains("Using options from"), "{}", stdout);
+	assert!(stdout.starts_with("Extracted "), "{}", stdout);
+	assert_ne!(fs::read_to_string(&artifact).unwrap(), original);
+	assert!(temp.path().join("__shared.js").exists());
+
+	// A NaN weight would make every cost comparison false
+	let output = run_cli(temp.path(), &["--gzip-weight", "NaN"], &[]);
+	assert!(!output.status.success());
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(
+		stderr.contains("option 'gzipWeight' must be a number from 0 to 1"),
+		"{}",
+		stderr
+	);
+	let err = Deduplicator::new(Config {
+		generated_dir: temp.path().to_path_buf(),
+		gzip_weight: f64::NAN,
+		..Config::default()
+	})
+	.run()
+	.unwrap_err();
+	assert!(err.to_string().contains("'gzipWeight'"), "{}", err);
+}
+
+#[test]
+fn test_workspace_runs_every_project() {
+	let temp = tempfile::tempdir().unwrap();
+	let root = temp.path();
+	fs::write(
+		root.join("relay.config.json"),
+		r#"{
+			"sources": {
+				"web/src": "web",
+				"mobile/src": "mobile",
+				"admin/src": "admin",
+				"common": ["colo1", "colo2"]
+			},
+			"projects": {
+				"admin": { "output": "admin/__generated__" },
+				"colo1": {},
+				"colo2": {},
+				"mobile": { "output": "mobile/__generated__", "language": "flow" },
+				"web": { "output": "web/__generated__", "language": "typescript" }
+			}
+		}"#,
+	)
+	.unwrap();
+	let copy_into = |set: &str, dir: &std::path::Path| {
+		fs::create_dir_all(dir).unwrap();
+		let fixtures = copy_fixtures(set);
+		for entry in fs::read_dir(fixtures.path()).unwrap() {
+			let entry = entry.unwrap();
+			fs::copy(entry.path(), dir.join(entry.file_name())).unwrap();
+		}
+	};
+	copy_into("input", &root.join("web/__generated__"));
+	copy_into("flow", &root.join("mobile/__generated__"));
+	copy_into("input", &root.join("common/feature/__generated__"));
+	// The first project fails; the others still run
+	fs::create_dir_all(root.join("admin/__generated__")).unwrap();
+	fs::write(
+		root.join("admin/__generated__/Handwritten.graphql.ts"),
+		"export const node = { \"kind\": \"Fragment\" };\n",
+	)
+	.unwrap();
+
+	let workspace = root.to_str().unwrap();
+	let output = run_cli(
+		root,
+		&["--workspace", workspace, "--skip-config-check"],
+		&[],
+	);
+	assert!(!output.status.success());
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(stderr.contains("1 of 4 projects failed"), "{}", stderr);
+	assert!(
+		stderr.contains("admin (relay.config.json): Refusing to process"),
+		"{}",
+		stderr
+	);
+
+	let table = &stdout[stdout.find("\nProject ").expect("summary table")..];
+	let row = |label: &str| {
+		table
+			.lines()
+			.find(|l| l.starts_with(label))
+			.unwrap_or_else(|| panic!("no row for {} in {}", label, stdout))
+			.to_string()
+	};
+	assert!(row("admin (relay.config.json)").contains("FAILED"));
+	assert!(row("mobile (relay.config.json)").contains("ok: "));
+	assert!(row("web (relay.config.json)").contains("ok: "));
+	// Projects sharing a source root are processed once
+	assert!(row("colo1 (relay.config.json), colo2 (relay.config.json)").contains("ok: "));
+	assert!(stdout.contains("Processed 4 projects"), "{}", stdout);
+
+	assert!(root.join("web/__generated__/__shared.ts").exists());
+	assert!(root.join("mobile/__generated__/__shared.js").exists());
+	assert!(root.join("common/__generated__/__shared.ts").exists());
+	assert!(!root.join("admin/__generated__/__shared.ts").exists());
+
+	// A single shared module for several projects is refused up front
+	let output = run_cli(
+		root,
+		&[
+			"--workspace",
+			workspace,
+			"--shared-module",
+			"lib/__shared.ts",
+		],
+		&[],
+	);
+	assert!(!output.status.success());
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(
+		stderr.contains("can't be used with 4 projects"),
+		"{}",
+		stderr
+	);
+	assert!(!root.join("lib").exists());
+}
+
+#[test]
+fn test_doctor_reports_installed_versions() {
+	let temp = tempfile::tempdir().unwrap();
+	let root = temp.path();
+	fs::write(
+		root.join("relay.config.json"),
+		r#"{
+			"src": "./src",
+			"artifactDirectory": "./src/__generated__",
+			"language": "typescript",
+			"featureFlags": {
+				"disable_deduping_common_structures_in_artifacts": { "kind": "enabled" },
+				"enforce_fragment_alias_where_ambiguous": { "kind": "disabled" }
+			}
+		}"#,
+	)
+	.unwrap();
+	let generated = root.join("src/__generated__");
+	fs::create_dir_all(&generated).unwrap();
+	let fixtures = copy_fixtures("input");
+	for entry in fs::read_dir(fixtures.path()).unwrap() {
+		let entry = entry.unwrap();
+		fs::copy(entry.path(), generated.join(entry.file_name())).unwrap();
+	}
+	let install = |version: &str| {
+		for package in ["relay-compiler", "relay-runtime"] {
+			let dir = root.join("node_modules").join(package);
+			fs::create_dir_all(&dir).unwrap();
+			fs::write(
+				dir.join("package.json"),
+				format!(r#"{{ "name": "{}", "version": "{}" }}"#, package, version),
+			)
+			.unwrap();
+		}
+	};
+
+	install("18.2.0");
+	let output = run_cli(root, &["doctor"], &[]);
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert!(output.status.success(), "{}", stdout);
+	assert!(
+		stdout.contains("[ok] relay-compiler 18.2.0\n"),
+		"{}",
+		stdout
+	);
+	assert!(stdout.contains("[ok] relay-runtime 18.2.0\n"), "{}", stdout);
+	assert!(
+		stdout.contains(
+			"[ok] relay config: disable_deduping_common_structures_in_artifacts is enabled\n"
+		),
+		"{}",
+		stdout
+	);
+	assert!(
+		stdout.contains("[ok] src/__generated__: 3 artifacts can be processed\n"),
+		"{}",
+		stdout
+	);
+	assert!(stdout.ends_with("No problems found.\n"), "{}", stdout);
+
+	// Nightlies are newer than the table, not unsupported
+	install("0.0.0-main-ab12cd34");
+	let output = run_cli(root, &["doctor"], &[]);
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	assert!(output.status.success(), "{}", stdout);
+	assert!(
+		stdout.contains("[warn] relay-compiler is a nightly build"),
+		"{}",
+		stdout
+	);
+
+	install("12.0.0");
+	let output = run_cli(root, &["doctor"], &[]);
+	let stdout = String::from_utf8_lossy(&output.stdout);
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(!output.status.success());
+	assert!(
+		stdout.contains("[error] relay-compiler 12.0.0 is not supported"),
+		"{}",
+		stdout
+	);
+	assert!(stderr.contains("doctor found 1 problem(s)"), "{}", stderr);
+}
+
+#[test]
+fn test_migrate_subcommand_takes_the_directory() {
+	let temp = tempfile::tempdir().unwrap();
+	let dir = temp.path().join("gen");
+	fs::create_dir_all(&dir).unwrap();
+	let fixtures = copy_fixtures("input");
+	for entry in fs::read_dir(fixtures.path()).unwrap() {
+		let entry = entry.unwrap();
+		fs::copy(entry.path(), dir.join(entry.file_name())).unwrap();
+	}
+	let legacy_path = write_legacy_artifacts(&dir);
+
+	// Directory and options after the subcommand, as documented
+	let output = run_cli(
+		temp.path(),
+		&[
+			"migrate",
+			"gen",
+			"--from",
+			"gen/legacy/__shared.ts",
+			"--skip-config-check",
+		],
+		&[],
+	);
+	assert!(output.status.success(), "{:?}", output);
+	for name in ["FileOne", "FileTwo", "FileThree"] {
+		let content = fs::read_to_string(dir.join(format!("{}.graphql.ts", name))).unwrap();
+		assert!(!content.contains("_0000000"), "{}", content);
+		assert!(content.contains("from \"./__shared\""), "{}", content);
+	}
+	assert!(!legacy_path.exists());
+
+	// Given on both sides of the subcommand, the directory is ambiguous
+	let output = run_cli(temp.path(), &["gen", "migrate", "gen"], &[]);
+	assert!(!output.status.success());
+	let stderr = String::from_utf8_lossy(&output.stderr);
+	assert!(stderr.contains("not both"), "{}", stderr);
+}
+
+#[test]
+fn test_rerun_keeps_lockfile_and_names() {
+	let temp = copy_fixtures("input");
+	let config = Config {
+		generated_dir: temp.path().to_path_buf(),
+		..Config::default()
+	};
+	let snapshot = || {
+		let mut files = Vec::new();
+		for entry in fs::read_dir(temp.path()).unwrap() {
+			let path = entry.unwrap().path();
+			files.push((path.clone(), fs::read_to_string(&path).unwrap()));
+		}
+		files.sort();
+		files
+	};
+
+	let stats = Deduplicator::new(config.clone()).run().unwrap();
+	assert!(stats.total_extracted > 0);
+	let first = snapshot();
+	assert!(temp.path().join("__shared.lock.json").exists());
+
+	// Nothing is left to extract, so nothing changes, the lockfile included
+	let stats = Deduplicator::new(config).run().unwrap();
+	assert_eq!(stats.total_extracted, 0);
+	assert_eq!(snapshot(), first);
+}